@@ -0,0 +1,144 @@
+use crate::error::{Error, Result};
+use serde::Deserialize;
+
+// Digits after the decimal point of a CKB amount, one shannon being 10^-8 CKB
+pub const CKB_DECIMALS: u8 = 8;
+
+/// An amount in a config file: an integer counting whole units of the asset (base
+/// units for tokens, whole CKB for CKB), or a string in human units with optional `_`
+/// separators, a fractional part and the asset's symbol, e.g. `"10_000.5 RUSD"`
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum AmountConfig {
+    Units(u64),
+    Text(String),
+}
+
+impl AmountConfig {
+    /// Base units of the amount for an asset with `symbol` and `decimals`, where an
+    /// integer counts `unit` base units
+    pub fn base_units(&self, symbol: &str, decimals: u8, unit: u128) -> Result<u128> {
+        match self {
+            AmountConfig::Units(units) => u128::from(*units).checked_mul(unit).ok_or_else(|| {
+                Error::InvalidAmount(format!("{} amount {} is too large", symbol, units))
+            }),
+            AmountConfig::Text(text) => parse_amount(text, symbol, decimals),
+        }
+    }
+}
+
+/// Parse an amount in human units, like `10_000.5` or `10_000.5 RUSD`, into base
+/// units of an asset with `symbol` and `decimals`. A symbol, matched
+/// case-insensitively, must be the asset's.
+pub fn parse_amount(text: &str, symbol: &str, decimals: u8) -> Result<u128> {
+    let invalid =
+        |reason: &str| Error::InvalidAmount(format!("Invalid amount {:?}: {}", text, reason));
+    let mut parts = text.split_whitespace();
+    let number = parts.next().ok_or_else(|| invalid("empty"))?;
+    if let Some(unit) = parts.next()
+        && !unit.eq_ignore_ascii_case(symbol)
+    {
+        return Err(invalid(&format!("expected a {} amount", symbol)));
+    }
+    if parts.next().is_some() {
+        return Err(invalid("expected a number and a symbol"));
+    }
+
+    let number = number.replace('_', "");
+    let (whole, fraction) = number.split_once('.').unwrap_or((&number, ""));
+    if whole.is_empty() && fraction.is_empty()
+        || !whole
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid("not a number"));
+    }
+    if fraction.len() > usize::from(decimals) {
+        return Err(invalid(&format!("{} has {} decimals", symbol, decimals)));
+    }
+    let digits = format!(
+        "{}{:0<width$}",
+        whole,
+        fraction,
+        width = usize::from(decimals)
+    );
+    let digits = digits.trim_start_matches('0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    digits.parse().map_err(|_| invalid("too large"))
+}
+
+/// Format base units of an asset with `decimals` in human units, without trailing
+/// zeros in the fractional part
+pub fn format_amount(amount: u128, decimals: u8) -> String {
+    let scale = 10u128.pow(u32::from(decimals));
+    let (whole, fraction) = (amount / scale, amount % scale);
+    if fraction == 0 {
+        return whole.to_string();
+    }
+    let fraction = format!("{:0width$}", fraction, width = usize::from(decimals));
+    format!("{}.{}", whole, fraction.trim_end_matches('0'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_without_trailing_zeros() {
+        assert_eq!(format_amount(0, CKB_DECIMALS), "0");
+        assert_eq!(format_amount(61_00000000, CKB_DECIMALS), "61");
+        assert_eq!(format_amount(61_50000000, CKB_DECIMALS), "61.5");
+        assert_eq!(format_amount(1, CKB_DECIMALS), "0.00000001");
+        assert_eq!(format_amount(1_050, 2), "10.5");
+        assert_eq!(format_amount(42, 0), "42");
+        assert_eq!(
+            format_amount(u128::MAX, 0),
+            "340282366920938463463374607431768211455"
+        );
+    }
+
+    #[test]
+    fn parses_human_units() {
+        assert_eq!(parse_amount("61", "CKB", 8).unwrap(), 61_00000000);
+        assert_eq!(parse_amount("10_000.5 rusd", "RUSD", 2).unwrap(), 1_000_050);
+        assert_eq!(parse_amount(".5", "CKB", 8).unwrap(), 50000000);
+        assert_eq!(parse_amount("0.0", "CKB", 8).unwrap(), 0);
+        assert!(parse_amount("", "CKB", 8).is_err());
+        assert!(parse_amount(".", "CKB", 8).is_err());
+        assert!(parse_amount("1.234", "RUSD", 2).is_err());
+        assert!(parse_amount("1 CKB", "RUSD", 2).is_err());
+        assert!(parse_amount("1 RUSD extra", "RUSD", 2).is_err());
+        assert!(parse_amount("-1", "CKB", 8).is_err());
+        assert!(parse_amount("1e5", "CKB", 8).is_err());
+        assert!(parse_amount("340282366920938463463374607431768211456", "UDT", 0).is_err());
+    }
+
+    #[test]
+    fn parse_and_format_round_trip() {
+        for amount in [0, 1, 99, 100, 123_456_789, 61_00000000] {
+            for decimals in [0, 2, 8] {
+                let text = format_amount(amount, decimals);
+                assert_eq!(parse_amount(&text, "X", decimals).unwrap(), amount);
+            }
+        }
+    }
+
+    #[test]
+    fn config_integers_count_units() {
+        let units = AmountConfig::Units(61);
+        assert_eq!(
+            units.base_units("CKB", 8, 100_000_000).unwrap(),
+            61_00000000
+        );
+        assert!(
+            AmountConfig::Units(u64::MAX)
+                .base_units("CKB", 8, u128::MAX)
+                .is_err()
+        );
+        let text = AmountConfig::Text("61.5 CKB".to_string());
+        assert_eq!(text.base_units("CKB", 8, 100_000_000).unwrap(), 61_50000000);
+    }
+}
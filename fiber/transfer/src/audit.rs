@@ -0,0 +1,90 @@
+use ckb_jsonrpc_types::Script;
+use ckb_types::core::TransactionView;
+use ckb_types::prelude::*;
+use serde_json::{Map, Value, json};
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// Append-only JSON-lines record of what runs did, overridable via AUDIT_LOG env var; an
+// empty value turns it off
+const DEFAULT_AUDIT_LOG: &str = "transfer-audit.jsonl";
+
+/// Append an `event` line to the audit log, with the time in Unix milliseconds, the
+/// process id telling runs apart, and `fields`. A failure to write is warned about once
+/// and never fails the run.
+pub fn record(event: &str, fields: Value) {
+    static LOG: OnceLock<Option<Mutex<File>>> = OnceLock::new();
+    let log = LOG.get_or_init(|| {
+        let path = env::var("AUDIT_LOG").unwrap_or_else(|_| DEFAULT_AUDIT_LOG.to_string());
+        if path.is_empty() {
+            return None;
+        }
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                warn!("Failed to open audit log {}, not recording: {}", path, e);
+                None
+            }
+        }
+    });
+    let Some(file) = log else {
+        return;
+    };
+
+    let mut line = Map::new();
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    line.insert("time_ms".into(), millis.into());
+    line.insert("pid".into(), process::id().into());
+    line.insert("event".into(), event.into());
+    if let Value::Object(fields) = fields {
+        line.extend(fields);
+    }
+    let mut text = Value::Object(line).to_string();
+    text.push('\n');
+    if let Err(e) = file.lock().unwrap().write_all(text.as_bytes()) {
+        static REPORTED: OnceLock<()> = OnceLock::new();
+        REPORTED.get_or_init(|| warn!("Failed to write the audit log: {}", e));
+    }
+}
+
+/// The inputs and outputs of `tx`, as the audit log records a built transaction
+pub fn transaction_fields(tx: &TransactionView) -> Value {
+    let inputs: Vec<Value> = tx
+        .inputs()
+        .into_iter()
+        .map(|input| {
+            let out_point = input.previous_output();
+            let since: u64 = input.since().unpack();
+            json!({
+                "tx_hash": format!("{:#x}", out_point.tx_hash()),
+                "index": Unpack::<u32>::unpack(&out_point.index()),
+                "since": since,
+            })
+        })
+        .collect();
+    let outputs: Vec<Value> = tx
+        .outputs_with_data_iter()
+        .map(|(output, data)| {
+            let capacity: u64 = output.capacity().unpack();
+            json!({
+                "capacity": capacity,
+                "lock": Script::from(output.lock()),
+                "type": output.type_().to_opt().map(Script::from),
+                "data_len": data.len(),
+            })
+        })
+        .collect();
+    json!({
+        "tx_hash": format!("{:#x}", tx.hash()),
+        "size": tx.data().serialized_size_in_block(),
+        "inputs": inputs,
+        "outputs": outputs,
+    })
+}
@@ -0,0 +1,209 @@
+use crate::error::Error;
+use ckb_jsonrpc_types::OutPoint;
+use ckb_sdk::rpc::ckb_indexer::{Cell, SearchKey};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long a cached cell set is only topped up with the cells of new blocks before it
+// is collected from the indexer in full again, overridable via CELL_CACHE_TTL_SECS
+// env var
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// Live cells collected from the indexer per search key, e.g. per lock script. A set is
+/// kept up to date by adding the cells of the blocks indexed since it was collected and
+/// dropping the cells spent by transactions this tool sends, so repeated lookups only
+/// page through new blocks. Cells spent by anyone else stay in the set until it expires,
+/// or until a transaction spending them is refused for unresolvable inputs.
+pub struct CellCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    cells: Vec<Cell>,
+    /// Last block whose cells are in the set
+    tip: u64,
+    collected_at: Instant,
+}
+
+/// A cached cell set, with the last block whose cells are in it
+pub struct Cached {
+    pub cells: Vec<Cell>,
+    pub tip: u64,
+}
+
+impl CellCache {
+    /// The cache, unless disabled with CELL_CACHE=0
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        if env::var("CELL_CACHE").is_ok_and(|v| v == "0") {
+            return Ok(None);
+        }
+        Ok(Some(CellCache {
+            ttl: crate::rpc::env_secs("CELL_CACHE_TTL_SECS")?.unwrap_or(DEFAULT_TTL),
+            entries: Mutex::default(),
+        }))
+    }
+
+    /// The cells cached for `search_key`, `None` when there are none or they expired
+    pub fn get(&self, search_key: &SearchKey) -> Option<Cached> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&cache_key(search_key))
+            .filter(|entry| entry.collected_at.elapsed() < self.ttl)
+            .map(|entry| Cached {
+                cells: entry.cells.clone(),
+                tip: entry.tip,
+            })
+    }
+
+    /// Cache the cells collected in full for `search_key` up to block `tip`
+    pub fn insert(&self, search_key: &SearchKey, cells: Vec<Cell>, tip: u64) {
+        let entry = Entry {
+            cells,
+            tip,
+            collected_at: Instant::now(),
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(cache_key(search_key), entry);
+    }
+
+    /// Add the cells of the blocks after the cached ones up to `tip`, keeping when the
+    /// set was collected in full
+    pub fn extend(&self, search_key: &SearchKey, cells: Vec<Cell>, tip: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&cache_key(search_key))
+            && entry.tip < tip
+        {
+            entry.cells.extend(cells);
+            entry.tip = tip;
+        }
+    }
+
+    /// Drop the cells spent by a transaction this tool sent from every cached set
+    pub fn spend(&self, out_points: &[OutPoint]) {
+        let mut entries = self.entries.lock().unwrap();
+        for entry in entries.values_mut() {
+            entry
+                .cells
+                .retain(|cell| !out_points.contains(&cell.out_point));
+        }
+    }
+
+    /// Drop every cached set holding one of `out_points`, so the next lookup collects
+    /// it in full. For inputs the node refused as dead or unknown.
+    pub fn invalidate(&self, out_points: &[OutPoint]) {
+        self.entries.lock().unwrap().retain(|_, entry| {
+            !entry
+                .cells
+                .iter()
+                .any(|cell| out_points.contains(&cell.out_point))
+        });
+    }
+}
+
+fn cache_key(search_key: &SearchKey) -> String {
+    serde_json::to_string(search_key).expect("Search keys serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_jsonrpc_types::{CellOutput, JsonBytes, Script, ScriptHashType};
+    use ckb_sdk::rpc::ckb_indexer::ScriptType;
+    use ckb_types::H256;
+
+    fn search_key(args: u8) -> SearchKey {
+        SearchKey {
+            script: Script {
+                code_hash: H256::default(),
+                hash_type: ScriptHashType::Type,
+                args: JsonBytes::from_vec(vec![args]),
+            },
+            script_type: ScriptType::Lock,
+            script_search_mode: None,
+            filter: None,
+            with_data: None,
+            group_by_transaction: None,
+        }
+    }
+
+    fn cell(index: u32) -> Cell {
+        Cell {
+            output: CellOutput {
+                capacity: 61_00000000u64.into(),
+                lock: search_key(0).script,
+                type_: None,
+            },
+            output_data: None,
+            out_point: OutPoint {
+                tx_hash: H256::default(),
+                index: index.into(),
+            },
+            block_number: 1u64.into(),
+            tx_index: 0u32.into(),
+        }
+    }
+
+    fn cache(ttl: Duration) -> CellCache {
+        CellCache {
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    fn indices(cached: &Cached) -> Vec<u32> {
+        cached
+            .cells
+            .iter()
+            .map(|cell| cell.out_point.index.value())
+            .collect()
+    }
+
+    #[test]
+    fn extends_only_with_newer_blocks() {
+        let cache = cache(DEFAULT_TTL);
+        cache.insert(&search_key(1), vec![cell(0)], 10);
+        cache.extend(&search_key(1), vec![cell(1)], 12);
+        // A lookup that raced with the one above must not add its cells twice
+        cache.extend(&search_key(1), vec![cell(1)], 11);
+        let cached = cache.get(&search_key(1)).unwrap();
+        assert_eq!(indices(&cached), [0, 1]);
+        assert_eq!(cached.tip, 12);
+        // Sets that were never collected in full are not started by an extension
+        cache.extend(&search_key(2), vec![cell(2)], 12);
+        assert!(cache.get(&search_key(2)).is_none());
+    }
+
+    #[test]
+    fn drops_spent_cells_from_every_set() {
+        let cache = cache(DEFAULT_TTL);
+        cache.insert(&search_key(1), vec![cell(0), cell(1)], 10);
+        cache.insert(&search_key(2), vec![cell(1), cell(2)], 10);
+        cache.spend(&[cell(1).out_point]);
+        assert_eq!(indices(&cache.get(&search_key(1)).unwrap()), [0]);
+        assert_eq!(indices(&cache.get(&search_key(2)).unwrap()), [2]);
+    }
+
+    #[test]
+    fn invalidates_the_sets_holding_refused_inputs() {
+        let cache = cache(DEFAULT_TTL);
+        cache.insert(&search_key(1), vec![cell(0)], 10);
+        cache.insert(&search_key(2), vec![cell(1)], 10);
+        cache.invalidate(&[cell(1).out_point]);
+        assert!(cache.get(&search_key(1)).is_some());
+        assert!(cache.get(&search_key(2)).is_none());
+    }
+
+    #[test]
+    fn expires_after_the_ttl() {
+        let cache = cache(Duration::from_millis(20));
+        cache.insert(&search_key(1), vec![cell(0)], 10);
+        assert!(cache.get(&search_key(1)).is_some());
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(cache.get(&search_key(1)).is_none());
+    }
+}
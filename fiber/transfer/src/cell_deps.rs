@@ -0,0 +1,264 @@
+use crate::error::{Error, Result};
+use crate::get_genesis_block;
+use crate::network::{NetworkPreset, SudtScript, Token};
+use crate::rpc::{self, RetryingClient};
+use ckb_hash::blake2b_256;
+use ckb_jsonrpc_types::{BlockView, CellOutput, OutPoint, ScriptHashType};
+use ckb_sdk::constants::SIGHASH_TYPE_HASH;
+use ckb_types::H256;
+use ckb_types::core::DepType;
+use ckb_types::packed::{self, Byte, Script as PackedScript};
+use ckb_types::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Code hash, hash type and configured code cell of a contract deployment
+type Deployment = (H256, ScriptHashType, Option<(H256, u32)>);
+
+/// Resolves the cell deps of the scripts transfers use: the dep group of the sighash
+/// lock and the code cells of UDT contracts, from the network's deployment records
+/// when configured and from the genesis block otherwise. Each is looked up once.
+pub struct CellDepResolver {
+    /// Out point of the sighash dep group, `None` looks it up in the genesis block
+    sighash_dep_group: Option<(H256, u32)>,
+    genesis: RefCell<Option<BlockView>>,
+    sighash: RefCell<Option<packed::OutPoint>>,
+    /// Code cells by the deployment they were resolved for
+    code_cells: RefCell<HashMap<Deployment, packed::OutPoint>>,
+}
+
+impl CellDepResolver {
+    pub fn new(preset: &NetworkPreset) -> Self {
+        CellDepResolver {
+            sighash_dep_group: preset.sighash_dep_group.clone(),
+            genesis: RefCell::new(None),
+            sighash: RefCell::new(None),
+            code_cells: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Cell deps of a transfer spending sighash cells and cells of `tokens`
+    pub fn transfer(
+        &self,
+        client: &RetryingClient,
+        tokens: &[&Token],
+    ) -> Result<Vec<packed::CellDep>> {
+        let mut cell_deps = vec![cell_dep(self.sighash(client)?, DepType::DepGroup)];
+        // Tokens of the same contract share its code cell
+        let mut sudt_cell_deps = Vec::new();
+        for token in tokens {
+            let out_point = self.code_cell(client, &token.script)?;
+            if !sudt_cell_deps.contains(&out_point) {
+                cell_deps.push(cell_dep(out_point.clone(), DepType::Code));
+                sudt_cell_deps.push(out_point);
+            }
+        }
+        Ok(cell_deps)
+    }
+
+    /// The dep group of the sighash lock: the configured one, checked to hold the
+    /// lock's code, or else the genesis cell listing the genesis code cell of the lock.
+    /// Fails with `Error::CellDep` when there is none.
+    pub fn sighash(&self, client: &RetryingClient) -> Result<packed::OutPoint> {
+        if let Some(out_point) = self.sighash.borrow().as_ref() {
+            return Ok(out_point.clone());
+        }
+        let out_point = match &self.sighash_dep_group {
+            Some((tx_hash, index)) => {
+                let out_point = OutPoint {
+                    tx_hash: tx_hash.clone(),
+                    index: (*index).into(),
+                };
+                let (_, data) = live_cell(client, &out_point, "sighash dep group")?;
+                let listed = dep_group_members(&data).ok_or_else(|| {
+                    Error::CellDep(format!("Cell {:#x}:{} is not a dep group", tx_hash, index))
+                })?;
+                let mut holds_code = false;
+                for member in listed {
+                    let member: OutPoint = member.into();
+                    let (output, _) = live_cell(client, &member, "sighash dep group member")?;
+                    holds_code |= type_hash(&output).is_some_and(|h| h == SIGHASH_TYPE_HASH);
+                }
+                if !holds_code {
+                    return Err(Error::CellDep(format!(
+                        "Dep group {:#x}:{} doesn't hold the sighash lock code",
+                        tx_hash, index
+                    )));
+                }
+                out_point.into()
+            }
+            None => self.find_genesis_sighash_dep_group(client)?,
+        };
+        *self.sighash.borrow_mut() = Some(out_point.clone());
+        Ok(out_point)
+    }
+
+    fn find_genesis_sighash_dep_group(&self, client: &RetryingClient) -> Result<packed::OutPoint> {
+        self.with_genesis(client, |genesis| {
+            let outputs = || {
+                genesis.transactions.iter().flat_map(|tx| {
+                    let outputs = tx.inner.outputs.iter().zip(&tx.inner.outputs_data);
+                    outputs
+                        .enumerate()
+                        .map(|(index, (output, data))| (out_point(&tx.hash, index), output, data))
+                })
+            };
+            let code = outputs()
+                .find(|(_, output, _)| type_hash(output).is_some_and(|h| h == SIGHASH_TYPE_HASH))
+                .map(|(out_point, _, _)| out_point)
+                .ok_or_else(|| {
+                    Error::CellDep("No genesis cell holds the sighash lock code".to_string())
+                })?;
+            outputs()
+                .find(|(_, _, data)| {
+                    dep_group_members(data.as_bytes())
+                        .is_some_and(|members| members.contains(&code))
+                })
+                .map(|(out_point, _, _)| {
+                    debug!("Sighash dep group found at {}", out_point);
+                    out_point
+                })
+                .ok_or_else(|| {
+                    Error::CellDep(
+                        "No genesis dep group holds the sighash lock code, set \
+                         sighash.dep_group"
+                            .to_string(),
+                    )
+                })
+        })
+    }
+
+    /// The code cell of a UDT contract: the configured out point, or the genesis cell
+    /// holding the code when none is configured. The cell is checked against the code
+    /// hash, failing with `Error::CellDep` when it doesn't match or is gone.
+    pub fn code_cell(
+        &self,
+        client: &RetryingClient,
+        sudt: &SudtScript,
+    ) -> Result<packed::OutPoint> {
+        let key = (
+            sudt.code_hash.clone(),
+            sudt.hash_type,
+            sudt.cell_dep.clone(),
+        );
+        if let Some(out_point) = self.code_cells.borrow().get(&key) {
+            return Ok(out_point.clone());
+        }
+        let out_point = match &sudt.cell_dep {
+            Some((tx_hash, index)) => {
+                let out_point = OutPoint {
+                    tx_hash: tx_hash.clone(),
+                    index: (*index).into(),
+                };
+                let (output, data) = live_cell(client, &out_point, "sUDT code cell")?;
+                if !holds_code(sudt, &output, &data) {
+                    return Err(Error::CellDep(format!(
+                        "Cell {:#x}:{} doesn't hold the sUDT code with {:?} hash {:#x}",
+                        tx_hash, index, sudt.hash_type, sudt.code_hash
+                    )));
+                }
+                out_point.into()
+            }
+            None => self.find_genesis_code_cell(client, sudt)?,
+        };
+        self.code_cells.borrow_mut().insert(key, out_point.clone());
+        Ok(out_point)
+    }
+
+    /// Find the cell holding the sUDT code among the genesis block's outputs, where a
+    /// devnet deploys its system scripts
+    fn find_genesis_code_cell(
+        &self,
+        client: &RetryingClient,
+        sudt: &SudtScript,
+    ) -> Result<packed::OutPoint> {
+        self.with_genesis(client, |genesis| {
+            for tx in &genesis.transactions {
+                let outputs = tx.inner.outputs.iter().zip(&tx.inner.outputs_data);
+                for (index, (output, data)) in outputs.enumerate() {
+                    if holds_code(sudt, output, data.as_bytes()) {
+                        debug!("sUDT code cell found at {:#x}:{}", tx.hash, index);
+                        return Ok(out_point(&tx.hash, index));
+                    }
+                }
+            }
+            Err(Error::CellDep(format!(
+                "No genesis cell holds the sUDT code with {:?} hash {:#x}, set sudt.cell_dep",
+                sudt.hash_type, sudt.code_hash
+            )))
+        })
+    }
+
+    fn with_genesis<T>(
+        &self,
+        client: &RetryingClient,
+        f: impl FnOnce(&BlockView) -> Result<T>,
+    ) -> Result<T> {
+        let mut genesis = self.genesis.borrow_mut();
+        if genesis.is_none() {
+            *genesis = Some(get_genesis_block(client)?);
+        }
+        f(genesis.as_ref().unwrap())
+    }
+}
+
+fn cell_dep(out_point: packed::OutPoint, dep_type: DepType) -> packed::CellDep {
+    packed::CellDep::new_builder()
+        .out_point(out_point)
+        .dep_type(Byte::new(dep_type as u8))
+        .build()
+}
+
+fn out_point(tx_hash: &H256, index: usize) -> packed::OutPoint {
+    packed::OutPoint::new_builder()
+        .tx_hash(tx_hash.0.pack())
+        .index(index as u32)
+        .build()
+}
+
+/// The output and data of a live cell, failing with `Error::CellDep` when it is gone
+fn live_cell(
+    client: &RetryingClient,
+    out_point: &OutPoint,
+    what: &str,
+) -> Result<(CellOutput, Vec<u8>)> {
+    let cell = rpc::block_on(client.get_live_cell(out_point.clone(), true))
+        .map_err(Error::rpc("Failed to get a cell dep"))?;
+    let info = cell.cell.filter(|_| cell.status == "live").ok_or_else(|| {
+        Error::CellDep(format!(
+            "{} {:#x}:{} is not live",
+            what,
+            out_point.tx_hash,
+            out_point.index.value()
+        ))
+    })?;
+    let data = info.data.map(|data| data.content).unwrap_or_default();
+    Ok((info.output, data.into_bytes().to_vec()))
+}
+
+/// The out points a dep group cell lists, `None` when its data is not an out point
+/// vector
+fn dep_group_members(data: &[u8]) -> Option<Vec<packed::OutPoint>> {
+    packed::OutPointVec::from_slice(data)
+        .ok()
+        .filter(|members| !members.is_empty())
+        .map(|members| members.into_iter().collect())
+}
+
+fn type_hash(output: &CellOutput) -> Option<H256> {
+    output.type_.as_ref().map(|type_script| {
+        PackedScript::from(type_script.clone())
+            .calc_script_hash()
+            .unpack()
+    })
+}
+
+/// Whether a cell holds the sUDT code: the blake2b hash of its data is the code hash
+/// for the `data` hash types, the hash of its type script for `type`
+fn holds_code(sudt: &SudtScript, output: &CellOutput, data: &[u8]) -> bool {
+    match sudt.hash_type {
+        ScriptHashType::Type => type_hash(output).is_some_and(|hash| hash == sudt.code_hash),
+        _ => blake2b_256(data) == sudt.code_hash.0,
+    }
+}
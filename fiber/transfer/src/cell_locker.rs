@@ -0,0 +1,248 @@
+use crate::LiveCell;
+use crate::error::{Error, Result};
+use ckb_jsonrpc_types::OutPoint;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Lease file shared by invocations in the same directory, overridable via
+// CELL_LOCK_FILE env var
+const DEFAULT_LOCK_FILE: &str = "transfer-cell-locks.json";
+// How long selected cells stay reserved, overridable via CELL_LOCK_TTL_SECS env var;
+// long enough for a transaction to commit
+const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(600);
+
+/// Reserves the input cells of a transaction for a TTL in a lease file, so concurrent
+/// invocations funding from the same key don't select the same live cells. The file is
+/// locked while it is read and rewritten, and expired leases are dropped on every access.
+pub struct CellLocker {
+    path: PathBuf,
+    ttl: Duration,
+    /// Only read the leases of other runs, without creating or writing the file
+    dry_run: bool,
+}
+
+/// Out points reserved when the snapshot was taken
+pub struct Reservations(BTreeSet<String>);
+
+impl Reservations {
+    pub fn contains(&self, out_point: &OutPoint) -> bool {
+        self.0.contains(&lease_key(out_point))
+    }
+}
+
+impl CellLocker {
+    /// Read the CELL_LOCK_FILE and CELL_LOCK_TTL_SECS env vars. A `dry_run` locker
+    /// sees the reservations of other runs but records none of its own.
+    pub fn from_env(dry_run: bool) -> Result<Self> {
+        Ok(CellLocker {
+            path: PathBuf::from(
+                env::var("CELL_LOCK_FILE").unwrap_or_else(|_| DEFAULT_LOCK_FILE.to_string()),
+            ),
+            ttl: crate::rpc::env_secs("CELL_LOCK_TTL_SECS")?.unwrap_or(DEFAULT_LEASE_TTL),
+            dry_run,
+        })
+    }
+
+    /// The lease file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn reservations(&self) -> Result<Reservations> {
+        Ok(Reservations(
+            self.with_leases(|leases| leases.keys().cloned().collect())?,
+        ))
+    }
+
+    /// Reserve all of `cells`, or none of them when another invocation holds any of
+    /// them. Returns whether they were reserved.
+    pub fn reserve(&self, cells: &[LiveCell]) -> Result<bool> {
+        let expires = unix_secs(SystemTime::now() + self.ttl);
+        self.with_leases(|leases| {
+            let keys: Vec<String> = cells.iter().map(|c| lease_key(&c.out_point)).collect();
+            if keys.iter().any(|key| leases.contains_key(key)) {
+                return false;
+            }
+            for key in keys {
+                leases.insert(key, expires);
+            }
+            true
+        })
+    }
+
+    /// Reserve `cells` even where another lease holds them, e.g. for inputs taken over
+    /// from the transaction of an earlier run
+    pub fn take_over(&self, cells: &[LiveCell]) -> Result<()> {
+        let expires = unix_secs(SystemTime::now() + self.ttl);
+        self.with_leases(|leases| {
+            for cell in cells {
+                leases.insert(lease_key(&cell.out_point), expires);
+            }
+        })
+    }
+
+    /// Drop the leases on `cells`, e.g. when their transaction was never sent
+    pub fn release(&self, cells: &[LiveCell]) -> Result<()> {
+        self.with_leases(|leases| {
+            for cell in cells {
+                leases.remove(&lease_key(&cell.out_point));
+            }
+        })
+    }
+
+    /// Run `f` on the unexpired leases (out point -> expiry in unix seconds) with the
+    /// lease file exclusively locked, then write them back. A dry run only reads them,
+    /// under a shared lock, and a missing file holds none.
+    fn with_leases<T>(&self, f: impl FnOnce(&mut BTreeMap<String, u64>) -> T) -> Result<T> {
+        let fail = || {
+            Error::io(format!(
+                "Failed to access cell lock file {}",
+                self.path.display()
+            ))
+        };
+        let opened = OpenOptions::new()
+            .read(true)
+            .write(!self.dry_run)
+            .create(!self.dry_run)
+            .truncate(false)
+            .open(&self.path);
+        let mut file: File = match opened {
+            Err(e) if self.dry_run && e.kind() == ErrorKind::NotFound => {
+                return Ok(f(&mut BTreeMap::new()));
+            }
+            opened => opened.map_err(fail())?,
+        };
+        if self.dry_run {
+            file.lock_shared().map_err(fail())?;
+        } else {
+            file.lock().map_err(fail())?;
+        }
+
+        let mut content = String::new();
+        file.read_to_string(&mut content).map_err(fail())?;
+        let mut leases: BTreeMap<String, u64> = if content.trim().is_empty() {
+            BTreeMap::new()
+        } else {
+            serde_json::from_str(&content).map_err(|e| {
+                Error::Config(format!(
+                    "Invalid cell lock file {}: {}",
+                    self.path.display(),
+                    e
+                ))
+            })?
+        };
+        let now = unix_secs(SystemTime::now());
+        leases.retain(|_, expires| *expires > now);
+
+        let result = f(&mut leases);
+        if self.dry_run {
+            return Ok(result);
+        }
+
+        let content = serde_json::to_string_pretty(&leases).map_err(|e| {
+            Error::Config(format!(
+                "Failed to serialize cell lock file {}: {}",
+                self.path.display(),
+                e
+            ))
+        })?;
+        file.set_len(0)
+            .and_then(|()| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(content.as_bytes()))
+            .map_err(fail())?;
+        // The lock is released when the file is closed
+        Ok(result)
+    }
+}
+
+fn lease_key(out_point: &OutPoint) -> String {
+    format!("{:#x}:{}", out_point.tx_hash, out_point.index.value())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_jsonrpc_types::{CellOutput, JsonBytes, Script};
+    use ckb_types::H256;
+    use std::fs;
+
+    fn cell(byte: u8) -> LiveCell {
+        LiveCell {
+            out_point: OutPoint {
+                tx_hash: H256([byte; 32]),
+                index: 0.into(),
+            },
+            output: CellOutput {
+                capacity: 100_00000000.into(),
+                lock: Script::default(),
+                type_: None,
+            },
+            output_data: JsonBytes::default(),
+        }
+    }
+
+    fn locker(dir: &tempfile::TempDir, ttl: Duration, dry_run: bool) -> CellLocker {
+        CellLocker {
+            path: dir.path().join(DEFAULT_LOCK_FILE),
+            ttl,
+            dry_run,
+        }
+    }
+
+    #[test]
+    fn reserves_all_cells_or_none() {
+        let dir = tempfile::tempdir().unwrap();
+        let locker = locker(&dir, DEFAULT_LEASE_TTL, false);
+        assert!(locker.reserve(&[cell(1), cell(2)]).unwrap());
+        // Another run selecting one of them gets none
+        assert!(!locker.reserve(&[cell(2), cell(3)]).unwrap());
+        let reserved = locker.reservations().unwrap();
+        assert!(reserved.contains(&cell(1).out_point));
+        assert!(reserved.contains(&cell(2).out_point));
+        assert!(!reserved.contains(&cell(3).out_point));
+    }
+
+    #[test]
+    fn takes_over_and_releases_leases() {
+        let dir = tempfile::tempdir().unwrap();
+        let locker = locker(&dir, DEFAULT_LEASE_TTL, false);
+        assert!(locker.reserve(&[cell(1)]).unwrap());
+        locker.take_over(&[cell(1), cell(2)]).unwrap();
+        assert!(locker.reservations().unwrap().contains(&cell(2).out_point));
+        locker.release(&[cell(1), cell(2)]).unwrap();
+        assert!(locker.reserve(&[cell(1), cell(2)]).unwrap());
+    }
+
+    #[test]
+    fn drops_expired_leases() {
+        let dir = tempfile::tempdir().unwrap();
+        let expired = locker(&dir, Duration::ZERO, false);
+        assert!(expired.reserve(&[cell(1)]).unwrap());
+        let locker = locker(&dir, DEFAULT_LEASE_TTL, false);
+        assert!(!locker.reservations().unwrap().contains(&cell(1).out_point));
+        assert!(locker.reserve(&[cell(1)]).unwrap());
+    }
+
+    #[test]
+    fn dry_runs_only_read_the_leases() {
+        let dir = tempfile::tempdir().unwrap();
+        let dry_run = locker(&dir, DEFAULT_LEASE_TTL, true);
+        assert!(dry_run.reserve(&[cell(1)]).unwrap());
+        assert!(!dry_run.path().exists());
+
+        let locker = locker(&dir, DEFAULT_LEASE_TTL, false);
+        assert!(locker.reserve(&[cell(2)]).unwrap());
+        let content = fs::read_to_string(locker.path()).unwrap();
+        assert!(dry_run.reserve(&[cell(3)]).unwrap());
+        assert!(dry_run.reservations().unwrap().contains(&cell(2).out_point));
+        assert_eq!(fs::read_to_string(locker.path()).unwrap(), content);
+    }
+}
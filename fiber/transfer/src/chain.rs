@@ -0,0 +1,481 @@
+use crate::error::{Error, Result};
+use crate::rpc::{self, ChainBackend, Deadline, HttpSettings, RetryPolicy};
+use crate::supervisor::{self, PidFile};
+use ckb_sdk::constants::SIGHASH_TYPE_HASH;
+use ckb_types::H256;
+use clap::ValueEnum;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// How long the CKB node may take to answer RPC after it started, overridable via
+// CKB_READY_TIMEOUT_SECS env var
+const DEFAULT_NODE_READY_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// RPC modules the funding steps and the internal miner need besides `ckb init`'s
+const RPC_MODULES: [&str; 3] = ["Indexer", "Miner", "IntegrationTest"];
+// Contracts of the generated dev chain spec beside ckb's bundled cells, in the order of
+// their genesis cellbase outputs after the four bundled ones. The devnet preset's
+// Fiber scripts and the sUDT code are found at these positions.
+const SPEC_CONTRACTS: [&str; 5] = [
+    "auth",
+    "funding-lock",
+    "commitment-lock",
+    "simple_udt",
+    "xudt_rce",
+];
+/// Output of the generated spec's genesis cellbase holding the sUDT code
+pub const SUDT_GENESIS_OUTPUT: u32 = 7;
+// CKB issued to the source account in the generated spec, as in the bundled dev.toml
+const SOURCE_GENESIS_CAPACITY: u64 = 50_198_735_037 * 100_000_000;
+// Args of the unspendable lock holding the burnt share of the genesis issuance
+const BURN_LOCK_ARGS: &str = "0x62e907b15cbf27d5425399ebf6f0fb50ebb88f18";
+// Capacity of the source account's genesis sUDT cell
+const SUDT_GENESIS_CAPACITY: u64 = 20_000_000_000 * 100_000_000;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Miner {
+    /// `ckb miner` as a second child process
+    Ckb,
+    /// This tool calls `generate_block` on the node at the block interval
+    Internal,
+    /// No blocks unless something else mines
+    None,
+}
+
+/// How to run the local CKB dev node
+pub struct ChainSettings<'a> {
+    /// `ckb` binary, looked up in PATH unless it is a path
+    pub ckb_bin: &'a Path,
+    /// Chain spec of the dev chain, e.g. the repository's `ckb/dev.toml`
+    pub spec: &'a Path,
+    /// Directory holding the node's config, data and logs, initialized on first use
+    pub data_dir: &'a Path,
+    pub rpc_port: u16,
+    pub p2p_port: u16,
+    pub miner: Miner,
+    pub block_interval: Duration,
+    /// Sighash lock args (hex) receiving the mining rewards
+    pub block_assembler_args: &'a str,
+}
+
+/// A CKB dev node, and its miner, running as child processes of this tool. Dropping it
+/// stops them.
+pub struct DevChain {
+    /// Child processes by name, e.g. `ckb run`, with their pid files next to their logs
+    children: Vec<(&'static str, Child, PidFile)>,
+    internal_miner: Option<BlockProducer>,
+    rpc_url: String,
+}
+
+impl DevChain {
+    /// Initialize the data directory unless it holds a node already, then start
+    /// `ckb run` and the miner with their output captured in the data directory's
+    /// `ckb-run.log` and `ckb-miner.log`
+    pub fn start(settings: &ChainSettings, http_settings: &HttpSettings) -> Result<Self> {
+        if settings.data_dir.join("ckb.toml").exists() {
+            info!("Reusing the CKB node in {}", settings.data_dir.display());
+        } else {
+            init(settings)?;
+        }
+        let mut chain = DevChain {
+            children: Vec::new(),
+            internal_miner: None,
+            rpc_url: format!("http://127.0.0.1:{}", settings.rpc_port),
+        };
+        chain.spawn("ckb run", settings, &["run", "--indexer"], "ckb-run.log")?;
+        match settings.miner {
+            Miner::Ckb => chain.spawn("ckb miner", settings, &["miner"], "ckb-miner.log")?,
+            Miner::Internal => {
+                chain.internal_miner = Some(BlockProducer::start(
+                    &chain.rpc_url,
+                    http_settings,
+                    settings.block_interval,
+                )?);
+            }
+            Miner::None => {}
+        }
+        Ok(chain)
+    }
+
+    fn spawn(
+        &mut self,
+        name: &'static str,
+        settings: &ChainSettings,
+        args: &[&str],
+        log_file: &str,
+    ) -> Result<()> {
+        let log_path = settings.data_dir.join(log_file);
+        let mut command = Command::new(settings.ckb_bin);
+        command.args(args).arg("-C").arg(settings.data_dir);
+        let child =
+            supervisor::spawn_logged(&mut command, name, &log_path, false).map_err(|e| {
+                Error::Chain(format!(
+                    "Failed to start {} ({}): {}",
+                    name,
+                    settings.ckb_bin.display(),
+                    e
+                ))
+            })?;
+        let pid_file = match PidFile::create(&log_path.with_extension("pid"), &child) {
+            Ok(pid_file) => pid_file,
+            Err(e) => {
+                let mut child = child;
+                supervisor::stop(name, &mut child);
+                return Err(e);
+            }
+        };
+        self.children.push((name, child, pid_file));
+        Ok(())
+    }
+
+    pub fn rpc_url(&self) -> &str {
+        &self.rpc_url
+    }
+
+    /// Poll the node's RPC until it answers, failing early when a child exits
+    pub fn wait_ready(&mut self, http_settings: &HttpSettings, deadline: &Deadline) -> Result<()> {
+        let timeout =
+            crate::rpc::env_secs("CKB_READY_TIMEOUT_SECS")?.unwrap_or(DEFAULT_NODE_READY_TIMEOUT);
+        let client = http_settings.ckb_client(&self.rpc_url)?;
+        let started = Instant::now();
+        loop {
+            self.check()?;
+            match client.get_tip_block_number() {
+                Ok(tip) => {
+                    info!(
+                        "CKB node is ready at {}, tip block {}",
+                        self.rpc_url,
+                        tip.value()
+                    );
+                    return Ok(());
+                }
+                Err(e) if started.elapsed() >= timeout => {
+                    return Err(Error::Timeout(format!(
+                        "CKB node at {} not ready after {}s: {}",
+                        self.rpc_url,
+                        timeout.as_secs(),
+                        e
+                    )));
+                }
+                Err(_) => {}
+            }
+            deadline.check("waiting for the CKB node")?;
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Keep the node running until an interrupt, or one of the child processes exits,
+    /// which fails
+    pub fn supervise(&mut self) -> Result<()> {
+        supervisor::handle_interrupts();
+        loop {
+            if supervisor::interrupted() {
+                info!("Interrupted, stopping the CKB node");
+                return Ok(());
+            }
+            self.check()?;
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Fail when one of the child processes exited
+    pub fn check(&mut self) -> Result<()> {
+        for (name, child, _) in &mut self.children {
+            if let Some(status) = child
+                .try_wait()
+                .map_err(|e| Error::Chain(format!("Failed to check on {}: {}", name, e)))?
+            {
+                return Err(Error::Chain(format!(
+                    "{} exited ({}), see its log in the data directory",
+                    name, status
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DevChain {
+    fn drop(&mut self) {
+        self.internal_miner.take();
+        // Miner first, so it doesn't log errors about the node going away
+        for (name, child, _) in self.children.iter_mut().rev() {
+            supervisor::stop(name, child);
+        }
+    }
+}
+
+/// A thread of this tool mining a block per interval on a dev chain until dropped
+pub struct BlockProducer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BlockProducer {
+    pub fn start(rpc_url: &str, http_settings: &HttpSettings, interval: Duration) -> Result<Self> {
+        // The next block is due before a retry would be
+        let client = rpc::new_ckb_client(&[rpc_url], ChainBackend::FullNode, http_settings)?
+            .with_retry_policy(RetryPolicy::single_attempt());
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        // Mine on the run's runtime, which drives the client's pooled connections
+        let runtime = tokio::runtime::Handle::try_current().ok();
+        let thread = thread::spawn(move || {
+            let _runtime = runtime.as_ref().map(|runtime| runtime.enter());
+            // Failures are expected until the node answers for the first time
+            let mut mined = false;
+            while !flag.load(Ordering::Relaxed) {
+                match rpc::block_on(client.generate_block()) {
+                    Ok(_) => mined = true,
+                    Err(e) if mined => warn!("Failed to generate a block: {}", e),
+                    Err(_) => {}
+                }
+                thread::sleep(interval);
+            }
+        });
+        Ok(BlockProducer {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for BlockProducer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// `ckb init` a dev node in the data directory, with the block assembler paying the
+/// configured lock and the chain spec read from where it is, so the paths of the
+/// contracts it embeds stay relative to it
+fn init(settings: &ChainSettings) -> Result<()> {
+    let spec = fs::canonicalize(settings.spec).map_err(|e| {
+        Error::Chain(format!(
+            "Chain spec {} not found: {}",
+            settings.spec.display(),
+            e
+        ))
+    })?;
+    fs::create_dir_all(settings.data_dir).map_err(|e| {
+        Error::Chain(format!(
+            "Failed to create {}: {}",
+            settings.data_dir.display(),
+            e
+        ))
+    })?;
+    info!(
+        "Initializing a CKB dev node in {} with chain spec {}",
+        settings.data_dir.display(),
+        spec.display()
+    );
+    let output = Command::new(settings.ckb_bin)
+        .arg("init")
+        .arg("-C")
+        .arg(settings.data_dir)
+        .args(["--chain", "dev"])
+        .args(["--rpc-port", &settings.rpc_port.to_string()])
+        .args(["--p2p-port", &settings.p2p_port.to_string()])
+        .args(["--ba-code-hash", &format!("{:#x}", SIGHASH_TYPE_HASH)])
+        .args(["--ba-arg", settings.block_assembler_args])
+        .args(["--ba-hash-type", "type", "--ba-message", "0x"])
+        .output()
+        .map_err(|e| {
+            Error::Chain(format!(
+                "Failed to run {} init: {}",
+                settings.ckb_bin.display(),
+                e
+            ))
+        })?;
+    if !output.status.success() {
+        return Err(Error::Chain(format!(
+            "ckb init failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let ckb_toml = settings.data_dir.join("ckb.toml");
+    let config = read(&ckb_toml)?
+        .lines()
+        .map(|line| {
+            if line.starts_with("spec = ") {
+                format!("spec = {{ file = {:?} }}", spec.display().to_string())
+            } else if let Some(modules) = line.strip_prefix("modules = [") {
+                let missing: String = RPC_MODULES
+                    .iter()
+                    .filter(|module| !modules.contains(&format!("{:?}", module)))
+                    .map(|module| format!("{:?}, ", module))
+                    .collect();
+                format!("modules = [{}{}", missing, modules)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    write(&ckb_toml, &config)?;
+
+    // The dummy worker of the dev miner waits a constant delay between blocks
+    let miner_toml = settings.data_dir.join("ckb-miner.toml");
+    let config = read(&miner_toml)?
+        .lines()
+        .map(|line| {
+            if line.starts_with("value = ") {
+                format!("value = {}", settings.block_interval.as_millis())
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    write(&miner_toml, &config)
+}
+
+/// A dev chain spec issuing the genesis funds to a source account: its CKB, and sUDT
+/// owned by its lock
+pub struct DevSpec<'a> {
+    /// Directory holding the contract binaries, absolute so the spec works anywhere
+    pub contracts_dir: &'a Path,
+    /// Sighash lock args of the source account
+    pub source_lock_args: &'a [u8],
+    /// Lock hash of the source account, the args of its sUDT
+    pub sudt_args: &'a H256,
+    /// sUDT code hash, the blake2b hash of `simple_udt`
+    pub sudt_code_hash: &'a H256,
+    /// Base units of sUDT issued to the source account
+    pub sudt_supply: u128,
+}
+
+impl DevSpec<'_> {
+    /// Render the spec, laid out like the repository's `ckb/dev.toml`
+    pub fn render(&self) -> String {
+        let mut spec = String::new();
+        let zero_hash = format!("{:#x}", H256::default());
+        let source_args = hex::encode(self.source_lock_args);
+        writeln!(spec, "# Generated by `chain spec`").unwrap();
+        writeln!(spec, "name = \"ckb_dev\"\n").unwrap();
+        writeln!(spec, "[genesis]").unwrap();
+        writeln!(spec, "version = 0").unwrap();
+        writeln!(spec, "parent_hash = \"{}\"", zero_hash).unwrap();
+        writeln!(spec, "timestamp = 0").unwrap();
+        writeln!(spec, "compact_target = 0x20010000").unwrap();
+        writeln!(spec, "uncles_hash = \"{}\"", zero_hash).unwrap();
+        writeln!(spec, "nonce = \"0x0\"\n").unwrap();
+        writeln!(spec, "[genesis.genesis_cell]").unwrap();
+        writeln!(spec, "message = \"ckb_dev\"\n").unwrap();
+        writeln!(spec, "[genesis.genesis_cell.lock]").unwrap();
+        write_zero_lock(&mut spec, &zero_hash, "data");
+
+        let bundled = [
+            ("secp256k1_blake160_sighash_all", true, "100_000_0000_0000"),
+            ("dao", true, "16_000_0000_0000"),
+            ("secp256k1_data", false, "1_048_617_0000_0000"),
+            ("secp256k1_blake160_multisig_all", true, "100_000_0000_0000"),
+        ];
+        for (cell, create_type_id, capacity) in bundled {
+            writeln!(spec, "[[genesis.system_cells]]").unwrap();
+            writeln!(spec, "file = {{ bundled = \"specs/cells/{}\" }}", cell).unwrap();
+            writeln!(spec, "create_type_id = {}", create_type_id).unwrap();
+            writeln!(spec, "capacity = {}", capacity).unwrap();
+        }
+        for contract in SPEC_CONTRACTS {
+            let path = self.contracts_dir.join(contract);
+            writeln!(spec, "[[genesis.system_cells]]").unwrap();
+            writeln!(spec, "file = {{ file = {:?} }}", path.display().to_string()).unwrap();
+            writeln!(spec, "create_type_id = false").unwrap();
+            writeln!(spec, "capacity = 200_000_0000_0000").unwrap();
+        }
+        writeln!(spec, "\n[genesis.system_cells_lock]").unwrap();
+        write_zero_lock(&mut spec, &zero_hash, "data");
+        for group in [
+            "secp256k1_blake160_sighash_all",
+            "secp256k1_blake160_multisig_all",
+        ] {
+            writeln!(spec, "[[genesis.dep_groups]]").unwrap();
+            writeln!(spec, "name = \"{}\"", group).unwrap();
+            writeln!(spec, "files = [").unwrap();
+            writeln!(spec, "  {{ bundled = \"specs/cells/secp256k1_data\" }},").unwrap();
+            writeln!(spec, "  {{ bundled = \"specs/cells/{}\" }},", group).unwrap();
+            writeln!(spec, "]").unwrap();
+        }
+        writeln!(spec, "\n[genesis.bootstrap_lock]").unwrap();
+        write_zero_lock(&mut spec, &zero_hash, "type");
+
+        writeln!(spec, "# Burn").unwrap();
+        writeln!(spec, "[[genesis.issued_cells]]").unwrap();
+        writeln!(spec, "capacity = 8_400_000_000_00000000").unwrap();
+        writeln!(spec, "lock.code_hash = \"{}\"", zero_hash).unwrap();
+        writeln!(spec, "lock.args = \"{}\"", BURN_LOCK_ARGS).unwrap();
+        writeln!(spec, "lock.hash_type = \"data\"\n").unwrap();
+        writeln!(spec, "# Source account").unwrap();
+        writeln!(spec, "[[genesis.issued_cells]]").unwrap();
+        writeln!(spec, "capacity = {}", SOURCE_GENESIS_CAPACITY).unwrap();
+        write_sighash_lock(&mut spec, &source_args);
+        writeln!(spec, "\n# sUDT owned by the source account's lock").unwrap();
+        writeln!(spec, "[[genesis.issued_cells]]").unwrap();
+        writeln!(spec, "capacity = {}", SUDT_GENESIS_CAPACITY).unwrap();
+        write_sighash_lock(&mut spec, &source_args);
+        writeln!(spec, "type.code_hash = \"{:#x}\"", self.sudt_code_hash).unwrap();
+        writeln!(spec, "type.args = \"{:#x}\"", self.sudt_args).unwrap();
+        writeln!(spec, "type.hash_type = \"data\"").unwrap();
+        writeln!(
+            spec,
+            "data = \"0x{}\"\n",
+            hex::encode(self.sudt_supply.to_le_bytes())
+        )
+        .unwrap();
+
+        writeln!(spec, "[params]").unwrap();
+        for param in [
+            "initial_primary_epoch_reward = 1_917_808_21917808",
+            "secondary_epoch_reward = 613_698_63013698",
+            "max_block_cycles = 10_000_000_000",
+            "cellbase_maturity = 0",
+            "primary_epoch_reward_halving_interval = 8760",
+            "epoch_duration_target = 80",
+            "genesis_epoch_length = 10",
+            "permanent_difficulty_in_dummy = true",
+            "starting_block_limiting_dao_withdrawing_lock = 0",
+        ] {
+            writeln!(spec, "{}", param).unwrap();
+        }
+        writeln!(spec, "\n[params.hardfork]").unwrap();
+        writeln!(spec, "ckb2023 = 0\n").unwrap();
+        writeln!(spec, "[pow]").unwrap();
+        writeln!(spec, "func = \"Dummy\"").unwrap();
+        spec
+    }
+}
+
+fn write_zero_lock(spec: &mut String, zero_hash: &str, hash_type: &str) {
+    writeln!(spec, "code_hash = \"{}\"", zero_hash).unwrap();
+    writeln!(spec, "args = \"0x\"").unwrap();
+    writeln!(spec, "hash_type = \"{}\"\n", hash_type).unwrap();
+}
+
+fn write_sighash_lock(spec: &mut String, args: &str) {
+    writeln!(spec, "lock.code_hash = \"{:#x}\"", SIGHASH_TYPE_HASH).unwrap();
+    writeln!(spec, "lock.args = \"0x{}\"", args).unwrap();
+    writeln!(spec, "lock.hash_type = \"type\"").unwrap();
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .map_err(|e| Error::Chain(format!("Failed to read {}: {}", path.display(), e)))
+}
+
+fn write(path: &Path, content: &str) -> Result<()> {
+    fs::write(path, format!("{}\n", content))
+        .map_err(|e| Error::Chain(format!("Failed to write {}: {}", path.display(), e)))
+}
@@ -0,0 +1,948 @@
+use crate::chain::Miner;
+use crate::config;
+use crate::logging::LogFormat;
+use crate::network;
+use crate::since;
+use crate::supervisor::RestartPolicy;
+use ckb_sdk::Since;
+use ckb_types::H256;
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Fund Fiber demo node accounts with CKB and sUDT
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Settings file, the built-in devnet defaults are used when it doesn't exist
+    #[arg(long, global = true, default_value = config::DEFAULT_CONFIG_FILE)]
+    pub config: PathBuf,
+
+    /// Network preset: `devnet`, `testnet` or `mainnet`, overriding NETWORK and the
+    /// config file. It sets the RPC endpoint, the deployed scripts, the address prefix
+    /// and the safety limits.
+    #[arg(long, global = true, value_parser = ["devnet", "testnet", "mainnet"])]
+    pub network: Option<String>,
+
+    /// CKB RPC endpoint, overriding CKB_RPC_URL, the config file and the network preset
+    #[arg(long, global = true)]
+    pub rpc_url: Option<String>,
+
+    /// Fee rate in shannons per 1000 bytes of the transactions sent, overriding FEE_RATE
+    /// and the config file. The fee is checked again at the signed size. The node's
+    /// minimum replacement fee applies to `bump-fee` when it is higher.
+    #[arg(long, global = true)]
+    pub fee_rate: Option<u64>,
+
+    /// Create the key files of configured accounts that don't exist yet with new random
+    /// keys, so a clean checkout can bootstrap itself
+    #[arg(long, global = true)]
+    pub generate_missing_keys: bool,
+
+    /// Log more: -v for debug, -vv for trace
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Log less: -q for warnings and errors only, -qq for errors only
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// Log line format, logs go to stderr
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        env = "LOG_FORMAT",
+        default_value = "text"
+    )]
+    pub log_format: LogFormat,
+
+    /// Mine a block on the dev chain every this many milliseconds while the command
+    /// runs, instead of relying on an external miner. Ignored by `chain`, which runs
+    /// its own miner.
+    #[arg(long, global = true, env = "MINE_INTERVAL_MS")]
+    pub mine_interval_ms: Option<u64>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Bootstrap the whole demo: keys, CKB, sUDT, funding, Fiber nodes, peers,
+    /// channels and a smoke test payment, keeping what it started running
+    Up(UpArgs),
+    /// Fund the bootnode and node1-3 from the source account (the docker-compose startup flow)
+    Setup(SetupArgs),
+    /// Transfer CKB to one or more recipients
+    TransferCkb(TransferArgs),
+    /// Transfer sUDT to one or more recipients
+    TransferSudt(TransferSudtArgs),
+    /// Show the CKB and token balances of the source account and every node, or of
+    /// given accounts
+    #[command(alias = "balance")]
+    Balances(BalancesArgs),
+    /// Replace a transaction stuck in the pool with one paying a higher fee
+    BumpFee(BumpFeeArgs),
+    /// Mint sUDT owned by the issuer's lock, e.g. to bootstrap a fresh devnet
+    IssueSudt(IssueSudtArgs),
+    /// Destroy sUDT held by node keys, e.g. to clean up between demo runs
+    BurnSudt(BurnSudtArgs),
+    /// Merge all sUDT cells of a key into one cell
+    Consolidate(ConsolidateArgs),
+    /// Write the Fiber nodes' config files and CKB keys from this tool's config
+    GenConfig(GenConfigArgs),
+    /// Wait until the configured Fiber nodes answer RPC
+    WaitNodes(WaitNodesArgs),
+    /// Check the CKB node, indexer, keys, source balances and Fiber nodes once, failing
+    /// when anything the demo needs is broken
+    Status(StatusArgs),
+    /// Watch the node processes, balances, funding transactions and Fiber channels live
+    /// on one full-terminal screen
+    Dashboard(DashboardArgs),
+    /// Print the address, lock args and lock hash of the source and every node account,
+    /// and write them to a JSON address book
+    Addresses(AddressesArgs),
+    /// Pay an invoice of one Fiber node from another through the open channels and
+    /// wait until it settles
+    SmokeTest(SmokeTestArgs),
+    /// Stream payments from one Fiber node to another by an arrival process and report
+    /// their throughput and latency after a warm-up
+    Load(LoadArgs),
+    /// Make the `[[scenario]]` payments of the config file, most of them made to fail,
+    /// and check each one's error
+    Scenario(ScenarioArgs),
+    /// Create invoices on a Fiber node
+    Invoice(InvoiceArgs),
+    /// Pay an invoice from a Fiber node and wait until the payment succeeds
+    Pay(PayArgs),
+    /// Show the channels of the configured Fiber nodes with their states and balances
+    Channels(ChannelsArgs),
+    /// Cooperatively close the channels of the configured Fiber nodes, wait until the
+    /// closings commit on CKB and show the nodes' balances
+    Shutdown(ShutdownArgs),
+    /// Mine blocks on a dev chain through the node's RPC
+    GenerateBlocks(GenerateBlocksArgs),
+    /// Run the Fiber nodes of the config as child processes, restarting them when they
+    /// exit, until interrupted
+    RunNodes(RunNodesArgs),
+    /// Run a local CKB dev chain
+    Chain(ChainArgs),
+    /// Stop the chain and Fiber nodes this tool runs, then delete the chain's data
+    /// directory, the node stores and logs and this tool's state files, so the next
+    /// run starts from a clean slate. Keys and configs are kept.
+    Reset(ResetArgs),
+    /// Save or restore the state of a bootstrapped demo
+    Snapshot(SnapshotArgs),
+    /// Derive keys from a BIP39 mnemonic, read from MNEMONIC or MNEMONIC_FILE
+    Hd(HdArgs),
+    /// Write new random keys for additional nodes and add the nodes to the config file
+    Keygen(KeygenArgs),
+    /// Replace a node's key with a new random one, moving all its CKB and tokens to
+    /// the new key first
+    RotateKey(RotateKeyArgs),
+    /// Encrypt keys into ckb-cli compatible keystores, read with KEYSTORE_PASSWORD,
+    /// KEYSTORE_PASSWORD_FILE or a password prompt
+    Keystore(KeystoreArgs),
+    /// Check a key and print the public key, lock script, lock hash, address and Fiber
+    /// peer id it gives
+    InspectKey(InspectKeyArgs),
+}
+
+#[derive(Args)]
+pub struct SetupArgs {
+    /// Fund each node in its own transaction and keep going when one of them fails
+    #[arg(long, env = "PARTIAL_SUCCESS", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    pub partial_success: bool,
+
+    /// Continue an interrupted per-node funding run. Every run skips nodes funded by a
+    /// transaction from the state file that is still on chain, so this only keeps the
+    /// per-node mode.
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Ignore the state file and fund every node again, even if an earlier run did
+    #[arg(long)]
+    pub fresh: bool,
+
+    /// Fund only these nodes (comma-separated names, case-insensitive), e.g. to top up a
+    /// node added later
+    #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+    pub nodes: Vec<String>,
+
+    /// Fund every node except these (comma-separated names, case-insensitive)
+    #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+    pub skip_nodes: Vec<String>,
+
+    /// TOML or JSON file listing the accounts to fund instead of the configured nodes
+    #[arg(long = "recipients", value_name = "FILE")]
+    pub recipients_file: Option<PathBuf>,
+
+    /// Fund the nodes without opening the channels listed in the config file
+    #[arg(long)]
+    pub skip_channels: bool,
+
+    /// Fee rate in shannons per 1000 bytes of the channels' funding transactions,
+    /// overriding the config file's `funding_fee_rate` for channels that don't set
+    /// their own
+    #[arg(long)]
+    pub funding_fee_rate: Option<u64>,
+
+    /// Don't make the smoke test payment after opening the channels
+    #[arg(long)]
+    pub skip_smoke_test: bool,
+
+    /// Collect cells and build the funding transaction, print it and exit without
+    /// signing or sending
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Broadcast without showing the plan and asking for confirmation
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+#[derive(Args)]
+pub struct UpArgs {
+    /// Start a local CKB dev node like `chain up` instead of using the configured one
+    #[arg(long)]
+    pub local_chain: bool,
+
+    #[command(flatten)]
+    pub chain: ChainOptions,
+
+    /// Start the Fiber nodes like `run-nodes` instead of expecting them to run
+    #[arg(long)]
+    pub start_nodes: bool,
+
+    #[command(flatten)]
+    pub fnn: FnnOptions,
+
+    /// Skip the stages before this one, e.g. to resume a failed run. The chain and the
+    /// Fiber nodes are still started when requested.
+    #[arg(long, value_enum)]
+    pub from_stage: Option<UpStage>,
+
+    #[command(flatten)]
+    pub setup: SetupArgs,
+}
+
+/// Stages of `up`, in order
+#[derive(Clone, Copy, PartialEq, PartialOrd, ValueEnum)]
+pub enum UpStage {
+    /// Create the missing key files of the nodes
+    Keys,
+    /// Start the local chain, or check that the configured node answers
+    Chain,
+    /// Wait until the indexer caught up with the node
+    Indexer,
+    /// Issue the sUDT to the source account when it holds too little
+    Sudt,
+    /// Send the nodes their CKB and tokens
+    Fund,
+    /// Start the Fiber nodes when requested and wait until they answer
+    Nodes,
+    /// Connect the Fiber nodes of every channel
+    Peers,
+    /// Open the configured channels
+    Channels,
+    /// Pay the smoke test invoice through the channels
+    SmokeTest,
+}
+
+#[derive(Args)]
+pub struct TransferArgs {
+    /// Sender private key (hex) or key file, defaults to the source account
+    #[arg(long)]
+    pub from: Option<String>,
+
+    /// Recipient CKB address, private key (hex) or key file, repeat for several recipients
+    #[arg(
+        long = "to",
+        required_unless_present = "recipients_file",
+        requires = "amount"
+    )]
+    pub recipients: Vec<String>,
+
+    /// Amount sent to each recipient in CKB for transfer-ckb or in the token for
+    /// transfer-sudt, e.g. `100.5` or `100.5 CKB`
+    #[arg(long)]
+    pub amount: Option<String>,
+
+    /// TOML or JSON file listing recipients with their own amounts, instead of --to/--amount
+    #[arg(long = "recipients", value_name = "FILE", conflicts_with_all = ["recipients", "amount"])]
+    pub recipients_file: Option<PathBuf>,
+
+    /// Make every input spendable only from this point: `block:N`, `epoch:N[+I/L]` or
+    /// `time:SECONDS`, prefixed by `relative:` to count from when the input was
+    /// committed, or a raw 0x hex since
+    #[arg(long, value_parser = since::parse)]
+    pub since: Option<Since>,
+
+    /// Attach a cobuild message witness (the `SighashAll` witness layout) so
+    /// cobuild-aware tooling can read the transaction
+    #[arg(long)]
+    pub cobuild: bool,
+
+    /// Collect cells and build the transaction, print it and exit without signing or
+    /// sending
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Broadcast without showing the plan and asking for confirmation
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+#[derive(Args)]
+pub struct TransferSudtArgs {
+    #[command(flatten)]
+    pub transfer: TransferArgs,
+
+    /// Token to send, the preset's sUDT or a name from `tokens` in the config file
+    #[arg(long, default_value = network::SUDT_TOKEN_NAME)]
+    pub token: String,
+}
+
+#[derive(Args)]
+pub struct BalancesArgs {
+    /// CKB addresses, private keys (hex) or key files, defaults to the source account and
+    /// all configured nodes
+    pub accounts: Vec<String>,
+
+    /// A table for people or a JSON array for scripts
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: OutputFormat,
+}
+
+#[derive(Args)]
+pub struct BumpFeeArgs {
+    /// Hash of the pending transaction to replace
+    #[arg(long, value_parser = parse_tx_hash)]
+    pub tx: H256,
+
+    /// Sender private key (hex) or key file, defaults to the source account
+    #[arg(long)]
+    pub from: Option<String>,
+
+    /// Build the replacement, print it and exit without signing or sending
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Broadcast without showing the plan and asking for confirmation
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+#[derive(Args)]
+pub struct IssueSudtArgs {
+    /// Issuer private key (hex) or key file, defaults to the source account. The token
+    /// is the sUDT whose args are the lock hash of this key.
+    #[arg(long)]
+    pub from: Option<String>,
+
+    /// CKB address, private key (hex) or key file receiving the supply, defaults to the
+    /// issuer
+    #[arg(long)]
+    pub to: Option<String>,
+
+    /// Supply to mint in sUDT, e.g. `1_000_000` or `1_000_000 sUDT`; defaults to 10^15
+    /// base units
+    #[arg(long, env = "SUDT_SUPPLY")]
+    pub amount: Option<String>,
+
+    /// Build the issuance, print it and exit without signing or sending
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Broadcast without showing the plan and asking for confirmation
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+#[derive(Args)]
+pub struct BurnSudtArgs {
+    /// Holder private key (hex) or key file, repeat for several holders; defaults to
+    /// the configured nodes given by key
+    #[arg(long)]
+    pub from: Vec<String>,
+
+    /// Token to burn, the preset's sUDT or a name from `tokens` in the config file
+    #[arg(long, default_value = network::SUDT_TOKEN_NAME)]
+    pub token: String,
+
+    /// Amount burned from each holder in units of the token, e.g. `10.5`; defaults to
+    /// everything it holds
+    #[arg(long)]
+    pub amount: Option<String>,
+
+    /// Build each burn transaction, print it and exit without signing or sending
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Broadcast without showing the plan and asking for confirmation
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+#[derive(Args)]
+pub struct ConsolidateArgs {
+    /// Private key (hex) or key file holding the cells, defaults to the source account
+    #[arg(long)]
+    pub from: Option<String>,
+
+    /// Token to consolidate, the preset's sUDT or a name from `tokens` in the config file
+    #[arg(long, default_value = network::SUDT_TOKEN_NAME)]
+    pub token: String,
+
+    /// Build the transaction, print it and exit without signing or sending
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Broadcast without showing the plan and asking for confirmation
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+#[derive(Args)]
+pub struct GenConfigArgs {
+    /// Directory holding one directory per node, as in the repository's fiber/nodes
+    #[arg(long, default_value = "../nodes")]
+    pub out_dir: PathBuf,
+
+    /// Overwrite config and key files that differ from the generated ones
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct WaitNodesArgs {
+    /// Wait only for these nodes (comma-separated names, case-insensitive), defaults to
+    /// every node with a `fiber_rpc_url`
+    #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+    pub nodes: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct StatusArgs {
+    /// Check only the keys and Fiber nodes of these nodes (comma-separated names,
+    /// case-insensitive), defaults to every node
+    #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+    pub nodes: Vec<String>,
+
+    /// A table for people or a JSON array for scripts
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: OutputFormat,
+}
+
+#[derive(Args)]
+pub struct DashboardArgs {
+    #[command(flatten)]
+    pub dirs: StateDirs,
+
+    /// Seconds between refreshes
+    #[arg(long, default_value_t = 5)]
+    pub refresh_secs: u64,
+}
+
+#[derive(Args)]
+pub struct AddressesArgs {
+    /// JSON file to write the address book to
+    #[arg(long, default_value = "addresses.json")]
+    pub out: PathBuf,
+}
+
+#[derive(Args)]
+pub struct GenerateBlocksArgs {
+    /// Blocks to mine
+    #[arg(default_value_t = 1)]
+    pub count: u64,
+}
+
+#[derive(Args)]
+pub struct RunNodesArgs {
+    /// Run only these nodes (comma-separated names, case-insensitive), defaults to every
+    /// node with Fiber settings
+    #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+    pub nodes: Vec<String>,
+
+    #[command(flatten)]
+    pub fnn: FnnOptions,
+}
+
+/// Where the chain and the Fiber nodes keep their state
+#[derive(Args)]
+pub struct StateDirs {
+    /// Data directory of the CKB dev node, as given to `chain up`
+    #[arg(long, default_value = "ckb-dev")]
+    pub data_dir: PathBuf,
+
+    /// Directory holding one directory per node, as given to `run-nodes`
+    #[arg(long, default_value = "../nodes")]
+    pub nodes_dir: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ResetArgs {
+    #[command(flatten)]
+    pub dirs: StateDirs,
+
+    /// Directory of the node logs, as given to `run-nodes`
+    #[arg(long)]
+    pub log_dir: Option<PathBuf>,
+
+    /// Delete without listing what goes and asking for confirmation
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+#[derive(Args)]
+pub struct SnapshotArgs {
+    #[command(subcommand)]
+    pub command: SnapshotCommand,
+}
+
+#[derive(Subcommand)]
+pub enum SnapshotCommand {
+    /// Copy the chain's data directory, the Fiber node stores and this tool's state
+    /// files into a snapshot directory. The chain and nodes must be stopped.
+    Save(SnapshotSaveArgs),
+    /// Replace the state with a snapshot's, e.g. to get back to a funded demo with open
+    /// channels. The chain and nodes must be stopped.
+    Restore(SnapshotRestoreArgs),
+}
+
+#[derive(Args)]
+pub struct SnapshotSaveArgs {
+    /// Snapshot directory to create
+    pub dir: PathBuf,
+
+    #[command(flatten)]
+    pub dirs: StateDirs,
+
+    /// Replace an existing snapshot directory
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct SnapshotRestoreArgs {
+    /// Snapshot directory, as written by `snapshot save`
+    pub dir: PathBuf,
+
+    #[command(flatten)]
+    pub dirs: StateDirs,
+
+    /// Replace the state without listing it and asking for confirmation
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+#[derive(Args)]
+pub struct HdArgs {
+    #[command(subcommand)]
+    pub command: HdCommand,
+}
+
+#[derive(Subcommand)]
+pub enum HdCommand {
+    /// Write a new random mnemonic to a file readable only by its owner
+    New(HdNewArgs),
+    /// Print the derivation path, address and lock args of the first keys of an account
+    Addresses(HdAddressesArgs),
+    /// Print the private key at a derivation path, or write it to a key file
+    Export(HdExportArgs),
+}
+
+#[derive(Args)]
+pub struct HdNewArgs {
+    /// Mnemonic file to create, e.g. for MNEMONIC_FILE
+    #[arg(long, default_value = "ckb-keys/mnemonic")]
+    pub out: PathBuf,
+
+    /// Words of the mnemonic: 12, 15, 18, 21 or 24
+    #[arg(long, default_value_t = 12, value_parser = parse_mnemonic_words)]
+    pub words: usize,
+}
+
+#[derive(Args)]
+pub struct HdAddressesArgs {
+    /// Account of the keys, the `0'` in `m/44'/309'/0'/0/<index>`
+    #[arg(long, default_value_t = 0)]
+    pub account: u32,
+
+    /// Keys to print, from index 0
+    #[arg(long, default_value_t = 5)]
+    pub count: u32,
+}
+
+#[derive(Args)]
+pub struct HdExportArgs {
+    /// Derivation path of the key, e.g. `m/44'/309'/0'/0/1`
+    pub path: String,
+
+    /// Key file to write, e.g. a Fiber node's `ckb/key`, instead of printing the key
+    #[arg(long)]
+    pub out: Option<PathBuf>,
+
+    /// Replace an existing key file
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct InspectKeyArgs {
+    /// Hex private key, derivation path, or key file: a hex key file such as
+    /// `ckb-keys/node1-key` or a node's `ckb/key`, a keystore, or a Fiber node's binary
+    /// `fiber/sk`
+    pub key: String,
+}
+
+#[derive(Args)]
+pub struct KeygenArgs {
+    /// Nodes to add
+    #[arg(long)]
+    pub count: u32,
+
+    /// Name of the new nodes, numbered after the configured ones, e.g. `node4`
+    #[arg(long, default_value = "node")]
+    pub label: String,
+
+    /// Directory of the key files, named `<label><number>-key`
+    #[arg(long, default_value = "ckb-keys")]
+    pub keys_dir: PathBuf,
+}
+
+#[derive(Args)]
+pub struct RotateKeyArgs {
+    /// Node whose key file to replace (case-insensitive)
+    #[arg(long)]
+    pub node: String,
+
+    /// Directory holding one directory per node, as written by `gen-config`, whose
+    /// `ckb/key` of the node is replaced too
+    #[arg(long, default_value = "../nodes")]
+    pub nodes_dir: PathBuf,
+
+    /// Broadcast the sweep without showing the plan and asking for confirmation
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+#[derive(Args)]
+pub struct KeystoreArgs {
+    #[command(subcommand)]
+    pub command: KeystoreCommand,
+}
+
+#[derive(Subcommand)]
+pub enum KeystoreCommand {
+    /// Encrypt a key into a new keystore file readable only by its owner
+    Import(KeystoreImportArgs),
+}
+
+#[derive(Args)]
+pub struct KeystoreImportArgs {
+    /// Key to encrypt, as a hex private key, key file or derivation path; the source
+    /// key by default
+    pub key: Option<String>,
+
+    /// Keystore file to create, e.g. for SOURCE_PRIVATE_KEY
+    #[arg(long, default_value = "ckb-keys/source.json")]
+    pub out: PathBuf,
+
+    /// Encrypt with ckb-cli's light scrypt settings, faster to unlock but cheaper to
+    /// brute-force
+    #[arg(long)]
+    pub light: bool,
+
+    /// Replace an existing keystore file
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// How to run the Fiber nodes
+#[derive(Args)]
+pub struct FnnOptions {
+    /// `fnn` binary
+    #[arg(long, env = "FNN_BIN", default_value = "fnn")]
+    pub fnn: PathBuf,
+
+    /// Directory holding one directory per node, as written by `gen-config`
+    #[arg(long, default_value = "../nodes")]
+    pub nodes_dir: PathBuf,
+
+    /// When to restart a node that exited
+    #[arg(long, value_enum, default_value = "on-failure")]
+    pub restart: RestartPolicy,
+
+    /// Restarts of a node before the command fails
+    #[arg(long, default_value_t = 5)]
+    pub max_restarts: u32,
+
+    #[arg(long, default_value_t = 2)]
+    pub restart_delay_secs: u64,
+
+    /// Directory of the node logs (`<node>.log`), each node's directory by default
+    #[arg(long)]
+    pub log_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct SmokeTestArgs {
+    /// Node paying the invoice, defaults to `from` of `[smoke_test]` in the config file
+    #[arg(long)]
+    pub from: Option<String>,
+
+    /// Node issuing the invoice, defaults to `to` of `[smoke_test]` in the config file
+    #[arg(long)]
+    pub to: Option<String>,
+
+    /// Amount paid in CKB, or in the token with --token, e.g. `1` or `0.5 CKB`;
+    /// defaults to `amount` of `[smoke_test]` in the config file
+    #[arg(long)]
+    pub amount: Option<String>,
+
+    /// Token paid, the preset's sUDT or a name from `tokens` in the config file;
+    /// defaults to `token` of `[smoke_test]`, CKB when unset
+    #[arg(long)]
+    pub token: Option<String>,
+}
+
+#[derive(Args)]
+pub struct LoadArgs {
+    /// Node paying the invoices, defaults to `from` of `[load]` in the config file
+    #[arg(long)]
+    pub from: Option<String>,
+
+    /// Node issuing the invoices, defaults to `to` of `[load]` in the config file
+    #[arg(long)]
+    pub to: Option<String>,
+
+    /// Token paid, the preset's sUDT or a name from `tokens` in the config file;
+    /// defaults to `token` of `[load]`, CKB when unset
+    #[arg(long)]
+    pub token: Option<String>,
+
+    /// Amount of every payment in CKB, or in token base units with --token, instead of
+    /// the `amount` distribution of `[load]`
+    #[arg(long)]
+    pub amount: Option<f64>,
+
+    /// How payments arrive, defaults to the `process` of `[load]`'s `arrival`
+    #[arg(long, value_enum)]
+    pub arrival: Option<ArrivalProcess>,
+
+    /// Average payments per second
+    #[arg(long)]
+    pub rate: Option<f64>,
+
+    /// Payments per burst with `--arrival burst`
+    #[arg(long)]
+    pub burst_size: Option<u32>,
+
+    /// Seconds of payments made before measuring
+    #[arg(long)]
+    pub warm_up_secs: Option<u64>,
+
+    /// Seconds of measured payments
+    #[arg(long)]
+    pub duration_secs: Option<u64>,
+
+    /// Seed of the arrival gaps and amounts, to repeat a run
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// A table for people or a JSON object for scripts
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: OutputFormat,
+}
+
+#[derive(Args)]
+pub struct ScenarioArgs {
+    /// Run only these steps (comma-separated names, case-insensitive)
+    #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+    pub steps: Vec<String>,
+
+    /// A table for people or a JSON array for scripts
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: OutputFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ArrivalProcess {
+    /// Evenly spaced payments
+    Constant,
+    /// Independent payments with exponentially distributed gaps
+    Poisson,
+    /// Evenly spaced bursts of `--burst-size` payments
+    Burst,
+}
+
+#[derive(Args)]
+pub struct InvoiceArgs {
+    #[command(subcommand)]
+    pub command: InvoiceCommand,
+}
+
+#[derive(Subcommand)]
+pub enum InvoiceCommand {
+    /// Create an invoice and print it, to be paid with `pay`
+    New(NewInvoiceArgs),
+}
+
+#[derive(Args)]
+pub struct NewInvoiceArgs {
+    /// Node issuing the invoice (case-insensitive name)
+    #[arg(long)]
+    pub node: String,
+
+    /// Amount in units of the asset, e.g. `10` or `10.5 CKB`
+    #[arg(long)]
+    pub amount: String,
+
+    /// Asset paid: CKB, the preset's sUDT or a name from `tokens` in the config file
+    #[arg(long, default_value = "CKB")]
+    pub asset: String,
+
+    /// Description embedded in the invoice
+    #[arg(long, default_value = "fiber-demo invoice")]
+    pub description: String,
+}
+
+#[derive(Args)]
+pub struct PayArgs {
+    /// Node paying the invoice (case-insensitive name)
+    #[arg(long)]
+    pub node: String,
+
+    /// Encoded invoice, as printed by `invoice new`
+    #[arg(long)]
+    pub invoice: String,
+}
+
+#[derive(Args)]
+pub struct ChannelsArgs {
+    /// List only the channels of these nodes (comma-separated names, case-insensitive),
+    /// defaults to every node with a `fiber_rpc_url`
+    #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+    pub nodes: Vec<String>,
+
+    /// A table for people or a JSON array for scripts
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: OutputFormat,
+}
+
+#[derive(Args)]
+pub struct ShutdownArgs {
+    /// Close only the channels of these nodes (comma-separated names, case-insensitive),
+    /// defaults to every node with a `fiber_rpc_url`
+    #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+    pub nodes: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct ChainArgs {
+    #[command(subcommand)]
+    pub command: ChainCommand,
+}
+
+#[derive(Subcommand)]
+pub enum ChainCommand {
+    /// Start a CKB dev node and its miner as child processes, wait until the node
+    /// answers RPC, fund the nodes like `setup` and keep the chain running until it is
+    /// interrupted
+    Up(ChainUpArgs),
+    /// Write a dev chain spec issuing the genesis CKB and sUDT to a source key, generated
+    /// unless given, for a chain of its own
+    Spec(ChainSpecArgs),
+}
+
+#[derive(Args)]
+pub struct ChainSpecArgs {
+    /// Spec file to write
+    #[arg(long, default_value = "specs/dev.toml")]
+    pub out: PathBuf,
+
+    /// Directory of the contract binaries embedded in the genesis, the repository's by
+    /// default
+    #[arg(long, default_value = "../../ckb/contracts")]
+    pub contracts: PathBuf,
+
+    /// Source private key as hex or a key file; defaults to the key in --key-out
+    #[arg(long)]
+    pub source_key: Option<String>,
+
+    /// Key file reused as the source key, or written with a new one when missing
+    #[arg(long, default_value = "ckb-keys/source-key")]
+    pub key_out: PathBuf,
+
+    /// sUDT issued to the source account in base units, 10^15 by default
+    #[arg(long, env = "SUDT_SUPPLY")]
+    pub sudt_supply: Option<String>,
+
+    /// Overwrite a spec that differs from the generated one
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct ChainUpArgs {
+    #[command(flatten)]
+    pub chain: ChainOptions,
+
+    /// Only start the chain, without funding the nodes
+    #[arg(long)]
+    pub no_setup: bool,
+
+    #[command(flatten)]
+    pub setup: SetupArgs,
+}
+
+/// How to run a local CKB dev node
+#[derive(Args)]
+pub struct ChainOptions {
+    /// `ckb` binary
+    #[arg(long, env = "CKB_BIN", default_value = "ckb")]
+    pub ckb: PathBuf,
+
+    /// Dev chain spec, the repository's by default
+    #[arg(long, default_value = "../../ckb/dev.toml")]
+    pub spec: PathBuf,
+
+    /// Directory of the node's config, data and logs, initialized when it holds no node
+    #[arg(long, default_value = "ckb-dev")]
+    pub data_dir: PathBuf,
+
+    #[arg(long, default_value_t = 8114)]
+    pub rpc_port: u16,
+
+    #[arg(long, default_value_t = 8115)]
+    pub p2p_port: u16,
+
+    /// What mines the blocks
+    #[arg(long, value_enum, default_value = "ckb")]
+    pub miner: Miner,
+
+    /// Time between blocks of the miner
+    #[arg(long, default_value_t = 1000)]
+    pub block_interval_ms: u64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+fn parse_mnemonic_words(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(words @ (12 | 15 | 18 | 21 | 24)) => Ok(words),
+        _ => Err("expected 12, 15, 18, 21 or 24".to_string()),
+    }
+}
+
+fn parse_tx_hash(value: &str) -> Result<H256, String> {
+    H256::from_str(value.trim_start_matches("0x")).map_err(|e| e.to_string())
+}
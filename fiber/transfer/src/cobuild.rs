@@ -0,0 +1,64 @@
+use ckb_types::bytes::Bytes;
+use ckb_types::core::TransactionView;
+use ckb_types::prelude::*;
+
+// Item id of the SighashAll variant of the cobuild WitnessLayout union. The ids start
+// at 0xff000001 so a layout can't be mistaken for a WitnessArgs, whose first four
+// bytes are its total size.
+const SIGHASH_ALL: u32 = 0xff00_0001;
+
+/// The transaction with a cobuild `SighashAll` witness appended after the input
+/// witnesses, carrying the transaction's message for cobuild-aware tooling. The
+/// sighash lock keeps its WitnessArgs witnesses and signs the appended witness with
+/// the rest of the transaction, so the message can't be altered once signed. Neither
+/// the sighash lock nor the sUDT script define actions, so the message has none and
+/// the seal is empty.
+pub fn attach(tx: TransactionView) -> TransactionView {
+    let witness = sighash_all(&message(&[]), &[]);
+    tx.as_advanced_builder().witness(witness.pack()).build()
+}
+
+/// Whether the transaction carries a cobuild `SighashAll` witness after its input
+/// witnesses
+pub fn is_attached(tx: &TransactionView) -> bool {
+    tx.witnesses()
+        .into_iter()
+        .skip(tx.inputs().len())
+        .any(|witness| witness.raw_data().starts_with(&SIGHASH_ALL.to_le_bytes()))
+}
+
+/// `WitnessLayout::SighashAll { message, seal }`
+fn sighash_all(message: &[u8], seal: &[u8]) -> Bytes {
+    let mut layout = SIGHASH_ALL.to_le_bytes().to_vec();
+    layout.extend(table(&[message, &fixvec(seal)]));
+    layout.into()
+}
+
+/// `Message { actions }` with the given serialized actions
+fn message(actions: &[&[u8]]) -> Vec<u8> {
+    table(&[&table(actions)])
+}
+
+/// Molecule `Bytes`
+fn fixvec(bytes: &[u8]) -> Vec<u8> {
+    let mut vec = (bytes.len() as u32).to_le_bytes().to_vec();
+    vec.extend(bytes);
+    vec
+}
+
+/// Molecule table, which a dynamic vector is laid out as too: the total size, the
+/// offset of each field, then the fields
+fn table(fields: &[&[u8]]) -> Vec<u8> {
+    let header = 4 * (fields.len() + 1);
+    let total = header + fields.iter().map(|field| field.len()).sum::<usize>();
+    let mut table = (total as u32).to_le_bytes().to_vec();
+    let mut offset = header;
+    for field in fields {
+        table.extend((offset as u32).to_le_bytes());
+        offset += field.len();
+    }
+    for field in fields {
+        table.extend(*field);
+    }
+    table
+}
@@ -0,0 +1,264 @@
+use crate::LiveCell;
+use serde::Deserialize;
+
+// Subsets the least-waste search tries before settling for the best found so far
+const LEAST_WASTE_MAX_TRIES: usize = 100_000;
+
+/// Picks the cells a transfer spends among the sender's unreserved cells
+pub trait CoinSelector {
+    /// Cells of `candidates`, each given with its value, worth at least `target`
+    /// together, in spending order. `None` when all of them are worth less.
+    fn select(
+        &self,
+        candidates: Vec<(LiveCell, u128)>,
+        target: u128,
+    ) -> Option<Vec<(LiveCell, u128)>>;
+}
+
+/// The configured selection strategy, `coin_selection` in the config
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoinSelection {
+    /// Cells in indexer order until enough, the oldest first
+    #[default]
+    IndexerOrder,
+    /// The largest cells first, spending as few cells as possible
+    LargestFirst,
+    /// The smallest cells first, cleaning up dust as a side effect
+    SmallestFirst,
+    /// The cells whose total exceeds the target the least, leaving the smallest change
+    LeastWaste,
+}
+
+impl CoinSelector for CoinSelection {
+    fn select(
+        &self,
+        candidates: Vec<(LiveCell, u128)>,
+        target: u128,
+    ) -> Option<Vec<(LiveCell, u128)>> {
+        match self {
+            CoinSelection::IndexerOrder => IndexerOrder.select(candidates, target),
+            CoinSelection::LargestFirst => LargestFirst.select(candidates, target),
+            CoinSelection::SmallestFirst => SmallestFirst.select(candidates, target),
+            CoinSelection::LeastWaste => LeastWaste.select(candidates, target),
+        }
+    }
+}
+
+pub struct IndexerOrder;
+
+impl CoinSelector for IndexerOrder {
+    fn select(
+        &self,
+        candidates: Vec<(LiveCell, u128)>,
+        target: u128,
+    ) -> Option<Vec<(LiveCell, u128)>> {
+        take_until(candidates, target)
+    }
+}
+
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(
+        &self,
+        mut candidates: Vec<(LiveCell, u128)>,
+        target: u128,
+    ) -> Option<Vec<(LiveCell, u128)>> {
+        candidates.sort_by(|(_, a), (_, b)| b.cmp(a));
+        take_until(candidates, target)
+    }
+}
+
+pub struct SmallestFirst;
+
+impl CoinSelector for SmallestFirst {
+    fn select(
+        &self,
+        mut candidates: Vec<(LiveCell, u128)>,
+        target: u128,
+    ) -> Option<Vec<(LiveCell, u128)>> {
+        candidates.sort_by_key(|(_, value)| *value);
+        take_until(candidates, target)
+    }
+}
+
+/// Depth-first search over the cells, largest first, for the set exceeding the target
+/// the least, and with the fewest cells among equal ones. The first set found is the
+/// largest-first one, so the search always has an answer when the cells suffice; it
+/// stops at an exact match or after `LEAST_WASTE_MAX_TRIES` sets.
+pub struct LeastWaste;
+
+impl CoinSelector for LeastWaste {
+    fn select(
+        &self,
+        mut candidates: Vec<(LiveCell, u128)>,
+        target: u128,
+    ) -> Option<Vec<(LiveCell, u128)>> {
+        if target == 0 {
+            return Some(Vec::new());
+        }
+        candidates.sort_by(|(_, a), (_, b)| b.cmp(a));
+        let values: Vec<u128> = candidates.iter().map(|(_, value)| *value).collect();
+        // Value of the cells from each index on, to prune branches that can't reach
+        // the target
+        let mut remaining = vec![0u128; values.len() + 1];
+        for i in (0..values.len()).rev() {
+            remaining[i] = remaining[i + 1] + values[i];
+        }
+        if remaining[0] < target {
+            return None;
+        }
+        let mut search = Search {
+            values: &values,
+            remaining: &remaining,
+            target,
+            chosen: Vec::new(),
+            best: None,
+            tries: 0,
+        };
+        search.descend(0, 0);
+        let (_, best) = search.best?;
+        let mut candidates: Vec<Option<(LiveCell, u128)>> =
+            candidates.into_iter().map(Some).collect();
+        Some(
+            best.into_iter()
+                .filter_map(|i| candidates[i].take())
+                .collect(),
+        )
+    }
+}
+
+struct Search<'a> {
+    values: &'a [u128],
+    remaining: &'a [u128],
+    target: u128,
+    chosen: Vec<usize>,
+    /// Excess over the target and indexes of the best set so far
+    best: Option<(u128, Vec<usize>)>,
+    tries: usize,
+}
+
+impl Search<'_> {
+    fn descend(&mut self, index: usize, sum: u128) {
+        if sum >= self.target {
+            self.tries += 1;
+            let excess = sum - self.target;
+            let better = self.best.as_ref().is_none_or(|(best, cells)| {
+                excess < *best || excess == *best && self.chosen.len() < cells.len()
+            });
+            if better {
+                self.best = Some((excess, self.chosen.clone()));
+            }
+            return;
+        }
+        if index == self.values.len() || sum + self.remaining[index] < self.target {
+            return;
+        }
+        if self.done() {
+            return;
+        }
+        self.chosen.push(index);
+        self.descend(index + 1, sum + self.values[index]);
+        self.chosen.pop();
+        if !self.done() {
+            self.descend(index + 1, sum);
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.tries >= LEAST_WASTE_MAX_TRIES || self.best.as_ref().is_some_and(|(e, _)| *e == 0)
+    }
+}
+
+/// Candidates in order until they are worth `target`
+fn take_until(candidates: Vec<(LiveCell, u128)>, target: u128) -> Option<Vec<(LiveCell, u128)>> {
+    let mut selected = Vec::new();
+    let mut total = 0;
+    for (cell, value) in candidates {
+        if total >= target {
+            break;
+        }
+        total += value;
+        selected.push((cell, value));
+    }
+    (total >= target).then_some(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_jsonrpc_types::{CellOutput, JsonBytes, OutPoint, Script};
+    use ckb_types::H256;
+
+    fn candidates(values: &[u128]) -> Vec<(LiveCell, u128)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                let cell = LiveCell {
+                    out_point: OutPoint {
+                        tx_hash: H256::default(),
+                        index: (index as u32).into(),
+                    },
+                    output: CellOutput {
+                        capacity: (*value as u64).into(),
+                        lock: Script::default(),
+                        type_: None,
+                    },
+                    output_data: JsonBytes::default(),
+                };
+                (cell, *value)
+            })
+            .collect()
+    }
+
+    fn selected(selector: &dyn CoinSelector, values: &[u128], target: u128) -> Option<Vec<u128>> {
+        selector
+            .select(candidates(values), target)
+            .map(|cells| cells.into_iter().map(|(_, value)| value).collect())
+    }
+
+    #[test]
+    fn ordered_selectors_take_cells_until_enough() {
+        let values = [30, 10, 50, 20];
+        assert_eq!(selected(&IndexerOrder, &values, 35), Some(vec![30, 10]));
+        assert_eq!(selected(&LargestFirst, &values, 35), Some(vec![50]));
+        assert_eq!(
+            selected(&SmallestFirst, &values, 35),
+            Some(vec![10, 20, 30])
+        );
+        assert_eq!(selected(&IndexerOrder, &values, 0), Some(vec![]));
+    }
+
+    #[test]
+    fn selectors_fail_when_the_cells_fall_short() {
+        let values = [30, 10, 50, 20];
+        for selection in [
+            CoinSelection::IndexerOrder,
+            CoinSelection::LargestFirst,
+            CoinSelection::SmallestFirst,
+            CoinSelection::LeastWaste,
+        ] {
+            assert_eq!(selected(&selection, &values, 111), None, "{:?}", selection);
+            assert!(
+                selected(&selection, &values, 110).is_some(),
+                "{:?}",
+                selection
+            );
+        }
+    }
+
+    #[test]
+    fn least_waste_prefers_exact_then_fewer_cells() {
+        assert_eq!(
+            selected(&LeastWaste, &[50, 30, 20, 10], 40),
+            Some(vec![30, 10])
+        );
+        // 60 is reached exactly by one cell or by two; one wins
+        assert_eq!(selected(&LeastWaste, &[60, 40, 20], 60), Some(vec![60]));
+        // Nothing is exact, 35 leaves the least excess
+        assert_eq!(selected(&LeastWaste, &[50, 35, 25], 33), Some(vec![35]));
+        assert_eq!(selected(&LeastWaste, &[5], 0), Some(vec![]));
+    }
+}
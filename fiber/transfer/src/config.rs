@@ -0,0 +1,1186 @@
+use crate::amount::{AmountConfig, CKB_DECIMALS};
+use crate::coin_selection::CoinSelection;
+use crate::distribution::Distribution;
+use crate::error::{Error, Result};
+use crate::fiber::{ChannelPlan, PaymentPlan};
+use crate::load::{Arrival, LoadPlan};
+use crate::network::{
+    self, AcpScript, NetworkPreset, SUDT_TOKEN_NAME, SafetyLimits, SudtScript, Token,
+};
+use crate::rpc::ChainBackend;
+use crate::scenario::{ScenarioStep, StepKind};
+use ckb_jsonrpc_types::ScriptHashType;
+use ckb_types::H256;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::info;
+
+// Config file read from the working directory unless --config is given
+pub const DEFAULT_CONFIG_FILE: &str = "fiber-demo.toml";
+
+// Network preset (devnet, testnet or mainnet), overridable via NETWORK env var
+const DEFAULT_NETWORK: &str = "devnet";
+
+// Devnet genesis account, overridable via SOURCE_PRIVATE_KEY env var
+const SOURCE_PRIVATE_KEY: &str = "63d86723e08f0f813a36ce6aa123bb2289d90680ae1e99d4de8cdb334553f24d";
+
+// Key file paths
+const BOOTNODE_KEY_FILE: &str = "ckb-keys/bootnode-key";
+
+const NODE1_KEY_FILE: &str = "ckb-keys/node1-key";
+const NODE2_KEY_FILE: &str = "ckb-keys/node2-key";
+const NODE3_KEY_FILE: &str = "ckb-keys/node3-key";
+
+// Ports the Fiber nodes listen on inside their containers
+const FIBER_P2P_PORT: u16 = 8228;
+const FIBER_RPC_PORT: u16 = 41716;
+
+// Fiber node RPC endpoints as seen from the transfer container, forwarded to each
+// node's RPC port by its start.sh
+const BOOTNODE_FIBER_RPC_URL: &str = "http://fiber-bootnode:10000";
+const NODE1_FIBER_RPC_URL: &str = "http://fiber-node1:10000";
+const NODE2_FIBER_RPC_URL: &str = "http://fiber-node2:10000";
+const NODE3_FIBER_RPC_URL: &str = "http://fiber-node3:10000";
+
+// 1 billion CKB per node, unless the node sets its own amount
+const CKB_TRANSFER_AMOUNT: u64 = 1_000_000_000;
+// 1 billion sUDT per node receiving sUDT, unless the node sets its own amount
+const SUDT_TRANSFER_AMOUNT: u64 = 1_000_000_000;
+// Smoke test payment made after the channels open, in whole CKB, from the first to
+// the last demo node
+const SMOKE_TEST_AMOUNT: u64 = 1;
+const SMOKE_TEST_FROM: &str = "Node1";
+const SMOKE_TEST_TO: &str = "Node3";
+// Payment load generated by the load command unless [load] says otherwise: one 1 CKB
+// payment per second for a minute, after 10 seconds of warm-up, with at most 64
+// payments unsettled at a time
+const LOAD_RATE: f64 = 1.0;
+const LOAD_AMOUNT: f64 = 1.0;
+const LOAD_WARM_UP_SECS: u64 = 10;
+const LOAD_DURATION_SECS: u64 = 60;
+const LOAD_MAX_IN_FLIGHT: usize = 64;
+// Expiry of the invoice an expired_invoice scenario step pays, in seconds
+const SCENARIO_INVOICE_EXPIRY_SECS: u64 = 1;
+// sUDT minted by issue-sudt unless --amount or SUDT_SUPPLY is given, enough for many
+// setup runs
+pub const DEFAULT_SUDT_SUPPLY: u128 = 1_000_000_000_000_000;
+
+// Fee rate in shannons per 1000 bytes; the node's default minimum is 1000
+const DEFAULT_FEE_RATE: u64 = 1000;
+
+/// Settings of a run, loaded from `fiber-demo.toml`. Every field is optional and
+/// defaults to the docker-compose devnet setup.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Network preset, `devnet`, `testnet` or `mainnet`
+    pub network: String,
+    /// CKB RPC endpoint, defaults to the preset's
+    pub ckb_rpc_url: Option<String>,
+    /// Endpoints of the same chain failed over to when `ckb_rpc_url` fails
+    pub ckb_rpc_fallback_urls: Vec<String>,
+    /// What `ckb_rpc_url` is, a full node with its indexer or a light client
+    pub backend: ChainBackend,
+    /// Hex private key of the funding account
+    pub source_private_key: String,
+    /// CKB sent to accounts that don't set `ckb_amount`, whole CKB when an integer
+    pub ckb_amount: AmountConfig,
+    /// sUDT sent to accounts that don't set `sudt_amount`, base units when an integer
+    pub sudt_amount: AmountConfig,
+    /// Accounts funded by `setup`
+    pub nodes: Vec<NodeConfig>,
+    /// How transfers pick the sender's cells to spend
+    pub coin_selection: CoinSelection,
+    /// Fee rate of the transactions sent, in shannons per 1000 bytes
+    pub fee_rate: u64,
+    /// Overrides of the preset's safety limits
+    pub limits: LimitsConfig,
+    /// Overrides of the preset's sighash lock deployment
+    pub sighash: SighashConfig,
+    /// Overrides of the preset's sUDT deployment
+    pub sudt: SudtConfig,
+    /// UDT tokens funded by `setup` in addition to the preset's sUDT
+    pub tokens: Vec<TokenConfig>,
+    /// Overrides of the preset's anyone-can-pay lock deployment
+    pub acp: AcpConfig,
+    /// Channels `setup` opens between the nodes once they are funded
+    pub channels: Vec<ChannelConfig>,
+    /// Channel graph opened in addition to `channels`
+    pub topology: Option<TopologyConfig>,
+    /// Fee rate of the channels' funding transactions in shannons per 1000 bytes,
+    /// for channels that don't set their own; unset leaves it to the Fiber node
+    pub funding_fee_rate: Option<u64>,
+    /// Settings of the Fiber node configs written by `gen-config`
+    pub fiber: FiberConfig,
+    /// Payment `setup` makes through the channels once they are open
+    pub smoke_test: SmokeTestConfig,
+    /// Payments the `load` command makes through the channels
+    pub load: LoadConfig,
+    /// Payments the `scenario` command makes, most of them made to fail
+    pub scenario: Vec<ScenarioStepConfig>,
+}
+
+/// An account to fund, either a configured node or an entry of a recipients file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    pub name: String,
+    /// CKB address, key file path or hex private key of the account
+    #[serde(alias = "address", alias = "key_file", alias = "key")]
+    pub account: String,
+    /// Defaults to the top-level `ckb_amount`
+    pub ckb_amount: Option<AmountConfig>,
+    /// TOML integers are 64-bit, which is plenty for the demo token; larger amounts
+    /// can be given as strings. Defaults to the top-level `sudt_amount`.
+    pub sudt_amount: Option<AmountConfig>,
+    /// Amounts of the additional `tokens` by token name, defaulting to each token's
+    /// `amount`
+    #[serde(default)]
+    pub token_amounts: BTreeMap<String, AmountConfig>,
+    /// Lock of the account's token cells, its own sighash lock by default
+    #[serde(default)]
+    pub lock: LockKind,
+    /// Assets the account receives, both by default. `sudt` covers the additional
+    /// tokens too.
+    #[serde(default = "all_assets")]
+    pub assets: Vec<Asset>,
+    /// JSON-RPC endpoint of the account's Fiber node, needed to open or accept channels
+    pub fiber_rpc_url: Option<String>,
+    /// The account's Fiber node, whose config `gen-config` writes
+    pub fiber: Option<FiberNodeConfig>,
+}
+
+/// A Fiber node funded by the account of a `nodes` entry
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FiberNodeConfig {
+    /// Directory of the node under the `gen-config` output directory, e.g. `node1`
+    pub dir: String,
+    /// Host name the node is announced and reached by, e.g. `fiber-node1`
+    pub name: String,
+    #[serde(default = "default_p2p_port")]
+    pub p2p_port: u16,
+    #[serde(default = "default_rpc_port")]
+    pub rpc_port: u16,
+    /// Whether the other nodes bootstrap from this one
+    #[serde(default)]
+    pub bootnode: bool,
+}
+
+fn default_p2p_port() -> u16 {
+    FIBER_P2P_PORT
+}
+
+fn default_rpc_port() -> u16 {
+    FIBER_RPC_PORT
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FiberConfig {
+    /// CKB RPC endpoint as seen from the Fiber nodes, defaults to the funder's
+    pub ckb_rpc_url: Option<String>,
+    /// `chain` of the generated node configs, defaults to the preset's. A relative spec
+    /// path resolves in the node's directory when `run-nodes` starts it.
+    pub chain: Option<String>,
+}
+
+/// A payment from one node to another, routed through the open channels
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmokeTestConfig {
+    /// Name of the node paying the invoice
+    pub from: String,
+    /// Name of the node issuing the invoice
+    pub to: String,
+    /// Token paid, CKB when unset
+    pub token: Option<String>,
+    /// Whole CKB or token base units when an integer
+    pub amount: AmountConfig,
+    /// Make the payment at the end of `setup`, when it opened channels
+    pub enabled: bool,
+}
+
+impl Default for SmokeTestConfig {
+    fn default() -> Self {
+        SmokeTestConfig {
+            from: SMOKE_TEST_FROM.to_string(),
+            to: SMOKE_TEST_TO.to_string(),
+            token: None,
+            amount: AmountConfig::Units(SMOKE_TEST_AMOUNT),
+            enabled: true,
+        }
+    }
+}
+
+/// A stream of payments from one node to another, for performance runs
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadConfig {
+    /// Name of the node paying the invoices
+    pub from: String,
+    /// Name of the node issuing the invoices
+    pub to: String,
+    /// Token paid, CKB when unset
+    pub token: Option<String>,
+    /// Arrival process of the payments
+    pub arrival: Arrival,
+    /// Distribution of the payment amounts, in CKB or token base units
+    pub amount: Distribution,
+    /// Seconds of payments made before measuring
+    pub warm_up_secs: u64,
+    /// Seconds of measured payments
+    pub duration_secs: u64,
+    /// Unsettled payments above which arrivals are skipped
+    pub max_in_flight: usize,
+    /// Seed of the arrival gaps and amounts, a random one when unset
+    pub seed: Option<u64>,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        LoadConfig {
+            from: SMOKE_TEST_FROM.to_string(),
+            to: SMOKE_TEST_TO.to_string(),
+            token: None,
+            arrival: Arrival::Constant { rate: LOAD_RATE },
+            amount: Distribution::Fixed { value: LOAD_AMOUNT },
+            warm_up_secs: LOAD_WARM_UP_SECS,
+            duration_secs: LOAD_DURATION_SECS,
+            max_in_flight: LOAD_MAX_IN_FLIGHT,
+            seed: None,
+        }
+    }
+}
+
+/// A step of the `scenario` command: a payment made to succeed, or made to fail one
+/// way with the error it must fail with
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScenarioStepConfig {
+    /// Shown in the results, defaults to the kind
+    pub name: Option<String>,
+    pub kind: StepKind,
+    /// Name of the node paying the invoice
+    pub from: String,
+    /// Name of the node issuing the invoice
+    pub to: String,
+    /// Token paid, CKB when unset
+    pub token: Option<String>,
+    /// Whole CKB or token base units when an integer. Defaults to one more than the
+    /// payer's channels hold for `above_capacity` and to 1 otherwise.
+    pub amount: Option<AmountConfig>,
+    /// Text the error of the payment must contain, e.g. the Fiber node's error name.
+    /// Unset, `pay` must succeed and the other kinds must fail with any error.
+    pub expect_error: Option<String>,
+    /// Expiry of the invoice of `expired_invoice`, 1 second by default
+    pub expiry_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Asset {
+    Ckb,
+    Sudt,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LockKind {
+    #[default]
+    Sighash,
+    /// Anyone-can-pay with the account's sighash args, so later payments can top up
+    /// the cell instead of creating new ones
+    Acp,
+}
+
+fn all_assets() -> Vec<Asset> {
+    vec![Asset::Ckb, Asset::Sudt]
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SighashConfig {
+    /// Out point of the lock's dep group, looked up in the genesis block when not set
+    pub dep_group: Option<CellDepConfig>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SudtConfig {
+    pub code_hash: Option<H256>,
+    pub hash_type: Option<ScriptHashType>,
+    /// Hex owner lock hash
+    pub args: Option<String>,
+    pub cell_dep: Option<CellDepConfig>,
+    /// Digits after the decimal point of amounts in human units, 0 by default
+    pub decimals: Option<u8>,
+}
+
+/// An additional UDT token. The deployment defaults to the preset's sUDT or xUDT, as
+/// `kind` says, so only `name` and `args` are needed for another token of the same
+/// contract.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    /// Name used in logs and in the nodes' `token_amounts`
+    pub name: String,
+    #[serde(default)]
+    pub kind: TokenKind,
+    pub code_hash: Option<H256>,
+    pub hash_type: Option<ScriptHashType>,
+    /// Hex owner lock hash
+    pub args: String,
+    pub cell_dep: Option<CellDepConfig>,
+    /// Digits after the decimal point of amounts in human units, 0 by default
+    pub decimals: Option<u8>,
+    /// Sent to accounts that don't set their own, defaults to the top-level
+    /// `sudt_amount`
+    pub amount: Option<AmountConfig>,
+}
+
+/// The contract a token's deployment defaults to
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    #[default]
+    Sudt,
+    Xudt,
+}
+
+/// Overrides of the preset's safety limits
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Shannons per 1000 bytes
+    pub max_fee_rate: Option<u64>,
+    /// Whole CKB
+    pub max_ckb_per_account: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcpConfig {
+    pub code_hash: Option<H256>,
+    /// `type` unless set
+    pub hash_type: Option<ScriptHashType>,
+}
+
+/// A channel between two nodes, funded by the opening node and optionally by the
+/// accepting one
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelConfig {
+    /// Name of the node opening the channel
+    pub from: String,
+    /// Name of the node accepting the channel
+    pub to: String,
+    /// Token funded into the channel, CKB when unset
+    pub token: Option<String>,
+    /// Whole CKB or token base units when an integer
+    pub funding_amount: AmountConfig,
+    /// Funding of the accepting node; unset leaves the acceptance to the node's
+    /// auto-accept settings
+    pub accept_amount: Option<AmountConfig>,
+    /// Announce the channel to the network, true by default
+    #[serde(default = "default_public")]
+    pub public: bool,
+    /// Fee rate of the funding transaction in shannons per 1000 bytes, defaulting to
+    /// the top-level `funding_fee_rate`
+    pub funding_fee_rate: Option<u64>,
+}
+
+fn default_public() -> bool {
+    true
+}
+
+/// A channel graph over several nodes, whose edges are opened like `channels` with
+/// the same funding, or with capacities and pushes sampled from distributions
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopologyConfig {
+    pub shape: TopologyShape,
+    /// Nodes of the graph in order, defaults to every node with a `fiber_rpc_url`
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    /// Center of a star, defaults to the first node
+    pub hub: Option<String>,
+    pub token: Option<String>,
+    /// Required unless `capacity` is set
+    pub funding_amount: Option<AmountConfig>,
+    pub accept_amount: Option<AmountConfig>,
+    #[serde(default = "default_public")]
+    pub public: bool,
+    pub funding_fee_rate: Option<u64>,
+    /// Distribution each channel's capacity is sampled from, in whole CKB or token
+    /// base units, instead of one `funding_amount`
+    pub capacity: Option<Distribution>,
+    /// Distribution of the share of each sampled capacity, from 0 to 1, that starts
+    /// on the accepting node's side. Fiber channels have no push amount, so the
+    /// accepting node funds that share with `accept_channel`.
+    pub push: Option<Distribution>,
+    /// Seed of the sampling, so every run samples the same channels. A random one is
+    /// drawn and logged when unset.
+    pub seed: Option<u64>,
+    #[serde(skip)]
+    drawn_seed: OnceLock<u64>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TopologyShape {
+    /// A channel between every two nodes
+    Mesh,
+    /// A channel from each node to the next, the last one closing the ring
+    Ring,
+    /// A channel from the hub to every other node
+    Star,
+}
+
+impl TopologyConfig {
+    /// The channels of the graph over `nodes`, with their capacities and pushes
+    /// sampled in order when `capacity` is set
+    fn edges(&self, nodes: &[NodeConfig]) -> Result<Vec<ChannelConfig>> {
+        let names: Vec<&str> = if self.nodes.is_empty() {
+            nodes
+                .iter()
+                .filter(|node| node.fiber_rpc_url.is_some())
+                .map(|node| node.name.as_str())
+                .collect()
+        } else {
+            self.nodes.iter().map(String::as_str).collect()
+        };
+        let pairs: Vec<(&str, &str)> = match self.shape {
+            TopologyShape::Mesh => names
+                .iter()
+                .enumerate()
+                .flat_map(|(i, from)| names[i + 1..].iter().map(move |to| (*from, *to)))
+                .collect(),
+            // Two nodes need a single channel
+            TopologyShape::Ring if names.len() == 2 => vec![(names[0], names[1])],
+            TopologyShape::Ring if names.len() > 2 => (0..names.len())
+                .map(|i| (names[i], names[(i + 1) % names.len()]))
+                .collect(),
+            TopologyShape::Ring => Vec::new(),
+            TopologyShape::Star => match self.hub.as_deref().or(names.first().copied()) {
+                Some(hub) => names
+                    .iter()
+                    .filter(|name| !name.eq_ignore_ascii_case(hub))
+                    .map(|name| (hub, *name))
+                    .collect(),
+                None => Vec::new(),
+            },
+        };
+        let mut sampler = match &self.capacity {
+            Some(capacity) => Some((capacity, ChaCha8Rng::seed_from_u64(self.seed()?))),
+            None => None,
+        };
+        pairs
+            .into_iter()
+            .map(|(from, to)| {
+                let (funding_amount, accept_amount) = match &mut sampler {
+                    Some((capacity, rng)) => {
+                        // Float to integer casts saturate, and a channel needs some
+                        // funding from its opener
+                        let capacity = (capacity.sample(rng).round() as u64).max(1);
+                        let share = self
+                            .push
+                            .as_ref()
+                            .map(|push| push.sample(rng).clamp(0.0, 1.0));
+                        let pushed = share.map(|share| {
+                            ((capacity as f64 * share).round() as u64).min(capacity - 1)
+                        });
+                        (
+                            AmountConfig::Units(capacity - pushed.unwrap_or(0)),
+                            pushed.map(AmountConfig::Units),
+                        )
+                    }
+                    None => (
+                        self.funding_amount.clone().ok_or_else(|| {
+                            Error::Config(
+                                "topology needs a funding_amount or a capacity".to_string(),
+                            )
+                        })?,
+                        self.accept_amount.clone(),
+                    ),
+                };
+                Ok(ChannelConfig {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    token: self.token.clone(),
+                    funding_amount,
+                    accept_amount,
+                    public: self.public,
+                    funding_fee_rate: self.funding_fee_rate,
+                })
+            })
+            .collect()
+    }
+
+    /// Fail with `Error::Config` when the sampling settings contradict each other or
+    /// the fixed amounts
+    fn check_sampling(&self) -> Result<()> {
+        let conflict = |message: &str| Err(Error::Config(format!("topology {}", message)));
+        match (&self.capacity, &self.push) {
+            (Some(_), _) if self.funding_amount.is_some() => {
+                conflict("sets both funding_amount and capacity")
+            }
+            (Some(_), _) if self.accept_amount.is_some() => {
+                conflict("sets both accept_amount and capacity, use push to sample it")
+            }
+            (Some(capacity), push) => {
+                capacity.check("topology capacity")?;
+                push.as_ref()
+                    .map_or(Ok(()), |push| push.check("topology push"))
+            }
+            (None, Some(_)) => conflict("sets push without a capacity to sample"),
+            (None, None) if self.seed.is_some() => {
+                conflict("sets seed without a capacity to sample")
+            }
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// The configured seed, or one drawn and logged on first use
+    fn seed(&self) -> Result<u64> {
+        if let Some(seed) = self.seed.or(self.drawn_seed.get().copied()) {
+            return Ok(seed);
+        }
+        let mut bytes = [0u8; 8];
+        getrandom::getrandom(&mut bytes).map_err(Error::random("a topology seed"))?;
+        let seed = *self.drawn_seed.get_or_init(|| u64::from_le_bytes(bytes));
+        info!(
+            "Sampling the topology's channels with seed {}; set seed in [topology] to sample the same ones again",
+            seed
+        );
+        Ok(seed)
+    }
+}
+
+impl ChannelConfig {
+    /// Whether both channels connect the same two nodes with the same asset, in either
+    /// direction
+    fn same_link(&self, other: &ChannelConfig) -> bool {
+        let same = |a: &str, b: &str| a.eq_ignore_ascii_case(b);
+        let same_token = match (&self.token, &other.token) {
+            (Some(a), Some(b)) => same(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        same_token
+            && (same(&self.from, &other.from) && same(&self.to, &other.to)
+                || same(&self.from, &other.to) && same(&self.to, &other.from))
+    }
+}
+
+/// Base units of `amount` of `token`, or of CKB when None, in channels and payments
+fn channel_units(token: Option<&Token>, amount: &AmountConfig) -> Result<u128> {
+    match token {
+        Some(token) => amount.base_units(&token.name, token.decimals, 1),
+        None => amount.base_units("CKB", CKB_DECIMALS, 100000000),
+    }
+}
+
+/// Order channels so they grow one connected network: each channel after the first
+/// joins a node an earlier channel connected, where there is one
+fn connected_order(mut remaining: Vec<ChannelPlan>) -> Vec<ChannelPlan> {
+    let mut ordered = Vec::with_capacity(remaining.len());
+    let mut connected: Vec<&str> = Vec::new();
+    while !remaining.is_empty() {
+        let next = remaining
+            .iter()
+            .position(|c| connected.contains(&c.from) || connected.contains(&c.to))
+            .unwrap_or(0);
+        let channel = remaining.remove(next);
+        connected.extend([channel.from, channel.to]);
+        ordered.push(channel);
+    }
+    ordered
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CellDepConfig {
+    pub tx_hash: H256,
+    pub index: u32,
+}
+
+/// Parse a script hash type as written in the config file: `data`, `type`, `data1` or
+/// `data2`
+fn parse_hash_type(value: &str) -> Result<ScriptHashType> {
+    match value.trim().to_lowercase().as_str() {
+        "data" => Ok(ScriptHashType::Data),
+        "type" => Ok(ScriptHashType::Type),
+        "data1" => Ok(ScriptHashType::Data1),
+        "data2" => Ok(ScriptHashType::Data2),
+        _ => Err(Error::Config(format!(
+            "Invalid SUDT_HASH_TYPE value {:?}, expected data, type, data1 or data2",
+            value
+        ))),
+    }
+}
+
+/// The token named `name` among `tokens`, matched case-insensitively
+pub fn find_token<'a>(tokens: &'a [Token], name: &str) -> Result<&'a Token> {
+    tokens
+        .iter()
+        .find(|token| token.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            Error::UnknownToken(format!(
+                "Unknown token {:?}, configured tokens: {}",
+                name,
+                tokens
+                    .iter()
+                    .map(|token| token.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        })
+}
+
+/// Fail when a node sets an amount of a token that isn't among `tokens`, so a
+/// misspelled name stops the run before any transfer instead of being ignored
+pub fn check_token_names(tokens: &[Token], nodes: &[NodeConfig]) -> Result<()> {
+    for node in nodes {
+        for name in node.token_amounts.keys() {
+            find_token(tokens, name)
+                .map_err(|e| Error::UnknownToken(format!("Node {}: {}", node.name, e)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Recipients file: `[[recipients]]` tables in TOML, or `{"recipients": [...]}` in JSON
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RecipientsFile {
+    recipients: Vec<NodeConfig>,
+}
+
+/// Load a recipients file, parsed as JSON when it has a `.json` extension and as TOML
+/// otherwise
+pub fn load_recipients(path: &Path) -> Result<Vec<NodeConfig>> {
+    let content = fs::read_to_string(path).map_err(Error::io(format!(
+        "Failed to read recipients file {}",
+        path.display()
+    )))?;
+    let file: RecipientsFile = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&content).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&content).map_err(|e| e.to_string())
+    }
+    .map_err(|e| Error::Config(format!("Invalid recipients file {}: {}", path.display(), e)))?;
+    if file.recipients.is_empty() {
+        return Err(Error::Config(format!(
+            "Recipients file {} lists no recipients",
+            path.display()
+        )));
+    }
+    Ok(file.recipients)
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let node = |name: &str, key_file: &str, fiber_rpc_url: &str, assets| {
+            let dir = name.to_lowercase();
+            NodeConfig {
+                name: name.to_string(),
+                account: key_file.to_string(),
+                ckb_amount: None,
+                sudt_amount: None,
+                token_amounts: BTreeMap::new(),
+                lock: LockKind::Sighash,
+                assets,
+                fiber_rpc_url: Some(fiber_rpc_url.to_string()),
+                fiber: Some(FiberNodeConfig {
+                    name: format!("fiber-{}", dir),
+                    bootnode: dir == "bootnode",
+                    dir,
+                    p2p_port: FIBER_P2P_PORT,
+                    rpc_port: FIBER_RPC_PORT,
+                }),
+            }
+        };
+        Config {
+            network: DEFAULT_NETWORK.to_string(),
+            ckb_rpc_url: None,
+            ckb_rpc_fallback_urls: Vec::new(),
+            source_private_key: SOURCE_PRIVATE_KEY.to_string(),
+            ckb_amount: AmountConfig::Units(CKB_TRANSFER_AMOUNT),
+            sudt_amount: AmountConfig::Units(SUDT_TRANSFER_AMOUNT),
+            // The bootnode only relays, so it gets no sUDT
+            nodes: vec![
+                node(
+                    "Bootnode",
+                    BOOTNODE_KEY_FILE,
+                    BOOTNODE_FIBER_RPC_URL,
+                    vec![Asset::Ckb],
+                ),
+                node("Node1", NODE1_KEY_FILE, NODE1_FIBER_RPC_URL, all_assets()),
+                node("Node2", NODE2_KEY_FILE, NODE2_FIBER_RPC_URL, all_assets()),
+                node("Node3", NODE3_KEY_FILE, NODE3_FIBER_RPC_URL, all_assets()),
+            ],
+            backend: ChainBackend::default(),
+            coin_selection: CoinSelection::default(),
+            fee_rate: DEFAULT_FEE_RATE,
+            limits: LimitsConfig::default(),
+            sighash: SighashConfig::default(),
+            sudt: SudtConfig::default(),
+            tokens: Vec::new(),
+            acp: AcpConfig::default(),
+            channels: Vec::new(),
+            topology: None,
+            funding_fee_rate: None,
+            fiber: FiberConfig::default(),
+            smoke_test: SmokeTestConfig::default(),
+            load: LoadConfig::default(),
+            scenario: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file, falling back to the defaults when it doesn't exist, then
+    /// apply the NETWORK, CKB_RPC_URL, CKB_RPC_FALLBACK_URLS, CKB_BACKEND,
+    /// SOURCE_PRIVATE_KEY, SUDT_CODE_HASH, SUDT_HASH_TYPE, SUDT_ARGS, COIN_SELECTION and
+    /// FEE_RATE env overrides
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut config = match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content).map_err(|e| {
+                Error::Config(format!("Invalid config file {}: {}", path.display(), e))
+            })?,
+            Err(_) => Config::default(),
+        };
+        if let Ok(network) = env::var("NETWORK") {
+            config.network = network;
+        }
+        if let Ok(url) = env::var("CKB_RPC_URL") {
+            config.ckb_rpc_url = Some(url);
+        }
+        if let Ok(urls) = env::var("CKB_RPC_FALLBACK_URLS") {
+            config.ckb_rpc_fallback_urls = urls
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Ok(backend) = env::var("CKB_BACKEND") {
+            config.backend = ChainBackend::deserialize(backend.as_str().into_deserializer())
+                .map_err(|e: serde::de::value::Error| {
+                    Error::Config(format!("Invalid CKB_BACKEND value {:?}: {}", backend, e))
+                })?;
+        }
+        if let Ok(key) = env::var("SOURCE_PRIVATE_KEY") {
+            config.source_private_key = key;
+        }
+        if let Ok(code_hash) = env::var("SUDT_CODE_HASH") {
+            config.sudt.code_hash = Some(
+                H256::from_str(code_hash.trim_start_matches("0x")).map_err(|e| {
+                    Error::Config(format!(
+                        "Invalid SUDT_CODE_HASH value {:?}: {}",
+                        code_hash, e
+                    ))
+                })?,
+            );
+        }
+        if let Ok(hash_type) = env::var("SUDT_HASH_TYPE") {
+            config.sudt.hash_type = Some(parse_hash_type(&hash_type)?);
+        }
+        if let Ok(args) = env::var("SUDT_ARGS") {
+            config.sudt.args = Some(args);
+        }
+        if let Ok(selection) = env::var("COIN_SELECTION") {
+            config.coin_selection = CoinSelection::deserialize(
+                selection.as_str().into_deserializer(),
+            )
+            .map_err(|e: serde::de::value::Error| {
+                Error::Config(format!(
+                    "Invalid COIN_SELECTION value {:?}: {}",
+                    selection, e
+                ))
+            })?;
+        }
+        if let Ok(rate) = env::var("FEE_RATE") {
+            config.fee_rate = rate
+                .trim()
+                .parse()
+                .map_err(|e| Error::Config(format!("Invalid FEE_RATE value {:?}: {}", rate, e)))?;
+        }
+        Ok(config)
+    }
+
+    /// The selected network preset with the configured sighash, sUDT and ACP overrides
+    /// applied
+    pub fn network_preset(&self) -> Result<NetworkPreset> {
+        let mut preset = network::preset(&self.network)?;
+        if let Some(code_hash) = &self.acp.code_hash {
+            preset.acp = Some(AcpScript {
+                code_hash: code_hash.clone(),
+                hash_type: self.acp.hash_type.unwrap_or(ScriptHashType::Type),
+            });
+        } else if let (Some(acp), Some(hash_type)) = (&mut preset.acp, self.acp.hash_type) {
+            acp.hash_type = hash_type;
+        }
+        preset.limits = self.limits()?;
+        if let Some(dep_group) = &self.sighash.dep_group {
+            preset.sighash_dep_group = Some((dep_group.tx_hash.clone(), dep_group.index));
+        }
+        let sudt = &mut preset.sudt;
+        if let Some(code_hash) = &self.sudt.code_hash {
+            sudt.code_hash = code_hash.clone();
+        }
+        if let Some(hash_type) = self.sudt.hash_type {
+            sudt.hash_type = hash_type;
+        }
+        if let Some(args) = &self.sudt.args {
+            sudt.args = hex::decode(args.trim_start_matches("0x"))
+                .map_err(|e| Error::Config(format!("Invalid sUDT args {:?}: {}", args, e)))?;
+        }
+        if let Some(cell_dep) = &self.sudt.cell_dep {
+            sudt.cell_dep = Some((cell_dep.tx_hash.clone(), cell_dep.index));
+        }
+        if sudt.args.is_empty() {
+            return Err(Error::Config(format!(
+                "sUDT args are required on {}, set sudt.args or SUDT_ARGS",
+                preset.name
+            )));
+        }
+        Ok(preset)
+    }
+
+    /// The network's safety limits with the config's overrides applied
+    pub fn limits(&self) -> Result<SafetyLimits> {
+        let limits = network::preset(&self.network)?.limits;
+        Ok(SafetyLimits {
+            max_fee_rate: self.limits.max_fee_rate.or(limits.max_fee_rate),
+            max_ckb_per_account: self
+                .limits
+                .max_ckb_per_account
+                .or(limits.max_ckb_per_account),
+        })
+    }
+
+    /// The tokens funded by `setup`: the preset's sUDT followed by the configured
+    /// `tokens`, which default to the preset's sUDT deployment
+    pub fn tokens(&self, preset: &NetworkPreset) -> Result<Vec<Token>> {
+        let decimals = self.sudt.decimals.unwrap_or(0);
+        let mut tokens = vec![Token {
+            name: SUDT_TOKEN_NAME.to_string(),
+            script: preset.sudt.clone(),
+            decimals,
+            amount: self.sudt_amount.base_units(SUDT_TOKEN_NAME, decimals, 1)?,
+        }];
+        for token in &self.tokens {
+            if tokens
+                .iter()
+                .any(|t| t.name.eq_ignore_ascii_case(&token.name))
+            {
+                return Err(Error::Config(format!(
+                    "Duplicate token name {:?}",
+                    token.name
+                )));
+            }
+            let deployment = match token.kind {
+                TokenKind::Sudt => &preset.sudt,
+                TokenKind::Xudt => &preset.xudt,
+            };
+            let script = SudtScript {
+                code_hash: token
+                    .code_hash
+                    .clone()
+                    .unwrap_or_else(|| deployment.code_hash.clone()),
+                hash_type: token.hash_type.unwrap_or(deployment.hash_type),
+                args: hex::decode(token.args.trim_start_matches("0x")).map_err(|e| {
+                    Error::Config(format!("Invalid args of token {:?}: {}", token.name, e))
+                })?,
+                cell_dep: match &token.cell_dep {
+                    Some(cell_dep) => Some((cell_dep.tx_hash.clone(), cell_dep.index)),
+                    None => deployment.cell_dep.clone(),
+                },
+            };
+            let decimals = token.decimals.unwrap_or(0);
+            tokens.push(Token {
+                name: token.name.clone(),
+                script,
+                decimals,
+                amount: token
+                    .amount
+                    .as_ref()
+                    .unwrap_or(&self.sudt_amount)
+                    .base_units(&token.name, decimals, 1)?,
+            });
+        }
+        for token in &tokens {
+            // Larger scales overflow u128
+            if token.decimals > 38 {
+                return Err(Error::Config(format!(
+                    "Token {:?} has {} decimals, at most 38 are supported",
+                    token.name, token.decimals
+                )));
+            }
+        }
+        check_token_names(&tokens, &self.nodes)?;
+        Ok(tokens)
+    }
+
+    /// Base units of `token` sent to `node`, 0 when it doesn't receive sUDT
+    pub fn token_amount(&self, node: &NodeConfig, token: &Token) -> Result<u128> {
+        if !node.assets.contains(&Asset::Sudt) {
+            return Ok(0);
+        }
+        let own_amount = match node
+            .token_amounts
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&token.name))
+            .map(|(_, amount)| amount)
+        {
+            Some(amount) => Some(amount),
+            None if token.name == SUDT_TOKEN_NAME => node.sudt_amount.as_ref(),
+            None => None,
+        };
+        match own_amount {
+            Some(amount) => amount.base_units(&token.name, token.decimals, 1),
+            None => Ok(token.amount),
+        }
+    }
+
+    /// Shannons sent to `node`, 0 when it doesn't receive CKB
+    pub fn ckb_amount(&self, node: &NodeConfig) -> Result<u64> {
+        if !node.assets.contains(&Asset::Ckb) {
+            return Ok(0);
+        }
+        let shannons = node
+            .ckb_amount
+            .as_ref()
+            .unwrap_or(&self.ckb_amount)
+            .base_units("CKB", CKB_DECIMALS, 100000000)?;
+        u64::try_from(shannons)
+            .map_err(|_| Error::InvalidAmount(format!("CKB amount of {} is too large", node.name)))
+    }
+
+    /// The configured `channels` followed by the edges of the `topology` they don't
+    /// already cover, with their nodes, tokens and amounts resolved, in the order they
+    /// are opened. Nodes are looked up among the accounts of the run.
+    pub fn channels<'a>(&'a self, tokens: &'a [Token]) -> Result<Vec<ChannelPlan<'a>>> {
+        let mut edges = self.channels.clone();
+        if let Some(topology) = &self.topology {
+            topology.check_sampling()?;
+            for edge in topology.edges(&self.nodes)? {
+                if !edges.iter().any(|channel| channel.same_link(&edge)) {
+                    edges.push(edge);
+                }
+            }
+        }
+        let channels = edges
+            .iter()
+            .map(|channel| {
+                let (from, from_rpc_url) = self.fiber_node(&channel.from, "channels")?;
+                let (to, to_rpc_url) = self.fiber_node(&channel.to, "channels")?;
+                let token = match &channel.token {
+                    Some(name) => Some(find_token(tokens, name)?),
+                    None => None,
+                };
+                let base_units = |amount: &AmountConfig| channel_units(token, amount);
+                let funding_fee_rate = channel.funding_fee_rate.or(self.funding_fee_rate);
+                if let Some(rate) = funding_fee_rate {
+                    self.limits()?.check_fee_rate(&self.network, rate)?;
+                }
+                Ok(ChannelPlan {
+                    from,
+                    from_rpc_url,
+                    to,
+                    to_rpc_url,
+                    token,
+                    funding_amount: base_units(&channel.funding_amount)?,
+                    accept_amount: channel.accept_amount.as_ref().map(base_units).transpose()?,
+                    public: channel.public,
+                    funding_fee_rate,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(connected_order(channels))
+    }
+
+    /// The smoke test payment described by `test`, with its nodes, token and amount
+    /// resolved
+    pub fn payment<'a>(
+        &'a self,
+        test: &SmokeTestConfig,
+        tokens: &'a [Token],
+    ) -> Result<PaymentPlan<'a>> {
+        let (from, from_rpc_url) = self.fiber_node(&test.from, "the smoke test")?;
+        let (to, to_rpc_url) = self.fiber_node(&test.to, "the smoke test")?;
+        let token = match &test.token {
+            Some(name) => Some(find_token(tokens, name)?),
+            None => None,
+        };
+        Ok(PaymentPlan {
+            from,
+            from_rpc_url,
+            to,
+            to_rpc_url,
+            token,
+            amount: channel_units(token, &test.amount)?,
+        })
+    }
+
+    /// The payment load described by `load`, with its nodes and token resolved and its
+    /// distributions checked
+    pub fn load_plan<'a>(&'a self, load: &LoadConfig, tokens: &'a [Token]) -> Result<LoadPlan<'a>> {
+        let (from, from_rpc_url) = self.fiber_node(&load.from, "the load")?;
+        let (to, to_rpc_url) = self.fiber_node(&load.to, "the load")?;
+        let token = match &load.token {
+            Some(name) => Some(find_token(tokens, name)?),
+            None => None,
+        };
+        load.arrival.check()?;
+        load.amount.check("load amount")?;
+        if load.duration_secs == 0 {
+            return Err(Error::Config(
+                "Invalid load duration_secs 0: nothing would be measured".to_string(),
+            ));
+        }
+        Ok(LoadPlan {
+            from,
+            from_rpc_url,
+            to,
+            to_rpc_url,
+            token,
+            arrival: load.arrival.clone(),
+            amount: load.amount.clone(),
+            warm_up: Duration::from_secs(load.warm_up_secs),
+            duration: Duration::from_secs(load.duration_secs),
+            max_in_flight: load.max_in_flight.max(1),
+            seed: load.seed,
+        })
+    }
+
+    /// The `scenario` steps with their nodes, tokens and amounts resolved
+    pub fn scenario_steps<'a>(&'a self, tokens: &'a [Token]) -> Result<Vec<ScenarioStep<'a>>> {
+        self.scenario
+            .iter()
+            .map(|step| {
+                let (from, from_rpc_url) = self.fiber_node(&step.from, "the scenario")?;
+                let (to, to_rpc_url) = self.fiber_node(&step.to, "the scenario")?;
+                let token = match &step.token {
+                    Some(name) => Some(find_token(tokens, name)?),
+                    None => None,
+                };
+                let amount = match (&step.amount, step.kind) {
+                    (Some(amount), _) => Some(channel_units(token, amount)?),
+                    (None, StepKind::AboveCapacity) => None,
+                    (None, _) => Some(channel_units(token, &AmountConfig::Units(1))?),
+                };
+                Ok(ScenarioStep {
+                    name: step
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| step.kind.name().to_string()),
+                    kind: step.kind,
+                    from,
+                    from_rpc_url,
+                    to,
+                    to_rpc_url,
+                    token,
+                    amount,
+                    expect_error: step.expect_error.clone(),
+                    expiry: Duration::from_secs(
+                        step.expiry_secs.unwrap_or(SCENARIO_INVOICE_EXPIRY_SECS),
+                    ),
+                })
+            })
+            .collect()
+    }
+
+    /// Name and Fiber RPC URL of the node called `name`, which `role` refers to
+    pub fn fiber_node(&self, name: &str, role: &str) -> Result<(&str, &str)> {
+        let node = self
+            .nodes
+            .iter()
+            .find(|node| node.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| Error::UnknownNode(format!("Unknown node {:?} in {}", name, role)))?;
+        let rpc_url = node.fiber_rpc_url.as_deref().ok_or_else(|| {
+            Error::InvalidAddress(format!(
+                "Node {} is in {} but has no fiber_rpc_url",
+                node.name, role
+            ))
+        })?;
+        Ok((node.name.as_str(), rpc_url))
+    }
+
+    /// Keep only the nodes named in `only` (all when empty) and not named in `skip`.
+    /// Names match case-insensitively and must all belong to configured nodes.
+    pub fn select_nodes(&mut self, only: &[String], skip: &[String]) -> Result<()> {
+        let is_named = |names: &[String], node: &NodeConfig| {
+            names
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&node.name))
+        };
+        for name in only.iter().chain(skip) {
+            if !self
+                .nodes
+                .iter()
+                .any(|node| node.name.eq_ignore_ascii_case(name))
+            {
+                return Err(Error::UnknownNode(format!(
+                    "Unknown node {:?}, configured nodes: {}",
+                    name,
+                    self.nodes
+                        .iter()
+                        .map(|node| node.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+        }
+        let keep =
+            |node: &NodeConfig| (only.is_empty() || is_named(only, node)) && !is_named(skip, node);
+        let dropped: Vec<String> = self
+            .nodes
+            .iter()
+            .filter(|node| !keep(node))
+            .map(|node| node.name.clone())
+            .collect();
+        self.nodes.retain(keep);
+        // Channels of nodes left out aren't opened either
+        let is_dropped = |name: &str| dropped.iter().any(|d| d.eq_ignore_ascii_case(name));
+        self.channels
+            .retain(|channel| !is_dropped(&channel.from) && !is_dropped(&channel.to));
+        if let Some(topology) = &mut self.topology {
+            topology.nodes.retain(|name| !is_dropped(name));
+            if topology.hub.as_deref().is_some_and(is_dropped) {
+                self.topology = None;
+            }
+        }
+        if is_dropped(&self.smoke_test.from) || is_dropped(&self.smoke_test.to) {
+            self.smoke_test.enabled = false;
+        }
+        if self.nodes.is_empty() {
+            return Err(Error::UnknownNode(
+                "The node selection leaves no nodes to fund".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn ckb_rpc_url<'a>(&'a self, preset: &'a NetworkPreset) -> &'a str {
+        self.ckb_rpc_url.as_deref().unwrap_or(preset.ckb_rpc_url)
+    }
+
+    /// The CKB RPC endpoint followed by its fallbacks
+    pub fn ckb_rpc_urls<'a>(&'a self, preset: &'a NetworkPreset) -> Vec<&'a str> {
+        let mut urls = vec![self.ckb_rpc_url(preset)];
+        urls.extend(self.ckb_rpc_fallback_urls.iter().map(String::as_str));
+        urls
+    }
+}
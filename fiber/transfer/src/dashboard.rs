@@ -0,0 +1,315 @@
+use crate::AccountBalance;
+use crate::error::{Error, Result};
+use crate::fiber::ChannelSummary;
+use crate::health::{Check, Health};
+use crate::logging;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long the screen waits for a key before checking for a new snapshot
+const INPUT_POLL: Duration = Duration::from_millis(200);
+
+/// Everything the dashboard shows, collected in one go
+pub struct Snapshot {
+    /// Where the CKB node and the network are, e.g. `devnet, block 1234`
+    pub chain: std::result::Result<String, String>,
+    /// The CKB node, the processes started by `chain up` and `run-nodes`, and the
+    /// Fiber nodes' RPC
+    pub processes: Vec<Check>,
+    pub token_names: Vec<String>,
+    pub balances: std::result::Result<Vec<AccountBalance>, String>,
+    pub transactions: std::result::Result<Vec<TransactionRow>, String>,
+    /// `None` when no node has a `fiber_rpc_url`
+    pub channels: Option<std::result::Result<Vec<ChannelSummary>, String>>,
+}
+
+/// A transaction recorded by a funding run
+pub struct TransactionRow {
+    pub tx_hash: String,
+    pub purpose: String,
+    pub recipients: String,
+    /// Pool or chain status, with the confirmations once committed
+    pub status: String,
+    pub health: Health,
+}
+
+/// Show snapshots taken by `collect` every `refresh` on the full terminal until `q` or
+/// Esc is pressed; `r` takes one right away. Logs are held back while the screen is
+/// up, so they don't tear it.
+pub fn run(refresh: Duration, collect: impl Fn() -> Snapshot + Send) -> Result<()> {
+    let (snapshots, received) = mpsc::channel();
+    let (trigger, triggered) = mpsc::channel::<()>();
+    logging::pause(true);
+    // Collect on the run's runtime, which drives the RPC clients' pooled connections
+    let runtime = tokio::runtime::Handle::try_current().ok();
+    let result = thread::scope(|scope| {
+        scope.spawn(move || {
+            let _runtime = runtime.as_ref().map(|runtime| runtime.enter());
+            loop {
+                if snapshots.send((collect(), Instant::now())).is_err() {
+                    return;
+                }
+                match triggered.recv_timeout(refresh) {
+                    Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+
+        let mut terminal = ratatui::init();
+        let result = show(&mut terminal, &received, &trigger);
+        ratatui::restore();
+        // Stops the collecting thread once it's done with the snapshot in progress
+        drop(trigger);
+        result
+    });
+    logging::pause(false);
+    result
+}
+
+fn show(
+    terminal: &mut DefaultTerminal,
+    received: &mpsc::Receiver<(Snapshot, Instant)>,
+    trigger: &mpsc::Sender<()>,
+) -> Result<()> {
+    let terminal_error = |e: std::io::Error| Error::Node(format!("Terminal failed: {}", e));
+    let mut latest: Option<(Snapshot, Instant)> = None;
+    let mut refreshing = true;
+    loop {
+        if let Ok(snapshot) = received.try_recv() {
+            latest = Some(snapshot);
+            refreshing = false;
+        }
+        terminal
+            .draw(|frame| draw(frame, latest.as_ref(), refreshing))
+            .map_err(terminal_error)?;
+        if !event::poll(INPUT_POLL).map_err(terminal_error)? {
+            continue;
+        }
+        if let Event::Key(key) = event::read().map_err(terminal_error)?
+            && key.kind == KeyEventKind::Press
+        {
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('r') if !refreshing => {
+                    refreshing = true;
+                    let _ = trigger.send(());
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, latest: Option<&(Snapshot, Instant)>, refreshing: bool) {
+    let Some((snapshot, taken_at)) = latest else {
+        frame.render_widget(
+            Paragraph::new("Collecting the first snapshot...").block(Block::bordered()),
+            frame.area(),
+        );
+        return;
+    };
+    // Each table takes its rows plus the borders and header, transactions the rest
+    let balance_rows = snapshot.balances.as_ref().map_or(1, Vec::len);
+    let channel_rows = match &snapshot.channels {
+        Some(Ok(channels)) => channels.len().max(1) + 3,
+        Some(Err(_)) => 4,
+        None => 0,
+    };
+    let [
+        header_area,
+        processes_area,
+        balances_area,
+        transactions_area,
+        channels_area,
+    ] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(snapshot.processes.len() as u16 + 3),
+        Constraint::Length(balance_rows as u16 + 3),
+        Constraint::Min(4),
+        Constraint::Length(channel_rows as u16),
+    ])
+    .areas(frame.area());
+
+    let chain = match &snapshot.chain {
+        Ok(chain) => chain.clone(),
+        Err(e) => format!("CKB node unreachable: {}", e),
+    };
+    let age = if refreshing {
+        "refreshing...".to_string()
+    } else {
+        format!("updated {}s ago", taken_at.elapsed().as_secs())
+    };
+    frame.render_widget(
+        Paragraph::new(format!("{} | {} | r refresh, q quit", chain, age)),
+        header_area,
+    );
+
+    let rows = snapshot
+        .processes
+        .iter()
+        .map(|check| {
+            Row::new([
+                check.component.clone(),
+                health_label(check.health).to_string(),
+                check.detail.clone(),
+            ])
+            .style(health_style(check.health))
+        })
+        .collect();
+    render_table(
+        frame,
+        processes_area,
+        "Processes",
+        &["Component", "Health", "Detail"],
+        rows,
+        &[
+            Constraint::Length(24),
+            Constraint::Length(6),
+            Constraint::Fill(1),
+        ],
+    );
+
+    match &snapshot.balances {
+        Ok(balances) => {
+            let mut header = vec!["Account", "Address", "CKB"];
+            header.extend(snapshot.token_names.iter().map(String::as_str));
+            let mut widths = vec![
+                Constraint::Length(12),
+                Constraint::Fill(1),
+                Constraint::Length(20),
+            ];
+            widths.extend(snapshot.token_names.iter().map(|_| Constraint::Length(20)));
+            let rows = balances
+                .iter()
+                .map(|balance| {
+                    let mut cells = vec![
+                        balance.account.clone(),
+                        balance.address.clone(),
+                        balance.ckb.clone(),
+                    ];
+                    cells.extend(balance.tokens.iter().map(|token| token.amount.clone()));
+                    Row::new(cells)
+                })
+                .collect();
+            render_table(frame, balances_area, "Balances", &header, rows, &widths)
+        }
+        Err(e) => render_error(frame, balances_area, "Balances", e),
+    }
+
+    match &snapshot.transactions {
+        Ok(transactions) => {
+            let rows = transactions
+                .iter()
+                .map(|tx| {
+                    Row::new([
+                        tx.tx_hash.clone(),
+                        tx.purpose.clone(),
+                        tx.recipients.clone(),
+                        tx.status.clone(),
+                    ])
+                    .style(health_style(tx.health))
+                })
+                .collect();
+            render_table(
+                frame,
+                transactions_area,
+                "Transactions",
+                &["Hash", "Purpose", "Recipients", "Status"],
+                rows,
+                &[
+                    Constraint::Length(66),
+                    Constraint::Length(18),
+                    Constraint::Fill(1),
+                    Constraint::Length(32),
+                ],
+            )
+        }
+        Err(e) => render_error(frame, transactions_area, "Transactions", e),
+    }
+
+    match &snapshot.channels {
+        Some(Ok(channels)) => {
+            let rows = channels
+                .iter()
+                .map(|c| {
+                    Row::new([
+                        c.channel_id.clone(),
+                        c.node.clone(),
+                        c.peer.clone(),
+                        c.state.clone(),
+                        c.local_balance.clone(),
+                        c.remote_balance.clone(),
+                        c.asset.clone(),
+                    ])
+                })
+                .collect();
+            render_table(
+                frame,
+                channels_area,
+                "Channels",
+                &[
+                    "Channel", "Node", "Peer", "State", "Local", "Remote", "Asset",
+                ],
+                rows,
+                &[
+                    Constraint::Fill(1),
+                    Constraint::Length(10),
+                    Constraint::Length(10),
+                    Constraint::Length(20),
+                    Constraint::Length(16),
+                    Constraint::Length(16),
+                    Constraint::Length(8),
+                ],
+            )
+        }
+        Some(Err(e)) => render_error(frame, channels_area, "Channels", e),
+        None => {}
+    }
+}
+
+fn render_table(
+    frame: &mut Frame,
+    area: Rect,
+    title: &str,
+    header: &[&str],
+    rows: Vec<Row>,
+    widths: &[Constraint],
+) {
+    let header = Row::new(header.iter().map(|cell| cell.to_string()))
+        .style(Style::new().add_modifier(Modifier::BOLD));
+    let table = Table::new(rows, widths)
+        .header(header)
+        .block(Block::bordered().title(Line::from(format!(" {} ", title))));
+    frame.render_widget(table, area);
+}
+
+fn render_error(frame: &mut Frame, area: Rect, title: &str, error: &str) {
+    let paragraph = Paragraph::new(error.to_string())
+        .style(health_style(Health::Fail))
+        .block(Block::bordered().title(Line::from(format!(" {} ", title))));
+    frame.render_widget(paragraph, area);
+}
+
+fn health_label(health: Health) -> &'static str {
+    match health {
+        Health::Ok => "ok",
+        Health::Warn => "warn",
+        Health::Fail => "FAIL",
+    }
+}
+
+fn health_style(health: Health) -> Style {
+    match health {
+        Health::Ok => Style::new(),
+        Health::Warn => Style::new().fg(Color::Yellow),
+        Health::Fail => Style::new().fg(Color::Red),
+    }
+}
@@ -0,0 +1,144 @@
+use crate::error::{Error, Result};
+use rand::Rng;
+use serde::Deserialize;
+use std::f64::consts::PI;
+
+/// A distribution values are sampled from, given in a config file as a table naming
+/// it, e.g. `{ distribution = "lognormal", median = 1000, sigma = 0.8 }`
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "distribution", rename_all = "lowercase")]
+pub enum Distribution {
+    /// Always `value`
+    Fixed { value: f64 },
+    /// Evenly between `min` and `max`
+    Uniform { min: f64, max: f64 },
+    /// Around `mean`, with about two thirds of the values within `std_dev` of it
+    Normal { mean: f64, std_dev: f64 },
+    /// Skewed towards small values with a long tail of large ones, like the channel
+    /// capacities of public networks: half the values are below `median`, and `sigma`
+    /// spreads them
+    LogNormal { median: f64, sigma: f64 },
+}
+
+impl Distribution {
+    /// Fail with `Error::Config` naming `what` when the parameters can't be sampled from
+    pub fn check(&self, what: &str) -> Result<()> {
+        let invalid = |reason: &str| Err(Error::Config(format!("Invalid {}: {}", what, reason)));
+        let finite = match *self {
+            Distribution::Fixed { value } => value.is_finite(),
+            Distribution::Uniform { min, max } => min.is_finite() && max.is_finite(),
+            Distribution::Normal { mean, std_dev } => mean.is_finite() && std_dev.is_finite(),
+            Distribution::LogNormal { median, sigma } => median.is_finite() && sigma.is_finite(),
+        };
+        if !finite {
+            return invalid("parameters must be finite numbers");
+        }
+        match *self {
+            Distribution::Uniform { min, max } if min > max => invalid("min is above max"),
+            Distribution::Normal { std_dev, .. } if std_dev < 0.0 => invalid("std_dev is negative"),
+            Distribution::LogNormal { median, .. } if median <= 0.0 => {
+                invalid("median must be positive")
+            }
+            Distribution::LogNormal { sigma, .. } if sigma < 0.0 => invalid("sigma is negative"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Draw a value from `rng`
+    pub fn sample(&self, rng: &mut impl Rng) -> f64 {
+        match *self {
+            Distribution::Fixed { value } => value,
+            Distribution::Uniform { min, max } => min + (max - min) * rng.r#gen::<f64>(),
+            Distribution::Normal { mean, std_dev } => mean + std_dev * standard_normal(rng),
+            Distribution::LogNormal { median, sigma } => {
+                median * (sigma * standard_normal(rng)).exp()
+            }
+        }
+    }
+}
+
+/// A standard normal value by the Box-Muller transform
+fn standard_normal(rng: &mut impl Rng) -> f64 {
+    // 1 - u is in (0, 1], keeping the logarithm finite
+    let u = 1.0 - rng.r#gen::<f64>();
+    let v = rng.r#gen::<f64>();
+    (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    fn samples(distribution: &Distribution, seed: u64) -> Vec<f64> {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        (0..1000).map(|_| distribution.sample(&mut rng)).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_same_samples() {
+        let distribution = Distribution::LogNormal {
+            median: 1000.0,
+            sigma: 0.8,
+        };
+        assert_eq!(samples(&distribution, 7), samples(&distribution, 7));
+        assert_ne!(samples(&distribution, 7), samples(&distribution, 8));
+    }
+
+    #[test]
+    fn samples_follow_the_parameters() {
+        let uniform = samples(
+            &Distribution::Uniform {
+                min: 10.0,
+                max: 20.0,
+            },
+            1,
+        );
+        assert!(uniform.iter().all(|v| (10.0..=20.0).contains(v)));
+
+        let normal = samples(
+            &Distribution::Normal {
+                mean: 100.0,
+                std_dev: 5.0,
+            },
+            2,
+        );
+        let mean = normal.iter().sum::<f64>() / normal.len() as f64;
+        assert!((mean - 100.0).abs() < 1.0, "mean {}", mean);
+
+        let mut lognormal = samples(
+            &Distribution::LogNormal {
+                median: 1000.0,
+                sigma: 1.0,
+            },
+            3,
+        );
+        lognormal.sort_by(f64::total_cmp);
+        assert!(lognormal.iter().all(|v| *v > 0.0));
+        let median = lognormal[lognormal.len() / 2];
+        assert!((800.0..1250.0).contains(&median), "median {}", median);
+    }
+
+    #[test]
+    fn parses_and_checks_config_tables() {
+        let parsed: Distribution =
+            toml::from_str("distribution = \"uniform\"\nmin = 1\nmax = 2").unwrap();
+        assert_eq!(parsed, Distribution::Uniform { min: 1.0, max: 2.0 });
+        assert!(toml::from_str::<Distribution>("distribution = \"zipf\"").is_err());
+        assert!(
+            Distribution::Uniform { min: 2.0, max: 1.0 }
+                .check("capacity")
+                .is_err()
+        );
+        assert!(
+            Distribution::LogNormal {
+                median: 0.0,
+                sigma: 1.0
+            }
+            .check("capacity")
+            .is_err()
+        );
+        assert!(Distribution::Fixed { value: 5.0 }.check("capacity").is_ok());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::amount::{CKB_DECIMALS, format_amount};
+use ckb_sdk::RpcError;
+use ckb_types::H256;
+use std::io;
+use thiserror::Error;
+
+/// Failures of a run. Each class exits with its own code, see `exit_code`.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// A CKB RPC call that still failed after its retries
+    #[error("{context}: {source}")]
+    Rpc {
+        context: &'static str,
+        #[source]
+        source: RpcError,
+    },
+
+    /// A cell dep the transaction needs could not be located on chain
+    #[error("{0}")]
+    CellDep(String),
+
+    #[error("Not enough {asset}. Have: {have}, Need: {need}")]
+    InsufficientFunds {
+        asset: String,
+        have: u128,
+        need: u128,
+    },
+
+    /// Inputs that can't cover the capacity of the outputs, change and fee, in shannons
+    #[error(
+        "Not enough CKB capacity. Have: {} CKB, Need: {} CKB",
+        format_amount((*.have).into(), CKB_DECIMALS),
+        format_amount((*.need).into(), CKB_DECIMALS)
+    )]
+    InsufficientCapacity { have: u64, need: u64 },
+
+    #[error("{0}")]
+    InvalidKey(String),
+
+    #[error("{0}")]
+    InvalidAddress(String),
+
+    #[error("{0}")]
+    InvalidAmount(String),
+
+    /// A node name that matches no configured node
+    #[error("{0}")]
+    UnknownNode(String),
+
+    /// A token name that matches neither the preset's sUDT nor a configured token
+    #[error("{0}")]
+    UnknownToken(String),
+
+    #[error("Signing failed: {0}")]
+    Signing(String),
+
+    #[error("Transaction {tx_hash:#x} rejected: {reason}")]
+    Rejected { tx_hash: H256, reason: String },
+
+    /// A confirmation timeout or the overall operation deadline
+    #[error("{0}")]
+    Timeout(String),
+
+    #[error("{0}")]
+    Declined(String),
+
+    /// A fee bump target that is no longer pending or can't be rebuilt
+    #[error("{0}")]
+    NotReplaceable(String),
+
+    /// A failed Fiber node RPC call
+    #[error("{0}")]
+    Fiber(String),
+
+    /// A local CKB node that failed to start or stopped
+    #[error("{0}")]
+    Chain(String),
+
+    /// A local Fiber node that failed to start or exited for good
+    #[error("{0}")]
+    Node(String),
+
+    /// Components `status` found broken or missing
+    #[error("Unhealthy: {}", .0.join(", "))]
+    Unhealthy(Vec<String>),
+
+    /// A snapshot that could not be saved or restored
+    #[error("{0}")]
+    Snapshot(String),
+
+    /// A Ledger device that is missing, locked or failed to answer
+    #[error("{0}")]
+    Ledger(String),
+
+    /// Nodes left unfunded in partial-success mode
+    #[error("Partial failure: funding needs a retry for {}", .0.join(", "))]
+    PartialFailure(Vec<String>),
+
+    /// An invalid config file, recipients file or env var value
+    #[error("{0}")]
+    Config(String),
+
+    /// A local file or directory that could not be read or written
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Attach the failed call to an RPC error, for use with `map_err`
+    pub fn rpc(context: &'static str) -> impl FnOnce(RpcError) -> Error {
+        move |source| Error::Rpc { context, source }
+    }
+
+    /// Attach what was being read or written to an I/O error, for use with `map_err`
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Error {
+        let context = context.into();
+        move |source| Error::Io { context, source }
+    }
+
+    /// Attach what the bytes were for to a failure of the system's random source
+    pub fn random(purpose: &'static str) -> impl FnOnce(getrandom::Error) -> Error {
+        move |e| Error::Io {
+            context: format!("Failed to get random bytes for {}", purpose),
+            source: io::Error::other(e.to_string()),
+        }
+    }
+
+    /// Advice logged after the error, for the failures that have some
+    pub fn hint(&self) -> Option<String> {
+        match self {
+            Error::InsufficientCapacity { have, need } => Some(format!(
+                "Short by {} CKB: send more CKB to the sender, lower the amounts or \
+                 FEE_RATE, or wait for cells reserved by other runs to be released",
+                format_amount((need - have).into(), CKB_DECIMALS)
+            )),
+            _ => None,
+        }
+    }
+
+    /// Process exit code of the failure class. Unexpected panics keep Rust's 101.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::PartialFailure(_) => 2,
+            Error::Rpc { .. } | Error::CellDep(_) => 3,
+            Error::InsufficientFunds { .. } | Error::InsufficientCapacity { .. } => 4,
+            Error::InvalidKey(_)
+            | Error::InvalidAddress(_)
+            | Error::InvalidAmount(_)
+            | Error::UnknownNode(_)
+            | Error::UnknownToken(_) => 5,
+            Error::Signing(_) => 6,
+            Error::Rejected { .. } => 7,
+            Error::Timeout(_) => 8,
+            Error::Declined(_) => 9,
+            Error::NotReplaceable(_) => 10,
+            Error::Fiber(_) => 11,
+            Error::Chain(_) => 12,
+            Error::Node(_) => 13,
+            Error::Unhealthy(_) => 14,
+            Error::Snapshot(_) => 15,
+            Error::Ledger(_) => 16,
+            Error::Config(_) => 17,
+            Error::Io { .. } => 18,
+        }
+    }
+}
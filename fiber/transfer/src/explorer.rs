@@ -0,0 +1,92 @@
+use crate::error::Error;
+use crate::rpc::HttpSettings;
+use ckb_types::H256;
+use serde::Deserialize;
+use std::thread;
+use std::time::Duration;
+use tracing::{instrument, warn};
+
+// The explorer indexes blocks with some delay behind the chain tip
+const EXPLORER_MAX_ATTEMPTS: u32 = 30;
+const EXPLORER_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+struct Response<T> {
+    data: T,
+}
+
+#[derive(Debug, Deserialize)]
+struct Resource<T> {
+    attributes: T,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransactionAttributes {
+    pub tx_status: String,
+    pub block_number: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddressAttributes {
+    /// Total capacity held by the address, in shannons
+    pub balance: String,
+}
+
+/// Minimal client for the CKB explorer REST API
+pub struct ExplorerClient {
+    base_url: String,
+    http: reqwest::blocking::Client,
+}
+
+impl ExplorerClient {
+    /// `base_url` is the API root, e.g. https://testnet-api.explorer.nervos.org/api
+    pub fn new(base_url: &str, settings: &HttpSettings) -> Result<Self, Error> {
+        Ok(ExplorerClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http: settings.shared_client("explorer")?,
+        })
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn get<T: for<'de> Deserialize<'de>>(&self, path: &str) -> Result<Option<T>, reqwest::Error> {
+        let response = self
+            .http
+            .get(format!("{}{}", self.base_url, path))
+            .header("Accept", "application/vnd.api+json")
+            .header("Content-Type", "application/vnd.api+json")
+            .send()?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json()?))
+    }
+
+    pub fn get_transaction(
+        &self,
+        tx_hash: &H256,
+    ) -> Result<Option<TransactionAttributes>, reqwest::Error> {
+        let response: Option<Response<Resource<TransactionAttributes>>> =
+            self.get(&format!("/v1/transactions/{:#x}", tx_hash))?;
+        Ok(response.map(|r| r.data.attributes))
+    }
+
+    pub fn get_address(&self, address: &str) -> Result<Option<AddressAttributes>, reqwest::Error> {
+        let response: Option<Response<Vec<Resource<AddressAttributes>>>> =
+            self.get(&format!("/v1/addresses/{}", address))?;
+        Ok(response.and_then(|r| r.data.into_iter().next().map(|d| d.attributes)))
+    }
+
+    /// Wait until the explorer has indexed the transaction as committed
+    pub fn wait_for_transaction(&self, tx_hash: &H256) -> Option<TransactionAttributes> {
+        for _ in 0..EXPLORER_MAX_ATTEMPTS {
+            match self.get_transaction(tx_hash) {
+                Ok(Some(tx)) if tx.tx_status == "committed" => return Some(tx),
+                Ok(_) => {}
+                Err(e) => warn!("Explorer request failed: {}", e),
+            }
+            thread::sleep(EXPLORER_RETRY_INTERVAL);
+        }
+        None
+    }
+}
@@ -0,0 +1,1303 @@
+use crate::amount::{CKB_DECIMALS, format_amount};
+use crate::config::FiberNodeConfig;
+use crate::error::{Error, Result};
+use crate::metrics;
+use crate::network::{FiberScript, Token};
+use crate::rate_limit::RateLimiter;
+use crate::report::{ChannelRow, ChannelStatus};
+use crate::rpc::{Deadline, HttpSettings, RetryPolicy};
+use ckb_jsonrpc_types::{OutPoint, Script, Uint64, Uint128};
+use ckb_types::H256;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, instrument, warn};
+
+// How long a new channel may take to become ready, overridable via
+// CHANNEL_READY_TIMEOUT_SECS env var; funding needs a few blocks to commit
+const DEFAULT_CHANNEL_READY_TIMEOUT: Duration = Duration::from_secs(300);
+// How long the peers may take to connect and see the opening, before open_channel
+// and accept_channel give up
+const CHANNEL_NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(30);
+const CHANNEL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// CKB a channel's funders must hold beyond the CKB they put in: a sighash change cell
+// of 61 CKB plus the funding transaction's fee at its fee rate, estimated at this many
+// bytes. Fiber's default funding fee rate is 1000 shannons per 1000 bytes.
+const FUNDING_CHANGE_CAPACITY: u128 = 61 * 100_000_000;
+const FUNDING_TX_SIZE_ESTIMATE: u128 = 2000;
+const DEFAULT_FUNDING_FEE_RATE: u64 = 1000;
+pub const CHANNEL_READY: &str = "CHANNEL_READY";
+const CHANNEL_SHUTTING_DOWN: &str = "SHUTTING_DOWN";
+// A cooperatively closed channel turns CLOSED once its closing transaction committed
+const CHANNEL_CLOSED: &str = "CLOSED";
+// How long closing the channels may take, overridable via CHANNEL_CLOSE_TIMEOUT_SECS
+// env var
+const DEFAULT_CHANNEL_CLOSE_TIMEOUT: Duration = Duration::from_secs(300);
+// How long the Fiber nodes may take to answer RPC after startup, overridable via
+// FIBER_READY_TIMEOUT_SECS env var
+const DEFAULT_NODE_READY_TIMEOUT: Duration = Duration::from_secs(120);
+const NODE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// How long a payment may take to settle, overridable via PAYMENT_TIMEOUT_SECS env var
+const DEFAULT_PAYMENT_TIMEOUT: Duration = Duration::from_secs(60);
+// How long the payer may take to learn a route to the payee, new channels being
+// announced by gossip
+const PAYMENT_ROUTE_TIMEOUT: Duration = Duration::from_secs(60);
+// Polling interval of payments whose latency is measured, its resolution
+const MEASURED_PAYMENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Methods that change a node's state each time they are taken, so a request that timed
+// out after the node took it must not be sent again
+const NON_IDEMPOTENT_METHODS: &[&str] = &["open_channel", "accept_channel"];
+pub const PAYMENT_SUCCESS: &str = "Success";
+pub const PAYMENT_FAILED: &str = "Failed";
+const INVOICE_PAID: &str = "Paid";
+
+/// Reply of `node_info`, reduced to the fields the tool uses
+#[derive(Deserialize)]
+pub struct NodeInfo {
+    pub node_name: Option<String>,
+    pub version: Option<String>,
+    /// Multiaddrs of the node, ending in `/p2p/<peer id>`
+    pub addresses: Vec<String>,
+}
+
+impl NodeInfo {
+    /// An address of the node and the peer id it ends in
+    pub fn peer_address(&self) -> Option<(&str, &str)> {
+        self.addresses.iter().find_map(|address| {
+            address
+                .rsplit_once("/p2p/")
+                .map(|(_, peer_id)| (address.as_str(), peer_id))
+        })
+    }
+}
+
+/// A channel listed by `list_channels`
+#[derive(Deserialize)]
+pub struct ChannelInfo {
+    pub channel_id: String,
+    /// Peer id of the other end
+    pub peer_id: String,
+    /// UDT funded into the channel, None for a CKB channel
+    pub funding_udt_type_script: Option<Script>,
+    pub state: ChannelState,
+    /// Base units of the channel's asset owned by the listing node
+    pub local_balance: Uint128,
+    /// Base units owned by the other end
+    pub remote_balance: Uint128,
+}
+
+#[derive(Deserialize)]
+pub struct ChannelState {
+    /// E.g. `NEGOTIATING_FUNDING`, `CHANNEL_READY` or `CLOSED`
+    pub state_name: String,
+}
+
+/// Reply of `send_payment` and `get_payment`
+#[derive(Deserialize)]
+pub struct PaymentInfo {
+    pub payment_hash: String,
+    /// `Created`, `Inflight`, `Success` or `Failed`
+    pub status: String,
+    pub failed_error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    result: Option<Value>,
+    error: Option<RpcErrorObject>,
+}
+
+#[derive(Deserialize)]
+struct RpcErrorObject {
+    code: i64,
+    message: String,
+}
+
+/// Minimal JSON-RPC client for a Fiber node (fnn). Connection failures and timeouts
+/// are retried like CKB RPC calls.
+pub struct FiberClient {
+    url: String,
+    http: reqwest::blocking::Client,
+    /// The FIBER_RPC_RATE_LIMIT of the node
+    limiter: Option<Arc<RateLimiter>>,
+    policy: RetryPolicy,
+}
+
+impl FiberClient {
+    /// Proxy and CA apply, and the FIBER_RPC_* credentials rather than the CKB RPC ones
+    pub fn new(url: &str, settings: &HttpSettings) -> Result<Self> {
+        Self::with_policy(url, settings, RetryPolicy::from_env()?)
+    }
+
+    /// A client that doesn't retry, for readiness probes
+    pub fn probe(url: &str, settings: &HttpSettings) -> Result<Self> {
+        Self::with_policy(url, settings, RetryPolicy::single_attempt())
+    }
+
+    fn with_policy(url: &str, settings: &HttpSettings, policy: RetryPolicy) -> Result<Self> {
+        Ok(FiberClient {
+            url: url.to_string(),
+            http: settings.fiber_client()?,
+            limiter: settings.fiber_rate_limiter(url),
+            policy,
+        })
+    }
+
+    #[instrument(level = "debug", skip(self, params), fields(url = %self.url))]
+    fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T> {
+        let request = json!({"id": 1, "jsonrpc": "2.0", "method": method, "params": params});
+        let fail = |reason: String| {
+            Error::Fiber(format!(
+                "Fiber RPC {} on {} failed: {}",
+                method, self.url, reason
+            ))
+        };
+        // A connect error means the request never reached the node, after the others
+        // the node may have taken it
+        let idempotent = !NON_IDEMPOTENT_METHODS.contains(&method);
+        let mut attempt = 1;
+        let response: RpcResponse = loop {
+            if let Some(limiter) = &self.limiter {
+                limiter.acquire();
+            }
+            let started = Instant::now();
+            let result = self
+                .http
+                .post(&self.url)
+                .json(&request)
+                .send()
+                .and_then(|response| response.error_for_status()?.json());
+            metrics::observe_rpc("fiber", method, started.elapsed(), result.is_ok());
+            match result {
+                Err(e)
+                    if attempt < self.policy.max_attempts()
+                        && (e.is_connect()
+                            || (idempotent && (e.is_timeout() || e.is_request()))) =>
+                {
+                    let delay = self.policy.delay(attempt);
+                    warn!(
+                        "Fiber RPC {} failed (attempt {}/{}), retrying in {:.1}s: {}",
+                        method,
+                        attempt,
+                        self.policy.max_attempts(),
+                        delay.as_secs_f64(),
+                        e
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => return Err(fail(e.to_string())),
+                Ok(response) => break response,
+            }
+        };
+        if let Some(error) = response.error {
+            return Err(fail(format!("{} ({})", error.message, error.code)));
+        }
+        serde_json::from_value(response.result.unwrap_or(Value::Null))
+            .map_err(|e| fail(format!("unexpected reply: {}", e)))
+    }
+
+    pub fn node_info(&self) -> Result<NodeInfo> {
+        self.call("node_info", json!([]))
+    }
+
+    pub fn connect_peer(&self, address: &str) -> Result<()> {
+        self.call::<Value>("connect_peer", json!([{ "address": address }]))
+            .map(|_| ())
+    }
+
+    pub fn disconnect_peer(&self, peer_id: &str) -> Result<()> {
+        self.call::<Value>("disconnect_peer", json!([{ "peer_id": peer_id }]))
+            .map(|_| ())
+    }
+
+    /// Open a channel funded with `funding_amount` base units of CKB, or of the UDT
+    /// `udt_script`, paying `funding_fee_rate` shannons per 1000 bytes for the funding
+    /// transaction when set. Returns the temporary channel id.
+    pub fn open_channel(
+        &self,
+        peer_id: &str,
+        funding_amount: u128,
+        udt_script: Option<&Script>,
+        public: bool,
+        funding_fee_rate: Option<u64>,
+    ) -> Result<String> {
+        let mut params = json!({
+            "peer_id": peer_id,
+            "funding_amount": Uint128::from(funding_amount),
+            "public": public,
+        });
+        if let Some(script) = udt_script {
+            params["funding_udt_type_script"] = json!(script);
+        }
+        if let Some(rate) = funding_fee_rate {
+            params["funding_fee_rate"] = json!(Uint64::from(rate));
+        }
+        #[derive(Deserialize)]
+        struct Reply {
+            temporary_channel_id: String,
+        }
+        let reply: Reply = self.call("open_channel", json!([params]))?;
+        Ok(reply.temporary_channel_id)
+    }
+
+    /// Accept a channel opened by a peer, funding `funding_amount` base units of its
+    /// asset. Returns the channel id.
+    pub fn accept_channel(
+        &self,
+        temporary_channel_id: &str,
+        funding_amount: u128,
+    ) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Reply {
+            channel_id: String,
+        }
+        let reply: Reply = self.call(
+            "accept_channel",
+            json!([{
+                "temporary_channel_id": temporary_channel_id,
+                "funding_amount": Uint128::from(funding_amount),
+            }]),
+        )?;
+        Ok(reply.channel_id)
+    }
+
+    /// Start closing a channel cooperatively, paying the node's share to `close_script`
+    /// and the closing fee at `fee_rate` shannons per 1000 bytes
+    pub fn shutdown_channel(
+        &self,
+        channel_id: &str,
+        close_script: &Script,
+        fee_rate: u64,
+    ) -> Result<()> {
+        self.call::<Value>(
+            "shutdown_channel",
+            json!([{
+                "channel_id": channel_id,
+                "close_script": close_script,
+                "fee_rate": Uint64::from(fee_rate),
+            }]),
+        )
+        .map(|_| ())
+    }
+
+    /// Create an invoice over `amount` base units of CKB, or of the UDT `udt_script`,
+    /// paid by revealing `preimage` and expiring after `expiry`, the node's default when
+    /// None. Returns the encoded invoice.
+    pub fn new_invoice(
+        &self,
+        amount: u128,
+        currency: &str,
+        udt_script: Option<&Script>,
+        preimage: &H256,
+        description: &str,
+        expiry: Option<Duration>,
+    ) -> Result<String> {
+        let mut params = json!({
+            "amount": Uint128::from(amount),
+            "currency": currency,
+            "description": description,
+            "payment_preimage": format!("{:#x}", preimage),
+            "hash_algorithm": "sha256",
+        });
+        if let Some(script) = udt_script {
+            params["udt_type_script"] = json!(script);
+        }
+        if let Some(expiry) = expiry {
+            params["expiry"] = json!(Uint64::from(expiry.as_secs()));
+        }
+        #[derive(Deserialize)]
+        struct Reply {
+            invoice_address: String,
+        }
+        let reply: Reply = self.call("new_invoice", json!([params]))?;
+        Ok(reply.invoice_address)
+    }
+
+    /// Status of the invoice with `payment_hash`: `Open`, `Cancelled`, `Expired`,
+    /// `Received` or `Paid`
+    pub fn invoice_status(&self, payment_hash: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Reply {
+            status: String,
+        }
+        let reply: Reply = self.call("get_invoice", json!([{ "payment_hash": payment_hash }]))?;
+        Ok(reply.status)
+    }
+
+    pub fn send_payment(&self, invoice: &str) -> Result<PaymentInfo> {
+        self.call("send_payment", json!([{ "invoice": invoice }]))
+    }
+
+    pub fn get_payment(&self, payment_hash: &str) -> Result<PaymentInfo> {
+        self.call("get_payment", json!([{ "payment_hash": payment_hash }]))
+    }
+
+    /// Channels with `peer_id`, or with every peer when None, closed ones only with
+    /// `include_closed`
+    pub fn list_channels(
+        &self,
+        peer_id: Option<&str>,
+        include_closed: bool,
+    ) -> Result<Vec<ChannelInfo>> {
+        #[derive(Deserialize)]
+        struct Reply {
+            channels: Vec<ChannelInfo>,
+        }
+        let mut params = json!({ "include_closed": include_closed });
+        if let Some(peer_id) = peer_id {
+            params["peer_id"] = json!(peer_id);
+        }
+        let reply: Reply = self.call("list_channels", json!([params]))?;
+        Ok(reply.channels)
+    }
+}
+
+/// Poll `node_info` of every node (name, RPC URL) until all of them respond, logging
+/// each node's status, so later steps don't race the nodes' startup. Fails with
+/// `Error::Timeout` naming the nodes that never responded.
+pub fn wait_for_nodes(
+    nodes: &[(&str, &str)],
+    settings: &HttpSettings,
+    deadline: &Deadline,
+) -> Result<()> {
+    let timeout =
+        crate::rpc::env_secs("FIBER_READY_TIMEOUT_SECS")?.unwrap_or(DEFAULT_NODE_READY_TIMEOUT);
+    let started = Instant::now();
+    // Nodes not ready yet, with the last probe's error
+    let mut pending: Vec<(&str, FiberClient, Option<String>)> = nodes
+        .iter()
+        .map(|(name, url)| Ok((*name, FiberClient::probe(url, settings)?, None)))
+        .collect::<Result<_>>()?;
+    loop {
+        pending.retain_mut(|(name, client, last_error)| match client.node_info() {
+            Ok(info) => {
+                info!(
+                    "Fiber node {} is ready: {} (version {})",
+                    name,
+                    info.node_name.as_deref().unwrap_or("unnamed"),
+                    info.version.as_deref().unwrap_or("unknown")
+                );
+                false
+            }
+            Err(e) => {
+                if last_error.is_none() {
+                    info!("Waiting for Fiber node {}: {}", name, e);
+                }
+                *last_error = Some(e.to_string());
+                true
+            }
+        });
+        if pending.is_empty() {
+            return Ok(());
+        }
+        deadline.check("waiting for the Fiber nodes")?;
+        if started.elapsed() >= timeout {
+            return Err(Error::Timeout(format!(
+                "Fiber nodes not ready after {}s: {}",
+                timeout.as_secs(),
+                pending
+                    .iter()
+                    .map(|(name, _, last_error)| {
+                        format!("{} ({})", name, last_error.as_deref().unwrap_or_default())
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+        thread::sleep(NODE_POLL_INTERVAL);
+    }
+}
+
+/// A channel to open, resolved from a `[[channels]]` entry of the config file
+pub struct ChannelPlan<'a> {
+    /// Node opening the channel
+    pub from: &'a str,
+    pub from_rpc_url: &'a str,
+    /// Node accepting the channel
+    pub to: &'a str,
+    pub to_rpc_url: &'a str,
+    /// UDT funded into the channel, CKB when None
+    pub token: Option<&'a Token>,
+    /// Base units funded by the opening node
+    pub funding_amount: u128,
+    /// Base units funded by the accepting node. None leaves the acceptance to the
+    /// node's auto-accept settings.
+    pub accept_amount: Option<u128>,
+    /// Announce the channel to the network
+    pub public: bool,
+    /// Fee rate of the funding transaction in shannons per 1000 bytes, the node's
+    /// default when None
+    pub funding_fee_rate: Option<u64>,
+}
+
+impl ChannelPlan<'_> {
+    fn asset(&self) -> &str {
+        asset_name(self.token)
+    }
+
+    /// Shannons each funder needs beyond the CKB it puts into the channel, for the
+    /// change cell and fee of the funding transaction
+    fn funding_margin(&self) -> u128 {
+        let fee_rate = self.funding_fee_rate.unwrap_or(DEFAULT_FUNDING_FEE_RATE);
+        FUNDING_CHANGE_CAPACITY + (FUNDING_TX_SIZE_ESTIMATE * u128::from(fee_rate)).div_ceil(1000)
+    }
+
+    fn format(&self, amount: u128) -> String {
+        format_funds(self.token, amount)
+    }
+}
+
+fn asset_name(token: Option<&Token>) -> &str {
+    token.map_or("CKB", |token| token.name.as_str())
+}
+
+/// `<amount> <asset>` in human units of base units of `token`, CKB when None
+fn format_funds(token: Option<&Token>, amount: u128) -> String {
+    let decimals = token.map_or(CKB_DECIMALS, |token| token.decimals);
+    format!("{} {}", format_amount(amount, decimals), asset_name(token))
+}
+
+/// Connect the opening node of every channel to its peer, so the channels open over
+/// established connections. Opening a channel connects its peers too.
+pub fn connect_peers(
+    channels: &[ChannelPlan],
+    settings: &HttpSettings,
+    deadline: &Deadline,
+) -> Result<()> {
+    let mut pairs: Vec<&ChannelPlan> = Vec::new();
+    for channel in channels {
+        if !pairs
+            .iter()
+            .any(|c| c.from == channel.from && c.to == channel.to)
+        {
+            pairs.push(channel);
+        }
+    }
+    for channel in pairs {
+        deadline.check("connecting the Fiber nodes")?;
+        let acceptor = FiberClient::new(channel.to_rpc_url, settings)?;
+        let info = acceptor.node_info()?;
+        let (address, _) = info.peer_address().ok_or_else(|| {
+            Error::Fiber(format!(
+                "Fiber node {} reports no address with a peer id",
+                channel.to
+            ))
+        })?;
+        info!(
+            "Connecting {} to {} at {}",
+            channel.from, channel.to, address
+        );
+        FiberClient::new(channel.from_rpc_url, settings)?.connect_peer(address)?;
+    }
+    Ok(())
+}
+
+/// Open `channels` in order once the nodes are funded, waiting until each is ready.
+/// A failed channel doesn't stop the others; every channel gets a row with its
+/// outcome. A channel of the same asset that is already open or opening between the
+/// two nodes is left alone, so a re-run doesn't open it twice. Before a channel is
+/// opened, the nodes funding it must hold enough of its asset, and CKB for the funding
+/// transaction's change and fee on top, as reported by `balance` (node name, token or
+/// None for CKB) in base units.
+pub fn open_channels(
+    channels: &[ChannelPlan],
+    settings: &HttpSettings,
+    deadline: &Deadline,
+    balance: impl Fn(&str, Option<&Token>) -> Result<u128>,
+) -> Result<Vec<ChannelRow>> {
+    let mut nodes: Vec<(&str, &str)> = Vec::new();
+    for channel in channels {
+        for node in [
+            (channel.from, channel.from_rpc_url),
+            (channel.to, channel.to_rpc_url),
+        ] {
+            if !nodes.contains(&node) {
+                nodes.push(node);
+            }
+        }
+    }
+    wait_for_nodes(&nodes, settings, deadline)?;
+
+    let ready_timeout = crate::rpc::env_secs("CHANNEL_READY_TIMEOUT_SECS")?
+        .unwrap_or(DEFAULT_CHANNEL_READY_TIMEOUT);
+    let mut rows = Vec::new();
+    for channel in channels {
+        let (status, detail) =
+            match open_channel(channel, settings, deadline, ready_timeout, &balance) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    error!(
+                        "{} channel from {} to {} failed: {}",
+                        channel.asset(),
+                        channel.from,
+                        channel.to,
+                        e
+                    );
+                    (ChannelStatus::Failed, e.to_string())
+                }
+            };
+        rows.push(ChannelRow {
+            from: channel.from.to_string(),
+            to: channel.to.to_string(),
+            funding: channel.format(channel.funding_amount),
+            status,
+            detail,
+        });
+    }
+    Ok(rows)
+}
+
+/// Open one channel and wait until it is ready. Returns its status and channel id.
+fn open_channel(
+    channel: &ChannelPlan,
+    settings: &HttpSettings,
+    deadline: &Deadline,
+    ready_timeout: Duration,
+    balance: impl Fn(&str, Option<&Token>) -> Result<u128>,
+) -> Result<(ChannelStatus, String)> {
+    let opener = FiberClient::new(channel.from_rpc_url, settings)?;
+    let acceptor = FiberClient::new(channel.to_rpc_url, settings)?;
+    let udt_script = channel
+        .token
+        .map(|token| crate::get_sudt_type_script(&token.script));
+    let acceptor_info = acceptor.node_info()?;
+    let (address, peer_id) = acceptor_info.peer_address().ok_or_else(|| {
+        Error::Fiber(format!(
+            "Fiber node {} reports no address with a peer id",
+            channel.to
+        ))
+    })?;
+    let existing = opener
+        .list_channels(Some(peer_id), false)?
+        .into_iter()
+        .find(|c| c.funding_udt_type_script == udt_script);
+    if let Some(existing) = existing {
+        info!(
+            "{} already has a {} channel with {}: {} ({})",
+            channel.from,
+            channel.asset(),
+            channel.to,
+            existing.channel_id,
+            existing.state.state_name
+        );
+        return Ok((ChannelStatus::AlreadyOpen, existing.channel_id));
+    }
+
+    // The acceptor only funds the channel with an accept amount
+    let funders = [
+        (channel.from, Some(channel.funding_amount)),
+        (
+            channel.to,
+            channel.accept_amount.filter(|amount| *amount > 0),
+        ),
+    ];
+    for (node, amount) in funders {
+        let Some(amount) = amount else {
+            continue;
+        };
+        let ckb_amount = match channel.token {
+            Some(token) => {
+                let have = balance(node, Some(token))?;
+                if have < amount {
+                    return Err(Error::InsufficientFunds {
+                        asset: format!("{} held by {} for its channel", token.name, node),
+                        have,
+                        need: amount,
+                    });
+                }
+                0
+            }
+            None => amount,
+        };
+        let need = ckb_amount + channel.funding_margin();
+        let have = balance(node, None)?;
+        if have < need {
+            return Err(Error::InsufficientFunds {
+                asset: format!(
+                    "shannons held by {} for its channel and the funding transaction's change and fee",
+                    node
+                ),
+                have,
+                need,
+            });
+        }
+    }
+
+    info!(
+        "Opening a {} channel from {} to {} with {}",
+        channel.asset(),
+        channel.from,
+        channel.to,
+        channel.format(channel.funding_amount)
+    );
+    opener.connect_peer(address)?;
+    // The connection is set up in the background, so the first attempts may not find
+    // the peer yet. Other errors aren't retried, the node may have opened the channel
+    // anyway; the client retries connect errors itself.
+    let mut attempted = false;
+    let temporary_channel_id = retry_for(
+        CHANNEL_NEGOTIATION_TIMEOUT,
+        "opening the channel",
+        deadline,
+        peer_not_found,
+        || {
+            if attempted {
+                let pending = opener
+                    .list_channels(Some(peer_id), false)?
+                    .into_iter()
+                    .find(|c| c.funding_udt_type_script == udt_script);
+                if let Some(pending) = pending {
+                    info!(
+                        "{} is already opening channel {} with {} ({})",
+                        channel.from, pending.channel_id, channel.to, pending.state.state_name
+                    );
+                    return Ok(pending.channel_id);
+                }
+            }
+            attempted = true;
+            opener.open_channel(
+                peer_id,
+                channel.funding_amount,
+                udt_script.as_ref(),
+                channel.public,
+                channel.funding_fee_rate,
+            )
+        },
+    )?;
+    if let Some(accept_amount) = channel.accept_amount {
+        let channel_id = retry_for(
+            CHANNEL_NEGOTIATION_TIMEOUT,
+            "accepting the channel",
+            deadline,
+            |_| true,
+            || acceptor.accept_channel(&temporary_channel_id, accept_amount),
+        )?;
+        info!(
+            "{} accepted channel {} with {}",
+            channel.to,
+            channel_id,
+            channel.format(accept_amount)
+        );
+    }
+
+    let started = Instant::now();
+    loop {
+        deadline.check("waiting for a channel to become ready")?;
+        let ready = opener
+            .list_channels(Some(peer_id), false)?
+            .into_iter()
+            .find(|c| {
+                c.funding_udt_type_script == udt_script && c.state.state_name == CHANNEL_READY
+            });
+        if let Some(ready) = ready {
+            info!(
+                "Channel {} from {} to {} is ready",
+                ready.channel_id, channel.from, channel.to
+            );
+            return Ok((ChannelStatus::Opened, ready.channel_id));
+        }
+        if started.elapsed() >= ready_timeout {
+            return Err(Error::Timeout(format!(
+                "{} channel from {} to {} not ready after {}s",
+                channel.asset(),
+                channel.from,
+                channel.to,
+                ready_timeout.as_secs()
+            )));
+        }
+        thread::sleep(CHANNEL_POLL_INTERVAL);
+    }
+}
+
+/// A channel of the configured nodes, merged from the listings of both of its ends
+#[derive(Serialize)]
+pub struct ChannelSummary {
+    pub channel_id: String,
+    /// Node the balances are seen from
+    pub node: String,
+    /// Node at the other end, or its peer id when it isn't one of the listed nodes
+    pub peer: String,
+    /// State reported by `node`, followed by the peer's when it differs
+    pub state: String,
+    /// Balance of `node` in human units of the asset
+    pub local_balance: String,
+    /// Balance of the peer in human units of the asset
+    pub remote_balance: String,
+    /// `CKB`, a token name, or the args of a UDT that isn't a configured token
+    pub asset: String,
+}
+
+/// List the channels of `nodes` (name, RPC URL), each channel once even where both
+/// of its ends are listed, naming peers by the configured node they belong to and
+/// assets by the `tokens` they are. A node that doesn't answer is skipped with a
+/// warning, its channels with other listed nodes still appear from their side; when
+/// no node answers, the listing fails.
+pub fn channel_summary(
+    nodes: &[(&str, &str)],
+    tokens: &[Token],
+    settings: &HttpSettings,
+) -> Result<Vec<ChannelSummary>> {
+    let mut listings = Vec::new();
+    for (name, url) in nodes {
+        let client = FiberClient::new(url, settings)?;
+        let listing = client.node_info().and_then(|info| {
+            let peer_id = info.peer_address().map(|(_, peer_id)| peer_id.to_string());
+            Ok((peer_id, client.list_channels(None, false)?))
+        });
+        match listing {
+            Ok((peer_id, channels)) => listings.push((*name, peer_id, channels)),
+            Err(e) => warn!("Skipping the channels of {}: {}", name, e),
+        }
+    }
+    if listings.is_empty() {
+        return Err(Error::Fiber(
+            "None of the Fiber nodes listed its channels".to_string(),
+        ));
+    }
+    let peer_name = |peer_id: &str| {
+        listings
+            .iter()
+            .find(|(_, id, _)| id.as_deref() == Some(peer_id))
+            .map_or(peer_id, |(name, _, _)| *name)
+            .to_string()
+    };
+
+    let mut summary: Vec<ChannelSummary> = Vec::new();
+    for (name, _, channels) in &listings {
+        for channel in channels {
+            if let Some(seen) = summary
+                .iter_mut()
+                .find(|c| c.channel_id == channel.channel_id)
+            {
+                if seen.state != channel.state.state_name {
+                    seen.state = format!("{} / {}", seen.state, channel.state.state_name);
+                }
+                continue;
+            }
+            let token = channel.funding_udt_type_script.as_ref().map(|script| {
+                tokens
+                    .iter()
+                    .find(|token| crate::get_sudt_type_script(&token.script) == *script)
+                    .ok_or_else(|| format!("UDT 0x{}", hex::encode(script.args.as_bytes())))
+            });
+            let (asset, decimals) = match token {
+                None => ("CKB".to_string(), CKB_DECIMALS),
+                Some(Ok(token)) => (token.name.clone(), token.decimals),
+                Some(Err(unknown)) => (unknown, 0),
+            };
+            summary.push(ChannelSummary {
+                channel_id: channel.channel_id.clone(),
+                node: name.to_string(),
+                peer: peer_name(&channel.peer_id),
+                state: channel.state.state_name.clone(),
+                local_balance: format_amount(channel.local_balance.value(), decimals),
+                remote_balance: format_amount(channel.remote_balance.value(), decimals),
+                asset,
+            });
+        }
+    }
+    Ok(summary)
+}
+
+/// A Fiber node whose channels `close_channels` closes
+pub struct ClosingNode<'a> {
+    pub name: &'a str,
+    pub rpc_url: &'a str,
+    /// Lock receiving the node's balance of the channels it closes
+    pub close_script: Script,
+}
+
+/// Cooperatively close every open channel of `nodes`, each from the first node listing
+/// it, and wait until their closing transactions committed on CKB. Channels already
+/// shutting down are waited for too; channels in other states, e.g. still negotiating,
+/// are left alone. A channel that fails to close doesn't stop the others. Returns the
+/// channels that didn't close as `<channel id> (<node>): <error>`.
+pub fn close_channels(
+    nodes: &[ClosingNode],
+    fee_rate: u64,
+    settings: &HttpSettings,
+    deadline: &Deadline,
+) -> Result<Vec<String>> {
+    let endpoints: Vec<(&str, &str)> = nodes.iter().map(|n| (n.name, n.rpc_url)).collect();
+    wait_for_nodes(&endpoints, settings, deadline)?;
+
+    let mut seen: Vec<String> = Vec::new();
+    // Channels being closed: node, its client, channel id
+    let mut closing: Vec<(&str, FiberClient, String)> = Vec::new();
+    let mut failed = Vec::new();
+    for node in nodes {
+        let client = FiberClient::new(node.rpc_url, settings)?;
+        for channel in client.list_channels(None, false)? {
+            if seen.contains(&channel.channel_id) {
+                continue;
+            }
+            seen.push(channel.channel_id.clone());
+            let state = channel.state.state_name.as_str();
+            if state != CHANNEL_READY && state != CHANNEL_SHUTTING_DOWN {
+                warn!(
+                    "Leaving channel {} of {} alone: it is {}",
+                    channel.channel_id, node.name, state
+                );
+                continue;
+            }
+            if state == CHANNEL_READY {
+                info!("{} is closing channel {}", node.name, channel.channel_id);
+                if let Err(e) =
+                    client.shutdown_channel(&channel.channel_id, &node.close_script, fee_rate)
+                {
+                    error!("Failed to close channel {}: {}", channel.channel_id, e);
+                    failed.push(format!("{} ({}): {}", channel.channel_id, node.name, e));
+                    continue;
+                }
+            }
+            closing.push((
+                node.name,
+                FiberClient::new(node.rpc_url, settings)?,
+                channel.channel_id,
+            ));
+        }
+    }
+    if seen.is_empty() {
+        info!("No channels to close");
+    }
+
+    let timeout = crate::rpc::env_secs("CHANNEL_CLOSE_TIMEOUT_SECS")?
+        .unwrap_or(DEFAULT_CHANNEL_CLOSE_TIMEOUT);
+    let started = Instant::now();
+    while !closing.is_empty() {
+        deadline.check("waiting for the channels to close")?;
+        let mut still_open = Vec::new();
+        for (name, client, channel_id) in closing {
+            let closed = client.list_channels(None, true).map(|channels| {
+                channels
+                    .iter()
+                    .any(|c| c.channel_id == channel_id && c.state.state_name == CHANNEL_CLOSED)
+            });
+            match closed {
+                Ok(true) => info!("Channel {} of {} is closed", channel_id, name),
+                Ok(false) => still_open.push((name, client, channel_id)),
+                Err(e) => {
+                    error!("Failed to check channel {}: {}", channel_id, e);
+                    failed.push(format!("{} ({}): {}", channel_id, name, e));
+                }
+            }
+        }
+        closing = still_open;
+        if !closing.is_empty() {
+            if started.elapsed() >= timeout {
+                for (name, _, channel_id) in closing {
+                    failed.push(format!(
+                        "{} ({}): not closed after {}s",
+                        channel_id,
+                        name,
+                        timeout.as_secs()
+                    ));
+                }
+                break;
+            }
+            thread::sleep(CHANNEL_POLL_INTERVAL);
+        }
+    }
+    Ok(failed)
+}
+
+/// A payment from one node to another through the open channels, proving the Fiber
+/// network routes payments end to end
+pub struct PaymentPlan<'a> {
+    /// Node paying the invoice
+    pub from: &'a str,
+    pub from_rpc_url: &'a str,
+    /// Node issuing the invoice
+    pub to: &'a str,
+    pub to_rpc_url: &'a str,
+    /// UDT paid, CKB when None
+    pub token: Option<&'a Token>,
+    /// Base units paid
+    pub amount: u128,
+}
+
+impl PaymentPlan<'_> {
+    pub fn format(&self) -> String {
+        format!(
+            "{} from {} to {}",
+            format_funds(self.token, self.amount),
+            self.from,
+            self.to
+        )
+    }
+}
+
+/// Create an invoice on the payee, pay it from the payer and wait until the payer
+/// sees the payment succeed and the payee sees the invoice paid. Returns the payment
+/// hash. Invoices are in `currency`, the network's.
+pub fn smoke_test(
+    payment: &PaymentPlan,
+    currency: &str,
+    settings: &HttpSettings,
+    deadline: &Deadline,
+) -> Result<String> {
+    wait_for_nodes(
+        &[
+            (payment.from, payment.from_rpc_url),
+            (payment.to, payment.to_rpc_url),
+        ],
+        settings,
+        deadline,
+    )?;
+    let payer = FiberClient::new(payment.from_rpc_url, settings)?;
+    let payee = FiberClient::new(payment.to_rpc_url, settings)?;
+    let udt_script = payment
+        .token
+        .map(|token| crate::get_sudt_type_script(&token.script));
+    let (invoice, _) = create_invoice(
+        &payee,
+        payment.amount,
+        currency,
+        udt_script.as_ref(),
+        "fiber-demo smoke test",
+        None,
+    )?;
+    info!("Paying {}", payment.format());
+    let payment_hash = pay(&payer, &invoice, deadline)?;
+
+    let timeout = payment_timeout()?;
+    let started = Instant::now();
+    loop {
+        let status = payee.invoice_status(&payment_hash)?;
+        if status == INVOICE_PAID {
+            info!(
+                "Smoke test payment {} of {} settled",
+                payment_hash,
+                payment.format()
+            );
+            return Ok(payment_hash);
+        }
+        deadline.check("waiting for the invoice to be paid")?;
+        if started.elapsed() >= timeout {
+            return Err(Error::Timeout(format!(
+                "Invoice of payment {} ({}) still {} after {}s",
+                payment_hash,
+                payment.format(),
+                status,
+                timeout.as_secs()
+            )));
+        }
+        thread::sleep(CHANNEL_POLL_INTERVAL);
+    }
+}
+
+/// Create an invoice on `payee` over `amount` base units of CKB, or of the UDT
+/// `udt_script`, for a new random preimage, expiring after `expiry` when set. Returns
+/// the invoice and its payment hash.
+pub fn create_invoice(
+    payee: &FiberClient,
+    amount: u128,
+    currency: &str,
+    udt_script: Option<&Script>,
+    description: &str,
+    expiry: Option<Duration>,
+) -> Result<(String, String)> {
+    let mut preimage = [0u8; 32];
+    getrandom::getrandom(&mut preimage)
+        .map_err(|e| Error::Fiber(format!("Failed to generate a payment preimage: {}", e)))?;
+    let invoice = payee.new_invoice(
+        amount,
+        currency,
+        udt_script,
+        &H256(preimage),
+        description,
+        expiry,
+    )?;
+    // The invoice hashes the preimage with sha256
+    let payment_hash = H256(Sha256::digest(preimage).into());
+    Ok((invoice, format!("{:#x}", payment_hash)))
+}
+
+/// Pay `invoice` from `payer` and wait until the payment succeeds. Returns the
+/// payment hash.
+pub fn pay(payer: &FiberClient, invoice: &str, deadline: &Deadline) -> Result<String> {
+    send_payment(payer, invoice, deadline, true)
+}
+
+/// `pay`, logging the payment at debug level only and polling it more often so its
+/// latency can be measured, for runs of many payments
+pub fn pay_quietly(payer: &FiberClient, invoice: &str, deadline: &Deadline) -> Result<String> {
+    send_payment(payer, invoice, deadline, false)
+}
+
+/// `pay`, logging the payment at info level with `log` and at debug level and polling
+/// it every `MEASURED_PAYMENT_POLL_INTERVAL` without
+fn send_payment(
+    payer: &FiberClient,
+    invoice: &str,
+    deadline: &Deadline,
+    log: bool,
+) -> Result<String> {
+    let poll_interval = if log {
+        CHANNEL_POLL_INTERVAL
+    } else {
+        MEASURED_PAYMENT_POLL_INTERVAL
+    };
+    // Routes only exist once the payer has heard of the channels on the way
+    let mut payment = retry_for(
+        PAYMENT_ROUTE_TIMEOUT,
+        "sending the payment",
+        deadline,
+        |_| true,
+        || payer.send_payment(invoice),
+    )?;
+    if log {
+        info!("Payment {} sent", payment.payment_hash);
+    } else {
+        debug!("Payment {} sent", payment.payment_hash);
+    }
+    let timeout = payment_timeout()?;
+    let started = Instant::now();
+    loop {
+        match payment.status.as_str() {
+            PAYMENT_SUCCESS => {
+                if log {
+                    info!("Payment {} succeeded", payment.payment_hash);
+                } else {
+                    debug!("Payment {} succeeded", payment.payment_hash);
+                }
+                return Ok(payment.payment_hash);
+            }
+            PAYMENT_FAILED => {
+                return Err(Error::Fiber(format!(
+                    "Payment {} failed: {}",
+                    payment.payment_hash,
+                    payment.failed_error.as_deref().unwrap_or("no reason given")
+                )));
+            }
+            _ => {}
+        }
+        deadline.check("waiting for the payment to succeed")?;
+        if started.elapsed() >= timeout {
+            return Err(Error::Timeout(format!(
+                "Payment {} still {} after {}s",
+                payment.payment_hash,
+                payment.status,
+                timeout.as_secs()
+            )));
+        }
+        thread::sleep(poll_interval);
+        payment = payer.get_payment(&payment.payment_hash)?;
+    }
+}
+
+pub fn payment_timeout() -> Result<Duration> {
+    Ok(crate::rpc::env_secs("PAYMENT_TIMEOUT_SECS")?.unwrap_or(DEFAULT_PAYMENT_TIMEOUT))
+}
+
+/// Call `f` until it succeeds or fails with an error that isn't `retryable`, for at
+/// most `timeout`, returning its last error
+fn retry_for<T>(
+    timeout: Duration,
+    step: &str,
+    deadline: &Deadline,
+    retryable: impl Fn(&Error) -> bool,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let started = Instant::now();
+    loop {
+        deadline.check(step)?;
+        match f() {
+            Err(e) if retryable(&e) && started.elapsed() < timeout => {
+                warn!("Still {}: {}", step, e);
+                thread::sleep(CHANNEL_POLL_INTERVAL);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether a Fiber node rejected a request because it isn't connected to the peer yet
+fn peer_not_found(e: &Error) -> bool {
+    e.to_string().to_lowercase().contains("peer not found")
+}
+
+/// Peer id of a Fiber node: the base58 multihash (sha2-256) of the compressed public
+/// key of its `fiber/sk` secret key, as in its `/p2p/` addresses
+pub fn peer_id(secret_key: &[u8]) -> Result<String> {
+    let secret_key = SecretKey::from_slice(secret_key)
+        .map_err(|e| Error::InvalidKey(format!("Invalid Fiber secret key: {}", e)))?;
+    let public_key = PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret_key);
+    let mut multihash = vec![0x12, 0x20];
+    multihash.extend(Sha256::digest(public_key.serialize()));
+    Ok(base58(&multihash))
+}
+
+fn base58(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+    // Base 58 digits, least significant first
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in bytes {
+        let mut carry = u32::from(byte);
+        for digit in &mut digits {
+            carry += u32::from(*digit) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = bytes.iter().take_while(|&&byte| byte == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(
+            digits
+                .iter()
+                .rev()
+                .map(|&digit| ALPHABET[usize::from(digit)]),
+        )
+        .map(char::from)
+        .collect()
+}
+
+/// A UDT the Fiber nodes accept in channels
+pub struct WhitelistedUdt {
+    pub name: String,
+    pub script: Script,
+    pub cell_dep: OutPoint,
+}
+
+/// Settings shared by the config files of all Fiber nodes
+pub struct NodeConfigTemplate<'a> {
+    /// Chain spec path or network name
+    pub chain: &'a str,
+    /// CKB RPC endpoint as seen from the nodes
+    pub ckb_rpc_url: &'a str,
+    pub scripts: &'a [FiberScript],
+    /// Hash of the genesis cellbase holding the `scripts`
+    pub genesis_cellbase: &'a H256,
+    pub udts: &'a [WhitelistedUdt],
+}
+
+impl NodeConfigTemplate<'_> {
+    /// Render the `config.yml` of `node`, bootstrapping from `bootnode_addrs`
+    pub fn render(&self, node: &FiberNodeConfig, bootnode_addrs: &[String]) -> String {
+        let mut yml = String::new();
+        writeln!(
+            yml,
+            "# Generated by gen-config, edit the funder's config instead"
+        )
+        .unwrap();
+        writeln!(yml, "fiber:").unwrap();
+        writeln!(yml, "  chain: {}", self.chain).unwrap();
+        writeln!(yml, "  auto_announce_node: true").unwrap();
+        writeln!(yml, "  announce_private_addr: true").unwrap();
+        writeln!(yml, "  watchtower_check_interval_seconds: 1").unwrap();
+        writeln!(yml, "  gossip_store_maintenance_interval_ms: 1000").unwrap();
+        writeln!(yml, "  gossip_network_maintenance_interval_ms: 1000").unwrap();
+        writeln!(
+            yml,
+            "  listening_addr: \"/ip4/0.0.0.0/tcp/{}\"",
+            node.p2p_port
+        )
+        .unwrap();
+        writeln!(yml, "  announced_addrs:").unwrap();
+        writeln!(yml, "    - \"/dns4/{}/tcp/{}\"", node.name, node.p2p_port).unwrap();
+        writeln!(yml, "  announced_node_name: \"{}\"", node.name).unwrap();
+        if !bootnode_addrs.is_empty() {
+            writeln!(yml, "  bootnode_addrs:").unwrap();
+            for address in bootnode_addrs {
+                writeln!(yml, "    - \"{}\"", address).unwrap();
+            }
+        }
+        if !self.scripts.is_empty() {
+            writeln!(yml, "  scripts:").unwrap();
+            for script in self.scripts {
+                writeln!(yml, "    - name: {}", script.name).unwrap();
+                writeln!(yml, "      script:").unwrap();
+                writeln!(yml, "        code_hash: {:#x}", script.code_hash).unwrap();
+                writeln!(yml, "        hash_type: {}", script.hash_type).unwrap();
+                writeln!(yml, "        args: 0x").unwrap();
+                write_cell_dep(
+                    &mut yml,
+                    "      ",
+                    self.genesis_cellbase,
+                    script.genesis_output,
+                );
+            }
+        }
+        writeln!(yml, "rpc:").unwrap();
+        writeln!(yml, "  listening_addr: 127.0.0.1:{}", node.rpc_port).unwrap();
+        writeln!(yml, "  enabled_modules:").unwrap();
+        for module in RPC_MODULES {
+            writeln!(yml, "    - {}", module).unwrap();
+        }
+        writeln!(yml, "cch:").unwrap();
+        writeln!(yml, "  ignore_startup_failure: true").unwrap();
+        writeln!(
+            yml,
+            "  wrapped_btc_type_script_args: \"0x32e555f3ff8e135cece1351a6a2971518392c1e30375c1e006ad0ce8eac07947\""
+        )
+        .unwrap();
+        writeln!(
+            yml,
+            "  lnd_cert_path: ./deploy/lnd-init/lnd-ingrid/tls.cert"
+        )
+        .unwrap();
+        writeln!(yml).unwrap();
+        writeln!(yml, "ckb:").unwrap();
+        writeln!(yml, "  rpc_url: \"{}\"", self.ckb_rpc_url).unwrap();
+        writeln!(yml, "  tx_tracing_polling_interval_ms: 300").unwrap();
+        writeln!(yml, "  udt_whitelist:").unwrap();
+        for udt in self.udts {
+            writeln!(yml, "    - name: {}", udt.name).unwrap();
+            writeln!(yml, "      script:").unwrap();
+            writeln!(yml, "        code_hash: {:#x}", udt.script.code_hash).unwrap();
+            writeln!(yml, "        hash_type: {}", udt.script.hash_type).unwrap();
+            writeln!(
+                yml,
+                "        args: 0x{}",
+                hex::encode(udt.script.args.as_bytes())
+            )
+            .unwrap();
+            write_cell_dep(
+                &mut yml,
+                "      ",
+                &udt.cell_dep.tx_hash,
+                udt.cell_dep.index.value(),
+            );
+        }
+        writeln!(yml).unwrap();
+        writeln!(yml, "services:").unwrap();
+        for service in ["fiber", "rpc", "ckb"] {
+            writeln!(yml, "  - {}", service).unwrap();
+        }
+        yml
+    }
+}
+
+// RPC modules enabled on the demo nodes
+const RPC_MODULES: [&str; 10] = [
+    "cch",
+    "channel",
+    "payment",
+    "graph",
+    "info",
+    "invoice",
+    "peer",
+    "watchtower",
+    "dev",
+    "prof",
+];
+
+fn write_cell_dep(yml: &mut String, indent: &str, tx_hash: &H256, index: u32) {
+    writeln!(yml, "{}cell_deps:", indent).unwrap();
+    writeln!(yml, "{}  - cell_dep:", indent).unwrap();
+    writeln!(yml, "{}      out_point:", indent).unwrap();
+    writeln!(yml, "{}        tx_hash: {:#x}", indent, tx_hash).unwrap();
+    writeln!(yml, "{}        index: {:#x}", indent, index).unwrap();
+    writeln!(yml, "{}      dep_type: code", indent).unwrap();
+}
@@ -0,0 +1,187 @@
+use crate::error::{Error, Result};
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use sha2::Sha512;
+use std::env;
+use std::fs;
+
+/// Derivation path of CKB account `account`, as ckb-cli and wallets derive it; the
+/// address index follows
+pub fn account_path(account: u32) -> String {
+    format!("m/44'/309'/{}'/0", account)
+}
+
+// Index bit of hardened derivation, written `'` or `h` in paths
+pub const HARDENED: u32 = 1 << 31;
+
+/// Whether an account is given as a derivation path from the mnemonic
+pub fn is_path(account: &str) -> bool {
+    account == "m" || account.starts_with("m/")
+}
+
+/// A new random mnemonic of `words` words (12 to 24, a multiple of 3)
+pub fn generate_mnemonic(words: usize) -> Result<Mnemonic> {
+    let mut entropy = vec![0u8; words / 3 * 4];
+    getrandom::getrandom(&mut entropy).map_err(Error::random("a mnemonic"))?;
+    Mnemonic::from_entropy(&entropy)
+        .map_err(|e| Error::InvalidKey(format!("Can't make a {}-word mnemonic: {}", words, e)))
+}
+
+/// The mnemonic from the MNEMONIC env var, or else the file MNEMONIC_FILE names
+pub fn mnemonic_from_env() -> Result<Mnemonic> {
+    let (phrase, origin) = match (env::var("MNEMONIC"), env::var("MNEMONIC_FILE")) {
+        (Ok(phrase), _) => (phrase, "MNEMONIC".to_string()),
+        (Err(_), Ok(path)) => {
+            let phrase = fs::read_to_string(&path).map_err(|e| {
+                Error::InvalidKey(format!("Failed to read mnemonic from {}: {}", path, e))
+            })?;
+            (phrase, path)
+        }
+        (Err(_), Err(_)) => {
+            return Err(Error::InvalidKey(
+                "Keys given by derivation path need MNEMONIC or MNEMONIC_FILE".to_string(),
+            ));
+        }
+    };
+    Mnemonic::parse(phrase.trim())
+        .map_err(|e| Error::InvalidKey(format!("Invalid mnemonic in {}: {}", origin, e)))
+}
+
+/// Derive the private key (hex) at `path` from the mnemonic in the env, with the
+/// MNEMONIC_PASSPHRASE env var as the BIP39 passphrase
+pub fn derive_from_env(path: &str) -> Result<String> {
+    let passphrase = env::var("MNEMONIC_PASSPHRASE").unwrap_or_default();
+    let seed = mnemonic_from_env()?.to_seed(passphrase);
+    derive(&seed, path).map(|key| hex::encode(key.secret_bytes()))
+}
+
+/// BIP32 derivation of the private key at `path`, e.g. `m/44'/309'/0'/0/1`, from a
+/// BIP39 seed
+pub fn derive(seed: &[u8], path: &str) -> Result<SecretKey> {
+    let (key, chain_code) = split(hmac_sha512(b"Bitcoin seed", &[seed])).ok_or_else(|| {
+        Error::InvalidKey("The mnemonic gives no valid master key, use another".to_string())
+    })?;
+    derive_extended(key, chain_code, path).map(|(key, _)| key)
+}
+
+/// BIP32 derivation of the private key and chain code at `path` from an extended
+/// private key
+pub fn derive_extended(
+    mut key: SecretKey,
+    mut chain_code: [u8; 32],
+    path: &str,
+) -> Result<(SecretKey, [u8; 32])> {
+    let invalid = |reason: &str| Error::InvalidKey(format!("Invalid path {}: {}", path, reason));
+    let secp = Secp256k1::signing_only();
+    for index in parse_path(path)? {
+        let child = if index & HARDENED != 0 {
+            hmac_sha512(
+                &chain_code,
+                &[&[0], &key.secret_bytes(), &index.to_be_bytes()],
+            )
+        } else {
+            let public_key = PublicKey::from_secret_key(&secp, &key).serialize();
+            hmac_sha512(&chain_code, &[&public_key, &index.to_be_bytes()])
+        };
+        // Invalid children are astronomically unlikely, BIP32 skips to the next index
+        let (tweak, next_chain_code) =
+            split(child).ok_or_else(|| invalid("the next index must be used"))?;
+        key = key
+            .add_tweak(&Scalar::from(tweak))
+            .map_err(|_| invalid("the next index must be used"))?;
+        chain_code = next_chain_code;
+    }
+    Ok((key, chain_code))
+}
+
+/// The child indexes of a derivation path, with the hardened bit set for hardened ones
+pub fn parse_path(path: &str) -> Result<Vec<u32>> {
+    let invalid = |reason: &str| Error::InvalidKey(format!("Invalid path {}: {}", path, reason));
+    let indexes = path
+        .strip_prefix('m')
+        .ok_or_else(|| invalid("it doesn't start at m"))?;
+    indexes
+        .split('/')
+        .skip(1)
+        .map(|component| {
+            let (number, hardened) = match component.strip_suffix(['\'', 'h']) {
+                Some(number) => (number, HARDENED),
+                None => (component, 0),
+            };
+            number
+                .parse::<u32>()
+                .ok()
+                .filter(|index| index & HARDENED == 0)
+                .map(|index| index | hardened)
+                .ok_or_else(|| invalid(&format!("{:?} is not an index", component)))
+        })
+        .collect()
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> [u8; 64] {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes any key length");
+    for part in data {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// The key and chain code halves of an HMAC output, `None` when the key half is out of
+/// range
+fn split(output: [u8; 64]) -> Option<(SecretKey, [u8; 32])> {
+    let key = SecretKey::from_slice(&output[..32]).ok()?;
+    let mut chain_code = [0u8; 32];
+    chain_code.copy_from_slice(&output[32..]);
+    Some((key, chain_code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_bip32_test_vector_1() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let key = derive(&seed, "m/0'/1/2'/2/1000000000").unwrap();
+        assert_eq!(
+            hex::encode(key.secret_bytes()),
+            "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8"
+        );
+    }
+
+    #[test]
+    fn derives_the_first_ckb_cli_address_key() {
+        let mnemonic = Mnemonic::parse(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+             abandon about",
+        )
+        .unwrap();
+        let path = format!("{}/0", account_path(0));
+        assert_eq!(path, "m/44'/309'/0'/0/0");
+        let key = derive(&mnemonic.to_seed(""), &path).unwrap();
+        assert_eq!(
+            hex::encode(key.secret_bytes()),
+            "b217d9a18ff657c99872cc11a2fa2aa3e970cef8c6faa7d6e424bf057cb3707b"
+        );
+    }
+
+    #[test]
+    fn parses_hardened_and_normal_indexes() {
+        assert_eq!(parse_path("m").unwrap(), Vec::<u32>::new());
+        assert_eq!(
+            parse_path("m/44'/309h/0").unwrap(),
+            [44 | HARDENED, 309 | HARDENED, 0]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        assert!(parse_path("m/2147483648").is_err());
+        assert!(parse_path("m/2147483648'").is_err());
+        assert!(parse_path("44'/309'/0'").is_err());
+        assert!(parse_path("/0").is_err());
+        assert!(parse_path("m/x").is_err());
+        assert!(parse_path("m/").is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use serde::Serialize;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Ok,
+    /// Works, but something may need attention, e.g. an indexer catching up
+    Warn,
+    /// Broken or missing, the demo can't run like this
+    Fail,
+}
+
+/// Outcome of probing one component for `status`
+#[derive(Serialize)]
+pub struct Check {
+    pub component: String,
+    pub health: Health,
+    pub detail: String,
+}
+
+impl Check {
+    pub fn new(component: impl Into<String>, health: Health, detail: impl Into<String>) -> Self {
+        Check {
+            component: component.into(),
+            health,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Print the checks as a table, with green, yellow and red health when `color`
+pub fn print_table(checks: &[Check], color: bool) {
+    let width = checks
+        .iter()
+        .map(|check| check.component.len())
+        .chain(["Component".len()])
+        .max()
+        .unwrap_or_default();
+    println!("{:width$}  {:6}  Detail", "Component", "Health");
+    for check in checks {
+        let (label, code) = match check.health {
+            Health::Ok => ("ok", "32"),
+            Health::Warn => ("warn", "33"),
+            Health::Fail => ("FAIL", "31"),
+        };
+        let health = if color {
+            // Padded before coloring, the escape codes take no columns
+            format!("\x1b[{}m{:6}\x1b[0m", code, label)
+        } else {
+            format!("{:6}", label)
+        };
+        println!(
+            "{}",
+            format!("{:width$}  {}  {}", check.component, health, check.detail).trim_end()
+        );
+    }
+}
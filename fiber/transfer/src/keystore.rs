@@ -0,0 +1,317 @@
+use crate::error::{Error, Result};
+use crate::hd;
+use crate::progress;
+use aes::cipher::{KeyIvInit, StreamCipher};
+use ckb_hash::blake2b_256;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+// Path of the extended public key ckb-cli records in keystores to derive addresses
+const CKB_ROOT_PATH: &str = "m/44'/309'/0'";
+
+// scrypt cost of new keystores, ckb-cli's standard (N = 2^18, p = 1) and light
+// (N = 2^12, p = 6) settings
+const SCRYPT_LOG_N: u8 = 18;
+const SCRYPT_P: u32 = 1;
+const LIGHT_SCRYPT_LOG_N: u8 = 12;
+const LIGHT_SCRYPT_P: u32 = 6;
+const SCRYPT_R: u32 = 8;
+
+/// Encrypted key file in the format of ckb-cli's `account export` and keystore directory
+#[derive(Serialize, Deserialize)]
+struct Keystore {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<String>,
+    id: String,
+    version: u32,
+    /// Lock args of the key, hex without 0x
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash160: Option<String>,
+    crypto: Crypto,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ckb_root: Option<CkbRoot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Crypto {
+    cipher: String,
+    ciphertext: String,
+    cipherparams: CipherParams,
+    kdf: String,
+    kdfparams: KdfParams,
+    mac: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CipherParams {
+    iv: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KdfParams {
+    dklen: usize,
+    n: u64,
+    p: u32,
+    r: u32,
+    salt: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CkbRoot {
+    path: String,
+    pubkey: String,
+    chain_code: String,
+}
+
+/// Whether a key file holds a keystore rather than a hex key
+pub fn is_keystore(content: &str) -> bool {
+    content.trim_start().starts_with('{')
+}
+
+/// Decrypt the private key (hex) in a keystore read from `origin`
+pub fn decrypt(content: &str, origin: &str, password: &str) -> Result<String> {
+    let invalid =
+        |reason: String| Error::InvalidKey(format!("Invalid keystore {}: {}", origin, reason));
+    let keystore: Keystore = serde_json::from_str(content).map_err(|e| invalid(e.to_string()))?;
+    let crypto = &keystore.crypto;
+    if keystore.version != 3 || crypto.cipher != "aes-128-ctr" || crypto.kdf != "scrypt" {
+        return Err(invalid(format!(
+            "version {} with {} and {} is not supported, only version 3 with aes-128-ctr \
+             and scrypt",
+            keystore.version, crypto.cipher, crypto.kdf
+        )));
+    }
+    let field = |name: &str, value: &str| {
+        hex::decode(value.trim_start_matches("0x")).map_err(|e| invalid(format!("{}: {}", name, e)))
+    };
+    let params = &crypto.kdfparams;
+    if !params.n.is_power_of_two() || params.dklen != 32 {
+        return Err(invalid(format!(
+            "scrypt n {} and dklen {} are not supported",
+            params.n, params.dklen
+        )));
+    }
+    let kdf_key = kdf_key(
+        password,
+        &field("salt", &params.salt)?,
+        params.n.trailing_zeros() as u8,
+        params.p,
+        params.r,
+    )
+    .map_err(invalid)?;
+    let mut secret = field("ciphertext", &crypto.ciphertext)?;
+    if mac(&kdf_key, &secret).as_slice() != field("mac", &crypto.mac)? {
+        return Err(Error::InvalidKey(format!(
+            "Wrong password for keystore {}",
+            origin
+        )));
+    }
+    let iv = field("iv", &crypto.cipherparams.iv)?;
+    if iv.len() != 16 {
+        return Err(invalid("iv is not 16 bytes".to_string()));
+    }
+    Aes128Ctr::new(kdf_key[..16].into(), iv.as_slice().into()).apply_keystream(&mut secret);
+    // ckb-cli encrypts the key followed by its chain code
+    if secret.len() != 32 && secret.len() != 64 {
+        return Err(invalid("ciphertext is not 32 or 64 bytes".to_string()));
+    }
+    secret.truncate(32);
+    Ok(hex::encode(secret))
+}
+
+/// A new ckb-cli compatible keystore holding `key`, encrypted with ckb-cli's standard
+/// scrypt settings, or with cheaper ones when `light`
+pub fn encrypt(key: &SecretKey, password: &str, light: bool) -> Result<String> {
+    let chain_code: [u8; 32] = random()?;
+    let (log_n, p) = if light {
+        (LIGHT_SCRYPT_LOG_N, LIGHT_SCRYPT_P)
+    } else {
+        (SCRYPT_LOG_N, SCRYPT_P)
+    };
+    let salt: [u8; 32] = random()?;
+    let iv: [u8; 16] = random()?;
+    let kdf_key = kdf_key(password, &salt, log_n, p, SCRYPT_R).map_err(Error::InvalidKey)?;
+    let mut ciphertext = [key.secret_bytes(), chain_code].concat();
+    Aes128Ctr::new(kdf_key[..16].into(), (&iv).into()).apply_keystream(&mut ciphertext);
+
+    let secp = Secp256k1::signing_only();
+    let public_key = PublicKey::from_secret_key(&secp, key).serialize();
+    let (root_key, root_chain_code) = hd::derive_extended(*key, chain_code, CKB_ROOT_PATH)?;
+    let id: [u8; 16] = random()?;
+    let keystore = Keystore {
+        origin: Some("ckb-cli".to_string()),
+        id: uuid(id),
+        version: 3,
+        hash160: Some(hex::encode(&blake2b_256(public_key)[..20])),
+        crypto: Crypto {
+            cipher: "aes-128-ctr".to_string(),
+            mac: hex::encode(mac(&kdf_key, &ciphertext)),
+            ciphertext: hex::encode(ciphertext),
+            cipherparams: CipherParams {
+                iv: hex::encode(iv),
+            },
+            kdf: "scrypt".to_string(),
+            kdfparams: KdfParams {
+                dklen: 32,
+                n: 1 << log_n,
+                p,
+                r: SCRYPT_R,
+                salt: hex::encode(salt),
+            },
+        },
+        ckb_root: Some(CkbRoot {
+            path: CKB_ROOT_PATH.to_string(),
+            pubkey: hex::encode(PublicKey::from_secret_key(&secp, &root_key).serialize()),
+            chain_code: hex::encode(root_chain_code),
+        }),
+    };
+    serde_json::to_string(&keystore)
+        .map_err(|e| Error::InvalidKey(format!("Failed to serialize the keystore: {}", e)))
+}
+
+/// The keystore password from the KEYSTORE_PASSWORD env var, or else the file
+/// KEYSTORE_PASSWORD_FILE names, or else asked for on the terminal, twice when
+/// `confirm`
+pub fn password(origin: &str, confirm: bool) -> Result<String> {
+    if let Ok(password) = env::var("KEYSTORE_PASSWORD") {
+        return Ok(password);
+    }
+    if let Ok(path) = env::var("KEYSTORE_PASSWORD_FILE") {
+        let content = fs::read_to_string(&path).map_err(|e| {
+            Error::InvalidKey(format!(
+                "Failed to read keystore password from {}: {}",
+                path, e
+            ))
+        })?;
+        return Ok(content.trim_end_matches(['\r', '\n']).to_string());
+    }
+    if !io::stdin().is_terminal() {
+        return Err(Error::InvalidKey(format!(
+            "Keystore {} needs a password: stdin is not a terminal, set KEYSTORE_PASSWORD \
+             or KEYSTORE_PASSWORD_FILE",
+            origin
+        )));
+    }
+    let prompt = |prompt: String| {
+        progress::suspend(|| rpassword::prompt_password(prompt))
+            .map_err(|e| Error::InvalidKey(format!("Failed to read the password: {}", e)))
+    };
+    let password = prompt(format!("Password for {}: ", origin))?;
+    if confirm && prompt("Repeat the password: ".to_string())? != password {
+        return Err(Error::InvalidKey("The passwords don't match".to_string()));
+    }
+    Ok(password)
+}
+
+fn kdf_key(
+    password: &str,
+    salt: &[u8],
+    log_n: u8,
+    p: u32,
+    r: u32,
+) -> std::result::Result<[u8; 32], String> {
+    let params = scrypt::Params::new(log_n, r, p, 32)
+        .map_err(|e| format!("invalid scrypt parameters: {}", e))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(password.as_bytes(), salt, &params, &mut key)
+        .map_err(|e| format!("scrypt failed: {}", e))?;
+    Ok(key)
+}
+
+/// Keccak-256 of the second half of the derived key and the ciphertext, as in Ethereum
+/// keystores
+fn mac(kdf_key: &[u8; 32], ciphertext: &[u8]) -> [u8; 32] {
+    Keccak256::new()
+        .chain_update(&kdf_key[16..])
+        .chain_update(ciphertext)
+        .finalize()
+        .into()
+}
+
+fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(Error::random("a keystore"))?;
+    Ok(bytes)
+}
+
+/// Format random bytes as a version 4 UUID
+fn uuid(mut bytes: [u8; 16]) -> String {
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A keystore in the format of ckb-cli's `account export`, with light scrypt settings,
+    // encrypted with the password below. It holds the key of BIP32 test vector 1 and the
+    // chain code 0x20..0x3f.
+    const FIXTURE: &str = r#"{"origin":"ckb-cli","id":"6a1b36b2-4ac7-4c2b-9b4e-3c5f0f7f6c1d","version":3,"hash160":"ce94f44dcdf837fa7d2251a2b8ff24fdf85c1fe5","crypto":{"cipher":"aes-128-ctr","ciphertext":"7ceafeecfa934c886480bce515ce0454a4e33e38f50ea650014be73f9338edb2c1fe0680c8f40036d000e8d87da8ba7a1203c96fb1de4b159c8bf19f8e4415cb","cipherparams":{"iv":"606162636465666768696a6b6c6d6e6f"},"kdf":"scrypt","kdfparams":{"dklen":32,"n":4096,"p":6,"r":8,"salt":"404142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f"},"mac":"469d979d57c2a556c43f463bebb0a5f2cebbfb574ad00abe7dd05546199482a6"},"ckb_root":{"path":"m/44'/309'/0'","pubkey":"03c8b1a97c420e7e6acf059d9d9c359961983f7ba25057c2ed667546f147b877e8","chain_code":"c041816c7a3cc8139edf8d3deaf28f481d205078f4489ed57dee2cbd74a39afe"}}"#;
+    const FIXTURE_PASSWORD: &str = "fixture password";
+    const FIXTURE_KEY: &str = "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8";
+
+    fn fixture_key() -> SecretKey {
+        SecretKey::from_slice(&hex::decode(FIXTURE_KEY).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn decrypts_a_ckb_cli_keystore() {
+        assert!(is_keystore(FIXTURE));
+        let key = decrypt(FIXTURE, "fixture.json", FIXTURE_PASSWORD).unwrap();
+        assert_eq!(key, FIXTURE_KEY);
+    }
+
+    #[test]
+    fn rejects_a_wrong_password_by_the_mac() {
+        let Err(Error::InvalidKey(message)) = decrypt(FIXTURE, "fixture.json", "wrong") else {
+            panic!("a wrong password must fail");
+        };
+        assert_eq!(message, "Wrong password for keystore fixture.json");
+    }
+
+    #[test]
+    fn decrypts_its_own_keystores() {
+        let content = encrypt(&fixture_key(), FIXTURE_PASSWORD, true).unwrap();
+        assert_eq!(
+            decrypt(&content, "new.json", FIXTURE_PASSWORD).unwrap(),
+            FIXTURE_KEY
+        );
+        let keystore: Keystore = serde_json::from_str(&content).unwrap();
+        let fixture: Keystore = serde_json::from_str(FIXTURE).unwrap();
+        assert_eq!(keystore.hash160, fixture.hash160);
+        assert_eq!(keystore.crypto.kdfparams.n, 1 << LIGHT_SCRYPT_LOG_N);
+        assert_eq!(keystore.crypto.kdfparams.p, LIGHT_SCRYPT_P);
+    }
+
+    #[test]
+    fn records_the_ckb_root_as_ckb_cli_does() {
+        let chain_code: [u8; 32] = std::array::from_fn(|i| 0x20 + i as u8);
+        let (root_key, root_chain_code) =
+            hd::derive_extended(fixture_key(), chain_code, CKB_ROOT_PATH).unwrap();
+        let public_key =
+            PublicKey::from_secret_key(&Secp256k1::signing_only(), &root_key).serialize();
+        let root = serde_json::from_str::<Keystore>(FIXTURE)
+            .unwrap()
+            .ckb_root
+            .unwrap();
+        assert_eq!(root.path, CKB_ROOT_PATH);
+        assert_eq!(root.pubkey, hex::encode(public_key));
+        assert_eq!(root.chain_code, hex::encode(root_chain_code));
+    }
+}
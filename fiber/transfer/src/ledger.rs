@@ -0,0 +1,356 @@
+use crate::LiveCell;
+use crate::error::{Error, Result};
+use crate::hd;
+use crate::rpc::{self, RetryingClient};
+use crate::signing;
+use ckb_hash::blake2b_256;
+use ckb_jsonrpc_types::{Either, JsonBytes, Script, ScriptHashType};
+use ckb_sdk::constants::SIGHASH_TYPE_HASH;
+use ckb_types::core::TransactionView;
+use ckb_types::packed::{self, WitnessArgs};
+use ckb_types::prelude::*;
+use secp256k1::PublicKey;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use tracing::info;
+
+// Account given instead of a private key to sign with a Ledger device running the
+// Nervos app, optionally followed by `:` and the derivation path of the key
+const LEDGER_ACCOUNT: &str = "ledger";
+// Key of a bare `ledger` account, the first address of ckb-cli and wallets
+const DEFAULT_PATH: &str = "m/44'/309'/0'/0/0";
+
+// USB vendor id of Ledger devices
+const LEDGER_VENDOR_ID: &str = "00002C97";
+// HID usage page of the Ledger APDU interface, as the report descriptor starts
+const APDU_USAGE_PAGE: [u8; 3] = [0x06, 0xa0, 0xff];
+// Ledger HID framing: reports of 64 bytes on channel 0x0101 tagged 0x05
+const HID_PACKET_SIZE: usize = 64;
+const HID_CHANNEL: [u8; 2] = [0x01, 0x01];
+const HID_TAG_APDU: u8 = 0x05;
+
+// APDU class and instructions of the Nervos app
+const CLA: u8 = 0x80;
+const INS_GET_PUBLIC_KEY: u8 = 0x02;
+const INS_SIGN: u8 = 0x03;
+// P1 of the chunks of a signing request
+const SIGN_P1_FIRST: u8 = 0x00;
+const SIGN_P1_NEXT: u8 = 0x01;
+const SIGN_P1_LAST: u8 = 0x80;
+// Largest signing request chunk the app takes
+const MAX_CHUNK_SIZE: usize = 230;
+const SW_OK: u16 = 0x9000;
+
+// Public keys read from the device by account, so each is only asked for once a run
+static PUBLIC_KEYS: Mutex<Option<HashMap<String, PublicKey>>> = Mutex::new(None);
+
+/// Whether an account is a key on a Ledger device, `ledger` or `ledger:<path>`
+pub fn is_ledger(account: &str) -> bool {
+    account == LEDGER_ACCOUNT
+        || account
+            .strip_prefix(LEDGER_ACCOUNT)
+            .is_some_and(|rest| rest.starts_with(':'))
+}
+
+fn path(account: &str) -> &str {
+    match account.split_once(':') {
+        Some((_, path)) => path,
+        None => DEFAULT_PATH,
+    }
+}
+
+/// Sighash lock of the key of a Ledger account
+pub fn lock_script(account: &str) -> Result<Script> {
+    let public_key = public_key(account)?;
+    Ok(Script {
+        code_hash: SIGHASH_TYPE_HASH.clone(),
+        hash_type: ScriptHashType::Type,
+        args: JsonBytes::from_vec(blake2b_256(public_key.serialize())[..20].to_vec()),
+    })
+}
+
+fn public_key(account: &str) -> Result<PublicKey> {
+    let mut cache = PUBLIC_KEYS.lock().unwrap();
+    let cache = cache.get_or_insert_with(HashMap::new);
+    if let Some(public_key) = cache.get(account) {
+        return Ok(*public_key);
+    }
+    let response = Device::open()?.exchange(
+        INS_GET_PUBLIC_KEY,
+        0,
+        &apdu_path(&hd::parse_path(path(account))?),
+    )?;
+    let public_key = response
+        .split_first()
+        .and_then(|(&len, rest)| rest.get(..len as usize))
+        .and_then(|key| PublicKey::from_slice(key).ok())
+        .ok_or_else(|| {
+            Error::Ledger(format!(
+                "Unexpected public key from the Ledger: 0x{}",
+                hex::encode(&response)
+            ))
+        })?;
+    cache.insert(account.to_string(), public_key);
+    Ok(public_key)
+}
+
+/// Sign a transaction spending only cells of the Ledger account, after the user
+/// reviews and approves it on the device. The app hashes the transaction itself, so
+/// it is sent with the transactions that created its inputs, fetched from the node.
+pub fn sign_transaction(
+    client: &RetryingClient,
+    account: &str,
+    tx: TransactionView,
+    input_cells: &[&LiveCell],
+) -> Result<TransactionView> {
+    let lock = lock_script(account)?;
+    let groups = signing::group_inputs(input_cells);
+    if groups.len() != 1 || groups[0].lock != lock {
+        return Err(Error::Signing(format!(
+            "{} only signs transactions spending its own cells",
+            account
+        )));
+    }
+    let path = hd::parse_path(path(account))?;
+    let message = annotated_transaction(client, &path, &tx)?;
+    info!("Review and approve the transaction on the Ledger");
+    let mut device = Device::open()?;
+    let mut chunks = message.chunks(MAX_CHUNK_SIZE).peekable();
+    let mut p1 = SIGN_P1_FIRST;
+    let signature = loop {
+        let chunk = chunks.next().expect("the message is never empty");
+        if chunks.peek().is_none() {
+            break device.exchange(INS_SIGN, p1 | SIGN_P1_LAST, chunk)?;
+        }
+        device.exchange(INS_SIGN, p1, chunk)?;
+        p1 = SIGN_P1_NEXT;
+    };
+    // r and s followed by the recovery id, as the sighash lock takes them
+    if signature.len() != 65 {
+        return Err(Error::Ledger(format!(
+            "Unexpected signature from the Ledger: 0x{}",
+            hex::encode(&signature)
+        )));
+    }
+    // The signature goes in the placeholder at the group's first input
+    let first = groups[0].input_indices[0];
+    let mut witnesses: Vec<packed::Bytes> = tx.witnesses().into_iter().collect();
+    let witness = witnesses
+        .get(first)
+        .and_then(|witness| WitnessArgs::from_slice(&witness.raw_data()).ok())
+        .ok_or_else(|| Error::Signing(format!("Input {} has no WitnessArgs placeholder", first)))?
+        .as_builder()
+        .lock(Some(ckb_types::bytes::Bytes::from(signature)).pack())
+        .build();
+    witnesses[first] = witness.as_bytes().pack();
+    Ok(tx.as_advanced_builder().set_witnesses(witnesses).build())
+}
+
+/// The transaction as the Nervos app's `AnnotatedTransaction` molecule table: the
+/// signing and change paths, the input count, the raw transaction with every input
+/// annotated by the raw transaction that created it, and the witnesses
+fn annotated_transaction(
+    client: &RetryingClient,
+    path: &[u32],
+    tx: &TransactionView,
+) -> Result<Vec<u8>> {
+    let mut inputs = Vec::new();
+    for input in tx.inputs() {
+        let tx_hash = input.previous_output().tx_hash().unpack();
+        let source = match rpc::block_on(client.get_transaction(tx_hash))
+            .map_err(Error::rpc("Failed to get an input's transaction"))?
+            .and_then(|response| response.transaction)
+            .map(|tx| tx.inner)
+        {
+            Some(Either::Left(source)) => packed::Transaction::from(source.inner).raw(),
+            _ => {
+                return Err(Error::Signing(format!(
+                    "Node returned no body for transaction {:#x}",
+                    input.previous_output().tx_hash()
+                )));
+            }
+        };
+        inputs.push(table(&[input.as_slice(), source.as_slice()]));
+    }
+    let inputs: Vec<&[u8]> = inputs.iter().map(Vec::as_slice).collect();
+    let raw = tx.data().raw();
+    let bip32 = bip32(path);
+    Ok(table(&[
+        &bip32,
+        &bip32,
+        &(tx.inputs().len() as u32).to_le_bytes(),
+        &table(&[
+            raw.version().as_slice(),
+            raw.cell_deps().as_slice(),
+            raw.header_deps().as_slice(),
+            &table(&inputs),
+            raw.outputs().as_slice(),
+            raw.outputs_data().as_slice(),
+        ]),
+        tx.witnesses().as_slice(),
+    ]))
+}
+
+/// A molecule table, or dynamic vector, of serialized fields
+fn table(fields: &[&[u8]]) -> Vec<u8> {
+    let header = 4 * (fields.len() + 1);
+    let size = header + fields.iter().map(|field| field.len()).sum::<usize>();
+    let mut out = Vec::with_capacity(size);
+    out.extend_from_slice(&(size as u32).to_le_bytes());
+    let mut offset = header;
+    for field in fields {
+        out.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += field.len();
+    }
+    for field in fields {
+        out.extend_from_slice(field);
+    }
+    out
+}
+
+/// A derivation path as the molecule fixed vector of little-endian `Uint32`s
+fn bip32(path: &[u32]) -> Vec<u8> {
+    let mut out = (path.len() as u32).to_le_bytes().to_vec();
+    for index in path {
+        out.extend_from_slice(&index.to_le_bytes());
+    }
+    out
+}
+
+/// A derivation path as APDUs take it: the count, then big-endian indexes
+fn apdu_path(path: &[u32]) -> Vec<u8> {
+    let mut out = vec![path.len() as u8];
+    for index in path {
+        out.extend_from_slice(&index.to_be_bytes());
+    }
+    out
+}
+
+/// A connection to the Nervos app, over USB or to the Speculos emulator
+enum Device {
+    Hid(File),
+    Speculos(TcpStream),
+}
+
+impl Device {
+    /// Connect to the emulator at LEDGER_SPECULOS (host:port of its APDU port), or
+    /// else to the first Ledger device plugged in
+    fn open() -> Result<Self> {
+        if let Ok(address) = env::var("LEDGER_SPECULOS") {
+            return TcpStream::connect(&address)
+                .map(Device::Speculos)
+                .map_err(|e| {
+                    Error::Ledger(format!(
+                        "Failed to connect to Speculos at {}: {}",
+                        address, e
+                    ))
+                });
+        }
+        let path = find_hidraw().ok_or_else(|| {
+            Error::Ledger(
+                "No Ledger found: plug it in, unlock it and open the Nervos app".to_string(),
+            )
+        })?;
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map(Device::Hid)
+            .map_err(|e| {
+                Error::Ledger(format!(
+                    "Failed to open the Ledger at {}, check its udev rules: {}",
+                    path, e
+                ))
+            })
+    }
+
+    /// Send an APDU and return the response data, failing on a status other than OK
+    fn exchange(&mut self, ins: u8, p1: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut apdu = vec![CLA, ins, p1, 0, data.len() as u8];
+        apdu.extend_from_slice(data);
+        let mut response = match self {
+            Device::Hid(file) => hid_exchange(file, &apdu),
+            Device::Speculos(stream) => speculos_exchange(stream, &apdu),
+        }
+        .map_err(|e| Error::Ledger(format!("Ledger communication failed: {}", e)))?;
+        if response.len() < 2 {
+            return Err(Error::Ledger("Ledger sent no status".to_string()));
+        }
+        let status = response.split_off(response.len() - 2);
+        match u16::from_be_bytes([status[0], status[1]]) {
+            SW_OK => Ok(response),
+            0x6985 => Err(Error::Declined("Rejected on the Ledger".to_string())),
+            0x6e00 | 0x6d00 => Err(Error::Ledger(
+                "The Ledger is not in the Nervos app, open it".to_string(),
+            )),
+            0x5515 => Err(Error::Ledger("The Ledger is locked, unlock it".to_string())),
+            status => Err(Error::Ledger(format!("Ledger replied 0x{:04x}", status))),
+        }
+    }
+}
+
+/// The hidraw device of the APDU interface of a Ledger
+fn find_hidraw() -> Option<String> {
+    let mut names: Vec<String> = fs::read_dir("/sys/class/hidraw")
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    names.into_iter().find_map(|name| {
+        let device = format!("/sys/class/hidraw/{}/device", name);
+        let uevent = fs::read_to_string(format!("{}/uevent", device)).ok()?;
+        let descriptor = fs::read(format!("{}/report_descriptor", device)).ok()?;
+        (uevent
+            .lines()
+            .any(|line| line.starts_with("HID_ID=") && line.contains(LEDGER_VENDOR_ID))
+            && descriptor.starts_with(&APDU_USAGE_PAGE))
+        .then(|| format!("/dev/{}", name))
+    })
+}
+
+/// Send an APDU in HID reports, each with the channel, tag and sequence number, the
+/// first also with the APDU length, and read the response framed the same way
+fn hid_exchange(file: &mut File, apdu: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut payload = (apdu.len() as u16).to_be_bytes().to_vec();
+    payload.extend_from_slice(apdu);
+    for (sequence, chunk) in payload.chunks(HID_PACKET_SIZE - 5).enumerate() {
+        // hidraw takes the report id first, 0 for devices without numbered reports
+        let mut report = vec![0u8];
+        report.extend_from_slice(&HID_CHANNEL);
+        report.push(HID_TAG_APDU);
+        report.extend_from_slice(&(sequence as u16).to_be_bytes());
+        report.extend_from_slice(chunk);
+        report.resize(HID_PACKET_SIZE + 1, 0);
+        file.write_all(&report)?;
+    }
+    let mut response = Vec::new();
+    let mut length = None;
+    let mut report = [0u8; HID_PACKET_SIZE];
+    while length.is_none_or(|length| response.len() < length) {
+        file.read_exact(&mut report)?;
+        let mut data = &report[5..];
+        if length.is_none() {
+            length = Some(u16::from_be_bytes([data[0], data[1]]) as usize);
+            data = &data[2..];
+        }
+        response.extend_from_slice(data);
+    }
+    response.truncate(length.unwrap_or_default());
+    Ok(response)
+}
+
+/// Send an APDU to Speculos, prefixed by its length, and read the response data and
+/// status the same way (the length excludes the status)
+fn speculos_exchange(stream: &mut TcpStream, apdu: &[u8]) -> std::io::Result<Vec<u8>> {
+    stream.write_all(&(apdu.len() as u32).to_be_bytes())?;
+    stream.write_all(apdu)?;
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length)?;
+    let mut response = vec![0u8; u32::from_be_bytes(length) as usize + 2];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
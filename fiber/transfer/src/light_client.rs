@@ -0,0 +1,264 @@
+use crate::error::Error;
+use crate::rate_limit::RateLimiter;
+use anyhow::anyhow;
+use ckb_hash::blake2b_256;
+use ckb_jsonrpc_types::{
+    BlockView, CellData, CellInfo, CellWithStatus, Either, HeaderView, JsonBytes, OutPoint, Script,
+    Transaction, TransactionWithStatusResponse, Uint32,
+};
+use ckb_sdk::RpcError;
+use ckb_sdk::rpc::LightClientRpcAsyncClient;
+use ckb_sdk::rpc::ckb_indexer::{Cell, CellsCapacity, Order, Pagination, ScriptType, SearchKey};
+use ckb_sdk::rpc::ckb_light_client::{FetchStatus, ScriptStatus, SetScriptsCommand};
+use ckb_types::H256;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+// Block a newly watched script is synced from, overridable via LIGHT_CLIENT_START_BLOCK
+// env var. Cells created before it are invisible to the light client.
+const DEFAULT_START_BLOCK: u64 = 0;
+// How long to wait for a watched script to sync up to the tip, overridable via
+// LIGHT_CLIENT_SYNC_TIMEOUT_SECS env var
+const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(600);
+// How long to wait for the light client to fetch a transaction from its peers
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A CKB light client answering the calls the tool makes of a full node and its
+/// indexer. The light client only knows the cells of the scripts it was told to watch,
+/// so every script searched for is registered with `set_scripts` on first use and the
+/// search waits until the client has synced the script up to its tip. Calls are async,
+/// waiting for a sync or a fetch lets other lookups run meanwhile.
+pub struct LightClient {
+    inner: LightClientRpcAsyncClient,
+    /// The RPC_RATE_LIMIT of the endpoint, taken by each request sent
+    limiter: Option<Arc<RateLimiter>>,
+    start_block: u64,
+    sync_timeout: Duration,
+}
+
+impl LightClient {
+    pub fn new(
+        inner: LightClientRpcAsyncClient,
+        limiter: Option<Arc<RateLimiter>>,
+    ) -> Result<Self, Error> {
+        Ok(LightClient {
+            inner,
+            limiter,
+            start_block: crate::rpc::env_parse("LIGHT_CLIENT_START_BLOCK")?
+                .unwrap_or(DEFAULT_START_BLOCK),
+            sync_timeout: crate::rpc::env_secs("LIGHT_CLIENT_SYNC_TIMEOUT_SECS")?
+                .unwrap_or(DEFAULT_SYNC_TIMEOUT),
+        })
+    }
+
+    pub async fn get_cells(
+        &self,
+        search_key: SearchKey,
+        order: Order,
+        limit: Uint32,
+        after: Option<JsonBytes>,
+    ) -> Result<Pagination<Cell>, RpcError> {
+        self.watch(&search_key.script, &search_key.script_type)
+            .await?;
+        self.limited()
+            .await
+            .get_cells(search_key, order, limit, after)
+            .await
+    }
+
+    pub async fn get_cells_capacity(
+        &self,
+        search_key: SearchKey,
+    ) -> Result<CellsCapacity, RpcError> {
+        self.watch(&search_key.script, &search_key.script_type)
+            .await?;
+        self.limited().await.get_cells_capacity(search_key).await
+    }
+
+    pub async fn get_tip_header(&self) -> Result<HeaderView, RpcError> {
+        self.limited().await.get_tip_header().await
+    }
+
+    pub async fn get_genesis_block(&self) -> Result<BlockView, RpcError> {
+        self.limited().await.get_genesis_block().await
+    }
+
+    /// Transactions sent through the light client or touching a watched script; the
+    /// response lacks the fee fields a full node adds
+    pub async fn get_transaction(
+        &self,
+        hash: H256,
+    ) -> Result<Option<TransactionWithStatusResponse>, RpcError> {
+        self.limited().await.post("get_transaction", (hash,)).await
+    }
+
+    pub async fn send_transaction(&self, tx: Transaction) -> Result<H256, RpcError> {
+        self.limited().await.send_transaction(tx).await
+    }
+
+    /// The cell at `out_point`, read from its transaction fetched from the light
+    /// client's peers. The light client can't tell whether a cell was spent, so every
+    /// cell of a committed transaction is reported live, and a transaction spending or
+    /// depending on a dead one is only rejected once sent.
+    pub async fn get_live_cell(
+        &self,
+        out_point: OutPoint,
+        with_data: bool,
+    ) -> Result<CellWithStatus, RpcError> {
+        let unknown = CellWithStatus {
+            cell: None,
+            status: "unknown".to_string(),
+        };
+        let Some(tx) = self.fetch_transaction(&out_point.tx_hash).await? else {
+            return Ok(unknown);
+        };
+        if tx.tx_status.block_hash.is_none() {
+            return Ok(unknown);
+        }
+        let Some(Either::Left(view)) = tx.transaction.map(|tx| tx.inner) else {
+            return Ok(unknown);
+        };
+        let index = out_point.index.value() as usize;
+        let Some(output) = view.inner.outputs.get(index).cloned() else {
+            return Ok(unknown);
+        };
+        let data = with_data.then(|| {
+            let content = view.inner.outputs_data[index].clone();
+            CellData {
+                hash: blake2b_256(content.as_bytes()).into(),
+                content,
+            }
+        });
+        Ok(CellWithStatus {
+            cell: Some(CellInfo { output, data }),
+            status: "live".to_string(),
+        })
+    }
+
+    /// The client, once the request about to be sent got its turn under the rate limit
+    async fn limited(&self) -> &LightClientRpcAsyncClient {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire_async().await;
+        }
+        &self.inner
+    }
+
+    /// The transaction `hash`, fetched from the light client's peers unless it already
+    /// has it. `None` when no peer has it.
+    async fn fetch_transaction(
+        &self,
+        hash: &H256,
+    ) -> Result<Option<TransactionWithStatusResponse>, RpcError> {
+        let deadline = Instant::now() + FETCH_TIMEOUT;
+        loop {
+            let status: FetchStatus<TransactionWithStatusResponse> = self
+                .limited()
+                .await
+                .post("fetch_transaction", (hash.clone(),))
+                .await?;
+            match status {
+                FetchStatus::Fetched { data } => return Ok(Some(data)),
+                FetchStatus::NotFound => return Ok(None),
+                FetchStatus::Added { .. } | FetchStatus::Fetching { .. } => {
+                    if Instant::now() >= deadline {
+                        return Err(RpcError::Other(anyhow!(
+                            "Light client didn't fetch transaction {:#x} within {}s",
+                            hash,
+                            FETCH_TIMEOUT.as_secs()
+                        )));
+                    }
+                    debug!("Light client fetching transaction {:#x}", hash);
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Register `script` with the light client unless it already watches it, then wait
+    /// until the client has synced it up to its tip
+    async fn watch(&self, script: &Script, script_type: &ScriptType) -> Result<(), RpcError> {
+        let mut status = self.script_status(script, script_type).await?;
+        if status.is_none() {
+            info!(
+                "Registering script {} with the light client from block {}",
+                script_hash(script),
+                self.start_block
+            );
+            let script_status = ScriptStatus {
+                script: script.clone(),
+                script_type: script_type.clone(),
+                block_number: self.start_block.into(),
+            };
+            self.inner
+                .set_scripts(vec![script_status], Some(SetScriptsCommand::Partial))
+                .await?;
+        }
+
+        let deadline = Instant::now() + self.sync_timeout;
+        let mut reported = false;
+        loop {
+            let tip = self.get_tip_header().await?.inner.number.value();
+            let synced = match status.take() {
+                Some(synced) => synced,
+                None => self.script_status(script, script_type).await?.unwrap_or(0),
+            };
+            if synced >= tip {
+                if reported {
+                    info!(
+                        "Light client synced script {} at block {}",
+                        script_hash(script),
+                        tip
+                    );
+                }
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(RpcError::Other(anyhow!(
+                    "Light client didn't sync script {} within {}s: at block {}, tip {}",
+                    script_hash(script),
+                    self.sync_timeout.as_secs(),
+                    synced,
+                    tip
+                )));
+            }
+            if !reported {
+                info!(
+                    "Waiting for the light client to sync script {}: at block {}, tip {}",
+                    script_hash(script),
+                    synced,
+                    tip
+                );
+                reported = true;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Block the light client has synced `script` up to, `None` when it doesn't watch it
+    async fn script_status(
+        &self,
+        script: &Script,
+        script_type: &ScriptType,
+    ) -> Result<Option<u64>, RpcError> {
+        let wanted = watch_key(script, script_type);
+        Ok(self
+            .inner
+            .get_scripts()
+            .await?
+            .into_iter()
+            .find(|status| watch_key(&status.script, &status.script_type) == wanted)
+            .map(|status| status.block_number.value()))
+    }
+}
+
+/// Identity of a watched script, which `ScriptType` can't be compared for directly
+fn watch_key(script: &Script, script_type: &ScriptType) -> String {
+    serde_json::to_string(&(script_type, script)).expect("Scripts serialize")
+}
+
+fn script_hash(script: &Script) -> String {
+    let script = ckb_types::packed::Script::from(script.clone());
+    format!("{:#x}", script.calc_script_hash())
+}
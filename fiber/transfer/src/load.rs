@@ -0,0 +1,356 @@
+use crate::amount::CKB_DECIMALS;
+use crate::distribution::Distribution;
+use crate::error::{Error, Result};
+use crate::fiber::{self, FiberClient};
+use crate::network::Token;
+use crate::rpc::{Deadline, HttpSettings};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How the payments of a load run arrive, `rate` payments per second on average
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "process", rename_all = "lowercase")]
+pub enum Arrival {
+    /// Evenly spaced, one every 1 / `rate` seconds
+    Constant { rate: f64 },
+    /// Independently of each other, with exponentially distributed gaps, like the
+    /// payments of many unrelated users
+    Poisson { rate: f64 },
+    /// `size` payments at once, the bursts evenly spaced
+    Burst { rate: f64, size: u32 },
+}
+
+impl Arrival {
+    /// Fail with `Error::Config` when the process can't generate arrivals
+    pub fn check(&self) -> Result<()> {
+        let (rate, size) = match *self {
+            Arrival::Constant { rate } | Arrival::Poisson { rate } => (rate, 1),
+            Arrival::Burst { rate, size } => (rate, size),
+        };
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(Error::Config(format!(
+                "Invalid load arrival rate {}: must be a positive number of payments per second",
+                rate
+            )));
+        }
+        if size == 0 {
+            return Err(Error::Config(
+                "Invalid load burst size 0: a burst needs a payment".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Seconds from one arrival to the next, and how many payments arrive at once
+    fn next(&self, rng: &mut impl Rng) -> (f64, u32) {
+        match *self {
+            Arrival::Constant { rate } => (1.0 / rate, 1),
+            // 1 - u is in (0, 1], keeping the logarithm finite
+            Arrival::Poisson { rate } => (-(1.0 - rng.r#gen::<f64>()).ln() / rate, 1),
+            Arrival::Burst { rate, size } => (f64::from(size) / rate, size),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match *self {
+            Arrival::Constant { rate } => format!("constant, {} payments/s", rate),
+            Arrival::Poisson { rate } => format!("poisson, {} payments/s", rate),
+            Arrival::Burst { rate, size } => {
+                format!("bursts of {}, {} payments/s", size, rate)
+            }
+        }
+    }
+}
+
+/// Payments from one node to another arriving by `arrival`, each of an amount drawn
+/// from `amount`
+pub struct LoadPlan<'a> {
+    /// Node paying the invoices
+    pub from: &'a str,
+    pub from_rpc_url: &'a str,
+    /// Node issuing the invoices
+    pub to: &'a str,
+    pub to_rpc_url: &'a str,
+    /// UDT paid, CKB when None
+    pub token: Option<&'a Token>,
+    pub arrival: Arrival,
+    /// Amount of each payment in CKB, or in token base units
+    pub amount: Distribution,
+    /// Payments arriving this early in the run are made but not measured, so the
+    /// results leave out the nodes' route finding and connection setup
+    pub warm_up: Duration,
+    /// How long payments arrive after the warm-up
+    pub duration: Duration,
+    /// Payments arriving while this many are unsettled are skipped, so a slow network
+    /// doesn't pile up threads
+    pub max_in_flight: usize,
+    /// Seed of the arrival gaps and amounts; drawn and logged when None
+    pub seed: Option<u64>,
+}
+
+impl LoadPlan<'_> {
+    /// Base units of a sampled amount, at least one
+    fn base_units(&self, amount: f64) -> u128 {
+        let amount = match self.token {
+            Some(_) => amount,
+            None => amount * 10f64.powi(CKB_DECIMALS.into()),
+        };
+        // Float to integer casts saturate
+        (amount.round() as u128).max(1)
+    }
+}
+
+/// The measured part of a load run
+#[derive(Serialize)]
+pub struct LoadSummary {
+    pub seed: u64,
+    pub arrival: String,
+    /// Payments made during the warm-up, left out of the rest
+    pub warm_up_payments: usize,
+    pub sent: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Arrivals dropped because `max_in_flight` payments were unsettled
+    pub skipped: usize,
+    /// Succeeded payments per second of the measured phase
+    pub throughput: f64,
+    /// Latency percentiles of the succeeded payments, from creating the invoice to the
+    /// payment's success, in milliseconds
+    pub latency_ms: Option<Latency>,
+}
+
+#[derive(Serialize)]
+pub struct Latency {
+    pub p50: u128,
+    pub p90: u128,
+    pub p99: u128,
+    pub max: u128,
+}
+
+impl LoadSummary {
+    /// Rows of a two-column table
+    pub fn rows(&self) -> Vec<[String; 2]> {
+        let mut rows = vec![
+            ["Seed".to_string(), self.seed.to_string()],
+            ["Arrival".to_string(), self.arrival.clone()],
+            [
+                "Warm-up".to_string(),
+                format!("{} payments, not measured", self.warm_up_payments),
+            ],
+            [
+                "Payments".to_string(),
+                format!(
+                    "{} sent, {} succeeded, {} failed, {} skipped",
+                    self.sent, self.succeeded, self.failed, self.skipped
+                ),
+            ],
+            [
+                "Throughput".to_string(),
+                format!("{:.2} payments/s", self.throughput),
+            ],
+        ];
+        if let Some(latency) = &self.latency_ms {
+            rows.push([
+                "Latency".to_string(),
+                format!(
+                    "p50 {} ms, p90 {} ms, p99 {} ms, max {} ms",
+                    latency.p50, latency.p90, latency.p99, latency.max
+                ),
+            ]);
+        }
+        rows
+    }
+}
+
+/// Outcome of one payment
+struct Sample {
+    measured: bool,
+    latency: Duration,
+    succeeded: bool,
+}
+
+/// Make the payments of `plan` as they arrive, each on its own thread so a slow
+/// payment doesn't hold back the next arrivals, and wait until all of them settled
+pub fn run(
+    plan: &LoadPlan,
+    currency: &str,
+    settings: &HttpSettings,
+    deadline: &Deadline,
+) -> Result<LoadSummary> {
+    fiber::wait_for_nodes(
+        &[(plan.from, plan.from_rpc_url), (plan.to, plan.to_rpc_url)],
+        settings,
+        deadline,
+    )?;
+    let payer = FiberClient::new(plan.from_rpc_url, settings)?;
+    let payee = FiberClient::new(plan.to_rpc_url, settings)?;
+    let udt_script = plan
+        .token
+        .map(|token| crate::get_sudt_type_script(&token.script));
+    let seed = match plan.seed {
+        Some(seed) => seed,
+        None => {
+            let mut bytes = [0u8; 8];
+            getrandom::getrandom(&mut bytes).map_err(Error::random("a load seed"))?;
+            let seed = u64::from_le_bytes(bytes);
+            info!(
+                "Sampling the load with seed {}; pass --seed to repeat it",
+                seed
+            );
+            seed
+        }
+    };
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    info!(
+        "Paying from {} to {}: {}, {}s warm-up, then {}s measured",
+        plan.from,
+        plan.to,
+        plan.arrival.describe(),
+        plan.warm_up.as_secs(),
+        plan.duration.as_secs()
+    );
+
+    let samples = Mutex::new(Vec::new());
+    let in_flight = AtomicUsize::new(0);
+    let mut skipped = 0;
+    let end = (plan.warm_up + plan.duration).as_secs_f64();
+    let started = Instant::now();
+    let outcome = thread::scope(|scope| {
+        let mut at = 0.0;
+        while at < end {
+            let (gap, count) = plan.arrival.next(&mut rng);
+            let wait = Duration::from_secs_f64(at).saturating_sub(started.elapsed());
+            deadline.check("making the load's payments")?;
+            thread::sleep(wait);
+            let measured = at >= plan.warm_up.as_secs_f64();
+            for _ in 0..count {
+                // Amounts are drawn for skipped arrivals too, so the same seed draws the
+                // same amounts however fast the payments settle
+                let amount = plan.base_units(plan.amount.sample(&mut rng));
+                if in_flight.load(Ordering::SeqCst) >= plan.max_in_flight {
+                    if measured {
+                        skipped += 1;
+                    }
+                    continue;
+                }
+                in_flight.fetch_add(1, Ordering::SeqCst);
+                let (payer, payee, udt_script) = (&payer, &payee, udt_script.as_ref());
+                let (samples, in_flight) = (&samples, &in_flight);
+                scope.spawn(move || {
+                    let sent = Instant::now();
+                    let result = fiber::create_invoice(
+                        payee,
+                        amount,
+                        currency,
+                        udt_script,
+                        "fiber-demo load",
+                        None,
+                    )
+                    .and_then(|(invoice, _)| fiber::pay_quietly(payer, &invoice, deadline));
+                    let latency = sent.elapsed();
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    if let Err(e) = &result {
+                        warn!("Load payment failed: {}", e);
+                    }
+                    samples.lock().unwrap().push(Sample {
+                        measured,
+                        latency,
+                        succeeded: result.is_ok(),
+                    });
+                });
+            }
+            at += gap;
+        }
+        Ok(())
+    });
+    outcome?;
+
+    let samples = samples.into_inner().unwrap();
+    let (measured, warm_up): (Vec<Sample>, Vec<Sample>) =
+        samples.into_iter().partition(|sample| sample.measured);
+    let mut latencies: Vec<Duration> = measured
+        .iter()
+        .filter(|sample| sample.succeeded)
+        .map(|sample| sample.latency)
+        .collect();
+    latencies.sort();
+    let succeeded = latencies.len();
+    Ok(LoadSummary {
+        seed,
+        arrival: plan.arrival.describe(),
+        warm_up_payments: warm_up.len(),
+        sent: measured.len(),
+        succeeded,
+        failed: measured.len() - succeeded,
+        skipped,
+        throughput: succeeded as f64 / plan.duration.as_secs_f64(),
+        latency_ms: (!latencies.is_empty()).then(|| Latency {
+            p50: percentile(&latencies, 50).as_millis(),
+            p90: percentile(&latencies, 90).as_millis(),
+            p99: percentile(&latencies, 99).as_millis(),
+            max: latencies[latencies.len() - 1].as_millis(),
+        }),
+    })
+}
+
+/// Nearest-rank percentile of sorted, non-empty `values`
+fn percentile(values: &[Duration], percent: usize) -> Duration {
+    let rank = (values.len() * percent).div_ceil(100).max(1);
+    values[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrivals_keep_the_mean_rate() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        for arrival in [
+            Arrival::Constant { rate: 4.0 },
+            Arrival::Poisson { rate: 4.0 },
+            Arrival::Burst {
+                rate: 4.0,
+                size: 10,
+            },
+        ] {
+            let (seconds, payments) = (0..2000)
+                .map(|_| arrival.next(&mut rng))
+                .fold((0.0, 0), |(seconds, payments), (gap, count)| {
+                    (seconds + gap, payments + count)
+                });
+            let rate = f64::from(payments) / seconds;
+            assert!((rate - 4.0).abs() < 0.3, "{:?} gave {}", arrival, rate);
+        }
+    }
+
+    #[test]
+    fn parses_and_checks_arrivals() {
+        let parsed: Arrival = toml::from_str("process = \"burst\"\nrate = 5.0\nsize = 20").unwrap();
+        assert_eq!(
+            parsed,
+            Arrival::Burst {
+                rate: 5.0,
+                size: 20
+            }
+        );
+        assert!(Arrival::Poisson { rate: 0.0 }.check().is_err());
+        assert!(Arrival::Burst { rate: 1.0, size: 0 }.check().is_err());
+        assert!(Arrival::Constant { rate: 0.5 }.check().is_ok());
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let values: Vec<Duration> = (1..=10).map(Duration::from_secs).collect();
+        assert_eq!(percentile(&values, 50), Duration::from_secs(5));
+        assert_eq!(percentile(&values, 90), Duration::from_secs(9));
+        assert_eq!(percentile(&values, 99), Duration::from_secs(10));
+        assert_eq!(percentile(&values[..1], 50), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,54 @@
+use crate::progress;
+use clap::ValueEnum;
+use std::io::{self, Write};
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{Level, error};
+use tracing_subscriber::EnvFilter;
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Install the global subscriber, logging to stderr (above any progress bars) so command
+/// output on stdout stays clean. `verbosity` counts `-v` flags minus `-q` flags around the default INFO level;
+/// RUST_LOG, when set, takes precedence for fine-grained per-module filtering.
+pub fn init(verbosity: i8, format: LogFormat) {
+    let level = match verbosity {
+        i8::MIN..=-2 => Level::ERROR,
+        -1 => Level::WARN,
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        2..=i8::MAX => Level::TRACE,
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(format!("fiber_demo_startup_transfer_ckb_and_udt={}", level))
+    });
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(|| -> Box<dyn Write> {
+            if PAUSED.load(Ordering::Relaxed) {
+                Box::new(io::sink())
+            } else {
+                Box::new(progress::Stderr)
+            }
+        })
+        .with_target(false);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => {
+            builder.json().init();
+            // Keep stderr machine-readable: report panics as error events too
+            panic::set_hook(Box::new(|info| error!("{}", info)));
+        }
+    }
+}
+
+/// Drop log lines while a full-screen view like `dashboard` owns the terminal
+pub fn pause(paused: bool) {
+    PAUSED.store(paused, Ordering::Relaxed);
+}
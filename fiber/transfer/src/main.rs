@@ -1,95 +1,255 @@
+mod amount;
+mod audit;
+mod cell_cache;
+mod cell_deps;
+mod cell_locker;
+mod chain;
+mod cli;
+mod cobuild;
+mod coin_selection;
+mod config;
+mod dashboard;
+mod distribution;
+mod error;
+mod explorer;
+mod fiber;
+mod hd;
+mod health;
+mod keystore;
+mod ledger;
+mod light_client;
+mod load;
+mod logging;
+mod metrics;
+mod network;
+mod progress;
+mod proxy;
+mod rate_limit;
+mod remote_signer;
+mod report;
+mod rpc;
+mod scenario;
+mod signing;
+mod since;
+mod snapshot;
+mod state;
+mod subscription;
+mod supervisor;
+mod transfer_csv;
+mod webhook;
+
+use amount::{AmountConfig, CKB_DECIMALS, format_amount, parse_amount};
+use cell_deps::CellDepResolver;
+use cell_locker::{CellLocker, Reservations};
 use ckb_hash::blake2b_256;
-use ckb_jsonrpc_types::{CellOutput, JsonBytes, OutPoint, Script, ScriptHashType};
+use ckb_jsonrpc_types::{CellOutput, Either, JsonBytes, OutPoint, Script, ScriptHashType, Status};
 use ckb_sdk::constants::SIGHASH_TYPE_HASH;
-use ckb_sdk::rpc::ckb_indexer::{Order, SearchKey, SearchKeyFilter};
-use ckb_sdk::traits::{DefaultTransactionDependencyProvider, SecpCkbRawKeySigner};
-use ckb_sdk::tx_builder::unlock_tx;
-use ckb_sdk::unlock::{ScriptUnlocker, SecpSighashUnlocker};
-use ckb_sdk::{CkbRpcClient, ScriptId};
+use ckb_sdk::rpc::ckb_indexer::{Cell, SearchKey, SearchKeyFilter};
+use ckb_sdk::{Address, AddressPayload, NetworkType, Since};
+use ckb_types::H256;
 use ckb_types::core::TransactionView;
-use ckb_types::packed::{Byte, CellInput, CellOutputBuilder, Script as PackedScript, WitnessArgs};
+use ckb_types::packed::{Byte, CellInput, CellOutputBuilder, Script as PackedScript};
 use ckb_types::prelude::*;
-use ckb_types::{H256, h256};
+use clap::{Parser, ValueEnum};
+use cli::{
+    AddressesArgs, ArrivalProcess, BalancesArgs, BumpFeeArgs, BurnSudtArgs, ChainArgs,
+    ChainCommand, ChainOptions, ChainSpecArgs, ChainUpArgs, ChannelsArgs, Cli, Command,
+    ConsolidateArgs, DashboardArgs, FnnOptions, GenConfigArgs, GenerateBlocksArgs, HdAddressesArgs,
+    HdArgs, HdCommand, HdExportArgs, HdNewArgs, InspectKeyArgs, InvoiceCommand, IssueSudtArgs,
+    KeygenArgs, KeystoreArgs, KeystoreCommand, KeystoreImportArgs, LoadArgs, NewInvoiceArgs,
+    OutputFormat, PayArgs, ResetArgs, RotateKeyArgs, RunNodesArgs, ScenarioArgs, SetupArgs,
+    SmokeTestArgs, SnapshotArgs, SnapshotCommand, SnapshotRestoreArgs, SnapshotSaveArgs, StateDirs,
+    StatusArgs, TransferArgs, TransferSudtArgs, UpArgs, UpStage,
+};
+use coin_selection::CoinSelector;
+use config::{Config, LockKind, NodeConfig};
+use distribution::Distribution;
+use error::{Error, Result};
+use explorer::ExplorerClient;
+use health::{Check, Health};
+use load::Arrival;
+use network::{Explorer, NetworkPreset, SUDT_TOKEN_NAME, SudtScript, Token};
+use progress::{Progress, Stages};
+use report::{ChannelRow, ChannelStatus, FundingRow, Report};
+use rpc::{Deadline, HttpSettings, RetryingClient};
 use secp256k1::{PublicKey, Secp256k1, SecretKey};
-use std::collections::HashMap;
+use serde::Serialize;
+use serde_json::json;
+use signing::Signers;
+use state::RunState;
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
 use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, instrument, warn};
 
-const CKB_RPC_URL: &str = "http://ckb:8114";
-
-const SOURCE_PRIVATE_KEY: &str = "63d86723e08f0f813a36ce6aa123bb2289d90680ae1e99d4de8cdb334553f24d";
-
-// Key file paths
-const BOOTNODE_KEY_FILE: &str = "ckb-keys/bootnode-key";
-
-const NODE1_KEY_FILE: &str = "ckb-keys/node1-key";
-const NODE2_KEY_FILE: &str = "ckb-keys/node2-key";
-const NODE3_KEY_FILE: &str = "ckb-keys/node3-key";
+// Confirmations required before reporting success, overridable via CONFIRMATIONS env var
+const DEFAULT_CONFIRMATIONS: u64 = 1;
+// Blocks to keep watching a committed transaction for reorgs, overridable via REORG_WATCH_BLOCKS env var
+const DEFAULT_REORG_WATCH_BLOCKS: u64 = 4;
+// Directory of a Fiber node's database, under the node's directory as the containers
+// mount it
+const FNN_STORE_DIR: &str = "store";
+// Progress of per-node funding runs, overridable via STATE_FILE env var
+const DEFAULT_STATE_FILE: &str = "transfer-state.json";
+// How often long-lived modes sample the nodes' balances and channels for the metrics,
+// overridable via METRICS_INTERVAL_SECS env var
+const DEFAULT_METRICS_INTERVAL: Duration = Duration::from_secs(30);
+// Per-transaction limit for reaching the required confirmations, overridable via
+// CONFIRMATION_TIMEOUT_SECS env var
+const DEFAULT_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(600);
+// Interval between transaction status polls, and blocks generated while waiting with
+// GENERATE_BLOCKS=1
+const CONFIRMATION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Longest wait for the next tip header of the CKB_WS_URL subscription before polling
+// anyway
+const MAX_TIP_WAIT: Duration = Duration::from_secs(10);
+// Limit for the indexer to catch up with the node tip, overridable via
+// INDEXER_SYNC_TIMEOUT_SECS env var
+const DEFAULT_INDEXER_SYNC_TIMEOUT: Duration = Duration::from_secs(120);
+// Size above which transfers are split into several transactions, overridable via
+// MAX_TX_SIZE env var; below the node's limit of a block's 597,000 bytes
+const DEFAULT_MAX_TX_SIZE: u64 = 512_000;
+// Change too small for a cell of its own that is left to the miner rather than kept by
+// spending another cell, in shannons (1 CKB)
+const MAX_FOLDED_CHANGE: u64 = 100_000_000;
+// Payments per burst of `load --arrival burst` when neither --burst-size nor the
+// config file's arrival sets it
+const DEFAULT_BURST_SIZE: u32 = 10;
 
-const SUDT_CODE_HASH: H256 =
-    h256!("0xe1e354d6d643ad42724d40967e334984534e0367405c5ae42a9d7d63d77df419");
-const SUDT_ARGS: &str = "c219351b150b900e50a7039f1e448b844110927e5fd9bd30425806cb8ddff1fd";
-
-// 10 billion CKB = 10^9 * 10^8 shannons
-const CKB_TRANSFER_AMOUNT: u64 = 1_000_000_000_00000000;
-// 10 billion sUDT
-const SUDT_TRANSFER_AMOUNT: u128 = 1_000_000_000;
-// Minimum cell capacity for sUDT cell (142 CKB)
-const MIN_SUDT_CELL_CAPACITY: u64 = 142_00000000;
-// Minimum cell capacity for pure CKB cell (61 CKB)
-const MIN_CKB_CELL_CAPACITY: u64 = 61_00000000;
-// Transaction fee
-const TX_FEE: u64 = 100000;
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LiveCell {
     pub out_point: OutPoint,
     pub output: CellOutput,
     pub output_data: JsonBytes,
 }
 
-/// Read private key from file
-fn read_private_key(path: &str) -> String {
-    let content = fs::read_to_string(path)
-        .unwrap_or_else(|e| panic!("Failed to read private key from {}: {}", path, e));
-    content.trim().to_string()
+impl From<Cell> for LiveCell {
+    fn from(cell: Cell) -> Self {
+        LiveCell {
+            out_point: cell.out_point,
+            output: cell.output,
+            output_data: cell.output_data.unwrap_or_default(),
+        }
+    }
+}
+
+/// Read private key from file, decrypting it when the file is a keystore
+fn read_private_key(path: &str) -> Result<String> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        let hint = if e.kind() == io::ErrorKind::NotFound {
+            " (--generate-missing-keys creates it)"
+        } else {
+            ""
+        };
+        Error::InvalidKey(format!(
+            "Failed to read private key from {}: {}{}",
+            path, e, hint
+        ))
+    })?;
+    if keystore::is_keystore(&content) {
+        return keystore::decrypt(&content, path, &keystore::password(path, false)?);
+    }
+    Ok(content.trim().to_string())
+}
+
+/// Write a new random key to the key file of every account given by a key file path
+/// that doesn't exist. Key files are only readable by their owner.
+fn generate_missing_keys(nodes: &[NodeConfig]) -> Result<()> {
+    for node in nodes {
+        let path = Path::new(&node.account);
+        let hex_key = node.account.trim_start_matches("0x");
+        if Address::from_str(&node.account).is_ok()
+            || hex_key.len() == 64 && hex::decode(hex_key).is_ok()
+            || hd::is_path(&node.account)
+            || ledger::is_ledger(&node.account)
+            || remote_signer::is_remote(&node.account)
+            || path.exists()
+        {
+            continue;
+        }
+        let key = write_new_key(path)?;
+        let lock_script = get_lock_script_from_private_key(&key)?;
+        info!(
+            "Generated a key for {} in {}, lock args 0x{}",
+            node.name,
+            path.display(),
+            hex::encode(lock_script.args.as_bytes())
+        );
+    }
+    Ok(())
+}
+
+/// Write a random private key to a new file readable only by its owner, returning the
+/// key as hex
+fn write_new_key(path: &Path) -> Result<String> {
+    let secret_key = loop {
+        let mut bytes = [0u8; 32];
+        getrandom::getrandom(&mut bytes).map_err(Error::random("a key"))?;
+        // Fails for the zero key and values above the curve order
+        if let Ok(secret_key) = SecretKey::from_slice(&bytes) {
+            break secret_key;
+        }
+    };
+    let key = hex::encode(secret_key.secret_bytes());
+    write_secret(path, &key).map_err(Error::io(format!(
+        "Failed to write key file {}",
+        path.display()
+    )))?;
+    Ok(key)
+}
+
+/// Write a secret line to a new file readable only by its owner, creating its directory
+fn write_secret(path: &Path, secret: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", secret))
 }
 
-fn get_lock_script_from_private_key(private_key_hex: &str) -> Script {
+/// Sighash lock of a private key (hex), or of the key of a Ledger or remote account
+fn get_lock_script_from_private_key(private_key_hex: &str) -> Result<Script> {
+    if ledger::is_ledger(private_key_hex) {
+        return ledger::lock_script(private_key_hex);
+    }
+    if remote_signer::is_remote(private_key_hex) {
+        return remote_signer::lock_script(private_key_hex);
+    }
     let secp = Secp256k1::new();
-    let private_key_bytes = hex::decode(private_key_hex).expect("Invalid hex string");
-    let secret_key = SecretKey::from_slice(&private_key_bytes).expect("Invalid private key");
+    let private_key_bytes = hex::decode(private_key_hex)
+        .map_err(|e| Error::InvalidKey(format!("Invalid private key hex: {}", e)))?;
+    let secret_key = SecretKey::from_slice(&private_key_bytes)
+        .map_err(|e| Error::InvalidKey(format!("Invalid private key: {}", e)))?;
     let public_key = PublicKey::from_secret_key(&secp, &secret_key);
 
     let pubkey_bytes = public_key.serialize();
-    let pubkey_hash = blake2b_256(&pubkey_bytes);
+    let pubkey_hash = blake2b_256(pubkey_bytes);
     let pubkey_hash160: [u8; 20] = pubkey_hash[0..20].try_into().unwrap();
 
-    Script {
+    Ok(Script {
         code_hash: SIGHASH_TYPE_HASH.clone(),
         hash_type: ScriptHashType::Type,
         args: JsonBytes::from_vec(pubkey_hash160.to_vec()),
-    }
+    })
 }
 
-fn list_live_cells(client: &CkbRpcClient, private_key_hex: &str) -> Vec<LiveCell> {
-    let lock_script = Script {
-        code_hash: SIGHASH_TYPE_HASH.clone(),
-        hash_type: ScriptHashType::Type,
-        args: {
-            let secp = Secp256k1::new();
-            let secret_key = SecretKey::from_str(private_key_hex).unwrap();
-            let public_key = PublicKey::from_secret_key(&secp, &secret_key);
-            let pubkey_hash_160: [u8; 20] = blake2b_256(&public_key.serialize())[0..20]
-                .try_into()
-                .unwrap();
-
-            JsonBytes::from_vec(pubkey_hash_160.to_vec())
-        },
-    };
-
+/// List all live cells locked by `lock_script`
+#[instrument(level = "debug", skip_all)]
+fn list_live_cells(client: &RetryingClient, lock_script: &Script) -> Result<Vec<LiveCell>> {
     let search_key = SearchKey {
-        script: lock_script,
+        script: lock_script.clone(),
         script_type: ckb_sdk::rpc::ckb_indexer::ScriptType::Lock,
         script_search_mode: Some(ckb_sdk::rpc::ckb_indexer::SearchMode::Exact),
         filter: None,
@@ -97,59 +257,87 @@ fn list_live_cells(client: &CkbRpcClient, private_key_hex: &str) -> Vec<LiveCell
         group_by_transaction: Some(false),
     };
 
-    let mut live_cells = Vec::new();
-    let mut cursor = None;
-
-    loop {
-        let cells = client
-            .get_cells(
-                search_key.clone(),
-                Order::Asc,
-                100u32.into(),
-                cursor.clone(),
-            )
-            .expect("Failed to get cells");
-
-        if cells.objects.is_empty() {
-            break;
-        }
-
-        for cell in cells.objects {
-            live_cells.push(LiveCell {
-                out_point: cell.out_point,
-                output: cell.output,
-                output_data: cell.output_data.unwrap_or_default(),
-            });
-        }
-
-        cursor = Some(cells.last_cursor);
-    }
+    let live_cells: Vec<LiveCell> = rpc::block_on(client.collect_cells(search_key))
+        .map_err(Error::rpc("Failed to get cells"))?
+        .into_iter()
+        .map(LiveCell::from)
+        .collect();
 
-    live_cells
+    debug!("Collected {} live cell(s)", live_cells.len());
+    Ok(live_cells)
 }
 
 /// Find pure CKB cells (without type script)
-fn find_ckb_cells(client: &CkbRpcClient, private_key_hex: &str) -> Vec<LiveCell> {
-    let all_cells = list_live_cells(client, private_key_hex);
-    all_cells
+fn find_ckb_cells(client: &RetryingClient, lock_script: &Script) -> Result<Vec<LiveCell>> {
+    let all_cells = list_live_cells(client, lock_script)?;
+    Ok(all_cells
         .into_iter()
         .filter(|cell| cell.output.type_.is_none())
-        .collect()
+        .collect())
+}
+
+/// Get the total capacity of pure CKB cells (without type script) via the indexer's
+/// get_cells_capacity, without paging through the cells themselves
+async fn get_ckb_balance(client: &RetryingClient, lock_script: &Script) -> Result<u64> {
+    let search_key = SearchKey {
+        script: lock_script.clone(),
+        script_type: ckb_sdk::rpc::ckb_indexer::ScriptType::Lock,
+        script_search_mode: Some(ckb_sdk::rpc::ckb_indexer::SearchMode::Exact),
+        filter: Some(SearchKeyFilter {
+            script: None,
+            // Type script length in [0, 1) means no type script
+            script_len_range: Some([0u64.into(), 1u64.into()]),
+            output_data: None,
+            output_data_filter_mode: None,
+            output_data_len_range: None,
+            output_capacity_range: None,
+            block_range: None,
+        }),
+        with_data: Some(false),
+        group_by_transaction: None,
+    };
+
+    Ok(client
+        .get_cells_capacity(search_key)
+        .await
+        .map_err(Error::rpc("Failed to get cells capacity"))?
+        .map(|c| c.capacity.value())
+        .unwrap_or(0))
+}
+
+/// Get the total capacity of all cells locked by `lock_script`, including typed cells
+fn get_total_capacity(client: &RetryingClient, lock_script: &Script) -> Result<u64> {
+    let search_key = SearchKey {
+        script: lock_script.clone(),
+        script_type: ckb_sdk::rpc::ckb_indexer::ScriptType::Lock,
+        script_search_mode: Some(ckb_sdk::rpc::ckb_indexer::SearchMode::Exact),
+        filter: None,
+        with_data: Some(false),
+        group_by_transaction: None,
+    };
+
+    Ok(rpc::block_on(client.get_cells_capacity(search_key))
+        .map_err(Error::rpc("Failed to get cells capacity"))?
+        .map(|c| c.capacity.value())
+        .unwrap_or(0))
 }
 
 /// Get sUDT type script
-fn get_sudt_type_script() -> Script {
+fn get_sudt_type_script(sudt: &SudtScript) -> Script {
     Script {
-        code_hash: SUDT_CODE_HASH.clone(),
-        hash_type: ScriptHashType::Data,
-        args: JsonBytes::from_vec(hex::decode(SUDT_ARGS).unwrap()),
+        code_hash: sudt.code_hash.clone(),
+        hash_type: sudt.hash_type,
+        args: JsonBytes::from_vec(sudt.args.clone()),
     }
 }
 
-/// Find sUDT cells owned by the given private key
-fn find_sudt_cells(client: &CkbRpcClient, private_key_hex: &str) -> Vec<LiveCell> {
-    let lock_script = get_lock_script_from_private_key(private_key_hex);
-    let sudt_type_script = get_sudt_type_script();
+/// Find sUDT cells locked by `lock_script`
+async fn find_sudt_cells(
+    client: &RetryingClient,
+    lock_script: &Script,
+    sudt: &SudtScript,
+) -> Result<Vec<LiveCell>> {
+    let sudt_type_script = get_sudt_type_script(sudt);
 
     let search_key = SearchKey {
         script: lock_script.clone(),
@@ -168,35 +356,13 @@ fn find_sudt_cells(client: &CkbRpcClient, private_key_hex: &str) -> Vec<LiveCell
         group_by_transaction: None,
     };
 
-    let mut sudt_cells = Vec::new();
-    let mut cursor = None;
-
-    loop {
-        let cells = client
-            .get_cells(
-                search_key.clone(),
-                Order::Asc,
-                100u32.into(),
-                cursor.clone(),
-            )
-            .expect("Failed to get cells");
-
-        if cells.objects.is_empty() {
-            break;
-        }
-
-        for cell in cells.objects {
-            sudt_cells.push(LiveCell {
-                out_point: cell.out_point,
-                output: cell.output,
-                output_data: cell.output_data.unwrap_or_default(),
-            });
-        }
-
-        cursor = Some(cells.last_cursor);
-    }
-
-    sudt_cells
+    Ok(client
+        .collect_cells(search_key)
+        .await
+        .map_err(Error::rpc("Failed to get cells"))?
+        .into_iter()
+        .map(LiveCell::from)
+        .collect())
 }
 
 /// Parse sUDT amount from cell data (little-endian u128)
@@ -214,57 +380,84 @@ fn encode_sudt_amount(amount: u128) -> Vec<u8> {
     amount.to_le_bytes().to_vec()
 }
 
-/// Get secp256k1 cell dep (from genesis block)
-fn get_secp256k1_cell_dep(client: &CkbRpcClient) -> ckb_types::packed::OutPoint {
-    let genesis = client.get_block_by_number(0u64.into()).unwrap().unwrap();
-    let tx_hash = genesis.transactions[1].hash.clone();
-    ckb_types::packed::OutPoint::new_builder()
-        .tx_hash(tx_hash.0.pack())
-        .index(0u32)
-        .build()
+/// Capacity an output with `data_len` bytes of data occupies: one CKB per byte of its
+/// capacity field, lock, type script and data
+fn occupied_capacity(output: &ckb_types::packed::CellOutput, data_len: usize) -> Result<u64> {
+    ckb_types::core::Capacity::bytes(data_len)
+        .and_then(|data| output.occupied_capacity(data))
+        .map(|capacity| capacity.as_u64())
+        .map_err(|e| {
+            Error::InvalidAmount(format!(
+                "Capacity of an output with {} bytes of data overflows: {}",
+                data_len, e
+            ))
+        })
 }
 
-/// Get sUDT cell dep
-fn get_sudt_cell_dep(client: &CkbRpcClient) -> ckb_types::packed::OutPoint {
-    let genesis = client.get_block_by_number(0u64.into()).unwrap().unwrap();
-    let tx_hash = genesis.transactions[0].hash.clone();
-    ckb_types::packed::OutPoint::new_builder()
-        .tx_hash(tx_hash.0.pack())
-        .index(8u32)
-        .build()
+/// A plain output of `lock` with the least capacity it can hold, e.g. 61 CKB for a
+/// sighash lock
+fn min_ckb_output(lock: PackedScript) -> Result<ckb_types::packed::CellOutput> {
+    let output = CellOutputBuilder::default().lock(lock).build();
+    let capacity = occupied_capacity(&output, 0)?;
+    Ok(output
+        .as_builder()
+        .capacity(ckb_types::core::Capacity::shannons(capacity).pack())
+        .build())
 }
 
-/// Sign transaction
-fn sign_transaction(tx: TransactionView, private_key_hex: &str) -> TransactionView {
-    let private_key_bytes = hex::decode(private_key_hex).unwrap();
-    let secret_key = secp256k1::SecretKey::from_slice(&private_key_bytes).unwrap();
+/// An sUDT output of `lock` with the least capacity it can hold holding an amount, e.g.
+/// 142 CKB for a sighash lock
+fn min_sudt_output(lock: PackedScript, sudt: &SudtScript) -> Result<ckb_types::packed::CellOutput> {
+    let output = CellOutputBuilder::default()
+        .lock(lock)
+        .type_(Some(build_packed_sudt_type_script(sudt)).pack())
+        .build();
+    let capacity = occupied_capacity(&output, encode_sudt_amount(0).len())?;
+    Ok(output
+        .as_builder()
+        .capacity(ckb_types::core::Capacity::shannons(capacity).pack())
+        .build())
+}
 
-    let tx_dep_provider = DefaultTransactionDependencyProvider::new(CKB_RPC_URL, 10);
+fn capacity_of(output: &ckb_types::packed::CellOutput) -> u64 {
+    output.capacity().unpack()
+}
 
-    let signer = SecpCkbRawKeySigner::new_with_secret_keys(vec![secret_key]);
-    let script_id = ScriptId::new_type(SIGHASH_TYPE_HASH.clone());
-    let unlocker = SecpSighashUnlocker::from(Box::new(signer) as Box<_>);
+/// Capacity left of `have` shannons once `need` are spent, failing with
+/// `Error::InsufficientCapacity` instead of underflowing
+fn capacity_left(have: u64, need: u64) -> Result<u64> {
+    have.checked_sub(need)
+        .ok_or(Error::InsufficientCapacity { have, need })
+}
 
-    let mut unlockers: HashMap<ScriptId, Box<dyn ScriptUnlocker>> = HashMap::new();
-    unlockers.insert(script_id, Box::new(unlocker));
+/// Total of capacities in shannons, failing with `Error::InvalidAmount` when it
+/// overflows
+fn total_capacity(capacities: impl IntoIterator<Item = u64>) -> Result<u64> {
+    capacities
+        .into_iter()
+        .try_fold(0u64, u64::checked_add)
+        .ok_or_else(|| Error::InvalidAmount("The total capacity overflows".to_string()))
+}
 
-    let (signed_tx, _) = unlock_tx(tx, &tx_dep_provider, &unlockers).unwrap();
-    signed_tx
+fn get_genesis_block(client: &RetryingClient) -> Result<ckb_jsonrpc_types::BlockView> {
+    rpc::block_on(client.get_block_by_number(0u64.into()))
+        .map_err(Error::rpc("Failed to get genesis block"))?
+        .ok_or_else(|| Error::CellDep("Genesis block not found".to_string()))
 }
 
 /// Build packed lock script from private key
-fn build_packed_lock_script(private_key_hex: &str) -> PackedScript {
-    let lock_script = get_lock_script_from_private_key(private_key_hex);
-    PackedScript::new_builder()
+fn build_packed_lock_script(private_key_hex: &str) -> Result<PackedScript> {
+    let lock_script = get_lock_script_from_private_key(private_key_hex)?;
+    Ok(PackedScript::new_builder()
         .code_hash(lock_script.code_hash.0.pack())
         .hash_type(Byte::new(lock_script.hash_type as u8))
         .args(lock_script.args.as_bytes().pack())
-        .build()
+        .build())
 }
 
 /// Build packed sUDT type script
-fn build_packed_sudt_type_script() -> PackedScript {
-    let sudt_type_script = get_sudt_type_script();
+fn build_packed_sudt_type_script(sudt: &SudtScript) -> PackedScript {
+    let sudt_type_script = get_sudt_type_script(sudt);
     PackedScript::new_builder()
         .code_hash(sudt_type_script.code_hash.0.pack())
         .hash_type(Byte::new(sudt_type_script.hash_type as u8))
@@ -272,158 +465,457 @@ fn build_packed_sudt_type_script() -> PackedScript {
         .build()
 }
 
-/// Transfer CKB and sUDT in a single transaction
+/// An unsigned transfer transaction together with the cells it spends
+pub struct TransferPlan {
+    pub tx: TransactionView,
+    pub input_cells: Vec<LiveCell>,
+    /// In shannons, including change too small for a cell of its own
+    pub fee: u64,
+    pub dust_change: DustChange,
+    /// Tokens the transaction may move, naming and scaling their amounts in the summary
+    pub tokens: Vec<Token>,
+}
+
+/// What a transfer did with change too small for a cell of its own
+#[derive(Clone, Copy, Default)]
+pub enum DustChange {
+    #[default]
+    None,
+    /// Added to the fee, in shannons
+    Folded(u64),
+    /// Kept by spending `cells` more cells, so that it fills a change cell
+    ToppedUp { change: u64, cells: usize },
+}
+
+/// The outputs of one UDT token in a transfer
+pub struct TokenTransfer<'a> {
+    pub token: &'a Token,
+    pub recipients: Vec<(Script, u128)>, // (lock_script, amount)
+    /// Minted rather than collected from the sender's cells of the token, which the
+    /// sUDT script allows when the sender's lock hash is the token's args
+    pub issued: u128,
+    /// Collected from the sender's cells without an output, destroying it
+    pub burned: u128,
+}
+
+/// Transfer CKB and any number of UDT tokens in a single transaction. The recipient
+/// lists may be empty; cells of a token are only collected when it has recipients.
+/// The inputs are reserved in `locker` before broadcasting, and released again if the
+/// transaction is not sent. Unless `assume_yes` is set, the plan is shown and
+/// confirmed on the terminal before broadcasting.
+#[allow(clippy::too_many_arguments)]
 fn transfer_ckb_and_sudt(
-    client: &CkbRpcClient,
+    client: &RetryingClient,
+    locker: &CellLocker,
+    cell_deps: &CellDepResolver,
+    selector: &dyn CoinSelector,
     from_private_key: &str,
-    ckb_recipients: &[(&str, u64)],   // (private_key, ckb_amount)
-    sudt_recipients: &[(&str, u128)], // (private_key, sudt_amount)
-) -> H256 {
-    // Calculate total CKB amount needed for pure CKB outputs
-    let total_ckb_for_recipients: u64 = ckb_recipients.iter().map(|(_, amount)| *amount).sum();
-
-    // Calculate total sUDT amount needed
-    let total_sudt_amount: u128 = sudt_recipients.iter().map(|(_, amount)| *amount).sum();
+    ckb_recipients: &[(Script, u64)], // (lock_script, ckb_amount)
+    tokens: &[TokenTransfer],
+    fee_rate: u64,
+    since: Option<Since>,
+    cobuild: bool,
+    assume_yes: bool,
+) -> Result<TransactionView> {
+    let mut build_rate = fee_rate;
+    loop {
+        // Another invocation may reserve some of the selected cells in the meantime; the
+        // rebuilt plan leaves them out
+        let plan = loop {
+            let plan = build_transfer(
+                client,
+                locker,
+                cell_deps,
+                selector,
+                from_private_key,
+                ckb_recipients,
+                tokens,
+                build_rate,
+                since,
+                cobuild,
+            )?;
+            if locker.reserve(&plan.input_cells)? {
+                break plan;
+            }
+            warn!("Input cells were reserved by another run meanwhile, rebuilding the transfer");
+        };
 
-    // Calculate capacity needed for sUDT outputs
-    let sudt_outputs_capacity = MIN_SUDT_CELL_CAPACITY * sudt_recipients.len() as u64;
+        let result = (|| {
+            if !assume_yes {
+                confirm_plan(&plan)?;
+            }
+            send_transfer(client, from_private_key, &plan, fee_rate)
+        })();
+        match result {
+            Ok(Sent::Broadcast(tx)) => return Ok(tx),
+            Ok(Sent::Underpaid(rate)) => {
+                locker.release(&plan.input_cells)?;
+                build_rate = rate;
+            }
+            Err(e) => {
+                // The send error matters more than a lease left to expire
+                if let Err(release_error) = locker.release(&plan.input_cells) {
+                    warn!("{}", release_error);
+                }
+                return Err(e);
+            }
+        }
+    }
+}
 
-    // Total capacity needed (CKB outputs + sUDT outputs capacity + fee + potential change cells)
-    let total_capacity_needed =
-        total_ckb_for_recipients + sudt_outputs_capacity + TX_FEE + MIN_SUDT_CELL_CAPACITY;
+/// Collect input cells and build the unsigned transfer transaction paying at least
+/// `fee_rate` shannons per 1000 bytes, failing with `Error::InsufficientFunds` or
+/// `Error::InsufficientCapacity` when the sender can't cover it. The fee is recomputed from the size of each built
+/// transaction until the inputs and change it leads to no longer grow the size. Cells
+/// reserved in `locker` by other runs are not selected, the others are picked by
+/// `selector`. Every input gets `since` when given, and with `cobuild` the
+/// transaction carries a cobuild message witness.
+#[instrument(skip_all, fields(ckb_recipients = ckb_recipients.len(), tokens = tokens.len()))]
+#[allow(clippy::too_many_arguments)]
+fn build_transfer(
+    client: &RetryingClient,
+    locker: &CellLocker,
+    cell_deps: &CellDepResolver,
+    selector: &dyn CoinSelector,
+    from_private_key: &str,
+    ckb_recipients: &[(Script, u64)], // (lock_script, ckb_amount)
+    tokens: &[TokenTransfer],
+    fee_rate: u64,
+    since: Option<Since>,
+    cobuild: bool,
+) -> Result<TransferPlan> {
+    let reserved = locker.reservations()?;
+    let mut fee = 0;
+    loop {
+        let plan = assemble_transfer(
+            client,
+            cell_deps,
+            selector,
+            &reserved,
+            from_private_key,
+            ckb_recipients,
+            tokens,
+            fee,
+        )?;
+        let plan = match cobuild {
+            true => TransferPlan {
+                tx: cobuild::attach(plan.tx),
+                ..plan
+            },
+            false => plan,
+        };
+        let min_fee = min_fee(&plan.tx, fee_rate);
+        if plan.fee >= min_fee {
+            debug!(
+                "Fee {} shannons for {} bytes at {} shannons/KB",
+                plan.fee,
+                plan.tx.data().serialized_size_in_block(),
+                fee_rate
+            );
+            log_dust_change(plan.dust_change);
+            return Ok(match since {
+                Some(since) => TransferPlan {
+                    tx: since::apply(plan.tx, since),
+                    ..plan
+                },
+                None => plan,
+            });
+        }
+        fee = min_fee;
+    }
+}
 
-    // Collect sUDT cells first
-    let sudt_cells = find_sudt_cells(client, from_private_key);
-    assert!(!sudt_cells.is_empty(), "No sUDT cells found");
+fn log_dust_change(dust_change: DustChange) {
+    match dust_change {
+        DustChange::None => {}
+        DustChange::Folded(change) if change <= MAX_FOLDED_CHANGE => info!(
+            "Change of {} CKB is too small for a cell of its own, adding it to the fee",
+            format_amount(change.into(), CKB_DECIMALS)
+        ),
+        DustChange::Folded(change) => warn!(
+            "Change of {} CKB is too small for a cell of its own and no other cell can \
+             top it up, adding it to the fee",
+            format_amount(change.into(), CKB_DECIMALS)
+        ),
+        DustChange::ToppedUp { change, cells } => info!(
+            "Change of {} CKB is too small for a cell of its own, spending {} more \
+             cell(s) to keep it",
+            format_amount(change.into(), CKB_DECIMALS),
+            cells
+        ),
+    }
+}
 
-    let mut inputs = Vec::new();
-    let mut input_sudt_amount: u128 = 0;
-    let mut input_capacity: u64 = 0;
+/// Minimum fee of a transaction at `fee_rate` shannons per 1000 bytes. Witnesses hold
+/// zeroed placeholders of the signature size, so the unsigned transaction has the size
+/// of the signed one.
+fn min_fee(tx: &TransactionView, fee_rate: u64) -> u64 {
+    (tx.data().serialized_size_in_block() as u64 * fee_rate).div_ceil(1000)
+}
 
-    // Add sUDT cells as inputs
-    for cell in &sudt_cells {
-        inputs.push(
-            CellInput::new_builder()
-                .previous_output(
-                    ckb_types::packed::OutPoint::new_builder()
-                        .tx_hash(cell.out_point.tx_hash.0.pack())
-                        .index(cell.out_point.index.value() as u32)
-                        .build(),
-                )
+/// Build a cell input spending `cell`
+fn build_cell_input(cell: &LiveCell) -> CellInput {
+    CellInput::new_builder()
+        .previous_output(
+            ckb_types::packed::OutPoint::new_builder()
+                .tx_hash(cell.out_point.tx_hash.0.pack())
+                .index(cell.out_point.index.value())
                 .build(),
-        );
-        input_sudt_amount += parse_sudt_amount(cell.output_data.as_bytes());
-        input_capacity += u64::from(cell.output.capacity);
+        )
+        .build()
+}
+
+/// Collect input cells that are not `reserved` and build the unsigned transfer
+/// transaction paying `fee`. Change too small for a cell of its own is kept by spending
+/// more cells when it's above `MAX_FOLDED_CHANGE` and the sender has them, and added
+/// to the fee otherwise.
+#[allow(clippy::too_many_arguments)]
+fn assemble_transfer(
+    client: &RetryingClient,
+    cell_deps: &CellDepResolver,
+    selector: &dyn CoinSelector,
+    reserved: &Reservations,
+    from_private_key: &str,
+    ckb_recipients: &[(Script, u64)], // (lock_script, ckb_amount)
+    tokens: &[TokenTransfer],
+    fee: u64,
+) -> Result<TransferPlan> {
+    let tokens: Vec<&TokenTransfer> = tokens
+        .iter()
+        .filter(|t| !t.recipients.is_empty() || t.burned > 0)
+        .collect();
+
+    // Calculate total CKB amount needed for pure CKB outputs
+    let total_ckb_for_recipients =
+        total_capacity(ckb_recipients.iter().map(|(_, amount)| *amount))?;
+
+    let from_lock_script = get_lock_script_from_private_key(from_private_key)?;
+    let change_lock_script = build_packed_lock_script(from_private_key)?;
+
+    // sUDT outputs for the recipients of each token, each with the capacity its lock
+    // and data occupy
+    let sudt_outputs: Vec<_> = tokens
+        .iter()
+        .flat_map(|transfer| {
+            transfer.recipients.iter().map(|(recipient_lock, amount)| {
+                let lock = PackedScript::from(recipient_lock.clone());
+                Ok((min_sudt_output(lock, &transfer.token.script)?, *amount))
+            })
+        })
+        .collect::<Result<_>>()?;
+    let sudt_outputs_capacity =
+        total_capacity(sudt_outputs.iter().map(|(output, _)| capacity_of(output)))?;
+
+    // Total capacity needed (CKB outputs + sUDT outputs capacity + fee + potential
+    // change cells, one sUDT change cell per token)
+    let change_cells_capacity: u64 = if tokens.is_empty() {
+        capacity_of(&min_ckb_output(change_lock_script.clone())?)
+    } else {
+        tokens
+            .iter()
+            .map(|t| {
+                Ok(capacity_of(&min_sudt_output(
+                    change_lock_script.clone(),
+                    &t.token.script,
+                )?))
+            })
+            .sum::<Result<u64>>()?
+    };
+    let total_capacity_needed = total_capacity([
+        total_ckb_for_recipients,
+        sudt_outputs_capacity,
+        fee,
+        change_cells_capacity,
+    ])?;
+
+    let mut input_cells = Vec::new();
+    let mut input_capacity: u64 = 0;
 
-        if input_sudt_amount >= total_sudt_amount {
-            break;
+    // Collect the cells of each token first, skipping cells reserved by other runs. The
+    // tokens' cells are looked up at once.
+    let token_cells =
+        rpc::block_on(rpc::concurrently(tokens.iter().map(|transfer| {
+            find_sudt_cells(client, &from_lock_script, &transfer.token.script)
+        })))?;
+    let mut token_changes = Vec::new();
+    for (transfer, cells) in tokens.iter().zip(token_cells) {
+        let total_amount = transfer
+            .recipients
+            .iter()
+            .map(|(_, amount)| *amount)
+            .chain([transfer.burned])
+            .try_fold(0u128, u128::checked_add)
+            .ok_or_else(|| {
+                Error::InvalidAmount(format!(
+                    "The total {} amount overflows",
+                    transfer.token.name
+                ))
+            })?;
+        let mut input_amount = transfer.issued;
+        if input_amount < total_amount {
+            let candidates: Vec<(LiveCell, u128)> = cells?
+                .into_iter()
+                .filter(|cell| !reserved.contains(&cell.out_point))
+                .map(|cell| {
+                    let amount = parse_sudt_amount(cell.output_data.as_bytes());
+                    (cell, amount)
+                })
+                .collect();
+            let overflow = || {
+                Error::InvalidAmount(format!(
+                    "The {} amount of the sender's cells overflows",
+                    transfer.token.name
+                ))
+            };
+            let have = candidates
+                .iter()
+                .map(|(_, amount)| *amount)
+                .try_fold(input_amount, u128::checked_add)
+                .ok_or_else(overflow)?;
+            let selected = selector
+                .select(candidates, total_amount - input_amount)
+                .ok_or_else(|| Error::InsufficientFunds {
+                    asset: transfer.token.name.clone(),
+                    have,
+                    need: total_amount,
+                })?;
+            for (cell, amount) in selected {
+                input_amount = input_amount.checked_add(amount).ok_or_else(overflow)?;
+                input_capacity = total_capacity([input_capacity, cell.output.capacity.into()])?;
+                input_cells.push(cell);
+            }
         }
+        token_changes.push(input_amount - total_amount);
     }
 
-    assert!(
-        input_sudt_amount >= total_sudt_amount,
-        "Not enough sUDT. Have: {}, Need: {}",
-        input_sudt_amount,
-        total_sudt_amount
-    );
-
     // Add pure CKB cells if needed
-    let ckb_cells = find_ckb_cells(client, from_private_key);
-    for cell in &ckb_cells {
-        if input_capacity >= total_capacity_needed {
-            break;
-        }
-        inputs.push(
-            CellInput::new_builder()
-                .previous_output(
-                    ckb_types::packed::OutPoint::new_builder()
-                        .tx_hash(cell.out_point.tx_hash.0.pack())
-                        .index(cell.out_point.index.value() as u32)
-                        .build(),
-                )
-                .build(),
-        );
-        input_capacity += u64::from(cell.output.capacity);
+    if input_capacity < total_capacity_needed {
+        let candidates: Vec<(LiveCell, u128)> = find_ckb_cells(client, &from_lock_script)?
+            .into_iter()
+            .filter(|cell| !reserved.contains(&cell.out_point))
+            .map(|cell| {
+                let capacity = u64::from(cell.output.capacity);
+                (cell, capacity.into())
+            })
+            .collect();
+        let have = total_capacity(
+            [input_capacity].into_iter().chain(
+                candidates
+                    .iter()
+                    .map(|(cell, _)| u64::from(cell.output.capacity)),
+            ),
+        )?;
+        let selected = selector
+            .select(candidates, (total_capacity_needed - input_capacity).into())
+            .ok_or(Error::InsufficientCapacity {
+                have,
+                need: total_capacity_needed,
+            })?;
+        for (cell, _) in selected {
+            input_capacity = total_capacity([input_capacity, cell.output.capacity.into()])?;
+            input_cells.push(cell);
+        }
     }
 
-    assert!(
-        input_capacity >= total_capacity_needed,
-        "Not enough CKB capacity. Have: {}, Need: {}",
-        input_capacity,
-        total_capacity_needed
-    );
-
     // Build outputs
     let mut outputs = Vec::new();
     let mut outputs_data = Vec::new();
-    let sudt_type_script = build_packed_sudt_type_script();
 
     // 1. Pure CKB outputs for CKB recipients
-    for (recipient_key, ckb_amount) in ckb_recipients {
-        let lock_script = build_packed_lock_script(recipient_key);
-        let output = CellOutputBuilder::default()
-            .capacity(ckb_types::core::Capacity::shannons(*ckb_amount).pack())
-            .lock(lock_script)
-            .build();
-        outputs.push(output);
+    for (recipient_lock, ckb_amount) in ckb_recipients {
+        let output = min_ckb_output(PackedScript::from(recipient_lock.clone()))?;
+        if *ckb_amount < capacity_of(&output) {
+            return Err(Error::InvalidAmount(format!(
+                "The recipient with lock args 0x{} needs at least the {} CKB its cell \
+                 occupies, not {}",
+                hex::encode(recipient_lock.args.as_bytes()),
+                format_amount(capacity_of(&output).into(), CKB_DECIMALS),
+                format_amount((*ckb_amount).into(), CKB_DECIMALS)
+            )));
+        }
+        outputs.push(
+            output
+                .as_builder()
+                .capacity(ckb_types::core::Capacity::shannons(*ckb_amount).pack())
+                .build(),
+        );
         outputs_data.push(ckb_types::packed::Bytes::default());
     }
 
-    // 2. sUDT outputs for sUDT recipients
-    for (recipient_key, sudt_amount) in sudt_recipients {
-        let lock_script = build_packed_lock_script(recipient_key);
-        let output = CellOutputBuilder::default()
-            .capacity(ckb_types::core::Capacity::shannons(MIN_SUDT_CELL_CAPACITY).pack())
-            .lock(lock_script)
-            .type_(Some(sudt_type_script.clone()).pack())
-            .build();
+    // 2. sUDT outputs for the recipients of each token
+    for (output, sudt_amount) in sudt_outputs {
         outputs.push(output);
-        outputs_data.push(encode_sudt_amount(*sudt_amount).pack());
+        outputs_data.push(encode_sudt_amount(sudt_amount).pack());
     }
 
     // 3. Calculate change amounts
-    let used_capacity = total_ckb_for_recipients + sudt_outputs_capacity + TX_FEE;
-    let change_capacity = input_capacity - used_capacity;
-    let change_sudt_amount = input_sudt_amount - total_sudt_amount;
-
-    // 4. Add change outputs
-    let change_lock_script = build_packed_lock_script(from_private_key);
-
-    if change_sudt_amount > 0 {
-        // sUDT change cell
-        let sudt_change_output = CellOutputBuilder::default()
-            .capacity(ckb_types::core::Capacity::shannons(MIN_SUDT_CELL_CAPACITY).pack())
-            .lock(change_lock_script.clone())
-            .type_(Some(sudt_type_script.clone()).pack())
-            .build();
+    let used_capacity = total_capacity([total_ckb_for_recipients, sudt_outputs_capacity, fee])?;
+    let mut change_capacity = capacity_left(input_capacity, used_capacity)?;
+
+    // 4. Add change outputs: an sUDT change cell per token with change left, then the
+    // remaining CKB change (if any)
+    for (transfer, change_sudt_amount) in tokens.iter().zip(token_changes) {
+        if change_sudt_amount == 0 {
+            continue;
+        }
+        let sudt_change_output =
+            min_sudt_output(change_lock_script.clone(), &transfer.token.script)?;
+        change_capacity = capacity_left(change_capacity, capacity_of(&sudt_change_output))?;
         outputs.push(sudt_change_output);
         outputs_data.push(encode_sudt_amount(change_sudt_amount).pack());
-
-        // Remaining CKB change (if any)
-        let remaining_ckb_change = change_capacity - MIN_SUDT_CELL_CAPACITY;
-        if remaining_ckb_change > MIN_CKB_CELL_CAPACITY {
-            let ckb_change_output = CellOutputBuilder::default()
-                .capacity(ckb_types::core::Capacity::shannons(remaining_ckb_change).pack())
-                .lock(change_lock_script)
-                .build();
-            outputs.push(ckb_change_output);
-            outputs_data.push(ckb_types::packed::Bytes::default());
-        }
-    } else if change_capacity > MIN_CKB_CELL_CAPACITY {
-        // Only CKB change
-        let ckb_change_output = CellOutputBuilder::default()
-            .capacity(ckb_types::core::Capacity::shannons(change_capacity).pack())
-            .lock(change_lock_script)
-            .build();
-        outputs.push(ckb_change_output);
+    }
+    let ckb_change_output = min_ckb_output(change_lock_script)?;
+    let min_change = capacity_of(&ckb_change_output);
+    let mut dust_change = DustChange::None;
+    if change_capacity > 0 && change_capacity < min_change {
+        let top_up = if change_capacity > MAX_FOLDED_CHANGE {
+            let spare: Vec<(LiveCell, u128)> = find_ckb_cells(client, &from_lock_script)?
+                .into_iter()
+                .filter(|cell| {
+                    !reserved.contains(&cell.out_point)
+                        && !input_cells.iter().any(|c| c.out_point == cell.out_point)
+                })
+                .map(|cell| {
+                    let capacity = u64::from(cell.output.capacity);
+                    (cell, capacity.into())
+                })
+                .collect();
+            selector.select(spare, (min_change - change_capacity).into())
+        } else {
+            None
+        };
+        dust_change = match top_up {
+            Some(cells) => {
+                let topped_up = DustChange::ToppedUp {
+                    change: change_capacity,
+                    cells: cells.len(),
+                };
+                for (cell, _) in cells {
+                    let capacity = u64::from(cell.output.capacity);
+                    input_capacity = total_capacity([input_capacity, capacity])?;
+                    change_capacity = total_capacity([change_capacity, capacity])?;
+                    input_cells.push(cell);
+                }
+                topped_up
+            }
+            None => DustChange::Folded(change_capacity),
+        };
+    }
+    if change_capacity >= min_change {
+        outputs.push(
+            ckb_change_output
+                .as_builder()
+                .capacity(ckb_types::core::Capacity::shannons(change_capacity).pack())
+                .build(),
+        );
         outputs_data.push(ckb_types::packed::Bytes::default());
     }
 
     // Build transaction
     let mut tx_builder = TransactionView::new_advanced_builder();
 
-    for input in inputs.iter() {
-        tx_builder = tx_builder.input(input.clone());
+    for cell in &input_cells {
+        tx_builder = tx_builder.input(build_cell_input(cell));
     }
 
     for output in outputs {
@@ -434,112 +926,4608 @@ fn transfer_ckb_and_sudt(
         tx_builder = tx_builder.output_data(data);
     }
 
-    // Add witnesses (one for each input)
-    for _ in 0..inputs.len() {
-        tx_builder = tx_builder.witness(WitnessArgs::default().as_bytes().pack());
+    // Add witnesses (placeholder at the first input of each lock group)
+    let cell_refs: Vec<&LiveCell> = input_cells.iter().collect();
+    for witness in signing::build_witnesses(&cell_refs) {
+        tx_builder = tx_builder.witness(witness);
     }
 
+    let tokens: Vec<&Token> = tokens.iter().map(|transfer| transfer.token).collect();
     let tx = tx_builder
-        .cell_dep(
-            ckb_types::packed::CellDep::new_builder()
-                .out_point(get_secp256k1_cell_dep(client))
-                .dep_type(Byte::new(ckb_types::core::DepType::DepGroup as u8))
+        .cell_deps(cell_deps.transfer(client, &tokens)?)
+        .build();
+    let output_capacity = total_capacity(
+        tx.outputs()
+            .into_iter()
+            .map(|output| Unpack::<u64>::unpack(&output.capacity())),
+    )?;
+
+    Ok(TransferPlan {
+        tx,
+        input_cells,
+        fee: capacity_left(input_capacity, output_capacity)?,
+        dust_change,
+        tokens: tokens.into_iter().cloned().collect(),
+    })
+}
+
+/// Build the sweep of the sender's cells to `to_lock` paying at least `fee_rate`
+/// shannons per 1000 bytes, like `build_transfer`
+fn build_sweep(
+    client: &RetryingClient,
+    locker: &CellLocker,
+    cell_deps: &CellDepResolver,
+    from_private_key: &str,
+    to_lock: &Script,
+    tokens: &[Token],
+    fee_rate: u64,
+) -> Result<TransferPlan> {
+    let reserved = locker.reservations()?;
+    let mut fee = 0;
+    loop {
+        let plan = assemble_sweep(
+            client,
+            cell_deps,
+            &reserved,
+            from_private_key,
+            to_lock,
+            tokens,
+            fee,
+        )?;
+        let min_fee = min_fee(&plan.tx, fee_rate);
+        if plan.fee >= min_fee {
+            return Ok(plan);
+        }
+        fee = min_fee;
+    }
+}
+
+/// Build the unsigned transaction moving every CKB cell and every cell of `tokens`
+/// held by the sender, except those `reserved`, to `to_lock`: one cell per token
+/// holding its whole balance and one cell with the remaining capacity less `fee`,
+/// which goes to the last token cell when it's too small for a cell of its own. Cells
+/// of other types are left behind.
+fn assemble_sweep(
+    client: &RetryingClient,
+    cell_deps: &CellDepResolver,
+    reserved: &Reservations,
+    from_private_key: &str,
+    to_lock: &Script,
+    tokens: &[Token],
+    fee: u64,
+) -> Result<TransferPlan> {
+    let from_lock_script = get_lock_script_from_private_key(from_private_key)?;
+    let to_lock = PackedScript::from(to_lock.clone());
+    let mut input_cells = Vec::new();
+    let mut outputs = Vec::new();
+    let mut outputs_data = Vec::new();
+    let mut swept_tokens = Vec::new();
+    for token in tokens {
+        let cells: Vec<LiveCell> =
+            rpc::block_on(find_sudt_cells(client, &from_lock_script, &token.script))?
+                .into_iter()
+                .filter(|cell| !reserved.contains(&cell.out_point))
+                .collect();
+        if cells.is_empty() {
+            continue;
+        }
+        let amount: u128 = cells
+            .iter()
+            .map(|cell| parse_sudt_amount(cell.output_data.as_bytes()))
+            .sum();
+        input_cells.extend(cells);
+        outputs.push(min_sudt_output(to_lock.clone(), &token.script)?);
+        outputs_data.push(encode_sudt_amount(amount).pack());
+        swept_tokens.push(token);
+    }
+    input_cells.extend(
+        find_ckb_cells(client, &from_lock_script)?
+            .into_iter()
+            .filter(|cell| !reserved.contains(&cell.out_point)),
+    );
+
+    let input_capacity = total_capacity(
+        input_cells
+            .iter()
+            .map(|cell| u64::from(cell.output.capacity)),
+    )?;
+    let need = total_capacity(outputs.iter().map(capacity_of).chain([fee]))?;
+    if input_cells.is_empty() {
+        return Err(Error::InsufficientCapacity { have: 0, need });
+    }
+    let rest = capacity_left(input_capacity, need)?;
+    let rest_output = min_ckb_output(to_lock)?;
+    if rest >= capacity_of(&rest_output) {
+        outputs.push(
+            rest_output
+                .as_builder()
+                .capacity(ckb_types::core::Capacity::shannons(rest).pack())
                 .build(),
-        )
-        .cell_dep(
-            ckb_types::packed::CellDep::new_builder()
-                .out_point(get_sudt_cell_dep(client))
-                .dep_type(Byte::new(ckb_types::core::DepType::Code as u8))
+        );
+        outputs_data.push(ckb_types::packed::Bytes::default());
+    } else if let Some(last) = outputs.pop() {
+        let capacity = capacity_of(&last) + rest;
+        outputs.push(
+            last.as_builder()
+                .capacity(ckb_types::core::Capacity::shannons(capacity).pack())
                 .build(),
-        )
+        );
+    } else {
+        return Err(Error::InsufficientCapacity {
+            have: input_capacity,
+            need: capacity_of(&rest_output) + fee,
+        });
+    }
+
+    let cell_refs: Vec<&LiveCell> = input_cells.iter().collect();
+    let tx = TransactionView::new_advanced_builder()
+        .inputs(input_cells.iter().map(build_cell_input))
+        .outputs(outputs)
+        .outputs_data(outputs_data)
+        .witnesses(signing::build_witnesses(&cell_refs))
+        .cell_deps(cell_deps.transfer(client, &swept_tokens)?)
         .build();
+    Ok(TransferPlan {
+        tx,
+        input_cells,
+        fee,
+        dust_change: DustChange::None,
+        tokens: tokens.to_vec(),
+    })
+}
 
-    // Sign and send
-    let tx = sign_transaction(tx, from_private_key);
+/// Rebuild a pending transaction of the sender with a fee high enough to replace it in
+/// the pool: at least `fee_rate` shannons per 1000 bytes and the node's minimum
+/// replacement fee. Inputs that are still live are reused and the increase comes out
+/// of the sender's CKB change, topped up from other CKB cells of the sender, not
+/// reserved in `locker` by other runs, when the change can't cover it or a CKB input
+/// has been spent elsewhere.
+#[instrument(skip(client, locker, from_private_key, tokens))]
+fn build_fee_bump(
+    client: &RetryingClient,
+    locker: &CellLocker,
+    from_private_key: &str,
+    tx_hash: &H256,
+    tokens: &[Token],
+    fee_rate: u64,
+) -> Result<TransferPlan> {
+    let response = rpc::block_on(client.get_transaction(tx_hash.clone()))
+        .map_err(Error::rpc("Failed to get transaction"))?
+        .ok_or_else(|| Error::NotReplaceable(format!("Transaction {:#x} is unknown", tx_hash)))?;
+    let min_replace_fee = match (&response.tx_status.status, response.min_replace_fee) {
+        (Status::Pending, Some(fee)) => fee.value(),
+        (Status::Pending, None) => {
+            return Err(Error::NotReplaceable(format!(
+                "Transaction {:#x} can't be replaced, the node may have replace-by-fee disabled",
+                tx_hash
+            )));
+        }
+        (status, _) => {
+            return Err(Error::NotReplaceable(format!(
+                "Transaction {:#x} is {:?}, only pending transactions can be replaced",
+                tx_hash, status
+            )));
+        }
+    };
+    let original = match response.transaction.map(|tx| tx.inner) {
+        Some(Either::Left(tx)) => ckb_types::packed::Transaction::from(tx.inner).into_view(),
+        _ => {
+            return Err(Error::NotReplaceable(format!(
+                "Node returned no body for transaction {:#x}",
+                tx_hash
+            )));
+        }
+    };
 
-    let tx_hash = client
-        .send_transaction(tx.data().into(), None)
-        .expect("Failed to send combined transfer transaction");
+    // Reuse the inputs that are still live
+    let sender_lock = get_lock_script_from_private_key(from_private_key)?;
+    let mut input_cells = Vec::new();
+    for input in original.inputs() {
+        let out_point: OutPoint = input.previous_output().into();
+        let cell = rpc::block_on(client.get_live_cell(out_point.clone(), true))
+            .map_err(Error::rpc("Failed to get live cell"))?;
+        match cell.cell {
+            Some(info) if cell.status == "live" => input_cells.push(LiveCell {
+                out_point,
+                output: info.output,
+                output_data: info.data.map(|data| data.content).unwrap_or_default(),
+            }),
+            _ => {
+                // The spent cell is gone, so its assets can't be told apart; only plain
+                // CKB can be topped up from other cells
+                if original.outputs().into_iter().any(|o| o.type_().is_some()) {
+                    return Err(Error::NotReplaceable(format!(
+                        "Input {:#x}:{} of transaction {:#x} was spent elsewhere, run the transfer again",
+                        out_point.tx_hash,
+                        out_point.index.value(),
+                        tx_hash
+                    )));
+                }
+                warn!(
+                    "Input {:#x}:{} was spent elsewhere, replacing it",
+                    out_point.tx_hash,
+                    out_point.index.value()
+                );
+            }
+        }
+    }
 
-    println!(
-        "Combined CKB and sUDT transfer transaction sent: {:#x}",
-        tx_hash
-    );
-    tx_hash
+    // Keep every output except the sender's plain CKB change, which is recomputed
+    let outputs: Vec<_> = original
+        .outputs_with_data_iter()
+        .filter(|(output, data)| {
+            !(Script::from(output.lock()) == sender_lock
+                && output.type_().is_none()
+                && data.is_empty())
+        })
+        .collect();
+    let output_capacity = total_capacity(
+        outputs
+            .iter()
+            .map(|(output, _)| Unpack::<u64>::unpack(&output.capacity())),
+    )?;
+    let change_output = min_ckb_output(build_packed_lock_script(from_private_key)?)?;
+    let min_change_capacity = capacity_of(&change_output);
+
+    let mut spare_cells = None;
+    let mut fee = min_replace_fee;
+    loop {
+        let capacity_needed = total_capacity([output_capacity, fee, min_change_capacity])?;
+        let mut input_capacity =
+            total_capacity(input_cells.iter().map(|cell| cell.output.capacity.into()))?;
+        if input_capacity < capacity_needed {
+            let spare = match &mut spare_cells {
+                Some(spare) => spare,
+                None => {
+                    let reserved = locker.reservations()?;
+                    spare_cells.insert(
+                        find_ckb_cells(client, &sender_lock)?
+                            .into_iter()
+                            .filter(|cell| {
+                                !reserved.contains(&cell.out_point)
+                                    && !input_cells.iter().any(|c| c.out_point == cell.out_point)
+                            })
+                            .collect::<Vec<_>>()
+                            .into_iter(),
+                    )
+                }
+            };
+            while input_capacity < capacity_needed {
+                let Some(cell) = spare.next() else {
+                    return Err(Error::InsufficientCapacity {
+                        have: input_capacity,
+                        need: capacity_needed,
+                    });
+                };
+                input_capacity = total_capacity([input_capacity, cell.output.capacity.into()])?;
+                input_cells.push(cell);
+            }
+        }
+
+        let change_capacity =
+            capacity_left(input_capacity, total_capacity([output_capacity, fee])?)?;
+        let change = (change_capacity >= min_change_capacity).then(|| {
+            change_output
+                .clone()
+                .as_builder()
+                .capacity(ckb_types::core::Capacity::shannons(change_capacity).pack())
+                .build()
+        });
+        let tx = replacement_tx(&original, &input_cells, &outputs, change);
+
+        let total_output = total_capacity(
+            tx.outputs()
+                .into_iter()
+                .map(|output| Unpack::<u64>::unpack(&output.capacity())),
+        )?;
+        let plan = TransferPlan {
+            tx,
+            input_cells: input_cells.clone(),
+            fee: capacity_left(input_capacity, total_output)?,
+            dust_change: DustChange::None,
+            tokens: tokens.to_vec(),
+        };
+        let required_fee = min_fee(&plan.tx, fee_rate).max(min_replace_fee);
+        if plan.fee >= required_fee {
+            info!(
+                "Replacing {:#x}: fee {} shannons, at least {} required to replace it",
+                tx_hash, plan.fee, min_replace_fee
+            );
+            return Ok(plan);
+        }
+        fee = required_fee;
+    }
 }
 
-fn main() {
-    let client = CkbRpcClient::new(CKB_RPC_URL);
-
-    println!("=== Fiber Demo Startup: Transfer CKB and sUDT to nodes ===\n");
-
-    // Read private keys from files
-    let source_key = SOURCE_PRIVATE_KEY.to_string();
-    let bootnode_key = read_private_key(BOOTNODE_KEY_FILE);
-    let node1_key = read_private_key(NODE1_KEY_FILE);
-    let node2_key = read_private_key(NODE2_KEY_FILE);
-    let node3_key = read_private_key(NODE3_KEY_FILE);
-
-    println!("Loaded private keys from files:");
-    println!("  Source: {}", source_key);
-    println!("  Bootnode:  {}", BOOTNODE_KEY_FILE);
-    println!("  Node1:  {}", NODE1_KEY_FILE);
-    println!("  Node2:  {}", NODE2_KEY_FILE);
-    println!("  Node3:  {}", NODE3_KEY_FILE);
-    println!();
+/// The unsigned replacement of `original` spending `input_cells` into `outputs` and
+/// `change`. Inputs shared with the original keep their since and added ones take the
+/// since of its first input, so a timelocked transfer stays timelocked. Witnesses the
+/// original carries after its input witnesses, like a cobuild message, follow the
+/// witnesses of the new lock groups.
+fn replacement_tx(
+    original: &TransactionView,
+    input_cells: &[LiveCell],
+    outputs: &[(ckb_types::packed::CellOutput, ckb_types::bytes::Bytes)],
+    change: Option<ckb_types::packed::CellOutput>,
+) -> TransactionView {
+    let default_since: u64 = original
+        .inputs()
+        .get(0)
+        .map(|input| input.since().unpack())
+        .unwrap_or(0);
+    let mut tx_builder = TransactionView::new_advanced_builder()
+        .cell_deps(original.cell_deps())
+        .header_deps(original.header_deps());
+    for cell in input_cells {
+        let input = build_cell_input(cell);
+        let since = original
+            .inputs()
+            .into_iter()
+            .find(|reused| {
+                reused.previous_output().as_slice() == input.previous_output().as_slice()
+            })
+            .map_or(default_since, |reused| reused.since().unpack());
+        tx_builder = tx_builder.input(input.as_builder().since(since).build());
+    }
+    for (output, data) in outputs {
+        tx_builder = tx_builder.output(output.clone()).output_data(data.pack());
+    }
+    if let Some(change) = change {
+        tx_builder = tx_builder
+            .output(change)
+            .output_data(ckb_types::packed::Bytes::default());
+    }
+    let cell_refs: Vec<&LiveCell> = input_cells.iter().collect();
+    let trailing = original
+        .witnesses()
+        .into_iter()
+        .skip(original.inputs().len());
+    for witness in signing::build_witnesses(&cell_refs)
+        .into_iter()
+        .chain(trailing)
+    {
+        tx_builder = tx_builder.witness(witness);
+    }
+    tx_builder.build()
+}
 
-    // Print recipient addresses
-    println!("Target accounts:");
-    for (name, key) in [
-        ("Bootnode", &bootnode_key),
-        ("Node1", &node1_key),
-        ("Node2", &node2_key),
-        ("Node3", &node3_key),
-    ] {
-        let lock_script = get_lock_script_from_private_key(key);
-        println!(
-            "  {}: args = 0x{}",
-            name,
-            hex::encode(lock_script.args.as_bytes())
+/// Outcome of `send_transfer`
+enum Sent {
+    Broadcast(TransactionView),
+    /// The signed transaction is larger than planned and its fee falls short of the
+    /// fee rate at that size, so it was not broadcast. The plan is to be rebuilt at
+    /// this rate, which pays the shortfall at the planned size.
+    Underpaid(u64),
+}
+
+/// Sign a planned transfer with the sender's key, on the sender's Ledger or by the
+/// remote signer, and broadcast it once its fee is checked against `fee_rate` at the
+/// signed size: a signer may add witness data the placeholders didn't account for
+#[instrument(skip_all)]
+fn send_transfer(
+    client: &RetryingClient,
+    from_private_key: &str,
+    plan: &TransferPlan,
+    fee_rate: u64,
+) -> Result<Sent> {
+    let input_cells: Vec<&LiveCell> = plan.input_cells.iter().collect();
+    let tx = if ledger::is_ledger(from_private_key) {
+        ledger::sign_transaction(client, from_private_key, plan.tx.clone(), &input_cells)?
+    } else if remote_signer::is_remote(from_private_key) {
+        remote_signer::sign_transaction(from_private_key, plan.tx.clone(), &input_cells)?
+    } else {
+        signing::sign_transaction(
+            plan.tx.clone(),
+            &Signers::new(&[from_private_key])?,
+            &input_cells,
+        )?
+    };
+
+    let required_fee = min_fee(&tx, fee_rate);
+    if plan.fee < required_fee {
+        let planned_size = plan.tx.data().serialized_size_in_block() as u64;
+        let signed_size = tx.data().serialized_size_in_block() as u64;
+        let rate = (required_fee * 1000).div_ceil(planned_size);
+        warn!(
+            "Signed transaction is {} bytes, {} more than planned, and its fee of {} \
+             shannons is short of {} shannons; rebuilding it at {} shannons/KB",
+            signed_size,
+            signed_size.saturating_sub(planned_size),
+            plan.fee,
+            required_fee,
+            rate
         );
+        return Ok(Sent::Underpaid(rate));
     }
-    println!();
 
-    // Check source account balance
-    let ckb_cells = find_ckb_cells(&client, &source_key);
-    let total_ckb: u64 = ckb_cells.iter().map(|c| u64::from(c.output.capacity)).sum();
-    println!("Source CKB balance: {} CKB", total_ckb / 100000000);
+    let mut fields = audit::transaction_fields(&tx);
+    fields["fee"] = plan.fee.into();
+    audit::record("transaction_built", fields);
+    let tx_hash = rpc::block_on(client.send_transaction(tx.data().into(), None))
+        .map_err(Error::rpc("Failed to send transfer transaction"))?;
 
-    let sudt_cells = find_sudt_cells(&client, &source_key);
-    let total_sudt: u128 = sudt_cells
-        .iter()
-        .map(|c| parse_sudt_amount(c.output_data.as_bytes()))
-        .sum();
-    println!("Source sUDT balance: {}", total_sudt);
+    info!("Transfer transaction sent: {:#x}", tx_hash);
+    // Change goes back to the lock of the inputs, there is none to tell without them
+    if let Some(sender) = plan.input_cells.first() {
+        transfer_csv::record(&tx, &sender.output.lock);
+    }
+    Ok(Sent::Broadcast(tx))
+}
+
+/// Print a planned transaction as pretty JSON followed by a summary of its inputs,
+/// outputs (change being the outputs back to the sender) and fee
+fn print_plan(plan: &TransferPlan) {
+    let tx_json = ckb_jsonrpc_types::TransactionView::from(plan.tx.clone());
+    println!("{}", serde_json::to_string_pretty(&tx_json).unwrap());
     println!();
+    print_plan_summary(plan);
+}
 
-    // Combined transfer: CKB to 4 nodes, sUDT to 3 nodes (excluding bootnode)
+fn print_plan_summary(plan: &TransferPlan) {
+    // Change goes back to the lock of the inputs, an empty plan has none
+    let sender_lock = plan.input_cells.first().map(|cell| &cell.output.lock);
+    let input_capacity: u64 = plan
+        .input_cells
+        .iter()
+        .map(|cell| u64::from(cell.output.capacity))
+        .sum();
+    println!(
+        "Inputs: {} cell(s), {} CKB",
+        plan.input_cells.len(),
+        format_amount(input_capacity.into(), CKB_DECIMALS)
+    );
+    let since = plan
+        .tx
+        .inputs()
+        .into_iter()
+        .next()
+        .map(|input| input.since().unpack());
+    if let Some(since) = since.filter(|since: &u64| *since != 0) {
+        println!(
+            "Spendable from: {}",
+            since::describe(Since::from_raw_value(since))
+        );
+    }
+    if cobuild::is_attached(&plan.tx) {
+        println!("Witness layout: cobuild message attached");
+    }
+    println!("Outputs:");
+    let mut token_types: Vec<PackedScript> = Vec::new();
+    for output in plan.tx.outputs() {
+        if let Some(type_script) = output.type_().to_opt()
+            && !token_types.contains(&type_script)
+        {
+            token_types.push(type_script);
+        }
+    }
+    // Amounts of the plan's tokens in their human units, of other UDTs in base units
+    let format_token = |type_script: &PackedScript, amount: u128| {
+        let script = Script::from(type_script.clone());
+        match plan
+            .tokens
+            .iter()
+            .find(|token| get_sudt_type_script(&token.script) == script)
+        {
+            Some(token) => format!("{} {}", format_amount(amount, token.decimals), token.name),
+            None => format!(
+                "{} base units of UDT 0x{}",
+                amount,
+                hex::encode(script.args.as_bytes())
+            ),
+        }
+    };
+    let mut total_ckb_sent: u64 = 0;
+    let mut total_tokens_sent: Vec<u128> = vec![0; token_types.len()];
+    for (index, (output, data)) in plan.tx.outputs_with_data_iter().enumerate() {
+        let lock: Script = output.lock().into();
+        let capacity: u64 = output.capacity().unpack();
+        let token = output
+            .type_()
+            .to_opt()
+            .map(|type_script| (type_script, parse_sudt_amount(&data)));
+        let is_change = sender_lock == Some(&lock);
+        if !is_change {
+            total_ckb_sent += capacity;
+            if let Some((type_script, amount)) = &token
+                && let Some(position) = token_types.iter().position(|t| t == type_script)
+            {
+                total_tokens_sent[position] += amount;
+            }
+        }
+        println!(
+            "  #{}: args = 0x{}, {} CKB{}{}",
+            index,
+            hex::encode(lock.args.as_bytes()),
+            format_amount(capacity.into(), CKB_DECIMALS),
+            token
+                .map(|(type_script, amount)| format!(", {}", format_token(&type_script, amount)))
+                .unwrap_or_default(),
+            if is_change { " (change)" } else { "" }
+        );
+    }
     println!(
-        "Transferring {} CKB to each node and {} sUDT to node1/node2/node3 in a single transaction...",
-        CKB_TRANSFER_AMOUNT / 100000000,
-        SUDT_TRANSFER_AMOUNT
+        "Total sent: {} CKB{}",
+        format_amount(total_ckb_sent.into(), CKB_DECIMALS),
+        token_types
+            .iter()
+            .zip(&total_tokens_sent)
+            .map(|(type_script, amount)| format!(", {}", format_token(type_script, *amount)))
+            .collect::<String>()
     );
+    println!("Fee: {} CKB", format_amount(plan.fee.into(), CKB_DECIMALS));
+}
+
+/// Ask `question` on the terminal, true when it is answered yes. A closed stdin
+/// answers no.
+fn ask_yes(question: &str) -> Result<bool> {
+    print!("{}", question);
+    io::stdout()
+        .flush()
+        .map_err(Error::io("Failed to write the question to stdout"))?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(Error::io("Failed to read the answer from stdin"))?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Show the plan and ask for confirmation on the terminal, failing with
+/// `Error::Declined` when it is declined or when there is no terminal to ask on
+fn confirm_plan(plan: &TransferPlan) -> Result<()> {
+    // Asked while a stage spinner may be running
+    progress::suspend(|| {
+        println!("\nTransfer plan:");
+        print_plan_summary(plan);
+        if !io::stdin().is_terminal() {
+            return Err(Error::Declined(
+                "Not broadcasting without confirmation: stdin is not a terminal, pass --yes"
+                    .to_string(),
+            ));
+        }
+        if !ask_yes("Broadcast this transaction? [y/N] ")? {
+            return Err(Error::Declined("Transfer declined".to_string()));
+        }
+        Ok(())
+    })
+}
+
+/// Build a plan without signing or sending it, print it and report whether it is
+/// feasible; an infeasible plan fails with the error that prevented it
+fn dry_run(build: impl FnOnce() -> Result<TransferPlan>) -> Result<()> {
+    let plan = build().inspect_err(|_| error!("Dry run: the plan is not feasible"))?;
+    print_plan(&plan);
+    println!("\nDry run: the plan is feasible, nothing was broadcast");
+    Ok(())
+}
 
-    let ckb_recipients: Vec<(&str, u64)> = vec![
-        (&bootnode_key, CKB_TRANSFER_AMOUNT),
-        (&node1_key, CKB_TRANSFER_AMOUNT),
-        (&node2_key, CKB_TRANSFER_AMOUNT),
-        (&node3_key, CKB_TRANSFER_AMOUNT),
-    ];
+/// Read a number from the given env var, falling back to `default`
+fn env_u64(name: &str, default: u64) -> Result<u64> {
+    Ok(rpc::env_parse(name)?.unwrap_or(default))
+}
 
-    let sudt_recipients: Vec<(&str, u128)> = vec![
-        (&node1_key, SUDT_TRANSFER_AMOUNT),
-        (&node2_key, SUDT_TRANSFER_AMOUNT),
-        (&node3_key, SUDT_TRANSFER_AMOUNT),
-    ];
+/// Get the transaction status and, if committed, the block number and hash. A rejected
+/// transaction is a status like any other, callers waiting for it raise the error.
+#[instrument(level = "debug", skip(client))]
+async fn get_tx_commitment(
+    client: &RetryingClient,
+    tx_hash: &H256,
+) -> Result<(Status, Option<(u64, H256)>)> {
+    let tx = client
+        .get_transaction(tx_hash.clone())
+        .await
+        .map_err(Error::rpc("Failed to get transaction"))?;
 
-    let tx_hash = transfer_ckb_and_sudt(&client, &source_key, &ckb_recipients, &sudt_recipients);
+    match tx {
+        Some(tx) => {
+            let status = tx.tx_status.status;
+            let block = match (tx.tx_status.block_number, tx.tx_status.block_hash) {
+                (Some(number), Some(hash)) if status == Status::Committed => {
+                    Some((number.value(), hash))
+                }
+                _ => None,
+            };
+            Ok((status, block))
+        }
+        None => Ok((Status::Unknown, None)),
+    }
+}
 
-    println!("\n=== All transfers complete in single transaction! ===");
-    println!("Transaction hash: {:#x}", tx_hash);
+/// The error of a rejected transaction, with the reason the node gives for it
+async fn rejected(client: &RetryingClient, tx_hash: &H256) -> Error {
+    let reason = match client.get_transaction(tx_hash.clone()).await {
+        Ok(Some(tx)) => tx.tx_status.reason.unwrap_or_default(),
+        Ok(None) => "no longer known to the node".to_string(),
+        Err(e) => format!("reason unavailable: {}", e),
+    };
+    Error::Rejected {
+        tx_hash: tx_hash.clone(),
+        reason,
+    }
+}
+
+async fn get_tip_block_number(client: &RetryingClient) -> Result<u64> {
+    Ok(client
+        .get_tip_block_number()
+        .await
+        .map_err(Error::rpc("Failed to get tip block number"))?
+        .into())
+}
+
+/// Wait until the indexer has caught up with the node tip, so cell queries see every
+/// committed cell, failing after INDEXER_SYNC_TIMEOUT_SECS. On a freshly started
+/// devnet the indexer lags behind and would report no cells at all.
+async fn wait_for_indexer(client: &RetryingClient) -> Result<()> {
+    let timeout =
+        rpc::env_secs("INDEXER_SYNC_TIMEOUT_SECS")?.unwrap_or(DEFAULT_INDEXER_SYNC_TIMEOUT);
+    let started = Instant::now();
+    let mut waiting = false;
+    loop {
+        let tip = get_tip_block_number(client).await?;
+        let indexed = client
+            .get_indexer_tip()
+            .await
+            .map_err(Error::rpc("Failed to get indexer tip"))?
+            .map(|tip| tip.block_number.value());
+        match indexed {
+            Some(indexed) if indexed >= tip => {
+                if waiting {
+                    info!("Indexer synced at block {}", indexed);
+                }
+                return Ok(());
+            }
+            _ if !waiting => {
+                info!(
+                    "Waiting for the indexer to catch up: indexed {}, node tip {}",
+                    indexed.map_or("nothing".to_string(), |n| n.to_string()),
+                    tip
+                );
+                waiting = true;
+            }
+            _ => debug!("Indexer at {:?}, node tip {}", indexed, tip),
+        }
+
+        if started.elapsed() >= timeout {
+            return Err(Error::Timeout(format!(
+                "Indexer did not catch up with the node tip {} within {}s (indexed: {:?})",
+                tip,
+                timeout.as_secs(),
+                indexed
+            )));
+        }
+        tokio::time::sleep(CONFIRMATION_POLL_INTERVAL).await;
+    }
+}
+
+/// Confirmation depth and per-transaction timeout used when waiting for commitment
+#[derive(Clone, Copy)]
+struct ConfirmationPolicy {
+    confirmations: u64,
+    timeout: Duration,
+    /// Mine a block per poll instead of waiting for a miner, on a dev chain
+    generate_blocks: bool,
+}
+
+impl ConfirmationPolicy {
+    /// Read the CONFIRMATIONS, CONFIRMATION_TIMEOUT_SECS and GENERATE_BLOCKS env vars
+    fn from_env() -> Result<Self> {
+        Ok(ConfirmationPolicy {
+            confirmations: env_u64("CONFIRMATIONS", DEFAULT_CONFIRMATIONS)?,
+            timeout: rpc::env_secs("CONFIRMATION_TIMEOUT_SECS")?
+                .unwrap_or(DEFAULT_CONFIRMATION_TIMEOUT),
+            generate_blocks: env::var("GENERATE_BLOCKS").is_ok_and(|v| v == "1"),
+        })
+    }
+
+    /// Wait for the next poll, mining a block first when the policy generates them.
+    /// With the CKB_WS_URL tip subscription that is as soon as a block arrives after
+    /// the `seen` headers, otherwise after CONFIRMATION_POLL_INTERVAL.
+    async fn next_block(&self, client: &RetryingClient, seen: u64) -> Result<()> {
+        if self.generate_blocks {
+            let block_hash = client
+                .generate_block()
+                .await
+                .map_err(Error::rpc("Failed to generate a block"))?;
+            debug!("Generated block {:#x}", block_hash);
+        }
+        let notified = match subscription::tip_events() {
+            Some(events) => events.wait_after(seen, MAX_TIP_WAIT).await,
+            None => false,
+        };
+        if !notified {
+            tokio::time::sleep(CONFIRMATION_POLL_INTERVAL).await;
+        }
+        Ok(())
+    }
+}
+
+/// Tip headers received by the CKB_WS_URL subscription so far, taken before polling so
+/// `ConfirmationPolicy::next_block` wakes up for any block arriving meanwhile
+fn tip_headers_seen() -> u64 {
+    subscription::tip_events().map_or(0, |events| events.received())
+}
+
+/// Poll the transaction status (pending -> proposed -> committed) until it is committed
+/// and buried under the policy's confirmations (at least 1, the committing block
+/// itself), failing once the policy's timeout or the overall deadline passes.
+/// Returns the number and hash of the committing block.
+async fn wait_for_committed(
+    client: &RetryingClient,
+    tx_hash: &H256,
+    policy: &ConfirmationPolicy,
+    deadline: &Deadline,
+) -> Result<(u64, H256)> {
+    let confirmations = policy.confirmations.max(1);
+    let started = Instant::now();
+    let mut last_status = None;
+    let mut last_reported = 0;
+    let hash = format!("{:#x}", tx_hash);
+    let progress = Progress::bar(confirmations, format!("Confirming {}", &hash[..10]));
+    loop {
+        deadline.check("waiting for transaction confirmations")?;
+        let seen = tip_headers_seen();
+        let (status, block) = get_tx_commitment(client, tx_hash).await?;
+        if last_status.as_ref() != Some(&status) {
+            info!("Transaction {:#x} is {:?}", tx_hash, status);
+            progress.set_message(format!("Confirming {} ({:?})", &hash[..10], status));
+            last_status = Some(status.clone());
+        }
+        if status == Status::Rejected {
+            return Err(rejected(client, tx_hash).await);
+        }
+        if let Some((block_number, block_hash)) = block {
+            let current = (get_tip_block_number(client).await? + 1).saturating_sub(block_number);
+            progress.set_position(current.min(confirmations));
+            if current >= confirmations {
+                info!(
+                    "Transaction {:#x} committed in block {} with {} confirmation(s)",
+                    tx_hash, block_number, current
+                );
+                metrics::observe_confirmation(started.elapsed());
+                audit::record(
+                    "confirmed",
+                    json!({
+                        "tx_hash": format!("{:#x}", tx_hash),
+                        "block_number": block_number,
+                        "block_hash": format!("{:#x}", block_hash),
+                        "confirmations": current,
+                    }),
+                );
+                return Ok((block_number, block_hash));
+            }
+            if current != last_reported {
+                debug!("{}/{} confirmations", current, confirmations);
+                last_reported = current;
+            }
+        }
+
+        if started.elapsed() >= policy.timeout {
+            let hint = if status == Status::Pending {
+                format!(", run bump-fee --tx {:#x} to raise its fee", tx_hash)
+            } else {
+                String::new()
+            };
+            audit::record(
+                "confirmation_timeout",
+                json!({
+                    "tx_hash": format!("{:#x}", tx_hash),
+                    "status": format!("{:?}", status),
+                    "confirmations": confirmations,
+                }),
+            );
+            return Err(Error::Timeout(format!(
+                "Transaction {:#x} did not reach {} confirmation(s) within {}s (status: {:?}){}",
+                tx_hash,
+                confirmations,
+                policy.timeout.as_secs(),
+                status,
+                hint
+            )));
+        }
+        policy.next_block(client, seen).await?;
+    }
+}
+
+/// Keep watching a committed transaction until the tip is `window` blocks past its
+/// committing block. If a reorg moves it out of the canonical chain, wait for it to be
+/// re-committed, resubmitting it when the node no longer has it in the pool.
+/// Returns the number of the block the transaction finally settled in.
+async fn watch_for_reorg(
+    client: &RetryingClient,
+    tx: &TransactionView,
+    policy: &ConfirmationPolicy,
+    window: u64,
+    deadline: &Deadline,
+) -> Result<u64> {
+    let tx_hash: H256 = tx.hash().unpack();
+    let (mut committed_block, mut committed_hash) =
+        wait_for_committed(client, &tx_hash, policy, deadline).await?;
+
+    loop {
+        let seen = tip_headers_seen();
+        if get_tip_block_number(client).await? >= committed_block + window {
+            return Ok(committed_block);
+        }
+        policy.next_block(client, seen).await?;
+        deadline.check("watching the transaction for reorgs")?;
+
+        match get_tx_commitment(client, &tx_hash).await? {
+            (_, Some((_, block_hash))) if block_hash == committed_hash => {}
+            (_, Some((block_number, block_hash))) => {
+                warn!(
+                    "Reorg detected: transaction {:#x} moved from block {} to block {} ({:#x})",
+                    tx_hash, committed_block, block_number, block_hash
+                );
+                committed_block = block_number;
+                committed_hash = block_hash;
+            }
+            (Status::Rejected, None) => return Err(rejected(client, &tx_hash).await),
+            (status, None) => {
+                warn!(
+                    "Reorg detected: transaction {:#x} dropped from block {} (status: {:?})",
+                    tx_hash, committed_block, status
+                );
+                if status == Status::Unknown {
+                    info!("Resubmitting transaction {:#x}", tx_hash);
+                    client
+                        .send_transaction(tx.data().into(), None)
+                        .await
+                        .map_err(Error::rpc("Failed to resubmit transaction after reorg"))?;
+                }
+                (committed_block, committed_hash) =
+                    wait_for_committed(client, &tx_hash, policy, deadline).await?;
+            }
+        }
+    }
+}
+
+/// Cross-check the settled transaction and account balances against the explorer,
+/// warning about every place where the local node's view diverges from it
+fn verify_with_explorer(
+    client: &RetryingClient,
+    explorer: &ExplorerClient,
+    tx_hash: &H256,
+    block_number: u64,
+    accounts: &[(&str, &Script)], // (name, lock_script)
+    network: NetworkType,
+) -> Result<()> {
+    info!("Verifying results against explorer...");
+
+    match explorer.wait_for_transaction(tx_hash) {
+        Some(tx) if tx.block_number == block_number.to_string() => {
+            info!(
+                "Explorer: transaction {:#x} committed in block {}",
+                tx_hash, block_number
+            );
+        }
+        Some(tx) => warn!(
+            "Explorer reports transaction {:#x} in block {}, local node in block {}",
+            tx_hash, tx.block_number, block_number
+        ),
+        None => warn!(
+            "Explorer has not indexed transaction {:#x} as committed",
+            tx_hash
+        ),
+    }
+
+    for (name, lock_script) in accounts {
+        let address = Address::new(
+            network,
+            AddressPayload::from(PackedScript::from((*lock_script).clone())),
+            true,
+        )
+        .to_string();
+        let local_balance = get_total_capacity(client, lock_script)?;
+
+        match explorer.get_address(&address) {
+            Ok(Some(attributes)) if attributes.balance == local_balance.to_string() => {
+                info!(
+                    "Explorer: {} balance matches, {} shannons",
+                    name, local_balance
+                );
+            }
+            Ok(Some(attributes)) => warn!(
+                "{} balance differs, explorer: {} shannons, local: {} shannons",
+                name, attributes.balance, local_balance
+            ),
+            Ok(None) => warn!("Explorer does not know address {}", address),
+            Err(e) => warn!("Failed to query {} from explorer: {}", name, e),
+        }
+    }
+    Ok(())
+}
+
+/// Fund each node in its own transaction, waiting for it to commit before the next
+/// one. A failure is recorded in the report and the remaining nodes are still funded.
+/// Each transaction is recorded in `state` as soon as it is sent. Declining a node's
+/// confirmation prompt counts as a failure. Returns the names of the nodes that need a
+/// retry.
+#[allow(clippy::too_many_arguments)]
+fn fund_nodes_individually(
+    client: &RetryingClient,
+    locker: &CellLocker,
+    cell_deps: &CellDepResolver,
+    selector: &dyn CoinSelector,
+    source_key: &str,
+    tokens: &[Token],
+    nodes: &[FundedNode],
+    fee_rate: u64,
+    assume_yes: bool,
+    policy: &ConfirmationPolicy,
+    deadline: &Deadline,
+    explorer: Option<&Explorer>,
+    state: &mut RunState,
+    report: &mut Report,
+) -> Vec<String> {
+    let mut failed = Vec::new();
+    let progress = Progress::bar(nodes.len() as u64, "Funding nodes");
+    for node in nodes {
+        let name = node.name;
+        let token_transfers = token_transfers(tokens, std::slice::from_ref(node));
+        progress.set_message(format!("Funding {}", name));
+
+        let result = (|| {
+            info!("Funding {}...", name);
+            // Inputs and change are recomputed from the current chain state
+            let tx = transfer_ckb_and_sudt(
+                client,
+                locker,
+                cell_deps,
+                selector,
+                source_key,
+                &[(node.lock_script.clone(), node.ckb_amount)],
+                &token_transfers,
+                fee_rate,
+                None,
+                false,
+                assume_yes,
+            )?;
+            let tx_hash: H256 = tx.hash().unpack();
+            state.record(tx_hash.clone(), "setup (per node)", vec![name.to_string()])?;
+            rpc::block_on(wait_for_committed(client, &tx_hash, policy, deadline))?;
+            Ok::<_, Error>(tx_hash)
+        })();
+
+        match result {
+            Ok(tx_hash) => {
+                if let Some(explorer) = explorer {
+                    info!("Funded {}: {}", name, explorer.transaction(&tx_hash));
+                }
+                report.add_funding(funding_row(tokens, node));
+                report.add_outcome(name, Ok(tx_hash));
+            }
+            Err(e) => {
+                error!("Funding {} failed: {}", name, e);
+                report.add_outcome(name, Err(e.to_string()));
+                failed.push(name.to_string());
+            }
+        }
+        progress.inc();
+    }
+    failed
+}
+
+/// A node funded by `setup`
+struct FundedNode<'a> {
+    name: &'a str,
+    lock_script: Script,
+    /// Lock of the node's token cells: its own lock, or the anyone-can-pay lock with
+    /// `lock = "acp"`
+    token_lock_script: Script,
+    /// In shannons
+    ckb_amount: u64,
+    /// Base units, in `tokens` order
+    token_amounts: Vec<u128>,
+}
+
+/// The token outputs funding `nodes`, one transfer per token in `tokens` order
+fn token_transfers<'a>(tokens: &'a [Token], nodes: &[FundedNode]) -> Vec<TokenTransfer<'a>> {
+    tokens
+        .iter()
+        .enumerate()
+        .map(|(index, token)| TokenTransfer {
+            token,
+            recipients: nodes
+                .iter()
+                .filter(|node| node.token_amounts[index] > 0)
+                .map(|node| (node.token_lock_script.clone(), node.token_amounts[index]))
+                .collect(),
+            issued: 0,
+            burned: 0,
+        })
+        .collect()
+}
+
+/// The report row of a funded node
+fn funding_row(tokens: &[Token], node: &FundedNode) -> FundingRow {
+    FundingRow {
+        name: node.name.to_string(),
+        lock_args: format!("0x{}", hex::encode(node.lock_script.args.as_bytes())),
+        ckb_amount: node.ckb_amount,
+        token_amounts: tokens
+            .iter()
+            .zip(&node.token_amounts)
+            .filter(|(_, amount)| **amount > 0)
+            .map(|(token, amount)| {
+                format!("{} {}", format_amount(*amount, token.decimals), token.name)
+            })
+            .collect(),
+    }
+}
+
+/// The anyone-can-pay lock of an account with a sighash `lock_script`, so later
+/// payments can top up its cells without creating new ones
+fn build_acp_lock(preset: &NetworkPreset, name: &str, lock_script: &Script) -> Result<Script> {
+    let acp = preset.acp.as_ref().ok_or_else(|| {
+        Error::InvalidAddress(format!(
+            "{} asks for an anyone-can-pay lock, but none is deployed on {}; set acp.code_hash",
+            name, preset.name
+        ))
+    })?;
+    if lock_script.code_hash != SIGHASH_TYPE_HASH || lock_script.hash_type != ScriptHashType::Type {
+        return Err(Error::InvalidAddress(format!(
+            "The anyone-can-pay lock of {} needs a sighash account",
+            name
+        )));
+    }
+    Ok(Script {
+        code_hash: acp.code_hash.clone(),
+        hash_type: acp.hash_type,
+        args: lock_script.args.clone(),
+    })
+}
+
+fn main() {
+    let cli = Cli::parse();
+    logging::init(cli.verbose as i8 - cli.quiet as i8, cli.log_format);
+    progress::init(cli.log_format);
+    let result = rpc::runtime().and_then(|runtime| {
+        let _runtime = runtime.enter();
+        run_command(&cli)
+    });
+    if let Err(e) = result {
+        error!("{}", e);
+        if let Some(hint) = e.hint() {
+            info!("{}", hint);
+        }
+        if let Some(command) = transfer_command(&cli.command) {
+            webhook::transfer_failed(command, &e.to_string());
+        }
+        process::exit(e.exit_code());
+    }
+}
+
+/// Load the config, apply the global options and run the command
+fn run_command(cli: &Cli) -> Result<()> {
+    let mut config = Config::load(&cli.config)?;
+    if let Some(network) = &cli.network {
+        config.network = network.clone();
+    }
+    if let Some(rpc_url) = &cli.rpc_url {
+        config.ckb_rpc_url = Some(rpc_url.clone());
+    }
+    if let Some(fee_rate) = cli.fee_rate {
+        config.fee_rate = fee_rate;
+    }
+    if transfer_csv::enabled()
+        && let Ok(preset) = config.network_preset()
+        && let Ok(tokens) = config.tokens(&preset)
+    {
+        transfer_csv::set_tokens(&tokens);
+    }
+    let setup_args = match &cli.command {
+        Command::Setup(args) => Some(args),
+        Command::Chain(ChainArgs {
+            command: ChainCommand::Up(args),
+        }) => Some(&args.setup),
+        Command::Up(args) => Some(&args.setup),
+        _ => None,
+    };
+    if let Some(path) = setup_args.and_then(|args| args.recipients_file.as_ref()) {
+        config.nodes = config::load_recipients(path)?;
+    }
+    if let Some(rate) = setup_args.and_then(|args| args.funding_fee_rate) {
+        config.funding_fee_rate = Some(rate);
+    }
+    config
+        .limits()?
+        .check_fee_rate(&config.network, config.fee_rate)?;
+    if cli.generate_missing_keys {
+        generate_missing_keys(&config.nodes)?;
+    }
+    // A source key given by derivation path or key file is only resolved for the
+    // commands that sign or look up its cells, so the others need no mnemonic or
+    // keystore password
+    if uses_source_key(&cli.command) {
+        config.source_private_key = resolve_private_key(&config.source_private_key)?;
+    }
+    let _block_producer = cli
+        .mine_interval_ms
+        .filter(|_| match &cli.command {
+            Command::Chain(_)
+            | Command::Reset(_)
+            | Command::Status(_)
+            | Command::Dashboard(_)
+            | Command::Addresses(_)
+            | Command::Snapshot(_)
+            | Command::Hd(_)
+            | Command::Keygen(_)
+            | Command::Keystore(_)
+            | Command::InspectKey(_) => false,
+            Command::Up(args) => !args.local_chain,
+            _ => true,
+        })
+        .map(|interval| -> Result<_> {
+            let preset = config.network_preset()?;
+            let rpc_url = config.ckb_rpc_url(&preset);
+            info!("Mining a block every {}ms at {}", interval, rpc_url);
+            chain::BlockProducer::start(
+                rpc_url,
+                &HttpSettings::from_env()?,
+                Duration::from_millis(interval),
+            )
+        })
+        .transpose()?;
+    match &cli.command {
+        Command::Up(args) => config
+            .select_nodes(&args.setup.nodes, &args.setup.skip_nodes)
+            .and_then(|()| up(&mut config, args)),
+        Command::Setup(args) => config
+            .select_nodes(&args.nodes, &args.skip_nodes)
+            .and_then(|()| setup(&config, args)),
+        Command::TransferCkb(args) => transfer_ckb(&config, args),
+        Command::TransferSudt(args) => transfer_sudt(&config, args),
+        Command::Balances(args) => balances(&config, args),
+        Command::BumpFee(args) => bump_fee(&config, args),
+        Command::IssueSudt(args) => issue_sudt(&config, args),
+        Command::BurnSudt(args) => burn_sudt(&config, args),
+        Command::Consolidate(args) => consolidate(&config, args),
+        Command::GenConfig(args) => gen_config(&config, args),
+        Command::WaitNodes(args) => config
+            .select_nodes(&args.nodes, &[])
+            .and_then(|()| wait_nodes(&config)),
+        Command::Status(args) => config
+            .select_nodes(&args.nodes, &[])
+            .and_then(|()| status(&config, args)),
+        Command::Dashboard(args) => dashboard(&config, args),
+        Command::Addresses(args) => addresses(&config, args),
+        Command::SmokeTest(args) => smoke_test(&config, args),
+        Command::Load(args) => load(&config, args),
+        Command::Scenario(args) => scenario(&config, args),
+        Command::Invoice(args) => match &args.command {
+            InvoiceCommand::New(args) => new_invoice(&config, args),
+        },
+        Command::Pay(args) => pay(&config, args),
+        Command::Channels(args) => config
+            .select_nodes(&args.nodes, &[])
+            .and_then(|()| channels(&config, args)),
+        Command::Shutdown(args) => config
+            .select_nodes(&args.nodes, &[])
+            .and_then(|()| shutdown(&config)),
+        Command::GenerateBlocks(args) => generate_blocks(&config, args),
+        Command::RunNodes(args) => config
+            .select_nodes(&args.nodes, &[])
+            .and_then(|()| run_nodes(&config, args)),
+        Command::Chain(args) => match &args.command {
+            ChainCommand::Up(args) => config
+                .select_nodes(&args.setup.nodes, &args.setup.skip_nodes)
+                .and_then(|()| chain_up(&mut config, args)),
+            ChainCommand::Spec(args) => chain_spec(args),
+        },
+        Command::Reset(args) => reset(&config, args),
+        Command::Snapshot(SnapshotArgs { command }) => match command {
+            SnapshotCommand::Save(args) => snapshot_save(&config, args),
+            SnapshotCommand::Restore(args) => snapshot_restore(&config, args),
+        },
+        Command::Hd(HdArgs { command }) => match command {
+            HdCommand::New(args) => hd_new(args),
+            HdCommand::Addresses(args) => hd_addresses(&config, args),
+            HdCommand::Export(args) => hd_export(args),
+        },
+        Command::Keygen(args) => keygen(&config, &cli.config, args),
+        Command::InspectKey(args) => inspect_key(&config, args),
+        Command::RotateKey(args) => rotate_key(&config, args),
+        Command::Keystore(KeystoreArgs { command }) => match command {
+            KeystoreCommand::Import(args) => keystore_import(&config, args),
+        },
+    }
+}
+
+/// The name of a command that moves funds, whose failures are sent to the webhooks
+fn transfer_command(command: &Command) -> Option<&'static str> {
+    Some(match command {
+        Command::TransferCkb(_) => "transfer-ckb",
+        Command::TransferSudt(_) => "transfer-sudt",
+        Command::BumpFee(_) => "bump-fee",
+        Command::IssueSudt(_) => "issue-sudt",
+        Command::BurnSudt(_) => "burn-sudt",
+        Command::Consolidate(_) => "consolidate",
+        Command::RotateKey(_) => "rotate-key",
+        _ => return None,
+    })
+}
+
+/// Whether a command signs with the source key or looks up its cells
+fn uses_source_key(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Up(_)
+            | Command::Setup(_)
+            | Command::TransferCkb(_)
+            | Command::TransferSudt(_)
+            | Command::Balances(_)
+            | Command::BumpFee(_)
+            | Command::IssueSudt(_)
+            | Command::BurnSudt(_)
+            | Command::Consolidate(_)
+            | Command::Status(_)
+            | Command::Dashboard(_)
+            | Command::Addresses(_)
+            | Command::Chain(ChainArgs {
+                command: ChainCommand::Up(_),
+            })
+    )
+}
+
+// Stages of `up` with their names in the report
+const UP_STAGES: [(UpStage, &str); 9] = [
+    (UpStage::Keys, "Generate keys"),
+    (UpStage::Chain, "Start CKB"),
+    (UpStage::Indexer, "Wait for the indexer"),
+    (UpStage::Sudt, "Issue sUDT"),
+    (UpStage::Fund, "Fund nodes"),
+    (UpStage::Nodes, "Start Fiber nodes"),
+    (UpStage::Peers, "Connect peers"),
+    (UpStage::Channels, "Open channels"),
+    (UpStage::SmokeTest, "Smoke test"),
+];
+
+/// `up`: bootstrap the whole demo stage by stage, reported like `setup`, then keep the
+/// chain and Fiber nodes it started running until interrupted
+fn up(config: &mut Config, args: &UpArgs) -> Result<()> {
+    metrics::serve_from_env();
+    let fnn_settings = fnn_settings(&args.fnn);
+    // Declared first so it is dropped last, after the Fiber nodes using it
+    let mut chain = None;
+    let mut fiber_nodes = None;
+    let mut report = Report::new();
+    let result = run_up_stages(
+        config,
+        args,
+        &fnn_settings,
+        &mut chain,
+        &mut fiber_nodes,
+        &mut report,
+    );
+    if let Err(e) = &result {
+        report.set_error(&e.to_string());
+    }
+    let written = write_report(&report);
+    webhook::bootstrap_done("up", &report, matches!(result, Err(Error::Timeout(_))));
+    result?;
+    written?;
+
+    if chain.is_none() && fiber_nodes.is_none() {
+        info!("Demo is up");
+        return Ok(());
+    }
+    info!("Demo is up, interrupt to stop it");
+    supervisor::handle_interrupts();
+    sampling_metrics(config, || {
+        while !supervisor::interrupted() {
+            if let Some(chain) = &mut chain {
+                chain.check()?;
+            }
+            if let Some(fiber_nodes) = &mut fiber_nodes {
+                fiber_nodes.poll()?;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+        info!("Interrupted, stopping the demo");
+        Ok(())
+    })
+}
+
+fn run_up_stages<'a>(
+    config: &mut Config,
+    args: &UpArgs,
+    fnn_settings: &'a supervisor::FnnSettings<'a>,
+    chain: &mut Option<chain::DevChain>,
+    fiber_nodes: &mut Option<supervisor::FiberNodes<'a>>,
+    report: &mut Report,
+) -> Result<()> {
+    let http_settings = HttpSettings::from_env()?;
+    let deadline = Deadline::from_env()?;
+    let preset = config.network_preset()?;
+    // Resolved up front so a mistake in the channels or the payment fails before any
+    // stage
+    let tokens = config.tokens(&preset)?;
+    let with_channels = !args.setup.skip_channels && !config.channels(&tokens)?.is_empty();
+    let with_smoke_test = with_channels && !args.setup.skip_smoke_test && config.smoke_test.enabled;
+    if with_smoke_test {
+        config.payment(&config.smoke_test, &tokens)?;
+    }
+
+    let mut stages = Stages::new(UP_STAGES.len());
+    for (stage, title) in UP_STAGES {
+        let step = stages.next(title);
+        // Processes this run needs can't be skipped
+        let starts_processes = stage == UpStage::Chain && args.local_chain
+            || stage == UpStage::Nodes && args.start_nodes;
+        if args.from_stage.is_some_and(|from| stage < from) && !starts_processes {
+            info!("{}: skipped", step);
+            continue;
+        }
+        let idle = match stage {
+            UpStage::Peers | UpStage::Channels => !with_channels,
+            UpStage::SmokeTest => !with_smoke_test,
+            _ => false,
+        };
+        if idle {
+            info!("{}: nothing to do", step);
+            continue;
+        }
+        info!("{}", step);
+        let _spinner = Progress::spinner(&step);
+        let result = match stage {
+            UpStage::Keys => report.stage(title, || generate_missing_keys(&config.nodes)),
+            UpStage::Chain => report.stage(title, || {
+                if args.local_chain {
+                    *chain = Some(start_chain(config, &args.chain)?);
+                    return Ok(());
+                }
+                let client = rpc::new_ckb_client(
+                    &config.ckb_rpc_urls(&preset),
+                    config.backend,
+                    &http_settings,
+                )?;
+                info!(
+                    "CKB node at {} is at block {}",
+                    client.url(),
+                    rpc::block_on(get_tip_block_number(&client))?
+                );
+                Ok(())
+            }),
+            UpStage::Indexer => report.stage(title, || connect(config, &http_settings).map(|_| ())),
+            UpStage::Sudt => report.stage(title, || issue_missing_sudt(config)),
+            // Funding records its own stages
+            UpStage::Fund => match run(config, &args.setup, report)? {
+                failed if failed.is_empty() => Ok(()),
+                failed => Err(Error::PartialFailure(failed)),
+            },
+            UpStage::Nodes => report.stage(title, || {
+                if args.start_nodes {
+                    *fiber_nodes = Some(start_fiber_nodes(config, &args.fnn, fnn_settings)?);
+                }
+                if config.nodes.iter().any(|node| node.fiber_rpc_url.is_some()) {
+                    wait_nodes(config)?;
+                }
+                Ok(())
+            }),
+            UpStage::Peers => report.stage(title, || {
+                fiber::connect_peers(&config.channels(&tokens)?, &http_settings, &deadline)
+            }),
+            UpStage::Channels => open_channels_stage(config, &config.channels(&tokens)?, report),
+            UpStage::SmokeTest => smoke_test_stage(
+                &config.payment(&config.smoke_test, &tokens)?,
+                &preset,
+                report,
+            ),
+        };
+        if let Err(e) = result {
+            if let Some(name) = stage.to_possible_value() {
+                info!("Resume with `up --from-stage {}`", name.get_name());
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Issue the preset's sUDT to the source account when it holds less than the nodes
+/// receive. Only the sUDT whose args are the source's lock hash can be issued.
+fn issue_missing_sudt(config: &Config) -> Result<()> {
+    let http_settings = HttpSettings::from_env()?;
+    let (preset, client) = connect(config, &http_settings)?;
+    let token = config.tokens(&preset)?.swap_remove(0);
+    let need = config
+        .nodes
+        .iter()
+        .map(|node| config.token_amount(node, &token))
+        .sum::<Result<u128>>()?;
+    let source_lock = get_lock_script_from_private_key(&config.source_private_key)?;
+    let have: u128 = rpc::block_on(find_sudt_cells(&client, &source_lock, &token.script))?
+        .iter()
+        .map(|c| parse_sudt_amount(c.output_data.as_bytes()))
+        .sum();
+    if have >= need {
+        info!(
+            "Source holds {} {}, enough for the nodes",
+            format_amount(have, token.decimals),
+            token.name
+        );
+        return Ok(());
+    }
+    let owner_args: H256 = build_packed_lock_script(&config.source_private_key)?
+        .calc_script_hash()
+        .unpack();
+    if token.script.args != owner_args.as_bytes() {
+        return Err(Error::InsufficientFunds {
+            asset: format!(
+                "{} held by the source account, which doesn't own the token to issue it",
+                token.name
+            ),
+            have,
+            need,
+        });
+    }
+    let amount = (need - have).max(config::DEFAULT_SUDT_SUPPLY);
+    issue_sudt(
+        config,
+        &IssueSudtArgs {
+            from: None,
+            to: None,
+            amount: Some(format_amount(amount, token.decimals)),
+            dry_run: false,
+            yes: true,
+        },
+    )
+}
+
+/// Fund the demo nodes, writing a report when REPORT_DIR is set. Nodes left unfunded
+/// in partial-success mode fail with `Error::PartialFailure`.
+fn setup(config: &Config, args: &SetupArgs) -> Result<()> {
+    let mut report = Report::new();
+    let result = run_setup_stages(config, args, &mut report);
+    if let Err(e) = &result {
+        report.set_error(&e.to_string());
+    }
+
+    let written = write_report(&report);
+    webhook::bootstrap_done("setup", &report, matches!(result, Err(Error::Timeout(_))));
+
+    let failed = result?;
+    written?;
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::PartialFailure(failed))
+    }
+}
+
+/// The stages of `setup`: funding, then the channels and the smoke test once every
+/// node is funded. Returns the names of the nodes left unfunded.
+fn run_setup_stages(config: &Config, args: &SetupArgs, report: &mut Report) -> Result<Vec<String>> {
+    let preset = config.network_preset()?;
+    let tokens = config.tokens(&preset)?;
+    // Resolved up front so a mistake in the channels fails before any funding
+    let channels = if args.skip_channels || args.dry_run {
+        Vec::new()
+    } else {
+        config.channels(&tokens)?
+    };
+    let payment = if channels.is_empty() || args.skip_smoke_test || !config.smoke_test.enabled {
+        None
+    } else {
+        Some(config.payment(&config.smoke_test, &tokens)?)
+    };
+    let failed = run(config, args, report)?;
+    if failed.is_empty() && !channels.is_empty() {
+        open_channels_stage(config, &channels, report)?;
+    }
+    if failed.is_empty()
+        && let Some(payment) = &payment
+    {
+        smoke_test_stage(payment, &preset, report)?;
+    }
+    Ok(failed)
+}
+
+/// Open the channels as a stage of the report, failing when any of them failed
+fn open_channels_stage(
+    config: &Config,
+    channels: &[fiber::ChannelPlan],
+    report: &mut Report,
+) -> Result<()> {
+    let rows = report.stage("Open channels", || open_channels(config, channels))?;
+    let failed_channels: Vec<String> = rows
+        .iter()
+        .filter(|row| row.status == ChannelStatus::Failed)
+        .map(|row| format!("{} -> {}", row.from, row.to))
+        .collect();
+    info!(
+        "Channels: {} of {} open",
+        rows.len() - failed_channels.len(),
+        rows.len()
+    );
+    for row in rows {
+        report.add_channel(row);
+    }
+    if !failed_channels.is_empty() {
+        return Err(Error::Fiber(format!(
+            "Failed to open channels {}",
+            failed_channels.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Make the smoke test payment as a stage of the report
+fn smoke_test_stage(
+    payment: &fiber::PaymentPlan,
+    preset: &NetworkPreset,
+    report: &mut Report,
+) -> Result<()> {
+    let outcome = report.stage("Smoke test", || {
+        fiber::smoke_test(
+            payment,
+            preset.fiber_currency,
+            &HttpSettings::from_env()?,
+            &Deadline::from_env()?,
+        )
+    });
+    report.set_smoke_test(
+        payment.format(),
+        outcome
+            .as_ref()
+            .map(Clone::clone)
+            .map_err(ToString::to_string),
+    );
+    outcome.map(|_| ())
+}
+
+/// Write report.md to REPORT_DIR, plus report.html with REPORT_HTML=1
+fn write_report(report: &Report) -> Result<()> {
+    if let Ok(dir) = env::var("REPORT_DIR") {
+        let html = env::var("REPORT_HTML").is_ok_and(|v| v == "1");
+        report.write(Path::new(&dir), html)?;
+    }
+    Ok(())
+}
+
+/// Open the configured channels, checking the CKB and UDT holdings of the funding
+/// nodes' sighash locks, which the Fiber nodes fund channels from
+fn open_channels(config: &Config, channels: &[fiber::ChannelPlan]) -> Result<Vec<ChannelRow>> {
+    let http_settings = HttpSettings::from_env()?;
+    let (preset, client) = connect(config, &http_settings)?;
+    let locks: Vec<(&str, Script)> = config
+        .nodes
+        .iter()
+        .map(|node| {
+            Ok((
+                node.name.as_str(),
+                resolve_lock_script(&node.account, preset.address_network)?,
+            ))
+        })
+        .collect::<Result<_>>()?;
+    let balance = |name: &str, token: Option<&Token>| {
+        let (_, lock_script) = locks
+            .iter()
+            .find(|(node, _)| *node == name)
+            .ok_or_else(|| {
+                Error::UnknownNode(format!("Channel node {} is not configured", name))
+            })?;
+        match token {
+            Some(token) => Ok(
+                rpc::block_on(find_sudt_cells(&client, lock_script, &token.script))?
+                    .iter()
+                    .map(|c| parse_sudt_amount(c.output_data.as_bytes()))
+                    .sum(),
+            ),
+            None => Ok(rpc::block_on(get_ckb_balance(&client, lock_script))?.into()),
+        }
+    };
+    fiber::open_channels(channels, &http_settings, &Deadline::from_env()?, balance)
+}
+
+/// Connect to the CKB node of the configured network and wait for its indexer to sync
+fn connect(
+    config: &Config,
+    http_settings: &HttpSettings,
+) -> Result<(NetworkPreset, RetryingClient)> {
+    let preset = config.network_preset()?;
+    let urls = config.ckb_rpc_urls(&preset);
+    info!("Network: {} ({})", preset.name, urls.join(", "));
+    let client = rpc::new_ckb_client(&urls, config.backend, http_settings)?;
+    rpc::block_on(wait_for_indexer(&client))?;
+    Ok((preset, client))
+}
+
+/// Resolve an account given as a CKB address, a hex private key or a key file path to
+/// its lock script. Addresses must belong to `network`.
+fn resolve_lock_script(account: &str, network: NetworkType) -> Result<Script> {
+    match Address::from_str(account) {
+        Ok(address) => {
+            // Devnet shares the testnet "ckt" prefix
+            if address.network().to_prefix() != network.to_prefix() {
+                return Err(Error::InvalidAddress(format!(
+                    "Address {} is not a {} address",
+                    account,
+                    network.to_prefix()
+                )));
+            }
+            Ok(PackedScript::from(&address).into())
+        }
+        Err(_) => get_lock_script_from_private_key(&resolve_private_key(account)?),
+    }
+}
+
+/// Resolve a key argument given as a hex private key, a derivation path from the
+/// mnemonic (e.g. `m/44'/309'/0'/0/1`) or the path of a key file or keystore. Ledger
+/// and remote accounts are kept as given, their keys never leave the device or service.
+fn resolve_private_key(arg: &str) -> Result<String> {
+    if ledger::is_ledger(arg) || remote_signer::is_remote(arg) {
+        return Ok(arg.to_string());
+    }
+    let hex_key = arg.trim_start_matches("0x");
+    let key = if hex_key.len() == 64 && hex::decode(hex_key).is_ok() {
+        hex_key.to_string()
+    } else if hd::is_path(arg) {
+        hd::derive_from_env(arg)?
+    } else {
+        read_private_key(arg)?
+    };
+    if key.len() != 64 || hex::decode(&key).is_err() {
+        return Err(Error::InvalidKey(format!(
+            "{:?} does not hold a 32-byte hex private key",
+            arg
+        )));
+    }
+    Ok(key)
+}
+
+/// Run the funding flow. Returns the nodes that failed in partial-success mode.
+fn run(config: &Config, args: &SetupArgs, report: &mut Report) -> Result<Vec<String>> {
+    let deadline = Deadline::from_env()?;
+    let http_settings = HttpSettings::from_env()?;
+    let fee_rate = config.fee_rate;
+    let locker = CellLocker::from_env(args.dry_run)?;
+
+    info!("Fiber Demo Startup: transfer CKB and sUDT to nodes");
+    let (preset, client) = connect(config, &http_settings)?;
+    let cell_deps = CellDepResolver::new(&preset);
+    let tokens = config.tokens(&preset)?;
+
+    // Resolve node accounts from key files, keys or addresses
+    let source_key = config.source_private_key.clone();
+    let source_lock_script = get_lock_script_from_private_key(&source_key)?;
+    let node_locks: Vec<Script> = config
+        .nodes
+        .iter()
+        .map(|node| resolve_lock_script(&node.account, preset.address_network))
+        .collect::<Result<_>>()?;
+
+    // Named by address, the accounts may be given as private keys
+    let address = |lock_script: &Script| {
+        Address::new(
+            preset.address_network,
+            AddressPayload::from(PackedScript::from(lock_script.clone())),
+            true,
+        )
+    };
+    debug!("Source account: {}", address(&source_lock_script));
+    for (node, lock_script) in config.nodes.iter().zip(&node_locks) {
+        debug!("{} account: {}", node.name, address(lock_script));
+    }
+
+    // Balances of the source account and every node before funding
+    let mut accounts = vec![("Source".to_string(), source_lock_script.clone())];
+    accounts.extend(
+        config
+            .nodes
+            .iter()
+            .zip(&node_locks)
+            .map(|(node, lock_script)| (node.name.clone(), lock_script.clone())),
+    );
+    let explorer = preset.explorer();
+    for balance in account_balances(&client, &tokens, &accounts, preset.address_network)? {
+        info!(
+            "{}: {}, {}",
+            balance.account,
+            match &explorer {
+                Some(explorer) => explorer.address(&balance.address),
+                None => balance.address.clone(),
+            },
+            balance.summary()
+        );
+    }
+
+    let nodes: Vec<FundedNode> = config
+        .nodes
+        .iter()
+        .zip(node_locks)
+        .map(|(node, lock_script)| {
+            Ok(FundedNode {
+                name: node.name.as_str(),
+                token_lock_script: match node.lock {
+                    LockKind::Sighash => lock_script.clone(),
+                    LockKind::Acp => build_acp_lock(&preset, &node.name, &lock_script)?,
+                },
+                lock_script,
+                ckb_amount: config.ckb_amount(node).and_then(|amount| {
+                    preset
+                        .limits
+                        .check_ckb_amount(preset.name, &node.name, amount)
+                        .map(|()| amount)
+                })?,
+                token_amounts: tokens
+                    .iter()
+                    .map(|token| config.token_amount(node, token))
+                    .collect::<Result<_>>()?,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    // Skip nodes funded by an earlier run whose transaction is still on chain or in the
+    // pool, so a re-run after a crash doesn't fund them twice; --fresh ignores the
+    // state file
+    let policy = ConfirmationPolicy::from_env()?;
+    let state_path =
+        PathBuf::from(env::var("STATE_FILE").unwrap_or_else(|_| DEFAULT_STATE_FILE.to_string()));
+    let mut state = if args.fresh {
+        RunState::new(&state_path)
+    } else {
+        RunState::load(&state_path)?
+    };
+    let mut in_flight: Vec<H256> = Vec::new();
+    let mut remaining = Vec::new();
+    for node in nodes {
+        let Some(sent) = state.sent_to(node.name) else {
+            remaining.push(node);
+            continue;
+        };
+        match rpc::block_on(get_tx_commitment(&client, &sent.tx_hash))? {
+            (status @ (Status::Unknown | Status::Rejected), _) => {
+                warn!(
+                    "Earlier funding of {} ({:#x}) was {}, funding again",
+                    node.name,
+                    sent.tx_hash,
+                    if status == Status::Rejected {
+                        "rejected"
+                    } else {
+                        "dropped"
+                    }
+                );
+                remaining.push(node);
+            }
+            (status, _) => {
+                info!(
+                    "{} already funded by {:#x} ({}, {:?})",
+                    node.name, sent.tx_hash, sent.purpose, status
+                );
+                if !in_flight.contains(&sent.tx_hash) {
+                    in_flight.push(sent.tx_hash.clone());
+                }
+            }
+        }
+    }
+    let nodes = remaining;
+
+    let ckb_recipients: Vec<(Script, u64)> = nodes
+        .iter()
+        .filter(|node| node.ckb_amount > 0)
+        .map(|node| (node.lock_script.clone(), node.ckb_amount))
+        .collect();
+    let token_transfers = token_transfers(&tokens, &nodes);
+
+    // Earlier transactions still in the pool spend source cells the indexer reports
+    // as live, so they must commit before new inputs are collected
+    if !args.dry_run {
+        rpc::block_on(rpc::concurrently(in_flight.iter().map(|tx_hash| {
+            wait_for_committed(&client, tx_hash, &policy, &deadline)
+        })))?
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    }
+    if nodes.is_empty() {
+        info!("All nodes are already funded");
+        return Ok(Vec::new());
+    }
+
+    // A dry run plans the combined transaction, also in partial-success mode where it
+    // shows whether the source can cover all nodes at once
+    if args.dry_run {
+        dry_run(|| {
+            build_transfer(
+                &client,
+                &locker,
+                &cell_deps,
+                &config.coin_selection,
+                &source_key,
+                &ckb_recipients,
+                &token_transfers,
+                fee_rate,
+                None,
+                false,
+            )
+        })?;
+        return Ok(Vec::new());
+    }
+
+    // --partial-success funds nodes one transaction at a time so one failure
+    // doesn't abort the others; --resume continues such a run
+    if args.resume || args.partial_success {
+        let failed = fund_nodes_individually(
+            &client,
+            &locker,
+            &cell_deps,
+            &config.coin_selection,
+            &source_key,
+            &tokens,
+            &nodes,
+            fee_rate,
+            args.yes,
+            &policy,
+            &deadline,
+            explorer.as_ref(),
+            &mut state,
+            report,
+        );
+        info!(
+            "Funded {} of {} nodes",
+            nodes.len() - failed.len(),
+            nodes.len()
+        );
+        return Ok(failed);
+    }
+
+    // Combined transfer: CKB and every token to every node in one transaction
+    info!(
+        "Transferring CKB and {} token(s) to {} node(s) in a single transaction...",
+        tokens.len(),
+        nodes.len()
+    );
+    let reorg_window = env_u64("REORG_WATCH_BLOCKS", DEFAULT_REORG_WATCH_BLOCKS)?;
+    let waits = policy.confirmations > 0 || reorg_window > 0;
+    // Only meaningful against a public network, enabled by default by the testnet preset
+    let explorer_url = env::var("EXPLORER_API_URL")
+        .ok()
+        .or(preset.explorer_api_url.map(str::to_string))
+        .filter(|_| waits);
+    let mut stages = Stages::new(1 + usize::from(waits) + usize::from(explorer_url.is_some()));
+    let spinner = Progress::spinner(stages.next("Build, sign and send transfer"));
+    let tx = report.stage("Build, sign and send transfer", || {
+        transfer_ckb_and_sudt(
+            &client,
+            &locker,
+            &cell_deps,
+            &config.coin_selection,
+            &source_key,
+            &ckb_recipients,
+            &token_transfers,
+            fee_rate,
+            None,
+            false,
+            args.yes,
+        )
+    })?;
+    let tx_hash: H256 = tx.hash().unpack();
+    state.record(
+        tx_hash.clone(),
+        "setup",
+        nodes.iter().map(|node| node.name.to_string()).collect(),
+    )?;
+    report.set_transaction(tx_hash.clone());
+    for node in &nodes {
+        report.add_funding(funding_row(&tokens, node));
+    }
+
+    drop(spinner);
+
+    if waits {
+        info!(
+            "Waiting for {} confirmation(s), watching {} block(s) for reorgs...",
+            policy.confirmations, reorg_window
+        );
+        let spinner = Progress::spinner(stages.next("Wait for confirmations"));
+        let block_number = report.stage("Wait for confirmations", || {
+            rpc::block_on(watch_for_reorg(
+                &client,
+                &tx,
+                &policy,
+                reorg_window,
+                &deadline,
+            ))
+        })?;
+        drop(spinner);
+        info!("Transaction settled in block {}", block_number);
+        report.set_block_number(block_number);
+
+        if let Some(explorer_url) = explorer_url {
+            let _spinner = Progress::spinner(stages.next("Verify against explorer"));
+            let mut accounts = vec![("Source", &source_lock_script)];
+            accounts.extend(nodes.iter().map(|node| (node.name, &node.lock_script)));
+            report.stage("Verify against explorer", || {
+                verify_with_explorer(
+                    &client,
+                    &ExplorerClient::new(&explorer_url, &http_settings)?,
+                    &tx_hash,
+                    block_number,
+                    &accounts,
+                    preset.address_network,
+                )
+            })?;
+        }
+    }
+
+    info!(
+        "All transfers complete in a single transaction: {:#x}",
+        tx_hash
+    );
+    if let Some(explorer) = &explorer {
+        info!("Explorer: {}", explorer.transaction(&tx_hash));
+    }
+    Ok(Vec::new())
+}
+
+/// Send CKB and the token named `token` to `recipients` (account, ckb_amount in
+/// shannons, token_amount) in one transaction and wait for CONFIRMATIONS blocks.
+/// Accounts are addresses, keys or key files. A transaction larger than MAX_TX_SIZE
+/// is split into several, each paying a slice of the recipients.
+#[allow(clippy::too_many_arguments)]
+fn transfer(
+    config: &Config,
+    from: Option<&str>,
+    token: &str,
+    recipients: &[(String, u64, u128)],
+    since: Option<Since>,
+    cobuild: bool,
+    dry_run_only: bool,
+    assume_yes: bool,
+) -> Result<()> {
+    let deadline = Deadline::from_env()?;
+    let http_settings = HttpSettings::from_env()?;
+    let fee_rate = config.fee_rate;
+    let locker = CellLocker::from_env(dry_run_only)?;
+    let (preset, client) = connect(config, &http_settings)?;
+    let cell_deps = CellDepResolver::new(&preset);
+    let explorer = preset.explorer();
+
+    let from_key = match from {
+        Some(from) => resolve_private_key(from)?,
+        None => config.source_private_key.clone(),
+    };
+    let recipients: Vec<(Script, u64, u128)> = recipients
+        .iter()
+        .map(|(account, ckb_amount, sudt_amount)| {
+            let lock_script = resolve_lock_script(account, preset.address_network)?;
+            // Named by address, the account may be given as a private key
+            let address = Address::new(
+                preset.address_network,
+                AddressPayload::from(PackedScript::from(lock_script.clone())),
+                true,
+            );
+            preset
+                .limits
+                .check_ckb_amount(preset.name, &address.to_string(), *ckb_amount)?;
+            if let Some(explorer) = &explorer
+                && !dry_run_only
+            {
+                info!("Recipient {}", explorer.address(&address.to_string()));
+            }
+            Ok((lock_script, *ckb_amount, *sudt_amount))
+        })
+        .collect::<Result<_>>()?;
+    let tokens = config.tokens(&preset)?;
+    let token = config::find_token(&tokens, token)?;
+    // The CKB and token outputs paying a slice of the recipients
+    let outputs_of = |recipients: &[(Script, u64, u128)]| {
+        let ckb_recipients: Vec<(Script, u64)> = recipients
+            .iter()
+            .filter(|(_, ckb_amount, _)| *ckb_amount > 0)
+            .map(|(lock_script, ckb_amount, _)| (lock_script.clone(), *ckb_amount))
+            .collect();
+        let token_transfers = [TokenTransfer {
+            token,
+            recipients: recipients
+                .iter()
+                .filter(|(_, _, sudt_amount)| *sudt_amount > 0)
+                .map(|(lock_script, _, sudt_amount)| (lock_script.clone(), *sudt_amount))
+                .collect(),
+            issued: 0,
+            burned: 0,
+        }];
+        (ckb_recipients, token_transfers)
+    };
+    let build = |recipients: &[(Script, u64, u128)]| {
+        let (ckb_recipients, token_transfers) = outputs_of(recipients);
+        build_transfer(
+            &client,
+            &locker,
+            &cell_deps,
+            &config.coin_selection,
+            &from_key,
+            &ckb_recipients,
+            &token_transfers,
+            fee_rate,
+            since,
+            cobuild,
+        )
+    };
+
+    let plan = build(&recipients)?;
+    let size = plan.tx.data().serialized_size_in_block() as u64;
+    let max_tx_size = env_u64("MAX_TX_SIZE", DEFAULT_MAX_TX_SIZE)?;
+    if size <= max_tx_size {
+        if dry_run_only {
+            return dry_run(|| Ok(plan));
+        }
+        let (ckb_recipients, token_transfers) = outputs_of(&recipients);
+        let tx = transfer_ckb_and_sudt(
+            &client,
+            &locker,
+            &cell_deps,
+            &config.coin_selection,
+            &from_key,
+            &ckb_recipients,
+            &token_transfers,
+            fee_rate,
+            since,
+            cobuild,
+            assume_yes,
+        )?;
+        return rpc::block_on(wait_for_transfer(
+            &client,
+            &preset,
+            &tx.hash().unpack(),
+            &deadline,
+        ));
+    }
+
+    // Slices sized from the whole transaction's size, with a tenth to spare for the
+    // inputs and cell deps each of them has too
+    let per_shard = (recipients.len() as u64 * max_tx_size * 9 / 10 / size).max(1) as usize;
+    let shards: Vec<&[(Script, u64, u128)]> = recipients.chunks(per_shard).collect();
+    info!(
+        "The transfer is {} bytes, over MAX_TX_SIZE {}: splitting it into {} \
+         transactions of up to {} recipients",
+        size,
+        max_tx_size,
+        shards.len(),
+        per_shard
+    );
+    if dry_run_only {
+        println!(
+            "\nThe transfer takes {} transactions, later ones spending the change of \
+             earlier ones. The first:",
+            shards.len()
+        );
+        return dry_run(|| build(shards[0]));
+    }
+
+    let policy = ConfirmationPolicy::from_env()?;
+    let mut sent: Vec<(H256, usize)> = Vec::new();
+    // Sent transactions whose change later shards may need
+    let mut pending: VecDeque<H256> = VecDeque::new();
+    for (index, shard) in shards.iter().enumerate() {
+        info!(
+            "Sending transaction {}/{} to {} recipient(s)",
+            index + 1,
+            shards.len(),
+            shard.len()
+        );
+        let (ckb_recipients, token_transfers) = outputs_of(shard);
+        let tx = loop {
+            let result = transfer_ckb_and_sudt(
+                &client,
+                &locker,
+                &cell_deps,
+                &config.coin_selection,
+                &from_key,
+                &ckb_recipients,
+                &token_transfers,
+                fee_rate,
+                since,
+                cobuild,
+                assume_yes,
+            );
+            match result {
+                // The sender's cells are spent by earlier shards, whose change can be
+                // spent once they are committed
+                Err(Error::InsufficientCapacity { .. } | Error::InsufficientFunds { .. })
+                    if !pending.is_empty() =>
+                {
+                    let tx_hash = pending.pop_front().unwrap();
+                    info!("Waiting for {:#x} to commit to spend its change", tx_hash);
+                    rpc::block_on(async {
+                        wait_for_committed(&client, &tx_hash, &policy, &deadline).await?;
+                        wait_for_indexer(&client).await
+                    })
+                    .inspect_err(|_| log_shards(&sent, shards.len()))?;
+                }
+                result => break result.inspect_err(|_| log_shards(&sent, shards.len()))?,
+            }
+        };
+        let tx_hash: H256 = tx.hash().unpack();
+        pending.push_back(tx_hash.clone());
+        sent.push((tx_hash, shard.len()));
+    }
+    rpc::block_on(rpc::concurrently(sent.iter().map(|(tx_hash, _)| {
+        wait_for_transfer(&client, &preset, tx_hash, &deadline)
+    })))?
+    .into_iter()
+    .collect::<Result<()>>()
+    .inspect_err(|_| log_shards(&sent, shards.len()))?;
+    log_shards(&sent, shards.len());
+    Ok(())
+}
+
+/// Log the transactions of a split transfer sent so far, out of `total`
+fn log_shards(sent: &[(H256, usize)], total: usize) {
+    info!("Sent {} of {} transactions:", sent.len(), total);
+    for (index, (tx_hash, recipients)) in sent.iter().enumerate() {
+        info!(
+            "  {}/{}: {:#x}, {} recipient(s)",
+            index + 1,
+            total,
+            tx_hash,
+            recipients
+        );
+    }
+}
+
+/// Wait for CONFIRMATIONS blocks on a sent transfer and link it on the explorer
+async fn wait_for_transfer(
+    client: &RetryingClient,
+    preset: &NetworkPreset,
+    tx_hash: &H256,
+    deadline: &Deadline,
+) -> Result<()> {
+    let policy = ConfirmationPolicy::from_env()?;
+    if policy.confirmations > 0 {
+        let (block_number, _) = wait_for_committed(client, tx_hash, &policy, deadline).await?;
+        info!("Transaction committed in block {}", block_number);
+    }
+    if let Some(explorer) = preset.explorer() {
+        info!("Explorer: {}", explorer.transaction(tx_hash));
+    }
+    Ok(())
+}
+
+/// `bump-fee`: replace a pending transaction with one paying a higher fee, then wait
+/// for the replacement like the transfer commands do
+fn bump_fee(config: &Config, args: &BumpFeeArgs) -> Result<()> {
+    let deadline = Deadline::from_env()?;
+    let http_settings = HttpSettings::from_env()?;
+    let fee_rate = config.fee_rate;
+    let locker = CellLocker::from_env(args.dry_run)?;
+    let (preset, client) = connect(config, &http_settings)?;
+
+    let from_key = match &args.from {
+        Some(from) => resolve_private_key(from)?,
+        None => config.source_private_key.clone(),
+    };
+    let tokens = config.tokens(&preset)?;
+
+    if args.dry_run {
+        return dry_run(|| {
+            build_fee_bump(&client, &locker, &from_key, &args.tx, &tokens, fee_rate)
+        });
+    }
+
+    let mut build_rate = fee_rate;
+    let (plan, tx) = loop {
+        let plan = build_fee_bump(&client, &locker, &from_key, &args.tx, &tokens, build_rate)?;
+        if !args.yes {
+            confirm_plan(&plan)?;
+        }
+        match send_transfer(&client, &from_key, &plan, fee_rate)? {
+            Sent::Broadcast(tx) => break (plan, tx),
+            Sent::Underpaid(rate) => build_rate = rate,
+        }
+    };
+    // The original inputs may still be reserved by the run that sent them
+    locker.take_over(&plan.input_cells)?;
+    rpc::block_on(wait_for_transfer(
+        &client,
+        &preset,
+        &tx.hash().unpack(),
+        &deadline,
+    ))
+}
+
+/// `issue-sudt`: mint `--amount` of the token owned by the issuer, the sUDT whose args
+/// are the issuer's lock hash. The sUDT script lets inputs with the owner lock create
+/// tokens, so plain CKB cells of the issuer pay for the new cell.
+fn issue_sudt(config: &Config, args: &IssueSudtArgs) -> Result<()> {
+    let deadline = Deadline::from_env()?;
+    let http_settings = HttpSettings::from_env()?;
+    let fee_rate = config.fee_rate;
+    let locker = CellLocker::from_env(args.dry_run)?;
+    let (preset, client) = connect(config, &http_settings)?;
+    let cell_deps = CellDepResolver::new(&preset);
+    // The preset's sUDT with the issuer's args
+    let mut token = config.tokens(&preset)?.swap_remove(0);
+
+    let amount = match &args.amount {
+        Some(amount) => parse_amount(amount, &token.name, token.decimals)?,
+        None => config::DEFAULT_SUDT_SUPPLY,
+    };
+    if amount == 0 {
+        return Err(Error::InvalidAmount(
+            "sUDT supply must be positive".to_string(),
+        ));
+    }
+    let from_key = match &args.from {
+        Some(from) => resolve_private_key(from)?,
+        None => config.source_private_key.clone(),
+    };
+    let recipient = match &args.to {
+        Some(to) => resolve_lock_script(to, preset.address_network)?,
+        None => get_lock_script_from_private_key(&from_key)?,
+    };
+
+    let token_args = build_packed_lock_script(&from_key)?
+        .calc_script_hash()
+        .as_slice()
+        .to_vec();
+    if token.script.args != token_args {
+        warn!(
+            "The configured sUDT args are 0x{}, set SUDT_ARGS=0x{} to fund nodes with the issued token",
+            hex::encode(&token.script.args),
+            hex::encode(&token_args)
+        );
+    }
+    token.script.args = token_args;
+    info!(
+        "Issuing {} {} with args 0x{}",
+        format_amount(amount, token.decimals),
+        token.name,
+        hex::encode(&token.script.args)
+    );
+
+    let token_transfers = [TokenTransfer {
+        token: &token,
+        recipients: vec![(recipient, amount)],
+        issued: amount,
+        burned: 0,
+    }];
+    if args.dry_run {
+        return dry_run(|| {
+            build_transfer(
+                &client,
+                &locker,
+                &cell_deps,
+                &config.coin_selection,
+                &from_key,
+                &[],
+                &token_transfers,
+                fee_rate,
+                None,
+                false,
+            )
+        });
+    }
+
+    let tx = transfer_ckb_and_sudt(
+        &client,
+        &locker,
+        &cell_deps,
+        &config.coin_selection,
+        &from_key,
+        &[],
+        &token_transfers,
+        fee_rate,
+        None,
+        false,
+        args.yes,
+    )?;
+    rpc::block_on(wait_for_transfer(
+        &client,
+        &preset,
+        &tx.hash().unpack(),
+        &deadline,
+    ))
+}
+
+/// `burn-sudt`: destroy a token held by each holder, everything it holds or
+/// `--amount`, returning the capacity of the spent cells as CKB change. The sUDT
+/// script accepts outputs worth less than the inputs without the owner lock, so any
+/// holder can burn its own tokens.
+fn burn_sudt(config: &Config, args: &BurnSudtArgs) -> Result<()> {
+    let deadline = Deadline::from_env()?;
+    let http_settings = HttpSettings::from_env()?;
+    let fee_rate = config.fee_rate;
+    let locker = CellLocker::from_env(args.dry_run)?;
+    let (preset, client) = connect(config, &http_settings)?;
+    let cell_deps = CellDepResolver::new(&preset);
+    let tokens = config.tokens(&preset)?;
+    let token = config::find_token(&tokens, &args.token)?;
+
+    // (name, private key)
+    let holders: Vec<(String, String)> = if args.from.is_empty() {
+        let mut holders = Vec::new();
+        for node in &config.nodes {
+            // Nodes given by address have no key to sign with
+            if Address::from_str(&node.account).is_ok() {
+                debug!("Skipping {}, given by address", node.name);
+                continue;
+            }
+            holders.push((node.name.clone(), resolve_private_key(&node.account)?));
+        }
+        holders
+    } else {
+        args.from
+            .iter()
+            .map(|from| Ok((from.clone(), resolve_private_key(from)?)))
+            .collect::<Result<_>>()?
+    };
+
+    for (name, key) in &holders {
+        let lock_script = get_lock_script_from_private_key(key)?;
+        let balance: u128 = rpc::block_on(find_sudt_cells(&client, &lock_script, &token.script))?
+            .iter()
+            .map(|c| parse_sudt_amount(c.output_data.as_bytes()))
+            .sum();
+        let burned = match &args.amount {
+            Some(amount) => parse_amount(amount, &token.name, token.decimals)?,
+            None => balance,
+        };
+        if burned == 0 {
+            info!("{} holds no {}", name, token.name);
+            continue;
+        }
+        info!(
+            "Burning {} {} held by {}",
+            format_amount(burned, token.decimals),
+            token.name,
+            name
+        );
+
+        let token_transfers = [TokenTransfer {
+            token,
+            recipients: Vec::new(),
+            issued: 0,
+            burned,
+        }];
+        if args.dry_run {
+            dry_run(|| {
+                build_transfer(
+                    &client,
+                    &locker,
+                    &cell_deps,
+                    &config.coin_selection,
+                    key,
+                    &[],
+                    &token_transfers,
+                    fee_rate,
+                    None,
+                    false,
+                )
+            })?;
+            continue;
+        }
+        let tx = transfer_ckb_and_sudt(
+            &client,
+            &locker,
+            &cell_deps,
+            &config.coin_selection,
+            key,
+            &[],
+            &token_transfers,
+            fee_rate,
+            None,
+            false,
+            args.yes,
+        )?;
+        rpc::block_on(wait_for_transfer(
+            &client,
+            &preset,
+            &tx.hash().unpack(),
+            &deadline,
+        ))?;
+    }
+    Ok(())
+}
+
+/// `consolidate`: merge the token cells of a key into a single cell back to the key,
+/// returning the capacity of the other cells as CKB change. Cells reserved by other
+/// runs are left alone.
+fn consolidate(config: &Config, args: &ConsolidateArgs) -> Result<()> {
+    let deadline = Deadline::from_env()?;
+    let http_settings = HttpSettings::from_env()?;
+    let fee_rate = config.fee_rate;
+    let locker = CellLocker::from_env(args.dry_run)?;
+    let (preset, client) = connect(config, &http_settings)?;
+    let cell_deps = CellDepResolver::new(&preset);
+    let tokens = config.tokens(&preset)?;
+    let token = config::find_token(&tokens, &args.token)?;
+
+    let from_key = match &args.from {
+        Some(from) => resolve_private_key(from)?,
+        None => config.source_private_key.clone(),
+    };
+    let lock_script = get_lock_script_from_private_key(&from_key)?;
+    let reserved = locker.reservations()?;
+    let cells: Vec<LiveCell> =
+        rpc::block_on(find_sudt_cells(&client, &lock_script, &token.script))?
+            .into_iter()
+            .filter(|cell| !reserved.contains(&cell.out_point))
+            .collect();
+    if cells.len() < 2 {
+        info!(
+            "{} {} cell(s), nothing to consolidate",
+            cells.len(),
+            token.name
+        );
+        return Ok(());
+    }
+    let total: u128 = cells
+        .iter()
+        .map(|c| parse_sudt_amount(c.output_data.as_bytes()))
+        .sum();
+    info!(
+        "Consolidating {} {} cells holding {} {}",
+        cells.len(),
+        token.name,
+        format_amount(total, token.decimals),
+        token.name
+    );
+
+    // The whole balance goes back to the key in one output, so every cell is spent
+    let token_transfers = [TokenTransfer {
+        token,
+        recipients: vec![(lock_script, total)],
+        issued: 0,
+        burned: 0,
+    }];
+    if args.dry_run {
+        return dry_run(|| {
+            build_transfer(
+                &client,
+                &locker,
+                &cell_deps,
+                &config.coin_selection,
+                &from_key,
+                &[],
+                &token_transfers,
+                fee_rate,
+                None,
+                false,
+            )
+        });
+    }
+    let tx = transfer_ckb_and_sudt(
+        &client,
+        &locker,
+        &cell_deps,
+        &config.coin_selection,
+        &from_key,
+        &[],
+        &token_transfers,
+        fee_rate,
+        None,
+        false,
+        args.yes,
+    )?;
+    rpc::block_on(wait_for_transfer(
+        &client,
+        &preset,
+        &tx.hash().unpack(),
+        &deadline,
+    ))
+}
+
+/// Recipient accounts with their amounts in base units, from `--recipients` or from
+/// `--to` and `--amount`. `file_amount` picks the amount of a recipients file entry and
+/// `parse` reads `--amount`; entries that don't receive the asset are skipped.
+fn transfer_recipients(
+    args: &TransferArgs,
+    file_amount: impl Fn(&NodeConfig) -> Result<u128>,
+    parse: impl Fn(&str) -> Result<u128>,
+) -> Result<Vec<(String, u128)>> {
+    match &args.recipients_file {
+        Some(path) => {
+            let mut recipients = Vec::new();
+            for recipient in config::load_recipients(path)? {
+                let amount = file_amount(&recipient)?;
+                if amount > 0 {
+                    recipients.push((recipient.account, amount));
+                }
+            }
+            Ok(recipients)
+        }
+        None => {
+            let amount = args
+                .amount
+                .as_deref()
+                .ok_or_else(|| Error::Config("--amount is required with --to".to_string()))?;
+            let amount = parse(amount)?;
+            Ok(args
+                .recipients
+                .iter()
+                .map(|r| (r.clone(), amount))
+                .collect())
+        }
+    }
+}
+
+/// `transfer-ckb`: each recipient gets at least the capacity its plain cell occupies, 61
+/// CKB for a sighash lock
+fn transfer_ckb(config: &Config, args: &TransferArgs) -> Result<()> {
+    let recipients: Vec<(String, u64, u128)> = transfer_recipients(
+        args,
+        |r| config.ckb_amount(r).map(u128::from),
+        |amount| parse_amount(amount, "CKB", CKB_DECIMALS),
+    )?
+    .into_iter()
+    .map(|(account, shannons)| {
+        let shannons = u64::try_from(shannons).map_err(|_| {
+            Error::InvalidAmount(format!(
+                "CKB amount {} is too large",
+                format_amount(shannons, CKB_DECIMALS)
+            ))
+        })?;
+        Ok((account, shannons, 0))
+    })
+    .collect::<Result<_>>()?;
+    transfer(
+        config,
+        args.from.as_deref(),
+        SUDT_TOKEN_NAME,
+        &recipients,
+        args.since,
+        args.cobuild,
+        args.dry_run,
+        args.yes,
+    )
+}
+
+/// `transfer-sudt`: each recipient gets a new cell of the token with the minimum
+/// capacity
+fn transfer_sudt(config: &Config, args: &TransferSudtArgs) -> Result<()> {
+    let tokens = config.tokens(&config.network_preset()?)?;
+    let token = config::find_token(&tokens, &args.token)?;
+    let recipients: Vec<(String, u64, u128)> = transfer_recipients(
+        &args.transfer,
+        |r| {
+            config::check_token_names(&tokens, std::slice::from_ref(r))?;
+            config.token_amount(r, token)
+        },
+        |amount| parse_amount(amount, &token.name, token.decimals),
+    )?
+    .into_iter()
+    .map(|(account, amount)| {
+        if amount == 0 {
+            return Err(Error::InvalidAmount(format!(
+                "{} amount must be positive",
+                token.name
+            )));
+        }
+        Ok((account, 0, amount))
+    })
+    .collect::<Result<_>>()?;
+    transfer(
+        config,
+        args.transfer.from.as_deref(),
+        &token.name,
+        &recipients,
+        args.transfer.since,
+        args.transfer.cobuild,
+        args.transfer.dry_run,
+        args.transfer.yes,
+    )
+}
+
+/// `gen-config`: write the `config.yml` and `ckb/key` of every node with `fiber`
+/// settings, so the Fiber nodes use the keys this tool funds and accept the configured
+/// tokens. Bootnode peer ids come from the nodes' `fiber/sk` secret keys.
+fn gen_config(config: &Config, args: &GenConfigArgs) -> Result<()> {
+    let http_settings = HttpSettings::from_env()?;
+    let (preset, client) = connect(config, &http_settings)?;
+    let cell_deps = CellDepResolver::new(&preset);
+    let udts: Vec<fiber::WhitelistedUdt> = config
+        .tokens(&preset)?
+        .into_iter()
+        .map(|token| {
+            Ok(fiber::WhitelistedUdt {
+                script: get_sudt_type_script(&token.script),
+                cell_dep: cell_deps.code_cell(&client, &token.script)?.into(),
+                name: token.name,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    let nodes: Vec<_> = config
+        .nodes
+        .iter()
+        .filter_map(|node| node.fiber.as_ref().map(|fiber| (node, fiber)))
+        .collect();
+    if nodes.is_empty() {
+        return Err(Error::UnknownNode(
+            "No node has Fiber settings to generate a config for".to_string(),
+        ));
+    }
+    let mut bootnodes = Vec::new();
+    for (_, fiber) in nodes.iter().filter(|(_, fiber)| fiber.bootnode) {
+        let path = args.out_dir.join(&fiber.dir).join("fiber/sk");
+        let secret_key = fs::read(&path).map_err(|e| {
+            Error::InvalidKey(format!(
+                "Failed to read Fiber secret key {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let address = format!(
+            "/dns4/{}/tcp/{}/p2p/{}",
+            fiber.name,
+            fiber.p2p_port,
+            fiber::peer_id(&secret_key)?
+        );
+        bootnodes.push((fiber.name.as_str(), address));
+    }
+
+    let genesis_cellbase = get_genesis_block(&client)?.transactions[0].hash.clone();
+    let template = fiber::NodeConfigTemplate {
+        chain: config.fiber.chain.as_deref().unwrap_or(preset.fiber_chain),
+        ckb_rpc_url: config
+            .fiber
+            .ckb_rpc_url
+            .as_deref()
+            .unwrap_or(config.ckb_rpc_url(&preset)),
+        scripts: &preset.fiber_scripts,
+        genesis_cellbase: &genesis_cellbase,
+        udts: &udts,
+    };
+    for (node, fiber) in nodes {
+        if Address::from_str(&node.account).is_ok() {
+            return Err(Error::InvalidKey(format!(
+                "{} is given by address, its Fiber node needs the private key",
+                node.name
+            )));
+        }
+        let key = resolve_private_key(&node.account)?;
+        let bootnode_addrs: Vec<String> = bootnodes
+            .iter()
+            .filter(|(name, _)| *name != fiber.name)
+            .map(|(_, address)| address.clone())
+            .collect();
+        let dir = args.out_dir.join(&fiber.dir);
+        write_generated(
+            &dir.join("config.yml"),
+            &template.render(fiber, &bootnode_addrs),
+            args.force,
+        )?;
+        write_generated(&dir.join("ckb/key"), &format!("{}\n", key), args.force)?;
+    }
+    Ok(())
+}
+
+/// Write a file generated by `gen-config`. An existing file with other content is only
+/// replaced with `--force`.
+fn write_generated(path: &Path, content: &str, force: bool) -> Result<()> {
+    match fs::read_to_string(path) {
+        Ok(existing) if existing == content => {
+            info!("{} is up to date", path.display());
+            return Ok(());
+        }
+        Ok(_) if !force => {
+            return Err(Error::Declined(format!(
+                "{} differs from the generated file, pass --force to overwrite it",
+                path.display()
+            )));
+        }
+        _ => {}
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(Error::io(format!(
+            "Failed to create directory {}",
+            dir.display()
+        )))?;
+    }
+    fs::write(path, content).map_err(Error::io(format!("Failed to write {}", path.display())))?;
+    info!("Wrote {}", path.display());
+    Ok(())
+}
+
+/// `run-nodes`: run the Fiber node of every node with Fiber settings from the config
+/// `gen-config` wrote, restarting them by the policy until interrupted
+fn run_nodes(config: &Config, args: &RunNodesArgs) -> Result<()> {
+    let settings = fnn_settings(&args.fnn);
+    let mut fiber_nodes = start_fiber_nodes(config, &args.fnn, &settings)?;
+    info!("Fiber nodes running, interrupt to stop them");
+    sampling_metrics(config, || fiber_nodes.supervise())
+}
+
+/// Run `run`, meanwhile sampling the nodes' balances and channels in the background
+/// when metrics are served at METRICS_ADDR
+fn sampling_metrics<T>(config: &Config, run: impl FnOnce() -> T) -> T {
+    if !metrics::serve_from_env() {
+        return run();
+    }
+    let done = AtomicBool::new(false);
+    let runtime = tokio::runtime::Handle::try_current().ok();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let _runtime = runtime.as_ref().map(|runtime| runtime.enter());
+            if let Err(e) = sample_metrics(config, &done) {
+                warn!("Not sampling metrics: {}", e);
+            }
+        });
+        let result = run();
+        done.store(true, Ordering::Relaxed);
+        result
+    })
+}
+
+/// Update the balance and channel metrics of every node every METRICS_INTERVAL_SECS
+/// until `done`. Lookups that fail are skipped until the next round.
+fn sample_metrics(config: &Config, done: &AtomicBool) -> Result<()> {
+    let interval = rpc::env_secs("METRICS_INTERVAL_SECS")?.unwrap_or(DEFAULT_METRICS_INTERVAL);
+    let http_settings = HttpSettings::from_env()?;
+    let preset = config.network_preset()?;
+    let urls = config.ckb_rpc_urls(&preset);
+    let client = rpc::new_ckb_client(&urls, config.backend, &http_settings)?;
+    let tokens = config.tokens(&preset).unwrap_or_else(|e| {
+        warn!("Not sampling token balances: {}", e);
+        Vec::new()
+    });
+    let accounts: Vec<(&str, Script)> = config
+        .nodes
+        .iter()
+        .filter_map(
+            |node| match resolve_lock_script(&node.account, preset.address_network) {
+                Ok(lock_script) => Some((node.name.as_str(), lock_script)),
+                Err(e) => {
+                    warn!("Not sampling the balances of {}: {}", node.name, e);
+                    None
+                }
+            },
+        )
+        .collect();
+
+    while !done.load(Ordering::Relaxed) {
+        for (name, lock_script) in &accounts {
+            match rpc::block_on(get_ckb_balance(&client, lock_script)) {
+                Ok(balance) => metrics::set_balance(name, balance),
+                Err(e) => debug!("Failed to sample the CKB balance of {}: {}", name, e),
+            }
+            for token in &tokens {
+                match rpc::block_on(find_sudt_cells(&client, lock_script, &token.script)) {
+                    Ok(cells) => {
+                        let balance = cells
+                            .iter()
+                            .map(|c| parse_sudt_amount(c.output_data.as_bytes()))
+                            .sum();
+                        metrics::set_token_balance(name, &token.name, balance);
+                    }
+                    Err(e) => debug!(
+                        "Failed to sample the {} balance of {}: {}",
+                        token.name, name, e
+                    ),
+                }
+            }
+        }
+        for node in &config.nodes {
+            let Some(url) = &node.fiber_rpc_url else {
+                continue;
+            };
+            match fiber::FiberClient::probe(url, &http_settings)
+                .and_then(|client| client.list_channels(None, false))
+            {
+                Ok(channels) => {
+                    let mut counts = BTreeMap::new();
+                    for channel in channels {
+                        *counts.entry(channel.state.state_name).or_default() += 1;
+                    }
+                    metrics::set_channels(&node.name, counts);
+                }
+                Err(e) => debug!("Failed to sample the channels of {}: {}", node.name, e),
+            }
+        }
+        let next = Instant::now() + interval;
+        while !done.load(Ordering::Relaxed) && Instant::now() < next {
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+    Ok(())
+}
+
+fn fnn_settings(options: &FnnOptions) -> supervisor::FnnSettings<'_> {
+    supervisor::FnnSettings {
+        fnn_bin: &options.fnn,
+        restart: options.restart,
+        max_restarts: options.max_restarts,
+        restart_delay: Duration::from_secs(options.restart_delay_secs),
+        log_dir: options.log_dir.as_deref(),
+    }
+}
+
+/// Start the Fiber node of every node with Fiber settings
+fn start_fiber_nodes<'a>(
+    config: &Config,
+    options: &FnnOptions,
+    settings: &'a supervisor::FnnSettings<'a>,
+) -> Result<supervisor::FiberNodes<'a>> {
+    let nodes = fnn_nodes(config, &options.nodes_dir);
+    if nodes.is_empty() {
+        return Err(Error::UnknownNode(
+            "No node has Fiber settings to run a Fiber node for".to_string(),
+        ));
+    }
+    supervisor::FiberNodes::start(nodes, settings)
+}
+
+/// `wait-nodes`: wait until the Fiber node of every node with a `fiber_rpc_url`
+/// answers RPC
+fn wait_nodes(config: &Config) -> Result<()> {
+    let nodes: Vec<(&str, &str)> = config
+        .nodes
+        .iter()
+        .filter_map(|node| Some((node.name.as_str(), node.fiber_rpc_url.as_deref()?)))
+        .collect();
+    if nodes.is_empty() {
+        return Err(Error::UnknownNode(
+            "No node has a fiber_rpc_url to wait for".to_string(),
+        ));
+    }
+    fiber::wait_for_nodes(&nodes, &HttpSettings::from_env()?, &Deadline::from_env()?)
+}
+
+/// `status`: probe every component of the demo once, without retries, print their
+/// health and fail naming the broken ones
+fn status(config: &Config, args: &StatusArgs) -> Result<()> {
+    let http_settings = HttpSettings::from_env()?;
+    let preset = config.network_preset()?;
+    let rpc_url = config.ckb_rpc_url(&preset);
+    let client = rpc::new_ckb_client(&[rpc_url], config.backend, &http_settings)?
+        .with_retry_policy(rpc::RetryPolicy::single_attempt());
+    let mut checks = Vec::new();
+
+    let tip = rpc::block_on(get_tip_block_number(&client));
+    checks.push(match &tip {
+        Ok(tip) => Check::new(
+            "CKB node",
+            Health::Ok,
+            format!("{} at block {}", rpc_url, tip),
+        ),
+        Err(e) => Check::new("CKB node", Health::Fail, e.to_string()),
+    });
+    // Each fallback is probed on its own; one that is down only warns while the
+    // primary serves
+    for url in &config.ckb_rpc_fallback_urls {
+        let fallback = rpc::new_ckb_client(&[url.as_str()], config.backend, &http_settings)?
+            .with_retry_policy(rpc::RetryPolicy::single_attempt());
+        checks.push(match rpc::block_on(get_tip_block_number(&fallback)) {
+            Ok(tip) => Check::new(
+                "CKB fallback",
+                Health::Ok,
+                format!("{} at block {}", url, tip),
+            ),
+            Err(e) => Check::new("CKB fallback", Health::Warn, format!("{}: {}", url, e)),
+        });
+    }
+    let indexer = match tip {
+        Ok(tip) => match rpc::block_on(client.get_indexer_tip()) {
+            Ok(Some(indexed)) if indexed.block_number.value() >= tip => {
+                Check::new("Indexer", Health::Ok, format!("synced at block {}", tip))
+            }
+            Ok(Some(indexed)) => Check::new(
+                "Indexer",
+                Health::Warn,
+                format!(
+                    "at block {}, {} behind the node",
+                    indexed.block_number.value(),
+                    tip - indexed.block_number.value()
+                ),
+            ),
+            Ok(None) => Check::new("Indexer", Health::Warn, "nothing indexed yet"),
+            Err(e) => Check::new(
+                "Indexer",
+                Health::Fail,
+                Error::rpc("Failed to get indexer tip")(e).to_string(),
+            ),
+        },
+        Err(_) => Check::new("Indexer", Health::Fail, "needs the CKB node"),
+    };
+    let indexer_up = indexer.health != Health::Fail;
+    checks.push(indexer);
+
+    let source_lock = get_lock_script_from_private_key(&config.source_private_key);
+    checks.push(match &source_lock {
+        Ok(lock) => Check::new(
+            "Source key",
+            Health::Ok,
+            format!("lock args 0x{}", hex::encode(lock.args.as_bytes())),
+        ),
+        Err(e) => Check::new("Source key", Health::Fail, e.to_string()),
+    });
+    match source_lock {
+        Ok(lock) if indexer_up => {
+            checks.extend(source_balance_checks(config, &preset, &client, &lock)?)
+        }
+        Ok(_) => checks.push(Check::new(
+            "Source balance",
+            Health::Fail,
+            "needs the indexer",
+        )),
+        Err(_) => checks.push(Check::new(
+            "Source balance",
+            Health::Fail,
+            "needs the source key",
+        )),
+    }
+
+    for node in &config.nodes {
+        let component = format!("{} key", node.name);
+        let path = Path::new(&node.account);
+        let hex_key = node.account.trim_start_matches("0x");
+        let is_file = !(Address::from_str(&node.account).is_ok()
+            || hex_key.len() == 64 && hex::decode(hex_key).is_ok()
+            || hd::is_path(&node.account)
+            || ledger::is_ledger(&node.account)
+            || remote_signer::is_remote(&node.account));
+        checks.push(if is_file && !path.exists() {
+            Check::new(
+                component,
+                Health::Fail,
+                format!("{} is missing, `up` generates it", path.display()),
+            )
+        } else {
+            match resolve_lock_script(&node.account, preset.address_network) {
+                Ok(lock) => Check::new(
+                    component,
+                    Health::Ok,
+                    format!("lock args 0x{}", hex::encode(lock.args.as_bytes())),
+                ),
+                Err(e) => Check::new(component, Health::Fail, e.to_string()),
+            }
+        });
+    }
+    for node in &config.nodes {
+        let Some(rpc_url) = node.fiber_rpc_url.as_deref() else {
+            continue;
+        };
+        let component = format!("{} Fiber node", node.name);
+        checks.push(
+            match fiber::FiberClient::probe(rpc_url, &http_settings)?.node_info() {
+                Ok(info) => Check::new(
+                    component,
+                    Health::Ok,
+                    format!(
+                        "{} running {}",
+                        rpc_url,
+                        info.version.as_deref().unwrap_or("an unknown version")
+                    ),
+                ),
+                Err(e) => Check::new(component, Health::Fail, e.to_string()),
+            },
+        );
+    }
+
+    match args.format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&checks).unwrap()),
+        OutputFormat::Text => health::print_table(
+            &checks,
+            io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+        ),
+    }
+    let failed: Vec<String> = checks
+        .into_iter()
+        .filter(|check| check.health == Health::Fail)
+        .map(|check| check.component)
+        .collect();
+    if !failed.is_empty() {
+        return Err(Error::Unhealthy(failed));
+    }
+    Ok(())
+}
+
+/// `dashboard`: show the processes, balances, funding transactions and channels of the
+/// demo on one screen, refreshed every `--refresh-secs`
+fn dashboard(config: &Config, args: &DashboardArgs) -> Result<()> {
+    if !io::stdout().is_terminal() {
+        return Err(Error::Node(
+            "The dashboard needs a terminal, use `status`, `balances` or `channels` instead"
+                .to_string(),
+        ));
+    }
+    let http_settings = HttpSettings::from_env()?;
+    let preset = config.network_preset()?;
+    let client = rpc::new_ckb_client(
+        &config.ckb_rpc_urls(&preset),
+        config.backend,
+        &http_settings,
+    )?
+    .with_retry_policy(rpc::RetryPolicy::single_attempt());
+    let tokens = config.tokens(&preset)?;
+    let accounts = configured_accounts(config, preset.address_network)?;
+    let state_path =
+        PathBuf::from(env::var("STATE_FILE").unwrap_or_else(|_| DEFAULT_STATE_FILE.to_string()));
+
+    dashboard::run(Duration::from_secs(args.refresh_secs.max(1)), || {
+        dashboard_snapshot(
+            config,
+            &args.dirs,
+            &preset,
+            &client,
+            &http_settings,
+            &tokens,
+            &accounts,
+            &state_path,
+        )
+    })
+}
+
+/// Everything `dashboard` shows, each part failing on its own
+#[allow(clippy::too_many_arguments)]
+fn dashboard_snapshot(
+    config: &Config,
+    dirs: &StateDirs,
+    preset: &NetworkPreset,
+    client: &RetryingClient,
+    http_settings: &HttpSettings,
+    tokens: &[Token],
+    accounts: &[(String, Script)],
+    state_path: &Path,
+) -> dashboard::Snapshot {
+    let tip = rpc::block_on(get_tip_block_number(client));
+    let mut processes = vec![match &tip {
+        Ok(tip) => Check::new(
+            "CKB node",
+            Health::Ok,
+            format!("{} at block {}", client.url(), tip),
+        ),
+        Err(e) => Check::new("CKB node", Health::Fail, e.to_string()),
+    }];
+    let fnn_pid_files: Vec<PathBuf> = fnn_nodes(config, &dirs.nodes_dir)
+        .iter()
+        .map(|node| node.dir.join(supervisor::FNN_PID_FILE))
+        .collect();
+    for path in recorded_pid_files(config, dirs) {
+        if fnn_pid_files.contains(&path) {
+            continue;
+        }
+        let name = path
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+        processes.push(process_check(&name, &path));
+    }
+    for (node, path) in fnn_nodes(config, &dirs.nodes_dir)
+        .iter()
+        .zip(&fnn_pid_files)
+    {
+        processes.push(process_check(&format!("{} fnn", node.name), path));
+    }
+    for node in &config.nodes {
+        let Some(rpc_url) = node.fiber_rpc_url.as_deref() else {
+            continue;
+        };
+        let component = format!("{} Fiber RPC", node.name);
+        processes.push(
+            match fiber::FiberClient::probe(rpc_url, http_settings)
+                .and_then(|client| client.node_info())
+            {
+                Ok(info) => Check::new(
+                    component,
+                    Health::Ok,
+                    format!(
+                        "{} running {}",
+                        rpc_url,
+                        info.version.as_deref().unwrap_or("an unknown version")
+                    ),
+                ),
+                Err(e) => Check::new(component, Health::Fail, e.to_string()),
+            },
+        );
+    }
+
+    let transactions = tip.as_ref().map_err(ToString::to_string).and_then(|tip| {
+        let state = RunState::load(state_path).map_err(|e| e.to_string())?;
+        // The latest first
+        rpc::block_on(rpc::concurrently(state.sent.iter().rev().map(
+            |sent| async move {
+                let (status, block) = get_tx_commitment(client, &sent.tx_hash).await?;
+                Ok(transaction_row(sent, status, block, *tip))
+            },
+        )))
+        .and_then(|rows| rows.into_iter().collect::<Result<_>>())
+        .map_err(|e| e.to_string())
+    });
+
+    let fiber_nodes: Vec<(&str, &str)> = config
+        .nodes
+        .iter()
+        .filter_map(|node| Some((node.name.as_str(), node.fiber_rpc_url.as_deref()?)))
+        .collect();
+    let channels = (!fiber_nodes.is_empty()).then(|| {
+        fiber::channel_summary(&fiber_nodes, tokens, http_settings).map_err(|e| e.to_string())
+    });
+
+    dashboard::Snapshot {
+        chain: match &tip {
+            Ok(tip) => Ok(format!("{} at block {}", preset.name, tip)),
+            Err(e) => Err(e.to_string()),
+        },
+        processes,
+        token_names: tokens.iter().map(|token| token.name.clone()).collect(),
+        balances: match &tip {
+            Ok(_) => account_balances(client, tokens, accounts, preset.address_network)
+                .map_err(|e| e.to_string()),
+            Err(_) => Err("needs the CKB node".to_string()),
+        },
+        transactions,
+        channels,
+    }
+}
+
+/// Dashboard row of a transaction of the state file, given its status at `tip`
+fn transaction_row(
+    sent: &state::SentTransaction,
+    status: Status,
+    block: Option<(u64, H256)>,
+    tip: u64,
+) -> dashboard::TransactionRow {
+    let (status, health) = match (status, block) {
+        (Status::Committed, Some((block_number, _))) => (
+            format!(
+                "committed, {} confirmation(s)",
+                (tip + 1).saturating_sub(block_number)
+            ),
+            Health::Ok,
+        ),
+        (Status::Unknown, _) => ("unknown, dropped?".to_string(), Health::Fail),
+        (Status::Rejected, _) => ("rejected".to_string(), Health::Fail),
+        (status, _) => (format!("{:?}", status).to_lowercase(), Health::Warn),
+    };
+    dashboard::TransactionRow {
+        tx_hash: format!("{:#x}", sent.tx_hash),
+        purpose: sent.purpose.clone(),
+        recipients: sent.recipients.join(", "),
+        status,
+        health,
+    }
+}
+
+/// Whether the process recorded in a pid file runs
+fn process_check(name: &str, pid_file: &Path) -> Check {
+    match supervisor::recorded_running(pid_file) {
+        Ok(true) => Check::new(
+            name,
+            Health::Ok,
+            format!("running ({})", pid_file.display()),
+        ),
+        Ok(false) if pid_file.exists() => Check::new(
+            name,
+            Health::Fail,
+            format!("exited, {} left behind", pid_file.display()),
+        ),
+        Ok(false) => Check::new(name, Health::Warn, "not started by this tool"),
+        Err(e) => Check::new(name, Health::Fail, e.to_string()),
+    }
+}
+
+/// An account of the address book written by `addresses`
+#[derive(Serialize)]
+struct AddressEntry {
+    name: String,
+    address: String,
+    lock_args: String,
+    lock_hash: String,
+}
+
+/// `addresses`: print the address book of the source and every node account and write
+/// it to a JSON file, so tooling can refer to accounts without their keys
+fn addresses(config: &Config, args: &AddressesArgs) -> Result<()> {
+    let network = config.network_preset()?.address_network;
+    let mut accounts = vec![(
+        "Source".to_string(),
+        get_lock_script_from_private_key(&config.source_private_key)?,
+    )];
+    for node in &config.nodes {
+        accounts.push((
+            node.name.clone(),
+            resolve_lock_script(&node.account, network)?,
+        ));
+    }
+    let entries: Vec<AddressEntry> = accounts
+        .into_iter()
+        .map(|(name, lock_script)| {
+            let lock_script = PackedScript::from(lock_script);
+            let lock_hash: H256 = lock_script.calc_script_hash().unpack();
+            AddressEntry {
+                name,
+                address: Address::new(network, AddressPayload::from(lock_script.clone()), true)
+                    .to_string(),
+                lock_args: format!("0x{}", hex::encode(lock_script.args().raw_data())),
+                lock_hash: format!("{:#x}", lock_hash),
+            }
+        })
+        .collect();
+    for entry in &entries {
+        println!(
+            "{}: {}, args = {}, lock hash = {}",
+            entry.name, entry.address, entry.lock_args, entry.lock_hash
+        );
+    }
+    fs::write(&args.out, serde_json::to_string_pretty(&entries).unwrap())
+        .map_err(Error::io(format!("Failed to write {}", args.out.display())))?;
+    info!("Wrote {}", args.out.display());
+    Ok(())
+}
+
+/// Whether the source account holds the CKB and tokens the nodes receive. A shortfall
+/// of a token the source owns only warns, it can issue more.
+fn source_balance_checks(
+    config: &Config,
+    preset: &NetworkPreset,
+    client: &RetryingClient,
+    source_lock: &Script,
+) -> Result<Vec<Check>> {
+    let mut checks = Vec::new();
+    let need = config
+        .nodes
+        .iter()
+        .map(|node| config.ckb_amount(node).map(u128::from))
+        .sum::<Result<u128>>()?;
+    checks.push(match rpc::block_on(get_ckb_balance(client, source_lock)) {
+        Ok(have) => Check::new(
+            "Source CKB",
+            if u128::from(have) >= need {
+                Health::Ok
+            } else {
+                Health::Fail
+            },
+            format!(
+                "{} CKB, the nodes receive {}",
+                format_amount(have.into(), CKB_DECIMALS),
+                format_amount(need, CKB_DECIMALS)
+            ),
+        ),
+        Err(e) => Check::new("Source CKB", Health::Fail, e.to_string()),
+    });
+    let owner_args: H256 = build_packed_lock_script(&config.source_private_key)?
+        .calc_script_hash()
+        .unpack();
+    for token in config.tokens(preset)? {
+        let component = format!("Source {}", token.name);
+        let need = config
+            .nodes
+            .iter()
+            .map(|node| config.token_amount(node, &token))
+            .sum::<Result<u128>>()?;
+        let have = match rpc::block_on(find_sudt_cells(client, source_lock, &token.script)) {
+            Ok(cells) => cells
+                .iter()
+                .map(|c| parse_sudt_amount(c.output_data.as_bytes()))
+                .sum::<u128>(),
+            Err(e) => {
+                checks.push(Check::new(component, Health::Fail, e.to_string()));
+                continue;
+            }
+        };
+        let mut detail = format!(
+            "{} {}, the nodes receive {}",
+            format_amount(have, token.decimals),
+            token.name,
+            format_amount(need, token.decimals)
+        );
+        let health = if have >= need {
+            Health::Ok
+        } else if token.script.args == owner_args.as_bytes() {
+            detail.push_str(", the source owns it to issue more");
+            Health::Warn
+        } else {
+            Health::Fail
+        };
+        checks.push(Check::new(component, health, detail));
+    }
+    Ok(checks)
+}
+
+/// `smoke-test`: pay an invoice through the channels, with the `[smoke_test]` payment
+/// of the config file overridden by the arguments
+fn smoke_test(config: &Config, args: &SmokeTestArgs) -> Result<()> {
+    let mut test = config.smoke_test.clone();
+    if let Some(from) = &args.from {
+        test.from = from.clone();
+    }
+    if let Some(to) = &args.to {
+        test.to = to.clone();
+    }
+    if let Some(amount) = &args.amount {
+        test.amount = AmountConfig::Text(amount.clone());
+    }
+    if let Some(token) = &args.token {
+        test.token = Some(token.clone());
+    }
+    let preset = config.network_preset()?;
+    let tokens = config.tokens(&preset)?;
+    let payment = config.payment(&test, &tokens)?;
+    fiber::smoke_test(
+        &payment,
+        preset.fiber_currency,
+        &HttpSettings::from_env()?,
+        &Deadline::from_env()?,
+    )
+    .map(|_| ())
+}
+
+/// `load`: make the `[load]` payments of the config file, overridden by the arguments,
+/// and print their throughput and latency
+fn load(config: &Config, args: &LoadArgs) -> Result<()> {
+    let mut load = config.load.clone();
+    if let Some(from) = &args.from {
+        load.from = from.clone();
+    }
+    if let Some(to) = &args.to {
+        load.to = to.clone();
+    }
+    if let Some(token) = &args.token {
+        load.token = Some(token.clone());
+    }
+    if let Some(value) = args.amount {
+        load.amount = Distribution::Fixed { value };
+    }
+    if args.arrival.is_some() || args.rate.is_some() || args.burst_size.is_some() {
+        let (process, rate, size) = match load.arrival {
+            Arrival::Constant { rate } => (ArrivalProcess::Constant, rate, None),
+            Arrival::Poisson { rate } => (ArrivalProcess::Poisson, rate, None),
+            Arrival::Burst { rate, size } => (ArrivalProcess::Burst, rate, Some(size)),
+        };
+        let rate = args.rate.unwrap_or(rate);
+        load.arrival = match args.arrival.unwrap_or(process) {
+            ArrivalProcess::Constant => Arrival::Constant { rate },
+            ArrivalProcess::Poisson => Arrival::Poisson { rate },
+            ArrivalProcess::Burst => Arrival::Burst {
+                rate,
+                size: args.burst_size.or(size).unwrap_or(DEFAULT_BURST_SIZE),
+            },
+        };
+    }
+    if let Some(secs) = args.warm_up_secs {
+        load.warm_up_secs = secs;
+    }
+    if let Some(secs) = args.duration_secs {
+        load.duration_secs = secs;
+    }
+    if let Some(seed) = args.seed {
+        load.seed = Some(seed);
+    }
+    let preset = config.network_preset()?;
+    let tokens = config.tokens(&preset)?;
+    let plan = config.load_plan(&load, &tokens)?;
+    let summary = load::run(
+        &plan,
+        preset.fiber_currency,
+        &HttpSettings::from_env()?,
+        &Deadline::from_env()?,
+    )?;
+    match args.format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&summary).unwrap()),
+        OutputFormat::Text => {
+            let rows = summary.rows();
+            let rows: Vec<[&str; 2]> = rows.iter().map(|[a, b]| [a.as_str(), b.as_str()]).collect();
+            print_table(&["Load", "Result"], &rows);
+        }
+    }
+    Ok(())
+}
+
+/// `scenario`: make the `[[scenario]]` payments, or the `--steps` among them, and print
+/// each step's expected and observed outcome, failing when any step didn't pass
+fn scenario(config: &Config, args: &ScenarioArgs) -> Result<()> {
+    let preset = config.network_preset()?;
+    let tokens = config.tokens(&preset)?;
+    let mut steps = config.scenario_steps(&tokens)?;
+    if !args.steps.is_empty() {
+        for name in &args.steps {
+            if !steps
+                .iter()
+                .any(|step| step.name.eq_ignore_ascii_case(name))
+            {
+                return Err(Error::Config(format!(
+                    "No scenario step is called {:?}",
+                    name
+                )));
+            }
+        }
+        steps.retain(|step| {
+            args.steps
+                .iter()
+                .any(|name| step.name.eq_ignore_ascii_case(name))
+        });
+    }
+    if steps.is_empty() {
+        return Err(Error::Config(
+            "The config file lists no [[scenario]] steps".to_string(),
+        ));
+    }
+    let nodes: Vec<(&str, &str)> = config
+        .nodes
+        .iter()
+        .filter_map(|node| Some((node.name.as_str(), node.fiber_rpc_url.as_deref()?)))
+        .collect();
+    let http_settings = HttpSettings::from_env()?;
+    let deadline = Deadline::from_env()?;
+    let mut involved: Vec<(&str, &str)> = Vec::new();
+    for step in &steps {
+        for node in [(step.from, step.from_rpc_url), (step.to, step.to_rpc_url)] {
+            if !involved.contains(&node) {
+                involved.push(node);
+            }
+        }
+    }
+    fiber::wait_for_nodes(&involved, &http_settings, &deadline)?;
+    let outcomes = scenario::run(
+        &steps,
+        &nodes,
+        preset.fiber_currency,
+        &http_settings,
+        &deadline,
+    )?;
+    match args.format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&outcomes).unwrap()),
+        OutputFormat::Text => {
+            let rows: Vec<[&str; 5]> = outcomes
+                .iter()
+                .map(|o| {
+                    [
+                        o.step.as_str(),
+                        o.kind,
+                        &o.expected,
+                        &o.observed,
+                        if o.passed { "pass" } else { "FAIL" },
+                    ]
+                })
+                .collect();
+            print_table(&["Step", "Kind", "Expected", "Observed", "Result"], &rows);
+        }
+    }
+    let failed: Vec<&str> = outcomes
+        .iter()
+        .filter(|o| !o.passed)
+        .map(|o| o.step.as_str())
+        .collect();
+    if !failed.is_empty() {
+        return Err(Error::Fiber(format!(
+            "Scenario steps failed: {}",
+            failed.join(", ")
+        )));
+    }
+    info!("All {} scenario steps passed", outcomes.len());
+    Ok(())
+}
+
+/// `invoice new`: create an invoice on a node and print it
+fn new_invoice(config: &Config, args: &NewInvoiceArgs) -> Result<()> {
+    let preset = config.network_preset()?;
+    let tokens = config.tokens(&preset)?;
+    let (node, rpc_url) = config.fiber_node(&args.node, "the invoice")?;
+    let token = if args.asset.eq_ignore_ascii_case("CKB") {
+        None
+    } else {
+        Some(config::find_token(&tokens, &args.asset)?)
+    };
+    let (asset, decimals) = token.map_or(("CKB", CKB_DECIMALS), |token| {
+        (token.name.as_str(), token.decimals)
+    });
+    let amount = parse_amount(&args.amount, asset, decimals)?;
+    let client = fiber::FiberClient::new(rpc_url, &HttpSettings::from_env()?)?;
+    let (invoice, payment_hash) = fiber::create_invoice(
+        &client,
+        amount,
+        preset.fiber_currency,
+        token
+            .map(|token| get_sudt_type_script(&token.script))
+            .as_ref(),
+        &args.description,
+        None,
+    )?;
+    info!(
+        "{} issued an invoice over {} {}, payment hash {}",
+        node,
+        format_amount(amount, decimals),
+        asset,
+        payment_hash
+    );
+    println!("{}", invoice);
+    Ok(())
+}
+
+/// `pay`: pay an invoice from a node and print the payment hash once it succeeded
+fn pay(config: &Config, args: &PayArgs) -> Result<()> {
+    let (_, rpc_url) = config.fiber_node(&args.node, "the payment")?;
+    let client = fiber::FiberClient::new(rpc_url, &HttpSettings::from_env()?)?;
+    let payment_hash = fiber::pay(&client, &args.invoice, &Deadline::from_env()?)?;
+    println!("{}", payment_hash);
+    Ok(())
+}
+
+/// `channels`: print the channels of the configured Fiber nodes, one row per channel
+/// even where both ends are configured nodes
+fn channels(config: &Config, args: &ChannelsArgs) -> Result<()> {
+    let nodes: Vec<(&str, &str)> = config
+        .nodes
+        .iter()
+        .filter_map(|node| Some((node.name.as_str(), node.fiber_rpc_url.as_deref()?)))
+        .collect();
+    if nodes.is_empty() {
+        return Err(Error::UnknownNode(
+            "No node has a fiber_rpc_url to list channels of".to_string(),
+        ));
+    }
+    let tokens = config.tokens(&config.network_preset()?)?;
+    let summary = fiber::channel_summary(&nodes, &tokens, &HttpSettings::from_env()?)?;
+    match args.format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&summary).unwrap()),
+        OutputFormat::Text => {
+            let header = [
+                "Channel", "Node", "Peer", "State", "Local", "Remote", "Asset",
+            ];
+            let rows: Vec<[&str; 7]> = summary
+                .iter()
+                .map(|c| {
+                    [
+                        c.channel_id.as_str(),
+                        &c.node,
+                        &c.peer,
+                        &c.state,
+                        &c.local_balance,
+                        &c.remote_balance,
+                        &c.asset,
+                    ]
+                })
+                .collect();
+            print_table(&header, &rows);
+            info!("{} channels", summary.len());
+        }
+    }
+    Ok(())
+}
+
+/// `shutdown`: close the channels of the configured Fiber nodes to their sighash
+/// locks and print the nodes' balances once the closings committed
+fn shutdown(config: &Config) -> Result<()> {
+    let http_settings = HttpSettings::from_env()?;
+    let (preset, client) = connect(config, &http_settings)?;
+    let mut nodes = Vec::new();
+    let mut accounts = Vec::new();
+    for node in &config.nodes {
+        let Some(rpc_url) = node.fiber_rpc_url.as_deref() else {
+            continue;
+        };
+        let lock_script = resolve_lock_script(&node.account, preset.address_network)?;
+        nodes.push(fiber::ClosingNode {
+            name: &node.name,
+            rpc_url,
+            close_script: lock_script.clone(),
+        });
+        accounts.push((node.name.clone(), lock_script));
+    }
+    if nodes.is_empty() {
+        return Err(Error::UnknownNode(
+            "No node has a fiber_rpc_url to close channels of".to_string(),
+        ));
+    }
+    let failed = fiber::close_channels(
+        &nodes,
+        config.fee_rate,
+        &http_settings,
+        &Deadline::from_env()?,
+    )?;
+    let tokens = config.tokens(&preset)?;
+    print_balance_table(
+        &tokens,
+        &account_balances(&client, &tokens, &accounts, preset.address_network)?,
+    );
+    if !failed.is_empty() {
+        return Err(Error::Fiber(format!(
+            "Failed to close channels {}",
+            failed.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// `chain up`: run a CKB dev node with the block assembler paying the source account,
+/// fund the nodes from it once it answers RPC and keep it running
+fn chain_up(config: &mut Config, args: &ChainUpArgs) -> Result<()> {
+    metrics::serve_from_env();
+    let mut chain = start_chain(config, &args.chain)?;
+    if !args.no_setup {
+        setup(config, &args.setup)?;
+    }
+    info!(
+        "CKB dev node running at {}, interrupt to stop it",
+        chain.rpc_url()
+    );
+    sampling_metrics(config, || chain.supervise())
+}
+
+/// `chain spec`: write a dev chain spec issuing the genesis CKB and sUDT to a source
+/// key, generating the key unless one is given or already written
+fn chain_spec(args: &ChainSpecArgs) -> Result<()> {
+    let source_key = match &args.source_key {
+        Some(key) => resolve_private_key(key)?,
+        None if args.key_out.exists() => {
+            info!("Using the source key in {}", args.key_out.display());
+            resolve_private_key(&args.key_out.to_string_lossy())?
+        }
+        None => {
+            let key = write_new_key(&args.key_out)?;
+            info!("Generated a source key in {}", args.key_out.display());
+            key
+        }
+    };
+    let supply = match &args.sudt_supply {
+        Some(supply) => parse_amount(supply, network::SUDT_TOKEN_NAME, 0)?,
+        None => config::DEFAULT_SUDT_SUPPLY,
+    };
+    if supply == 0 {
+        return Err(Error::InvalidAmount(
+            "sUDT supply must be positive".to_string(),
+        ));
+    }
+
+    let contracts_dir = fs::canonicalize(&args.contracts).map_err(|e| {
+        Error::Chain(format!(
+            "Contracts directory {}: {}",
+            args.contracts.display(),
+            e
+        ))
+    })?;
+    let sudt_path = contracts_dir.join("simple_udt");
+    let sudt_code = fs::read(&sudt_path)
+        .map_err(|e| Error::Chain(format!("Failed to read {}: {}", sudt_path.display(), e)))?;
+    let sudt_code_hash = H256(blake2b_256(&sudt_code));
+    let preset = network::devnet();
+    if sudt_code_hash != preset.sudt.code_hash {
+        warn!(
+            "{} is not the devnet sUDT, set SUDT_CODE_HASH={:#x} to use it",
+            sudt_path.display(),
+            sudt_code_hash
+        );
+    }
+
+    let source_lock = get_lock_script_from_private_key(&source_key)?;
+    let sudt_args: H256 = build_packed_lock_script(&source_key)?
+        .calc_script_hash()
+        .unpack();
+    let spec = chain::DevSpec {
+        contracts_dir: &contracts_dir,
+        source_lock_args: source_lock.args.as_bytes(),
+        sudt_args: &sudt_args,
+        sudt_code_hash: &sudt_code_hash,
+        sudt_supply: supply,
+    };
+    write_generated(&args.out, &spec.render(), args.force)?;
+    // Points at the key rather than printing it, the log may be collected
+    let source_reference = match &args.source_key {
+        Some(key) if Path::new(key).is_file() => key.clone(),
+        Some(_) => "<the --source-key given>".to_string(),
+        None => args.key_out.display().to_string(),
+    };
+    info!(
+        "Genesis sUDT is output {} of the cellbase. Run against the chain with \
+         SOURCE_PRIVATE_KEY={} SUDT_ARGS={:#x}",
+        chain::SUDT_GENESIS_OUTPUT,
+        source_reference,
+        sudt_args
+    );
+    Ok(())
+}
+
+/// `generate-blocks`: mine blocks on a dev chain, e.g. to commit transactions when
+/// nothing else mines
+fn generate_blocks(config: &Config, args: &GenerateBlocksArgs) -> Result<()> {
+    let http_settings = HttpSettings::from_env()?;
+    let (_, client) = connect(config, &http_settings)?;
+    for _ in 0..args.count {
+        let block_hash = rpc::block_on(client.generate_block())
+            .map_err(Error::rpc("Failed to generate a block"))?;
+        debug!("Generated block {:#x}", block_hash);
+    }
+    info!(
+        "Generated {} block(s), tip is block {}",
+        args.count,
+        rpc::block_on(get_tip_block_number(&client))?
+    );
+    Ok(())
+}
+
+/// `hd new`: write a new random mnemonic for MNEMONIC_FILE
+fn hd_new(args: &HdNewArgs) -> Result<()> {
+    let mnemonic = hd::generate_mnemonic(args.words)?;
+    write_secret(&args.out, &mnemonic.to_string()).map_err(|e| {
+        Error::InvalidKey(format!(
+            "Failed to write mnemonic file {}: {}",
+            args.out.display(),
+            e
+        ))
+    })?;
+    info!(
+        "Wrote a {}-word mnemonic to {}. Set MNEMONIC_FILE={} and give keys as derivation \
+         paths, e.g. SOURCE_PRIVATE_KEY=\"{}/0\"",
+        args.words,
+        args.out.display(),
+        args.out.display(),
+        hd::account_path(0)
+    );
+    Ok(())
+}
+
+/// `hd addresses`: print the first keys of an account, to pick paths for the config
+fn hd_addresses(config: &Config, args: &HdAddressesArgs) -> Result<()> {
+    let network = config.network_preset()?.address_network;
+    for index in 0..args.count {
+        let path = format!("{}/{}", hd::account_path(args.account), index);
+        let lock_script = get_lock_script_from_private_key(&hd::derive_from_env(&path)?)?;
+        let address = Address::new(
+            network,
+            AddressPayload::from(PackedScript::from(lock_script.clone())),
+            true,
+        );
+        println!(
+            "{}  {}  0x{}",
+            path,
+            address,
+            hex::encode(lock_script.args.as_bytes())
+        );
+    }
+    Ok(())
+}
+
+/// `hd export`: print the key at a derivation path, or write it to a key file, e.g. for
+/// tooling that takes key files
+fn hd_export(args: &HdExportArgs) -> Result<()> {
+    if !hd::is_path(&args.path) {
+        return Err(Error::InvalidKey(format!(
+            "{} is not a derivation path, e.g. {}/0",
+            args.path,
+            hd::account_path(0)
+        )));
+    }
+    let key = hd::derive_from_env(&args.path)?;
+    let Some(out) = &args.out else {
+        println!("{}", key);
+        return Ok(());
+    };
+    if out.exists() {
+        if !args.force {
+            return Err(Error::InvalidKey(format!(
+                "{} exists, pass --force to replace it",
+                out.display()
+            )));
+        }
+        fs::remove_file(out).map_err(|e| {
+            Error::InvalidKey(format!("Failed to replace {}: {}", out.display(), e))
+        })?;
+    }
+    write_secret(out, &key).map_err(|e| {
+        Error::InvalidKey(format!("Failed to write key file {}: {}", out.display(), e))
+    })?;
+    info!("Wrote the key at {} to {}", args.path, out.display());
+    Ok(())
+}
+
+/// `inspect-key`: check that a key is a valid secp256k1 private key and print what it
+/// gives, to compare with the address a node was funded at or the peer id it runs as
+fn inspect_key(config: &Config, args: &InspectKeyArgs) -> Result<()> {
+    if ledger::is_ledger(&args.key) || remote_signer::is_remote(&args.key) {
+        return Err(Error::InvalidKey(format!(
+            "{} has no private key to inspect",
+            args.key
+        )));
+    }
+    // Fiber nodes keep their `fiber/sk` as raw bytes rather than hex
+    let raw_key = fs::read(&args.key)
+        .ok()
+        .filter(|content| content.len() == 32 && hex::decode(content.trim_ascii()).is_err());
+    let (secret_key, kind) = match raw_key {
+        Some(raw_key) => (raw_key, "binary key file, as a Fiber node's fiber/sk"),
+        None => (
+            hex::decode(resolve_private_key(&args.key)?)
+                .map_err(|e| Error::InvalidKey(format!("Invalid private key hex: {}", e)))?,
+            "hex key",
+        ),
+    };
+    let secret = SecretKey::from_slice(&secret_key).map_err(|e| {
+        Error::InvalidKey(format!(
+            "{} is not a valid secp256k1 private key: {}",
+            args.key, e
+        ))
+    })?;
+    let public_key = PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret);
+    let lock_script = get_lock_script_from_private_key(&hex::encode(&secret_key))?;
+    let packed_lock = PackedScript::from(lock_script.clone());
+    let lock_hash: H256 = packed_lock.calc_script_hash().unpack();
+    let preset = config.network_preset()?;
+    let address = Address::new(
+        preset.address_network,
+        AddressPayload::from(packed_lock),
+        true,
+    );
+    println!("Key: valid secp256k1 private key ({})", kind);
+    println!("Public key: 0x{}", hex::encode(public_key.serialize()));
+    println!("Blake160: 0x{}", hex::encode(lock_script.args.as_bytes()));
+    println!(
+        "Lock script: code hash {:#x}, hash type type, args 0x{}",
+        lock_script.code_hash,
+        hex::encode(lock_script.args.as_bytes())
+    );
+    println!("Lock hash: {:#x}", lock_hash);
+    println!("Address: {}", address);
+    if let Some(omnilock) = &preset.omnilock {
+        // Auth flag 0x00 (secp256k1 blake160 of the key) and no omnilock modes
+        let mut args = vec![0u8];
+        args.extend(lock_script.args.as_bytes());
+        args.push(0);
+        let omnilock_script = Script {
+            code_hash: omnilock.code_hash.clone(),
+            hash_type: omnilock.hash_type,
+            args: JsonBytes::from_vec(args),
+        };
+        let address = Address::new(
+            preset.address_network,
+            AddressPayload::from(PackedScript::from(omnilock_script)),
+            true,
+        );
+        println!("Omnilock address: {}", address);
+    }
+    println!(
+        "Fiber peer id, as a node's fiber/sk: {}",
+        fiber::peer_id(&secret_key)?
+    );
+    Ok(())
+}
+
+/// `keygen`: write a new random key for each of `--count` new nodes and append the
+/// nodes, with the Fiber node settings of the built-in ones, to the config file
+fn keygen(config: &Config, config_path: &Path, args: &KeygenArgs) -> Result<()> {
+    if !config_path.exists() {
+        return Err(Error::Declined(format!(
+            "{} doesn't exist, copy fiber-demo.example.toml there first so the new nodes \
+             join the configured ones",
+            config_path.display()
+        )));
+    }
+    let label = args.label.to_lowercase();
+    let taken = |dir: &str, key_file: &Path| {
+        key_file.exists()
+            || config
+                .nodes
+                .iter()
+                .any(|node| node.name.eq_ignore_ascii_case(dir))
+    };
+    // Numbered after the configured nodes of the label, e.g. node4 after node1..node3
+    let mut number = config
+        .nodes
+        .iter()
+        .filter_map(|node| node.name.to_lowercase().strip_prefix(&label)?.parse().ok())
+        .max()
+        .unwrap_or(0u32);
+    let mut nodes = String::new();
+    for _ in 0..args.count {
+        let (dir, key_file) = loop {
+            number += 1;
+            let dir = format!("{}{}", label, number);
+            let key_file = args.keys_dir.join(format!("{}-key", dir));
+            if !taken(&dir, &key_file) {
+                break (dir, key_file);
+            }
+        };
+        let key = write_new_key(&key_file)?;
+        let lock_script = get_lock_script_from_private_key(&key)?;
+        let mut chars = dir.chars();
+        let name: String = chars
+            .next()
+            .into_iter()
+            .flat_map(char::to_uppercase)
+            .chain(chars)
+            .collect();
+        info!(
+            "Generated a key for {} in {}, lock args 0x{}",
+            name,
+            key_file.display(),
+            hex::encode(lock_script.args.as_bytes())
+        );
+        nodes.push_str(&format!(
+            "\n[[nodes]]\nname = \"{name}\"\nkey_file = \"{}\"\n\
+             fiber_rpc_url = \"http://fiber-{dir}:10000\"\n\
+             [nodes.fiber]\ndir = \"{dir}\"\nname = \"fiber-{dir}\"\n",
+            key_file.display()
+        ));
+    }
+    fs::OpenOptions::new()
+        .append(true)
+        .open(config_path)
+        .and_then(|mut file| file.write_all(nodes.as_bytes()))
+        .map_err(Error::io(format!(
+            "Failed to write {}",
+            config_path.display()
+        )))?;
+    info!(
+        "Added {} node(s) to {}, run gen-config to write their Fiber configs",
+        args.count,
+        config_path.display()
+    );
+    Ok(())
+}
+
+/// `rotate-key`: write a new random key next to a node's key file, sweep the node's CKB
+/// and tokens to it, then swap the key files, keeping the old one as `<key file>.old`,
+/// and replace the key in the node's generated Fiber config. A new key left by an
+/// interrupted rotation is reused, so running it again finishes the rotation.
+fn rotate_key(config: &Config, args: &RotateKeyArgs) -> Result<()> {
+    let deadline = Deadline::from_env()?;
+    let node = config
+        .nodes
+        .iter()
+        .find(|node| node.name.eq_ignore_ascii_case(&args.node))
+        .ok_or_else(|| Error::UnknownNode(format!("Unknown node {:?}", args.node)))?;
+    let key_file = Path::new(&node.account);
+    let hex_key = node.account.trim_start_matches("0x");
+    if Address::from_str(&node.account).is_ok()
+        || hex_key.len() == 64 && hex::decode(hex_key).is_ok()
+        || hd::is_path(&node.account)
+        || ledger::is_ledger(&node.account)
+        || remote_signer::is_remote(&node.account)
+    {
+        return Err(Error::InvalidKey(format!(
+            "{}'s account is not a key file, only key files can be rotated",
+            node.name
+        )));
+    }
+    let old_key = resolve_private_key(&node.account)?;
+    let mut new_key_file = key_file.as_os_str().to_owned();
+    new_key_file.push(".new");
+    let new_key_file = PathBuf::from(new_key_file);
+    let new_key = if new_key_file.exists() {
+        info!(
+            "Resuming with the new key in {} from an earlier rotation",
+            new_key_file.display()
+        );
+        read_private_key(&new_key_file.to_string_lossy())?
+    } else {
+        write_new_key(&new_key_file)?
+    };
+    let new_lock = get_lock_script_from_private_key(&new_key)?;
+    info!(
+        "Rotating {}'s key to lock args 0x{}",
+        node.name,
+        hex::encode(new_lock.args.as_bytes())
+    );
+
+    let http_settings = HttpSettings::from_env()?;
+    let fee_rate = config.fee_rate;
+    let locker = CellLocker::from_env(false)?;
+    let (preset, client) = connect(config, &http_settings)?;
+    let cell_deps = CellDepResolver::new(&preset);
+    let tokens = config.tokens(&preset)?;
+    let old_lock = get_lock_script_from_private_key(&old_key)?;
+    if get_total_capacity(&client, &old_lock)? == 0 {
+        info!("{} holds no cells, nothing to sweep", node.name);
+    } else {
+        let mut build_rate = fee_rate;
+        let tx = loop {
+            let plan = loop {
+                let plan = build_sweep(
+                    &client, &locker, &cell_deps, &old_key, &new_lock, &tokens, build_rate,
+                )?;
+                if locker.reserve(&plan.input_cells)? {
+                    break plan;
+                }
+                warn!("Input cells were reserved by another run meanwhile, rebuilding the sweep");
+            };
+            let result = (|| {
+                if !args.yes {
+                    confirm_plan(&plan)?;
+                }
+                send_transfer(&client, &old_key, &plan, fee_rate)
+            })();
+            match result {
+                Ok(Sent::Broadcast(tx)) => break tx,
+                Ok(Sent::Underpaid(rate)) => {
+                    locker.release(&plan.input_cells)?;
+                    build_rate = rate;
+                }
+                Err(e) => {
+                    if let Err(release_error) = locker.release(&plan.input_cells) {
+                        warn!("{}", release_error);
+                    }
+                    return Err(e);
+                }
+            }
+        };
+        rpc::block_on(wait_for_transfer(
+            &client,
+            &preset,
+            &tx.hash().unpack(),
+            &deadline,
+        ))?;
+        let left = get_total_capacity(&client, &old_lock)?;
+        if left > 0 {
+            warn!(
+                "{} CKB in cells of other types stay with the old key",
+                format_amount(left.into(), CKB_DECIMALS)
+            );
+        }
+    }
+
+    let mut old_key_file = key_file.as_os_str().to_owned();
+    old_key_file.push(".old");
+    let old_key_file = PathBuf::from(old_key_file);
+    fs::rename(key_file, &old_key_file)
+        .and_then(|()| fs::rename(&new_key_file, key_file))
+        .map_err(|e| {
+            Error::InvalidKey(format!(
+                "Failed to replace {} with {}: {}",
+                key_file.display(),
+                new_key_file.display(),
+                e
+            ))
+        })?;
+    info!(
+        "Replaced {}, the old key is kept in {}",
+        key_file.display(),
+        old_key_file.display()
+    );
+    if let Some(fiber) = &node.fiber {
+        let path = args.nodes_dir.join(&fiber.dir).join("ckb/key");
+        if path.exists() {
+            fs::write(&path, format!("{}\n", new_key))
+                .map_err(Error::io(format!("Failed to write {}", path.display())))?;
+            info!(
+                "Replaced {}, restart the node to use the new key; channels it opened \
+                 before pay out to the old key when closed",
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// `keystore import`: encrypt a key into a new ckb-cli compatible keystore, e.g. to
+/// give the source key as SOURCE_PRIVATE_KEY=ckb-keys/source.json
+fn keystore_import(config: &Config, args: &KeystoreImportArgs) -> Result<()> {
+    let key = args.key.as_deref().unwrap_or(&config.source_private_key);
+    if ledger::is_ledger(key) {
+        return Err(Error::InvalidKey(
+            "The key of a Ledger account can't be exported".to_string(),
+        ));
+    }
+    if remote_signer::is_remote(key) {
+        return Err(Error::InvalidKey(
+            "The key of a remote account can't be exported".to_string(),
+        ));
+    }
+    let key = resolve_private_key(key)?;
+    let key_bytes = hex::decode(&key)
+        .map_err(|e| Error::InvalidKey(format!("Invalid private key hex: {}", e)))?;
+    let secret_key = SecretKey::from_slice(&key_bytes)
+        .map_err(|e| Error::InvalidKey(format!("Invalid private key: {}", e)))?;
+    if args.out.exists() && !args.force {
+        return Err(Error::InvalidKey(format!(
+            "{} exists, pass --force to replace it",
+            args.out.display()
+        )));
+    }
+    let out = args.out.display().to_string();
+    let password = keystore::password(&out, true)?;
+    let content = keystore::encrypt(&secret_key, &password, args.light)?;
+    if args.out.exists() {
+        fs::remove_file(&args.out)
+            .map_err(|e| Error::InvalidKey(format!("Failed to replace {}: {}", out, e)))?;
+    }
+    write_secret(&args.out, &content)
+        .map_err(|e| Error::InvalidKey(format!("Failed to write keystore {}: {}", out, e)))?;
+    let lock_script = get_lock_script_from_private_key(&key)?;
+    info!(
+        "Wrote a keystore with lock args 0x{} to {}",
+        hex::encode(lock_script.args.as_bytes()),
+        out
+    );
+    Ok(())
+}
+
+/// `reset`: stop the chain and Fiber nodes run by this tool and delete their state and
+/// this tool's, after listing it and asking for confirmation unless --yes
+fn reset(config: &Config, args: &ResetArgs) -> Result<()> {
+    let pid_files = recorded_pid_files(config, &args.dirs);
+    let mut doomed = vec![args.dirs.data_dir.clone()];
+    for node in fnn_nodes(config, &args.dirs.nodes_dir) {
+        doomed.push(node.dir.join(FNN_STORE_DIR));
+        let log_dir = args.log_dir.as_deref().unwrap_or(&node.dir);
+        doomed.push(log_dir.join(node.log_file()));
+    }
+    doomed.extend(tool_state_files()?);
+    doomed.retain(|path| path.exists());
+    if pid_files.is_empty() && doomed.is_empty() {
+        info!("Nothing to reset");
+        return Ok(());
+    }
+
+    if !args.yes {
+        println!("\nReset:");
+        for path in &pid_files {
+            println!("  stop the process recorded in {}", path.display());
+        }
+        for path in &doomed {
+            println!("  delete {}", path.display());
+        }
+        if !io::stdin().is_terminal() {
+            return Err(Error::Declined(
+                "Not resetting without confirmation: stdin is not a terminal, pass --yes"
+                    .to_string(),
+            ));
+        }
+        if !ask_yes("Delete these? [y/N] ")? {
+            return Err(Error::Declined("Reset declined".to_string()));
+        }
+    }
+
+    // Stop everything before deleting, so nothing writes to a store being deleted
+    for path in &pid_files {
+        supervisor::stop_recorded(path)?;
+    }
+    for path in &doomed {
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        removed.map_err(|e| Error::Node(format!("Failed to delete {}: {}", path.display(), e)))?;
+        info!("Deleted {}", path.display());
+    }
+    info!("Reset done, the next run starts from a clean slate");
+    Ok(())
+}
+
+/// `snapshot save`: copy the state of the stopped chain and Fiber nodes, and this tool's,
+/// into a snapshot directory
+fn snapshot_save(config: &Config, args: &SnapshotSaveArgs) -> Result<()> {
+    ensure_stopped(config, &args.dirs)?;
+    let mut entries = vec![snapshot::Entry::new(
+        args.dirs.data_dir.clone(),
+        PathBuf::from("chain"),
+    )];
+    for node in fnn_nodes(config, &args.dirs.nodes_dir) {
+        let name = node.dir.file_name().unwrap_or(node.name.as_ref());
+        entries.push(snapshot::Entry::new(
+            node.dir.join(FNN_STORE_DIR),
+            Path::new("nodes").join(name).join(FNN_STORE_DIR),
+        ));
+    }
+    for path in tool_state_files()? {
+        let copy = Path::new("state").join(path.file_name().unwrap_or(path.as_os_str()));
+        entries.push(snapshot::Entry::new(path, copy));
+    }
+    let started = Instant::now();
+    let saved = snapshot::save(&args.dir, entries, args.force)?;
+    for entry in &saved {
+        if entry.present {
+            debug!("Saved {}", entry.path.display());
+        } else {
+            debug!("{} is absent, restoring deletes it", entry.path.display());
+        }
+    }
+    info!(
+        "Saved {} paths to {} in {:.1}s, `snapshot restore {}` brings them back",
+        saved.iter().filter(|entry| entry.present).count(),
+        args.dir.display(),
+        started.elapsed().as_secs_f64(),
+        args.dir.display()
+    );
+    Ok(())
+}
+
+/// `snapshot restore`: replace the state of the stopped chain and Fiber nodes, and this
+/// tool's, with a snapshot's, after listing it and asking for confirmation unless --yes
+fn snapshot_restore(config: &Config, args: &SnapshotRestoreArgs) -> Result<()> {
+    let entries = snapshot::entries(&args.dir)?;
+    ensure_stopped(config, &args.dirs)?;
+    if !args.yes {
+        println!("\nRestore from {}:", args.dir.display());
+        for entry in &entries {
+            let action = if entry.present { "replace" } else { "delete" };
+            println!("  {} {}", action, entry.path.display());
+        }
+        if !io::stdin().is_terminal() {
+            return Err(Error::Declined(
+                "Not restoring without confirmation: stdin is not a terminal, pass --yes"
+                    .to_string(),
+            ));
+        }
+        if !ask_yes("Restore these? [y/N] ")? {
+            return Err(Error::Declined("Restore declined".to_string()));
+        }
+    }
+    let started = Instant::now();
+    snapshot::restore(&args.dir, &entries)?;
+    info!(
+        "Restored {} paths from {} in {:.1}s",
+        entries.len(),
+        args.dir.display(),
+        started.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+/// Fail when a chain or Fiber node this tool started still runs, since their state
+/// changes under a copy
+fn ensure_stopped(config: &Config, dirs: &StateDirs) -> Result<()> {
+    for path in recorded_pid_files(config, dirs) {
+        if supervisor::recorded_running(&path)? {
+            return Err(Error::Snapshot(format!(
+                "A process recorded in {} still runs, stop the chain and the Fiber nodes \
+                 first",
+                path.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Pid files left by the chain and Fiber nodes started with these directories
+fn recorded_pid_files(config: &Config, dirs: &StateDirs) -> Vec<PathBuf> {
+    let mut pid_files = Vec::new();
+    if let Ok(entries) = fs::read_dir(&dirs.data_dir) {
+        pid_files.extend(
+            entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "pid")),
+        );
+    }
+    for node in fnn_nodes(config, &dirs.nodes_dir) {
+        pid_files.push(node.dir.join(supervisor::FNN_PID_FILE));
+    }
+    pid_files.retain(|path| path.exists());
+    pid_files
+}
+
+/// The Fiber node of every node with Fiber settings, in its directory under `nodes_dir`
+fn fnn_nodes(config: &Config, nodes_dir: &Path) -> Vec<supervisor::FnnNode> {
+    config
+        .nodes
+        .iter()
+        .filter_map(|node| {
+            let fiber = node.fiber.as_ref()?;
+            Some(supervisor::FnnNode {
+                name: node.name.clone(),
+                dir: nodes_dir.join(&fiber.dir),
+            })
+        })
+        .collect()
+}
+
+/// Files this tool keeps its own state in, e.g. the funding run's transactions
+fn tool_state_files() -> Result<Vec<PathBuf>> {
+    Ok(vec![
+        PathBuf::from(env::var("STATE_FILE").unwrap_or_else(|_| DEFAULT_STATE_FILE.to_string())),
+        CellLocker::from_env(false)?.path().to_path_buf(),
+    ])
+}
+
+/// Start a CKB dev node with the block assembler paying the source account, wait until
+/// it answers RPC and point the config at it
+fn start_chain(config: &mut Config, options: &ChainOptions) -> Result<chain::DevChain> {
+    if config.network != "devnet" {
+        return Err(Error::Chain(format!(
+            "A local chain is a devnet node, but the config selects {}",
+            config.network
+        )));
+    }
+    let http_settings = HttpSettings::from_env()?;
+    let source_lock = get_lock_script_from_private_key(&config.source_private_key)?;
+    let block_assembler_args = format!("0x{}", hex::encode(source_lock.args.as_bytes()));
+    let mut chain = chain::DevChain::start(
+        &chain::ChainSettings {
+            ckb_bin: &options.ckb,
+            spec: &options.spec,
+            data_dir: &options.data_dir,
+            rpc_port: options.rpc_port,
+            p2p_port: options.p2p_port,
+            miner: options.miner,
+            block_interval: Duration::from_millis(options.block_interval_ms),
+            block_assembler_args: &block_assembler_args,
+        },
+        &http_settings,
+    )?;
+    chain.wait_ready(&http_settings, &Deadline::from_env()?)?;
+    config.ckb_rpc_url = Some(chain.rpc_url().to_string());
+    Ok(chain)
+}
+
+/// `balances`: print the CKB and token balances of the given accounts, or of the source
+/// account and all configured nodes
+fn balances(config: &Config, args: &BalancesArgs) -> Result<()> {
+    let http_settings = HttpSettings::from_env()?;
+    let (preset, client) = connect(config, &http_settings)?;
+
+    let network = preset.address_network;
+    let accounts: Vec<(String, Script)> = if args.accounts.is_empty() {
+        configured_accounts(config, network)?
+    } else {
+        args.accounts
+            .iter()
+            .map(|a| Ok((a.clone(), resolve_lock_script(a, network)?)))
+            .collect::<Result<_>>()?
+    };
+
+    let tokens = config.tokens(&preset)?;
+    let balances = account_balances(&client, &tokens, &accounts, network)?;
+    match args.format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&balances).unwrap()),
+        OutputFormat::Text => print_balance_table(&tokens, &balances),
+    }
+    Ok(())
+}
+
+/// The source account followed by every configured node, by name
+fn configured_accounts(config: &Config, network: NetworkType) -> Result<Vec<(String, Script)>> {
+    let mut accounts = vec![(
+        "Source".to_string(),
+        get_lock_script_from_private_key(&config.source_private_key)?,
+    )];
+    for node in &config.nodes {
+        accounts.push((
+            node.name.clone(),
+            resolve_lock_script(&node.account, network)?,
+        ));
+    }
+    Ok(accounts)
+}
+
+/// The balances of one account, as `balances --format json` prints them
+#[derive(Serialize)]
+struct AccountBalance {
+    account: String,
+    address: String,
+    /// Capacity of the account's pure CKB cells, in CKB
+    ckb: String,
+    shannons: u64,
+    tokens: Vec<TokenBalance>,
+}
+
+#[derive(Serialize)]
+struct TokenBalance {
+    token: String,
+    /// In the token's human units
+    amount: String,
+    /// In base units, as a string since it may not fit in a JSON number
+    base_units: String,
+}
+
+impl AccountBalance {
+    /// The balances on one line, e.g. `CKB = 1000, sUDT = 5`
+    fn summary(&self) -> String {
+        let mut summary = format!("CKB = {}", self.ckb);
+        for token in &self.tokens {
+            summary.push_str(&format!(", {} = {}", token.token, token.amount));
+        }
+        summary
+    }
+}
+
+/// Look up the CKB and token balances of the accounts (name, lock script), several
+/// accounts at once
+fn account_balances(
+    client: &RetryingClient,
+    tokens: &[Token],
+    accounts: &[(String, Script)],
+    network: NetworkType,
+) -> Result<Vec<AccountBalance>> {
+    rpc::block_on(rpc::concurrently(accounts.iter().map(
+        |(name, lock_script)| async move {
+            let mut token_balances = Vec::new();
+            for token in tokens {
+                let balance: u128 = find_sudt_cells(client, lock_script, &token.script)
+                    .await?
+                    .iter()
+                    .map(|c| parse_sudt_amount(c.output_data.as_bytes()))
+                    .sum();
+                token_balances.push(TokenBalance {
+                    token: token.name.clone(),
+                    amount: format_amount(balance, token.decimals),
+                    base_units: balance.to_string(),
+                });
+            }
+            let shannons = get_ckb_balance(client, lock_script).await?;
+            Ok(AccountBalance {
+                account: name.clone(),
+                address: Address::new(
+                    network,
+                    AddressPayload::from(PackedScript::from(lock_script.clone())),
+                    true,
+                )
+                .to_string(),
+                ckb: format_amount(shannons.into(), CKB_DECIMALS),
+                shannons,
+                tokens: token_balances,
+            })
+        },
+    )))?
+    .into_iter()
+    .collect()
+}
+
+/// Print the balances as a table, one row per account and one column per token
+fn print_balance_table(tokens: &[Token], balances: &[AccountBalance]) {
+    let mut header = vec!["Account", "Address", "CKB"];
+    header.extend(tokens.iter().map(|token| token.name.as_str()));
+    let rows: Vec<Vec<&str>> = balances
+        .iter()
+        .map(|balance| {
+            let mut row = vec![
+                balance.account.as_str(),
+                balance.address.as_str(),
+                balance.ckb.as_str(),
+            ];
+            row.extend(balance.tokens.iter().map(|token| token.amount.as_str()));
+            row
+        })
+        .collect();
+    print_table(&header, &rows);
+}
+
+/// Print rows under a header, each column as wide as its widest cell
+fn print_table<'a, R: AsRef<[&'a str]>>(header: &[&'a str], rows: &[R]) {
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row.as_ref()[i].len())
+                .chain([header[i].len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    for row in [header].into_iter().chain(rows.iter().map(AsRef::as_ref)) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The devnet genesis key and its lock args
+    const DEV_KEY: &str = "d00c06bfd800d27397002dca6fb0993d5ba6399b4238b2f29ee9deb97593d2bc";
+    const DEV_LOCK_ARGS: &str = "c8328aabcd9b9e8e64fbc566c4385c3bdeb219d7";
+
+    fn sudt() -> SudtScript {
+        SudtScript {
+            code_hash: H256([7; 32]),
+            hash_type: ScriptHashType::Type,
+            args: vec![1; 32],
+            cell_dep: None,
+        }
+    }
+
+    #[test]
+    fn derives_the_sighash_lock_of_a_key() {
+        let lock = get_lock_script_from_private_key(DEV_KEY).unwrap();
+        assert_eq!(lock.code_hash, SIGHASH_TYPE_HASH);
+        assert_eq!(hex::encode(lock.args.as_bytes()), DEV_LOCK_ARGS);
+        assert!(matches!(
+            get_lock_script_from_private_key("00"),
+            Err(Error::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn minimal_outputs_hold_their_occupied_capacity() {
+        let lock = build_packed_lock_script(DEV_KEY).unwrap();
+        // 8 bytes of capacity, a 32-byte code hash, the hash type and 20 bytes of args
+        assert_eq!(
+            capacity_of(&min_ckb_output(lock.clone()).unwrap()),
+            61_00000000
+        );
+        // Plus the type script with 32 bytes of args and the 16-byte amount
+        assert_eq!(
+            capacity_of(&min_sudt_output(lock, &sudt()).unwrap()),
+            142_00000000
+        );
+    }
+
+    #[test]
+    fn fee_rounds_up_per_kilobyte() {
+        let lock = build_packed_lock_script(DEV_KEY).unwrap();
+        let tx = TransactionView::new_advanced_builder()
+            .output(min_ckb_output(lock).unwrap())
+            .output_data(ckb_types::packed::Bytes::default())
+            .build();
+        let size = tx.data().serialized_size_in_block() as u64;
+        assert_eq!(min_fee(&tx, 1000), size);
+        assert_eq!(min_fee(&tx, 1500), (size * 3).div_ceil(2));
+        assert_eq!(min_fee(&tx, 1), 1);
+        assert_eq!(min_fee(&tx, 0), 0);
+    }
+
+    #[test]
+    fn capacity_arithmetic_reports_shortfalls_and_overflows() {
+        assert_eq!(capacity_left(100, 40).unwrap(), 60);
+        assert!(matches!(
+            capacity_left(40, 100),
+            Err(Error::InsufficientCapacity {
+                have: 40,
+                need: 100
+            })
+        ));
+        assert_eq!(total_capacity([1, 2, 3]).unwrap(), 6);
+        assert!(total_capacity([u64::MAX, 1]).is_err());
+    }
+
+    #[test]
+    fn sudt_amounts_are_little_endian_u128() {
+        assert_eq!(parse_sudt_amount(&encode_sudt_amount(1_050)), 1_050);
+        assert_eq!(parse_sudt_amount(&encode_sudt_amount(u128::MAX)), u128::MAX);
+        // Extra data after the amount is ignored, short data holds no amount
+        let mut data = encode_sudt_amount(5);
+        data.extend([9; 8]);
+        assert_eq!(parse_sudt_amount(&data), 5);
+        assert_eq!(parse_sudt_amount(&[1; 15]), 0);
+    }
+
+    #[test]
+    fn a_rejected_transaction_is_one_failing_dashboard_row() {
+        let sent = |byte: u8, name: &str| state::SentTransaction {
+            tx_hash: H256([byte; 32]),
+            purpose: "setup".to_string(),
+            recipients: vec![name.to_string()],
+        };
+        let rows = [
+            transaction_row(&sent(1, "Node1"), Status::Rejected, None, 100),
+            transaction_row(
+                &sent(2, "Node2"),
+                Status::Committed,
+                Some((90, H256([3; 32]))),
+                100,
+            ),
+        ];
+        assert_eq!(rows[0].status, "rejected");
+        assert!(rows[0].health == Health::Fail);
+        assert_eq!(rows[0].recipients, "Node1");
+        assert_eq!(rows[1].status, "committed, 11 confirmation(s)");
+        assert!(rows[1].health == Health::Ok);
+    }
+
+    #[test]
+    fn fee_bump_keeps_the_since_and_cobuild_witness() {
+        let lock = get_lock_script_from_private_key(DEV_KEY).unwrap();
+        let cell = |byte: u8| LiveCell {
+            out_point: OutPoint {
+                tx_hash: H256([byte; 32]),
+                index: 0.into(),
+            },
+            output: CellOutput {
+                capacity: 100_00000000.into(),
+                lock: lock.clone(),
+                type_: None,
+            },
+            output_data: JsonBytes::default(),
+        };
+        let (reused, spent, added) = (cell(1), cell(2), cell(3));
+        let timelock = Since::from_raw_value(1000);
+        let output = min_ckb_output(build_packed_lock_script(DEV_KEY).unwrap()).unwrap();
+        let mut builder = TransactionView::new_advanced_builder()
+            .input(build_cell_input(&reused))
+            .input(build_cell_input(&spent))
+            .output(output.clone())
+            .output_data(ckb_types::packed::Bytes::default());
+        for witness in signing::build_witnesses(&[&reused, &spent]) {
+            builder = builder.witness(witness);
+        }
+        let original = cobuild::attach(since::apply(builder.build(), timelock));
+
+        let replacement = replacement_tx(
+            &original,
+            &[reused, added],
+            &[(output, Default::default())],
+            None,
+        );
+        let sinces: Vec<u64> = replacement
+            .inputs()
+            .into_iter()
+            .map(|input| input.since().unpack())
+            .collect();
+        assert_eq!(sinces, [1000, 1000]);
+        // One witness per input, then the cobuild message
+        assert_eq!(replacement.witnesses().len(), 3);
+        assert!(cobuild::is_attached(&replacement));
+    }
 }
@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+// Upper bounds of the RPC latency histogram buckets, in seconds
+const RPC_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+// Upper bounds of the confirmation time histogram buckets, in seconds
+const CONFIRMATION_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+/// What the long-lived modes expose at METRICS_ADDR, updated as the run goes
+struct Registry {
+    /// By service (`ckb` or `fiber`) and method
+    rpc_latency: BTreeMap<(&'static str, String), Histogram>,
+    /// By service, method and result (`ok` or `error`)
+    rpc_requests: BTreeMap<(&'static str, String, &'static str), u64>,
+    /// By result (`sent` or `failed`)
+    transactions: BTreeMap<&'static str, u64>,
+    confirmation_time: Histogram,
+    /// Capacity of pure CKB cells by node, in shannons
+    balances: BTreeMap<String, u64>,
+    /// By node and token, in base units
+    token_balances: BTreeMap<(String, String), u128>,
+    /// By node and channel state
+    channels: BTreeMap<(String, String), u64>,
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: Vec::new(),
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        self.counts.resize(self.bounds.len(), 0);
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (index, bound) in self.bounds.iter().enumerate() {
+            cumulative += self.counts.get(index).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+        let braced = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, self.count);
+    }
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Registry {
+            rpc_latency: BTreeMap::new(),
+            rpc_requests: BTreeMap::new(),
+            transactions: BTreeMap::new(),
+            confirmation_time: Histogram::new(CONFIRMATION_BUCKETS),
+            balances: BTreeMap::new(),
+            token_balances: BTreeMap::new(),
+            channels: BTreeMap::new(),
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "# HELP fiber_demo_rpc_request_duration_seconds Latency of RPC requests, each \
+             attempt on its own\n# TYPE fiber_demo_rpc_request_duration_seconds histogram\n",
+        );
+        for ((service, method), histogram) in &self.rpc_latency {
+            let labels = format!("service=\"{}\",method=\"{}\"", service, escape(method));
+            histogram.render(&mut out, "fiber_demo_rpc_request_duration_seconds", &labels);
+        }
+        out.push_str(
+            "# HELP fiber_demo_rpc_requests_total RPC requests by result\n\
+             # TYPE fiber_demo_rpc_requests_total counter\n",
+        );
+        for ((service, method, result), count) in &self.rpc_requests {
+            let _ = writeln!(
+                out,
+                "fiber_demo_rpc_requests_total{{service=\"{}\",method=\"{}\",result=\"{}\"}} {}",
+                service,
+                escape(method),
+                result,
+                count
+            );
+        }
+        out.push_str(
+            "# HELP fiber_demo_transactions_total CKB transactions broadcast, by result\n\
+             # TYPE fiber_demo_transactions_total counter\n",
+        );
+        for (result, count) in &self.transactions {
+            let _ = writeln!(
+                out,
+                "fiber_demo_transactions_total{{result=\"{}\"}} {}",
+                result, count
+            );
+        }
+        out.push_str(
+            "# HELP fiber_demo_confirmation_seconds Time from broadcast until a transaction \
+             had its confirmations\n# TYPE fiber_demo_confirmation_seconds histogram\n",
+        );
+        self.confirmation_time
+            .render(&mut out, "fiber_demo_confirmation_seconds", "");
+        out.push_str(
+            "# HELP fiber_demo_node_balance_shannons Capacity of a node's pure CKB cells\n\
+             # TYPE fiber_demo_node_balance_shannons gauge\n",
+        );
+        for (node, balance) in &self.balances {
+            let _ = writeln!(
+                out,
+                "fiber_demo_node_balance_shannons{{node=\"{}\"}} {}",
+                escape(node),
+                balance
+            );
+        }
+        out.push_str(
+            "# HELP fiber_demo_node_token_balance Token balance of a node, in base units\n\
+             # TYPE fiber_demo_node_token_balance gauge\n",
+        );
+        for ((node, token), balance) in &self.token_balances {
+            let _ = writeln!(
+                out,
+                "fiber_demo_node_token_balance{{node=\"{}\",token=\"{}\"}} {}",
+                escape(node),
+                escape(token),
+                balance
+            );
+        }
+        out.push_str(
+            "# HELP fiber_demo_node_channels Channels a Fiber node lists, by state\n\
+             # TYPE fiber_demo_node_channels gauge\n",
+        );
+        for ((node, state), count) in &self.channels {
+            let _ = writeln!(
+                out,
+                "fiber_demo_node_channels{{node=\"{}\",state=\"{}\"}} {}",
+                escape(node),
+                escape(state),
+                count
+            );
+        }
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The registry when metrics are served, so runs without them record nothing
+fn registry() -> Option<MutexGuard<'static, Registry>> {
+    ENABLED
+        .load(Ordering::Relaxed)
+        .then(|| REGISTRY.lock().unwrap())
+}
+
+/// Serve /metrics at METRICS_ADDR (e.g. `0.0.0.0:9102`) from a background thread, when
+/// set. Returns whether metrics are served.
+pub fn serve_from_env() -> bool {
+    let Ok(addr) = env::var("METRICS_ADDR") else {
+        return false;
+    };
+    if ENABLED.load(Ordering::Relaxed) {
+        return true;
+    }
+    let listener = match TcpListener::bind(&addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to serve metrics at {}: {}", addr, e);
+            return false;
+        }
+    };
+    ENABLED.store(true, Ordering::Relaxed);
+    info!("Serving Prometheus metrics at http://{}/metrics", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = answer(stream) {
+                        debug!("Metrics request failed: {}", e);
+                    }
+                }
+                Err(e) => debug!("Metrics connection failed: {}", e),
+            }
+        }
+    });
+    true
+}
+
+/// Answer one HTTP request, with the metrics for `GET /metrics`
+fn answer(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request_line)?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", REGISTRY.lock().unwrap().render()),
+        _ => (
+            "404 Not Found",
+            "Not found, metrics are at /metrics\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Record an attempt of an RPC `method` of `service` (`ckb` or `fiber`)
+pub fn observe_rpc(service: &'static str, method: &str, elapsed: Duration, ok: bool) {
+    let Some(mut registry) = registry() else {
+        return;
+    };
+    registry
+        .rpc_latency
+        .entry((service, method.to_string()))
+        .or_insert_with(|| Histogram::new(RPC_BUCKETS))
+        .observe(elapsed.as_secs_f64());
+    let result = if ok { "ok" } else { "error" };
+    *registry
+        .rpc_requests
+        .entry((service, method.to_string(), result))
+        .or_default() += 1;
+}
+
+/// Record a transaction broadcast, or the failure to broadcast it
+pub fn transaction_sent(ok: bool) {
+    if let Some(mut registry) = registry() {
+        *registry
+            .transactions
+            .entry(if ok { "sent" } else { "failed" })
+            .or_default() += 1;
+    }
+}
+
+pub fn observe_confirmation(elapsed: Duration) {
+    if let Some(mut registry) = registry() {
+        registry.confirmation_time.observe(elapsed.as_secs_f64());
+    }
+}
+
+pub fn set_balance(node: &str, shannons: u64) {
+    if let Some(mut registry) = registry() {
+        registry.balances.insert(node.to_string(), shannons);
+    }
+}
+
+pub fn set_token_balance(node: &str, token: &str, amount: u128) {
+    if let Some(mut registry) = registry() {
+        registry
+            .token_balances
+            .insert((node.to_string(), token.to_string()), amount);
+    }
+}
+
+/// Replace the channel counts of `node` by state
+pub fn set_channels(node: &str, counts: BTreeMap<String, u64>) {
+    if let Some(mut registry) = registry() {
+        registry.channels.retain(|(name, _), _| name != node);
+        for (state, count) in counts {
+            registry.channels.insert((node.to_string(), state), count);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use crate::amount::{CKB_DECIMALS, format_amount};
+use crate::error::{Error, Result};
+use ckb_jsonrpc_types::ScriptHashType;
+use ckb_sdk::NetworkType;
+use ckb_types::{H256, h256};
+use std::env;
+
+// Name of the preset's sUDT among the tokens funded by setup
+pub const SUDT_TOKEN_NAME: &str = "sUDT";
+
+/// sUDT type script deployment on a network
+#[derive(Clone)]
+pub struct SudtScript {
+    pub code_hash: H256,
+    pub hash_type: ScriptHashType,
+    /// Owner lock hash identifying the token
+    pub args: Vec<u8>,
+    /// Out point of the code cell; `None` looks the cell up in the genesis block
+    pub cell_dep: Option<(H256, u32)>,
+}
+
+/// Anyone-can-pay lock deployment on a network
+#[derive(Clone)]
+pub struct AcpScript {
+    pub code_hash: H256,
+    pub hash_type: ScriptHashType,
+}
+
+/// Omnilock deployment on a network, for the omnilock addresses of keys
+pub struct OmnilockScript {
+    pub code_hash: H256,
+    pub hash_type: ScriptHashType,
+}
+
+/// Guards against costly typos on networks where CKB has value, e.g. an amount meant
+/// in CKB given in shannons
+#[derive(Clone, Copy, Default)]
+pub struct SafetyLimits {
+    /// Highest fee rate accepted, in shannons per 1000 bytes
+    pub max_fee_rate: Option<u64>,
+    /// Most CKB a single account is sent by one command, in whole CKB
+    pub max_ckb_per_account: Option<u64>,
+}
+
+impl SafetyLimits {
+    /// Fail with `Error::InvalidAmount` when `fee_rate` exceeds the limit
+    pub fn check_fee_rate(&self, network: &str, fee_rate: u64) -> Result<()> {
+        match self.max_fee_rate {
+            Some(max) if fee_rate > max => Err(Error::InvalidAmount(format!(
+                "Fee rate {} exceeds the {} safety limit of {} shannons per 1000 bytes, \
+                 raise limits.max_fee_rate to use it",
+                fee_rate, network, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail with `Error::InvalidAmount` when `account` would be sent more than the limit
+    pub fn check_ckb_amount(&self, network: &str, account: &str, shannons: u64) -> Result<()> {
+        match self.max_ckb_per_account {
+            Some(max) if shannons > max.saturating_mul(10u64.pow(CKB_DECIMALS.into())) => {
+                Err(Error::InvalidAmount(format!(
+                    "{} would receive {} CKB, more than the {} safety limit of {} CKB per \
+                     account; raise limits.max_ckb_per_account to send it",
+                    account,
+                    format_amount(shannons.into(), CKB_DECIMALS),
+                    network,
+                    max
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A contract the Fiber nodes of a network need configured, e.g. the funding lock
+/// deployed in the devnet genesis
+pub struct FiberScript {
+    /// Name fnn knows the contract by, e.g. `FundingLock`
+    pub name: &'static str,
+    pub code_hash: H256,
+    pub hash_type: ScriptHashType,
+    /// Output of the genesis cellbase holding the code. The cellbase hash depends on
+    /// the chain spec, so it is read from the chain.
+    pub genesis_output: u32,
+}
+
+/// A UDT token funded by `setup`: the preset's sUDT or one of the config's `tokens`
+#[derive(Clone)]
+pub struct Token {
+    pub name: String,
+    pub script: SudtScript,
+    /// Digits after the decimal point of amounts in human units
+    pub decimals: u8,
+    /// Base units sent to accounts that don't set their own amount
+    pub amount: u128,
+}
+
+/// Built-in settings for a network the tool can run against
+pub struct NetworkPreset {
+    pub name: &'static str,
+    pub ckb_rpc_url: &'static str,
+    pub address_network: NetworkType,
+    /// Block explorer web UI, for transaction and address links
+    pub explorer_url: Option<&'static str>,
+    /// Block explorer API, used to cross-verify results
+    pub explorer_api_url: Option<&'static str>,
+    /// Out point of the dep group of the sighash lock; `None` looks it up in the genesis
+    /// block
+    pub sighash_dep_group: Option<(H256, u32)>,
+    pub sudt: SudtScript,
+    /// The xUDT deployment, for `[[tokens]]` with `kind = "xudt"`. Tokens whose args
+    /// are just the owner lock hash carry their amount like sUDT.
+    pub xudt: SudtScript,
+    /// Not deployed on the docker-compose devnet unless configured
+    pub acp: Option<AcpScript>,
+    /// Not deployed on the docker-compose devnet
+    pub omnilock: Option<OmnilockScript>,
+    pub limits: SafetyLimits,
+    /// `chain` of the Fiber node configs: a chain spec path or a network fnn knows
+    pub fiber_chain: &'static str,
+    /// Currency of the Fiber invoices on the network
+    pub fiber_currency: &'static str,
+    /// Contracts written to the Fiber node configs, empty where fnn knows the network's
+    pub fiber_scripts: Vec<FiberScript>,
+}
+
+impl NetworkPreset {
+    /// Links to the block explorer web UI: the preset's, or EXPLORER_URL when set, e.g.
+    /// for a self-hosted explorer of a devnet; an empty EXPLORER_URL turns links off
+    pub fn explorer(&self) -> Option<Explorer> {
+        let base = match env::var("EXPLORER_URL") {
+            Ok(url) => url,
+            Err(_) => self.explorer_url?.to_string(),
+        };
+        let base = base.trim_end_matches('/');
+        (!base.is_empty()).then(|| Explorer {
+            base: base.to_string(),
+        })
+    }
+}
+
+/// Links to the pages of a block explorer web UI
+pub struct Explorer {
+    base: String,
+}
+
+impl Explorer {
+    pub fn transaction(&self, tx_hash: &H256) -> String {
+        format!("{}/transaction/{:#x}", self.base, tx_hash)
+    }
+
+    pub fn address(&self, address: &str) -> String {
+        format!("{}/address/{}", self.base, address)
+    }
+}
+
+/// The local docker-compose devnet
+pub fn devnet() -> NetworkPreset {
+    NetworkPreset {
+        name: "devnet",
+        ckb_rpc_url: "http://ckb:8114",
+        address_network: NetworkType::Dev,
+        explorer_url: None,
+        explorer_api_url: None,
+        sighash_dep_group: None,
+        sudt: SudtScript {
+            code_hash: h256!("0xe1e354d6d643ad42724d40967e334984534e0367405c5ae42a9d7d63d77df419"),
+            hash_type: ScriptHashType::Data,
+            args: hex::decode("c219351b150b900e50a7039f1e448b844110927e5fd9bd30425806cb8ddff1fd")
+                .unwrap(),
+            cell_dep: None,
+        },
+        // `xudt_rce` in the genesis, at its data hash like the mainnet deployment
+        xudt: SudtScript {
+            code_hash: h256!("0x50bd8d6680b8b9cf98b73f3c08faf8b2a21914311954118ad6609be6e78a1b95"),
+            hash_type: ScriptHashType::Data1,
+            args: Vec::new(),
+            cell_dep: None,
+        },
+        acp: None,
+        omnilock: None,
+        limits: SafetyLimits::default(),
+        // Mounted into each Fiber node container
+        fiber_chain: "/dev.toml",
+        fiber_currency: "Fibd",
+        fiber_scripts: vec![
+            FiberScript {
+                name: "FundingLock",
+                code_hash: h256!(
+                    "0x6c67887fe201ee0c7853f1682c0b77c0e6214044c156c7558269390a8afa6d7c"
+                ),
+                hash_type: ScriptHashType::Type,
+                genesis_output: 5,
+            },
+            FiberScript {
+                name: "CommitmentLock",
+                code_hash: h256!(
+                    "0x740dee83f87c6f309824d8fd3fbdd3c8380ee6fc9acc90b1a748438afcdf81d8"
+                ),
+                hash_type: ScriptHashType::Type,
+                genesis_output: 6,
+            },
+        ],
+    }
+}
+
+/// The public CKB testnet (Pudge) that the public fiber testnet runs on. The sUDT args
+/// depend on the token owner, so they must be provided with SUDT_ARGS.
+pub fn testnet() -> NetworkPreset {
+    NetworkPreset {
+        name: "testnet",
+        ckb_rpc_url: "https://testnet.ckb.dev/",
+        address_network: NetworkType::Testnet,
+        explorer_url: Some("https://pudge.explorer.nervos.org"),
+        explorer_api_url: Some("https://testnet-api.explorer.nervos.org/api"),
+        sighash_dep_group: Some((
+            h256!("0xf8de3bb47d055cdf460d93a2a6e1b05f7432f9777c8c474abf4eec1d4aee5d37"),
+            0,
+        )),
+        sudt: SudtScript {
+            code_hash: h256!("0xc5e5dcf215925f7ef4dfaf5f4b4f105bc321c02776d6e7d52a1db3fcd9d011a4"),
+            hash_type: ScriptHashType::Type,
+            args: Vec::new(),
+            cell_dep: Some((
+                h256!("0xe12877ebd2c3c364dc46c5c992bcfaf4fee33fa13eebdf82c591fc9825aab769"),
+                0,
+            )),
+        },
+        xudt: SudtScript {
+            code_hash: h256!("0x25c29dc317811a6f6f3985a7a9ebc4838bd388d19d0feeecf0bcd60f6c0975bb"),
+            hash_type: ScriptHashType::Type,
+            args: Vec::new(),
+            cell_dep: Some((
+                h256!("0xbf6fb538763efec2a70a6a3dcb7242787087e1030c4e7d86585bc63a9d337f5f"),
+                0,
+            )),
+        },
+        acp: Some(AcpScript {
+            code_hash: h256!("0x3419a1c09eb2567f6552ee7a8ecffd64155cffe0f1796e6e61ec088d740c1356"),
+            hash_type: ScriptHashType::Type,
+        }),
+        omnilock: Some(OmnilockScript {
+            code_hash: h256!("0xf329effd1c475a2978453c8600e1eaf0bc2087ee093c3ee64cc96ec6847752cb"),
+            hash_type: ScriptHashType::Type,
+        }),
+        // Testnet CKB is free, only runaway fees are caught
+        limits: SafetyLimits {
+            max_fee_rate: Some(1_000_000),
+            max_ckb_per_account: None,
+        },
+        fiber_chain: "testnet",
+        fiber_currency: "Fibt",
+        fiber_scripts: Vec::new(),
+    }
+}
+
+/// The CKB mainnet (Mirana). As on testnet, the sUDT args must be provided with
+/// SUDT_ARGS. Amounts are capped by the safety limits unless the config raises them.
+pub fn mainnet() -> NetworkPreset {
+    NetworkPreset {
+        name: "mainnet",
+        ckb_rpc_url: "https://mainnet.ckb.dev/",
+        address_network: NetworkType::Mainnet,
+        explorer_url: Some("https://explorer.nervos.org"),
+        explorer_api_url: Some("https://mainnet-api.explorer.nervos.org/api"),
+        sighash_dep_group: Some((
+            h256!("0x71a7ba8fc96349fea0ed3a5c47992e3b4084b031a42264a018e0072e8172e46c"),
+            0,
+        )),
+        sudt: SudtScript {
+            code_hash: h256!("0x5e7a36a77e68eecc013dfa2fe6a23f3b6c344b04005808694ae6dd45eea4cfd5"),
+            hash_type: ScriptHashType::Type,
+            args: Vec::new(),
+            cell_dep: Some((
+                h256!("0xc7813f6a415144643970c2e88e0bb6ca6a8edc5dd7c1022746f628284a9936d5"),
+                0,
+            )),
+        },
+        xudt: SudtScript {
+            code_hash: h256!("0x50bd8d6680b8b9cf98b73f3c08faf8b2a21914311954118ad6609be6e78a1b95"),
+            hash_type: ScriptHashType::Data1,
+            args: Vec::new(),
+            cell_dep: Some((
+                h256!("0xc07844ce21b38e4b071dd0e1ee3b0e27afd8d7532491327f39b786343f558ab7"),
+                0,
+            )),
+        },
+        acp: Some(AcpScript {
+            code_hash: h256!("0xd369597ff47f29fbc0d47d2e3775370d1250b85140c670e4718af712983a2354"),
+            hash_type: ScriptHashType::Type,
+        }),
+        omnilock: Some(OmnilockScript {
+            code_hash: h256!("0x9b819793a64463aed77c615d6cb226eea5487ccfc0783043a587254cda2b6f26"),
+            hash_type: ScriptHashType::Type,
+        }),
+        limits: SafetyLimits {
+            max_fee_rate: Some(100_000),
+            max_ckb_per_account: Some(10_000),
+        },
+        fiber_chain: "mainnet",
+        fiber_currency: "Fibb",
+        fiber_scripts: Vec::new(),
+    }
+}
+
+pub fn preset(name: &str) -> Result<NetworkPreset> {
+    match name {
+        "devnet" => Ok(devnet()),
+        "testnet" => Ok(testnet()),
+        "mainnet" => Ok(mainnet()),
+        _ => Err(Error::Config(format!(
+            "Unknown network {:?}, expected devnet, testnet or mainnet",
+            name
+        ))),
+    }
+}
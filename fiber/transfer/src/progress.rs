@@ -0,0 +1,130 @@
+use crate::logging::LogFormat;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// How often spinners redraw on their own, so they keep moving while a call blocks
+const TICK_INTERVAL: Duration = Duration::from_millis(120);
+
+static BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Turn progress bars on when stderr is an interactive terminal, logs are text and
+/// PROGRESS isn't `0`. Without them a run only logs, as before.
+pub fn init(format: LogFormat) {
+    let enabled = io::stderr().is_terminal()
+        && matches!(format, LogFormat::Text)
+        && env::var("PROGRESS").map_or(true, |value| value != "0");
+    if enabled {
+        let _ = BARS.set(MultiProgress::new());
+    }
+}
+
+/// Run `f` with the bars cleared from the terminal, for prompts and other output that
+/// would otherwise be drawn over
+pub fn suspend<T>(f: impl FnOnce() -> T) -> T {
+    match BARS.get() {
+        Some(bars) => bars.suspend(f),
+        None => f(),
+    }
+}
+
+/// Stderr for the log subscriber, writing each line above the bars
+pub struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// A spinner or bar on the terminal, cleared when dropped; does nothing when progress
+/// bars are off
+pub struct Progress(Option<ProgressBar>);
+
+impl Progress {
+    /// A spinner with the elapsed time, for work of unknown length
+    pub fn spinner(message: impl Into<String>) -> Self {
+        Self::add(
+            ProgressBar::new_spinner(),
+            "{spinner} {msg} ({elapsed})",
+            message,
+        )
+    }
+
+    /// A bar counting `len` steps, for work of known length
+    pub fn bar(len: u64, message: impl Into<String>) -> Self {
+        Self::add(
+            ProgressBar::new(len),
+            "{spinner} {msg} [{bar:30}] {pos}/{len} ({elapsed})",
+            message,
+        )
+    }
+
+    fn add(bar: ProgressBar, template: &str, message: impl Into<String>) -> Self {
+        let Some(bars) = BARS.get() else {
+            return Progress(None);
+        };
+        let bar = bars.add(bar);
+        bar.set_style(
+            ProgressStyle::with_template(template)
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        bar.set_message(message.into());
+        bar.enable_steady_tick(TICK_INTERVAL);
+        Progress(Some(bar))
+    }
+
+    pub fn set_message(&self, message: impl Into<String>) {
+        if let Some(bar) = &self.0 {
+            bar.set_message(message.into());
+        }
+    }
+
+    pub fn set_position(&self, position: u64) {
+        if let Some(bar) = &self.0 {
+            bar.set_position(position);
+        }
+    }
+
+    pub fn inc(&self) {
+        if let Some(bar) = &self.0 {
+            bar.inc(1);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(bar) = &self.0 {
+            bar.finish_and_clear();
+            if let Some(bars) = BARS.get() {
+                bars.remove(bar);
+            }
+        }
+    }
+}
+
+/// Numbered stages of a command, shown as `[3/7] Waiting for ...` spinners
+pub struct Stages {
+    total: usize,
+    started: usize,
+}
+
+impl Stages {
+    pub fn new(total: usize) -> Self {
+        Stages { total, started: 0 }
+    }
+
+    /// The label of the next stage, e.g. `[3/7] Open channels`
+    pub fn next(&mut self, title: &str) -> String {
+        self.started += 1;
+        format!("[{}/{}] {}", self.started, self.total, title)
+    }
+}
@@ -0,0 +1,114 @@
+use crate::error::Error;
+use std::env;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// A cap on the requests sent to an RPC endpoint
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    /// Requests per second
+    rate: f64,
+    burst: f64,
+}
+
+/// Token bucket capping the requests sent to one RPC endpoint at `rate` per second,
+/// allowing bursts of up to `burst` after a quiet spell. A request over the limit waits
+/// for its turn instead of failing; waiting requests are let through in the order they
+/// arrived.
+pub struct RateLimiter {
+    url: String,
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Requests that may be sent right away, negative when callers are already queued
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimit {
+    /// The limit set by the `{prefix}_RATE_LIMIT` (requests per second, may be
+    /// fractional) and `{prefix}_RATE_BURST` (default: the rate rounded up) env vars,
+    /// `None` without a rate
+    pub fn from_env(prefix: &str) -> Result<Option<Self>, Error> {
+        let name = format!("{}_RATE_LIMIT", prefix);
+        let Ok(value) = env::var(&name) else {
+            return Ok(None);
+        };
+        let invalid =
+            |name: &str, value: &str| Error::Config(format!("Invalid {} value {:?}", name, value));
+        let rate: f64 = value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|rate: &f64| rate.is_finite() && *rate > 0.0)
+            .ok_or_else(|| invalid(&name, &value))?;
+        let name = format!("{}_RATE_BURST", prefix);
+        let burst = match env::var(&name) {
+            Ok(value) => {
+                let burst: u32 = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|burst| *burst > 0)
+                    .ok_or_else(|| invalid(&name, &value))?;
+                f64::from(burst)
+            }
+            Err(_) => rate.ceil().max(1.0),
+        };
+        Ok(Some(RateLimit { rate, burst }))
+    }
+}
+
+impl RateLimiter {
+    pub fn new(url: &str, limit: RateLimit) -> Self {
+        RateLimiter {
+            url: url.to_string(),
+            rate: limit.rate,
+            burst: limit.burst,
+            bucket: Mutex::new(Bucket {
+                tokens: limit.burst,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a request's turn, sleeping until it comes when the limit is reached
+    pub fn acquire(&self) {
+        if let Some(wait) = self.take_turn() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// `acquire` for async callers, yielding to other tasks while waiting
+    pub async fn acquire_async(&self) {
+        if let Some(wait) = self.take_turn() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take the next turn, returning how long to wait for it when it isn't now
+    fn take_turn(&self) -> Option<Duration> {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.rate;
+            bucket.tokens = (bucket.tokens + refill).min(self.burst) - 1.0;
+            bucket.refilled_at = now;
+            if bucket.tokens >= 0.0 {
+                return None;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        };
+        debug!(
+            "Rate limit of {} reached, waiting {:.2}s",
+            self.url,
+            wait.as_secs_f64()
+        );
+        Some(wait)
+    }
+}
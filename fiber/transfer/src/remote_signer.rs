@@ -0,0 +1,219 @@
+use crate::LiveCell;
+use crate::error::{Error, Result};
+use crate::rpc::HttpSettings;
+use crate::signing;
+use ckb_hash::blake2b_256;
+use ckb_jsonrpc_types::{JsonBytes, Script, ScriptHashType};
+use ckb_sdk::constants::SIGHASH_TYPE_HASH;
+use ckb_sdk::traits::{Signer, SignerError};
+use ckb_types::bytes::Bytes;
+use ckb_types::core::TransactionView;
+use ckb_types::prelude::*;
+use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use secp256k1::{Message, Secp256k1};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tracing::info;
+
+// Account given instead of a private key to sign with a remote signing service,
+// followed by `:` and the lock args of the service's key
+const REMOTE_ACCOUNT_PREFIX: &str = "remote:";
+
+/// Whether an account is a key held by the remote signer, `remote:<lock args>`
+pub fn is_remote(account: &str) -> bool {
+    account.starts_with(REMOTE_ACCOUNT_PREFIX)
+}
+
+fn lock_args(account: &str) -> Result<[u8; 20]> {
+    let args = account[REMOTE_ACCOUNT_PREFIX.len()..].trim_start_matches("0x");
+    hex::decode(args)
+        .ok()
+        .and_then(|args| args.try_into().ok())
+        .ok_or_else(|| {
+            Error::InvalidKey(format!(
+                "Invalid remote account {:?}, expected remote:0x<20-byte lock args>",
+                account
+            ))
+        })
+}
+
+/// Sighash lock of the key of a remote account
+pub fn lock_script(account: &str) -> Result<Script> {
+    Ok(Script {
+        code_hash: SIGHASH_TYPE_HASH.clone(),
+        hash_type: ScriptHashType::Type,
+        args: JsonBytes::from_vec(lock_args(account)?.to_vec()),
+    })
+}
+
+/// Sign a transaction spending only cells of the remote account, with each sighash
+/// digest signed by the service at REMOTE_SIGNER_URL
+pub fn sign_transaction(
+    account: &str,
+    tx: TransactionView,
+    input_cells: &[&LiveCell],
+) -> Result<TransactionView> {
+    let lock = lock_script(account)?;
+    let groups = signing::group_inputs(input_cells);
+    if groups.iter().any(|group| group.lock != lock) {
+        return Err(Error::Signing(format!(
+            "{} only signs transactions spending its own cells",
+            account
+        )));
+    }
+    let signer = RemoteSigner::from_env(lock_args(account)?)?;
+    let error = signer.error.clone();
+    info!(
+        "Requesting signatures from the remote signer at {}",
+        signer.url
+    );
+    signing::unlock(tx, Box::new(signer), input_cells).map_err(|e| {
+        // The SDK only keeps the text of signer errors, so the signer's own error is
+        // preferred for its exit code
+        error.lock().unwrap().take().unwrap_or(e)
+    })
+}
+
+#[derive(Serialize)]
+struct SignRequest {
+    /// Lock args of the key to sign with
+    lock_args: JsonBytes,
+    /// The 32-byte sighash digest to sign
+    digest: JsonBytes,
+    /// The transaction being signed, for the service to check before signing
+    tx_hash: ckb_types::H256,
+    transaction: ckb_jsonrpc_types::Transaction,
+}
+
+#[derive(Deserialize)]
+struct SignResponse {
+    /// Recoverable signature: r, s and the recovery id
+    signature: JsonBytes,
+}
+
+/// ckb-sdk signer posting sighash digests to a signing service, configured by env vars:
+///   * REMOTE_SIGNER_URL: endpoint taking a POST of a JSON `SignRequest` and returning
+///     a JSON `SignResponse`
+///   * REMOTE_SIGNER_TOKEN: sent as `Authorization: Bearer <token>`
+///
+/// Proxy, CA and timeouts are the RPC ones, the RPC credentials are not sent.
+struct RemoteSigner {
+    url: String,
+    token: Option<String>,
+    lock_args: [u8; 20],
+    http: reqwest::blocking::Client,
+    /// First failure, kept for its exit code
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+impl RemoteSigner {
+    fn from_env(lock_args: [u8; 20]) -> Result<Self> {
+        let url = env::var("REMOTE_SIGNER_URL").map_err(|_| {
+            Error::Signing("Remote accounts need the signing service's REMOTE_SIGNER_URL".into())
+        })?;
+        let http = HttpSettings::from_env()?.shared_client("remote signer")?;
+        Ok(RemoteSigner {
+            url,
+            token: env::var("REMOTE_SIGNER_TOKEN").ok(),
+            lock_args,
+            http,
+            error: Default::default(),
+        })
+    }
+
+    /// Ask the service to sign `digest` and check the signature is by the account's key
+    fn request(&self, digest: &[u8], tx: &TransactionView) -> Result<Vec<u8>> {
+        let failed = |reason: String| {
+            Error::Signing(format!("Remote signer at {} failed: {}", self.url, reason))
+        };
+        let request = SignRequest {
+            lock_args: JsonBytes::from_vec(self.lock_args.to_vec()),
+            digest: JsonBytes::from_vec(digest.to_vec()),
+            tx_hash: tx.hash().unpack(),
+            transaction: tx.data().into(),
+        };
+        let mut builder = self.http.post(&self.url).json(&request);
+        if let Some(token) = &self.token {
+            builder = builder.bearer_auth(token);
+        }
+        let response = builder.send().map_err(|e| failed(e.to_string()))?;
+        let status = response.status();
+        if status == reqwest::StatusCode::FORBIDDEN {
+            let reason = response.text().unwrap_or_default();
+            return Err(Error::Declined(format!(
+                "Remote signer at {} refused to sign: {}",
+                self.url,
+                reason.trim()
+            )));
+        }
+        if !status.is_success() {
+            let reason = response.text().unwrap_or_default();
+            return Err(failed(format!("{} {}", status, reason.trim())));
+        }
+        let signature = response
+            .json::<SignResponse>()
+            .map_err(|e| failed(format!("invalid response: {}", e)))?
+            .signature
+            .into_bytes();
+        if !self.signed_by_key(digest, &signature) {
+            return Err(failed(format!(
+                "0x{} is not a signature of the digest by lock args 0x{}",
+                hex::encode(&signature),
+                hex::encode(self.lock_args)
+            )));
+        }
+        Ok(signature.to_vec())
+    }
+
+    fn signed_by_key(&self, digest: &[u8], signature: &[u8]) -> bool {
+        let (Ok(message), [compact @ .., recovery_id]) =
+            (Message::from_digest_slice(digest), signature)
+        else {
+            return false;
+        };
+        RecoveryId::try_from(*recovery_id as i32)
+            .and_then(|id| RecoverableSignature::from_compact(compact, id))
+            .and_then(|signature| {
+                Secp256k1::verification_only().recover_ecdsa(&message, &signature)
+            })
+            .is_ok_and(|key| blake2b_256(key.serialize())[..20] == self.lock_args)
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn match_id(&self, id: &[u8]) -> bool {
+        id == self.lock_args
+    }
+
+    fn sign(
+        &self,
+        id: &[u8],
+        message: &[u8],
+        recoverable: bool,
+        tx: &TransactionView,
+    ) -> std::result::Result<Bytes, SignerError> {
+        if !self.match_id(id) {
+            return Err(SignerError::IdNotFound);
+        }
+        if !recoverable || message.len() != 32 {
+            return Err(SignerError::InvalidMessage(
+                "the remote signer only signs 32-byte digests recoverably".to_string(),
+            ));
+        }
+        // The SDK signs from inside an async runtime, where blocking HTTP clients can't
+        // run, so the request is made on a thread of its own
+        let result = thread::scope(|scope| {
+            scope
+                .spawn(|| self.request(message, tx))
+                .join()
+                .expect("remote signer request panicked")
+        });
+        result.map(Bytes::from).map_err(|e| {
+            let message = e.to_string();
+            self.error.lock().unwrap().get_or_insert(e);
+            SignerError::Other(anyhow::anyhow!(message))
+        })
+    }
+}
@@ -0,0 +1,344 @@
+use crate::error::Error;
+use ckb_types::H256;
+use serde_json::{Value, json};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Funds sent to one account in the run
+pub struct FundingRow {
+    pub name: String,
+    pub lock_args: String,
+    /// In shannons
+    pub ckb_amount: u64,
+    /// Every token the account receives as `<amount> <token>` in human units
+    pub token_amounts: Vec<String>,
+}
+
+/// A channel the run opened or tried to open
+pub struct ChannelRow {
+    pub from: String,
+    pub to: String,
+    /// Funding of the opening node as `<amount> <asset>` in human units
+    pub funding: String,
+    pub status: ChannelStatus,
+    /// Channel id, or the error that stopped the opening
+    pub detail: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChannelStatus {
+    Opened,
+    /// Open or opening before the run
+    AlreadyOpen,
+    Failed,
+}
+
+/// Summary of a run, rendered as Markdown and optionally HTML for CI artifacts
+pub struct Report {
+    started: Instant,
+    stages: Vec<(String, Duration)>,
+    current_stage: Option<String>,
+    funding: Vec<FundingRow>,
+    transaction: Option<H256>,
+    block_number: Option<u64>,
+    /// Per-node results in partial-success mode
+    outcomes: Vec<(String, Result<H256, String>)>,
+    channels: Vec<ChannelRow>,
+    /// The smoke test payment and its payment hash or error
+    smoke_test: Option<(String, Result<String, String>)>,
+    error: Option<String>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report {
+            started: Instant::now(),
+            stages: Vec::new(),
+            current_stage: None,
+            funding: Vec::new(),
+            transaction: None,
+            block_number: None,
+            outcomes: Vec::new(),
+            channels: Vec::new(),
+            smoke_test: None,
+            error: None,
+        }
+    }
+
+    /// Run `f` as a named stage, recording how long it took. A failed stage stays
+    /// current so `set_error` can attribute the error to it.
+    pub fn stage<T, E>(&mut self, name: &str, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        self.current_stage = Some(name.to_string());
+        let start = Instant::now();
+        let result = f()?;
+        self.stages.push((name.to_string(), start.elapsed()));
+        self.current_stage = None;
+        Ok(result)
+    }
+
+    pub fn add_funding(&mut self, row: FundingRow) {
+        self.funding.push(row);
+    }
+
+    pub fn set_transaction(&mut self, tx_hash: H256) {
+        self.transaction = Some(tx_hash);
+    }
+
+    pub fn set_block_number(&mut self, block_number: u64) {
+        self.block_number = Some(block_number);
+    }
+
+    pub fn add_outcome(&mut self, name: &str, outcome: Result<H256, String>) {
+        self.outcomes.push((name.to_string(), outcome));
+    }
+
+    pub fn add_channel(&mut self, row: ChannelRow) {
+        self.channels.push(row);
+    }
+
+    /// Record the smoke test payment, described as `<amount> from <node> to <node>`
+    pub fn set_smoke_test(&mut self, payment: String, outcome: Result<String, String>) {
+        self.smoke_test = Some((payment, outcome));
+    }
+
+    /// Record the error that aborted the run, attributed to the stage in progress
+    pub fn set_error(&mut self, message: &str) {
+        self.error = Some(match &self.current_stage {
+            Some(stage) => format!("{} (during stage: {})", message, stage),
+            None => message.to_string(),
+        });
+    }
+
+    /// `OK`, `PARTIAL` when some nodes need a retry, or `FAILED`
+    pub fn status(&self) -> &'static str {
+        if self.error.is_some() {
+            "FAILED"
+        } else if self.outcomes.iter().any(|(_, o)| o.is_err()) {
+            "PARTIAL"
+        } else {
+            "OK"
+        }
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The report as JSON, for webhook payloads
+    pub fn to_json(&self) -> Value {
+        let funding: Vec<Value> = self
+            .funding
+            .iter()
+            .map(|row| {
+                json!({
+                    "name": row.name,
+                    "lock_args": row.lock_args,
+                    "ckb_shannons": row.ckb_amount,
+                    "tokens": row.token_amounts,
+                })
+            })
+            .collect();
+        let outcomes: Vec<Value> = self
+            .outcomes
+            .iter()
+            .map(|(name, outcome)| match outcome {
+                Ok(tx_hash) => json!({"node": name, "tx_hash": format!("{:#x}", tx_hash)}),
+                Err(e) => json!({"node": name, "error": e}),
+            })
+            .collect();
+        let channels: Vec<Value> = self
+            .channels
+            .iter()
+            .map(|row| {
+                json!({
+                    "from": row.from,
+                    "to": row.to,
+                    "funding": row.funding,
+                    "status": match row.status {
+                        ChannelStatus::Opened => "opened",
+                        ChannelStatus::AlreadyOpen => "already_open",
+                        ChannelStatus::Failed => "failed",
+                    },
+                    "detail": row.detail,
+                })
+            })
+            .collect();
+        let stages: Vec<Value> = self
+            .stages
+            .iter()
+            .map(|(name, duration)| json!({"name": name, "secs": duration.as_secs_f64()}))
+            .collect();
+        json!({
+            "status": self.status(),
+            "total_secs": self.started.elapsed().as_secs_f64(),
+            "transaction": self.transaction.as_ref().map(|tx_hash| format!("{:#x}", tx_hash)),
+            "block_number": self.block_number,
+            "funding": funding,
+            "outcomes": outcomes,
+            "channels": channels,
+            "smoke_test": self.smoke_test.as_ref().map(|(payment, outcome)| match outcome {
+                Ok(payment_hash) => json!({"payment": payment, "payment_hash": payment_hash}),
+                Err(e) => json!({"payment": payment, "error": e}),
+            }),
+            "stages": stages,
+            "failed_stage": self.current_stage,
+            "error": self.error,
+        })
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::new();
+        let status = self.status();
+        writeln!(md, "# Fiber Demo Startup Report\n").unwrap();
+        writeln!(md, "- Status: **{}**", status).unwrap();
+        writeln!(
+            md,
+            "- Total time: {:.1}s",
+            self.started.elapsed().as_secs_f64()
+        )
+        .unwrap();
+        if let Some(tx_hash) = &self.transaction {
+            writeln!(md, "- Transaction: `{:#x}`", tx_hash).unwrap();
+        }
+        if let Some(block_number) = self.block_number {
+            writeln!(md, "- Settled in block: {}", block_number).unwrap();
+        }
+        match &self.smoke_test {
+            Some((payment, Ok(payment_hash))) => writeln!(
+                md,
+                "- Smoke test: paid {} (payment `{}`)",
+                payment, payment_hash
+            )
+            .unwrap(),
+            Some((payment, Err(e))) => {
+                writeln!(md, "- Smoke test: paying {} failed: {}", payment, e).unwrap()
+            }
+            None => {}
+        }
+
+        writeln!(md, "\n## Funding\n").unwrap();
+        writeln!(md, "| Account | Lock args | CKB | Tokens |").unwrap();
+        writeln!(md, "|---------|-----------|-----|--------|").unwrap();
+        for row in &self.funding {
+            writeln!(
+                md,
+                "| {} | `{}` | {} | {} |",
+                row.name,
+                row.lock_args,
+                row.ckb_amount / 100000000,
+                row.token_amounts.join(", ")
+            )
+            .unwrap();
+        }
+
+        if !self.outcomes.is_empty() {
+            writeln!(md, "\n## Results\n").unwrap();
+            writeln!(md, "| Node | Status | Transaction / error |").unwrap();
+            writeln!(md, "|------|--------|---------------------|").unwrap();
+            for (name, outcome) in &self.outcomes {
+                match outcome {
+                    Ok(tx_hash) => writeln!(md, "| {} | funded | `{:#x}` |", name, tx_hash),
+                    Err(e) => writeln!(md, "| {} | needs retry | {} |", name, e),
+                }
+                .unwrap();
+            }
+        }
+
+        if !self.channels.is_empty() {
+            writeln!(md, "\n## Channels\n").unwrap();
+            writeln!(md, "| From | To | Funding | Status | Channel / error |").unwrap();
+            writeln!(md, "|------|----|---------|--------|-----------------|").unwrap();
+            for row in &self.channels {
+                let status = match row.status {
+                    ChannelStatus::Opened => "opened",
+                    ChannelStatus::AlreadyOpen => "already open",
+                    ChannelStatus::Failed => "failed",
+                };
+                writeln!(
+                    md,
+                    "| {} | {} | {} | {} | {} |",
+                    row.from, row.to, row.funding, status, row.detail
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(md, "\n## Topology\n").unwrap();
+        writeln!(md, "```mermaid\ngraph LR").unwrap();
+        for row in &self.funding {
+            writeln!(
+                md,
+                "    Source -->|{} CKB{}| {}",
+                row.ckb_amount / 100000000,
+                row.token_amounts
+                    .iter()
+                    .map(|amount| format!(", {}", amount))
+                    .collect::<String>(),
+                row.name
+            )
+            .unwrap();
+        }
+        for row in &self.channels {
+            if row.status != ChannelStatus::Failed {
+                writeln!(md, "    {} <-->|{}| {}", row.from, row.funding, row.to).unwrap();
+            }
+        }
+        writeln!(md, "```").unwrap();
+
+        writeln!(md, "\n## Timings\n").unwrap();
+        writeln!(md, "| Stage | Duration |").unwrap();
+        writeln!(md, "|-------|----------|").unwrap();
+        for (stage, duration) in &self.stages {
+            writeln!(md, "| {} | {:.1}s |", stage, duration.as_secs_f64()).unwrap();
+        }
+
+        if let Some(error) = &self.error {
+            writeln!(md, "\n## Errors\n\n```\n{}\n```", error).unwrap();
+        }
+        md
+    }
+
+    /// Self-contained HTML page embedding the Markdown report
+    pub fn to_html(&self) -> String {
+        let escaped = self
+            .to_markdown()
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Fiber Demo Startup Report</title>\n</head>\n<body>\n\
+             <pre style=\"font-family: monospace\">\n{}</pre>\n</body>\n</html>\n",
+            escaped
+        )
+    }
+
+    /// Write report.md (and report.html when `html` is set) into `dir`
+    pub fn write(&self, dir: &Path, html: bool) -> Result<(), Error> {
+        fs::create_dir_all(dir).map_err(Error::io(format!(
+            "Failed to create report dir {}",
+            dir.display()
+        )))?;
+        let md_path = dir.join("report.md");
+        fs::write(&md_path, self.to_markdown())
+            .map_err(Error::io(format!("Failed to write {}", md_path.display())))?;
+        info!("Report written to {}", md_path.display());
+
+        if html {
+            let html_path = dir.join("report.html");
+            fs::write(&html_path, self.to_html()).map_err(Error::io(format!(
+                "Failed to write {}",
+                html_path.display()
+            )))?;
+            info!("Report written to {}", html_path.display());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1052 @@
+use crate::audit;
+use crate::cell_cache::CellCache;
+use crate::error::Error;
+use crate::light_client::LightClient;
+use crate::metrics;
+use crate::progress::Progress;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use anyhow::anyhow;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ckb_jsonrpc_types::{
+    BlockNumber, BlockView, CellWithStatus, JsonBytes, OutPoint, OutputsValidator, Transaction,
+    TransactionWithStatusResponse, Uint32,
+};
+use ckb_sdk::rpc::ckb_indexer::{
+    Cell, CellsCapacity, Order, Pagination, SearchKey, SearchKeyFilter, Tip,
+};
+use ckb_sdk::rpc::{LightClientRpcAsyncClient, LightClientRpcClient};
+use ckb_sdk::{CkbRpcAsyncClient, CkbRpcClient, RpcError};
+use ckb_types::{H256, packed, prelude::*};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, StreamExt};
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// TCP keep-alive probe interval, overridable via RPC_KEEPALIVE_SECS env var
+const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(60);
+// How long an idle pooled connection is kept, overridable via RPC_POOL_IDLE_SECS env var
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+// Attempts per RPC call, overridable via RPC_MAX_ATTEMPTS env var
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+// First retry delay, doubled per retry, overridable via RPC_RETRY_DELAY_MS env var
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+// PoolRejectedDuplicatedTransaction
+const DUPLICATED_TX_ERROR: i64 = -1107;
+// Error code of CKB's send_transaction for inputs or deps that are dead or unknown
+const FAILED_TO_RESOLVE_ERROR: i64 = -301;
+// JSON-RPC error of a method in a module the node doesn't enable
+const METHOD_NOT_FOUND_ERROR: i64 = -32601;
+// Lookups run at once by `concurrently`, overridable via RPC_CONCURRENCY env var
+const DEFAULT_CONCURRENCY: usize = 8;
+// How long a failed endpoint is passed over while another one is healthy, overridable
+// via RPC_FAILOVER_COOLDOWN_SECS env var
+const DEFAULT_FAILOVER_COOLDOWN: Duration = Duration::from_secs(30);
+// Cells per get_cells page
+const CELLS_PAGE_SIZE: u32 = 100;
+
+/// What the CKB RPC endpoint is, `backend` in the config
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChainBackend {
+    /// A full node with its indexer enabled
+    #[default]
+    FullNode,
+    /// A CKB light client, which watches only the scripts the tool searches for
+    LightClient,
+}
+
+/// HTTP settings shared by every outbound RPC connection
+#[derive(Clone, Default)]
+pub struct HttpSettings {
+    /// Proxy for all requests, e.g. http://proxy:3128 or socks5h://127.0.0.1:1080
+    proxy: Option<reqwest::Proxy>,
+    /// URL of `proxy`, to tunnel the connections reqwest doesn't make
+    proxy_url: Option<reqwest::Url>,
+    /// Hosts reached directly despite the proxy, comma-separated
+    no_proxy: Option<String>,
+    /// Extra root certificate for endpoints signed by a private CA
+    ca_cert: Option<reqwest::Certificate>,
+    /// The same certificate for TLS connections reqwest doesn't make
+    ca_cert_native: Option<native_tls::Certificate>,
+    /// Authorization and custom headers sent to CKB RPC endpoints (never to the explorer)
+    headers: HeaderMap,
+    /// Authorization and custom headers sent to Fiber nodes
+    fiber_headers: HeaderMap,
+    connect_timeout: Duration,
+    /// Upper bound for a single request, including reading the response
+    request_timeout: Duration,
+    keepalive: Duration,
+    pool_idle_timeout: Duration,
+    /// Client for Fiber nodes and third-party services, built on first use and shared
+    /// by the clones of these settings so they reuse its pooled connections
+    shared_client: Arc<OnceLock<reqwest::blocking::Client>>,
+    /// Client for Fiber nodes, sending `fiber_headers`, shared like `shared_client`
+    fiber_client: Arc<OnceLock<reqwest::blocking::Client>>,
+    /// CKB RPC clients by URL, each with its own connection pool
+    ckb_clients: Arc<Mutex<HashMap<String, CkbRpcClient>>>,
+    /// CKB light client RPC clients by URL, each with its own connection pool
+    light_clients: Arc<Mutex<HashMap<String, LightClientRpcClient>>>,
+    /// Cap on the requests to each CKB RPC endpoint
+    rate_limit: Option<RateLimit>,
+    /// Cap on the requests to each Fiber node
+    fiber_rate_limit: Option<RateLimit>,
+    /// Rate limiters by endpoint URL, shared by every client of an endpoint
+    rate_limiters: Arc<Mutex<HashMap<String, Arc<RateLimiter>>>>,
+}
+
+impl HttpSettings {
+    /// The settings of this run, read from the env on the first call. Every call
+    /// returns them sharing the connection pools of the first.
+    pub fn from_env() -> Result<Self, Error> {
+        static SETTINGS: OnceLock<HttpSettings> = OnceLock::new();
+        if let Some(settings) = SETTINGS.get() {
+            return Ok(settings.clone());
+        }
+        let settings = Self::read_env()?;
+        Ok(SETTINGS.get_or_init(|| settings).clone())
+    }
+
+    /// Read settings from env vars:
+    ///   * RPC_PROXY: proxy URL
+    ///   * RPC_NO_PROXY: comma-separated hosts or domains not to proxy
+    ///   * RPC_CA_CERT: path to a PEM root certificate
+    ///   * RPC_BEARER_TOKEN: sent as `Authorization: Bearer <token>`
+    ///   * RPC_BASIC_AUTH: `user:password`, sent as `Authorization: Basic ...`
+    ///   * RPC_HEADERS: extra headers, `Name: value` pairs separated by newlines or `;`
+    ///   * FIBER_RPC_BEARER_TOKEN / FIBER_RPC_BASIC_AUTH / FIBER_RPC_HEADERS: the same
+    ///     for Fiber node RPC
+    ///   * RPC_CONNECT_TIMEOUT_SECS / RPC_TIMEOUT_SECS: per-call timeouts
+    ///   * RPC_KEEPALIVE_SECS / RPC_POOL_IDLE_SECS: TCP keep-alive interval and how
+    ///     long idle connections stay pooled
+    ///   * RPC_RATE_LIMIT / RPC_RATE_BURST: requests per second and burst allowed per
+    ///     CKB RPC endpoint, FIBER_RPC_RATE_LIMIT / FIBER_RPC_RATE_BURST per Fiber node
+    fn read_env() -> Result<Self, Error> {
+        let invalid_proxy = |url: &str, e: &dyn std::fmt::Display| {
+            Error::Config(format!("Invalid RPC_PROXY {:?}: {}", url, e))
+        };
+        let proxy_url = env::var("RPC_PROXY")
+            .ok()
+            .map(|url| reqwest::Url::parse(&url).map_err(|e| invalid_proxy(&url, &e)))
+            .transpose()?;
+        let no_proxy = env::var("RPC_NO_PROXY").ok();
+        let proxy = proxy_url
+            .as_ref()
+            .map(|url| {
+                reqwest::Proxy::all(url.clone())
+                    .map_err(|e| invalid_proxy(url.as_str(), &e))
+                    .map(|proxy| {
+                        proxy.no_proxy(no_proxy.as_deref().and_then(reqwest::NoProxy::from_string))
+                    })
+            })
+            .transpose()?;
+        let ca_pem = env::var("RPC_CA_CERT")
+            .ok()
+            .map(|path| {
+                let pem = fs::read(&path)
+                    .map_err(Error::io(format!("Failed to read CA certificate {}", path)))?;
+                Ok::<_, Error>((path, pem))
+            })
+            .transpose()?;
+        let invalid_cert = |path: &str, e: &dyn std::fmt::Display| {
+            Error::Config(format!("Invalid CA certificate {}: {}", path, e))
+        };
+        let ca_cert = ca_pem
+            .as_ref()
+            .map(|(path, pem)| {
+                reqwest::Certificate::from_pem(pem).map_err(|e| invalid_cert(path, &e))
+            })
+            .transpose()?;
+        let ca_cert_native = ca_pem
+            .as_ref()
+            .map(|(path, pem)| {
+                native_tls::Certificate::from_pem(pem).map_err(|e| invalid_cert(path, &e))
+            })
+            .transpose()?;
+
+        Ok(HttpSettings {
+            proxy,
+            proxy_url,
+            no_proxy,
+            ca_cert,
+            ca_cert_native,
+            headers: auth_headers("RPC")?,
+            fiber_headers: auth_headers("FIBER_RPC")?,
+            connect_timeout: env_secs("RPC_CONNECT_TIMEOUT_SECS")?
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            request_timeout: env_secs("RPC_TIMEOUT_SECS")?.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+            keepalive: env_secs("RPC_KEEPALIVE_SECS")?.unwrap_or(DEFAULT_KEEPALIVE),
+            pool_idle_timeout: env_secs("RPC_POOL_IDLE_SECS")?.unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT),
+            shared_client: Arc::default(),
+            fiber_client: Arc::default(),
+            ckb_clients: Arc::default(),
+            light_clients: Arc::default(),
+            rate_limit: RateLimit::from_env("RPC")?,
+            fiber_rate_limit: RateLimit::from_env("FIBER_RPC")?,
+            rate_limiters: Arc::default(),
+        })
+    }
+
+    /// Apply proxy, CA and authentication headers to an RPC client builder
+    fn apply(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        let mut builder = builder
+            .default_headers(self.headers.clone())
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .tcp_keepalive(self.keepalive)
+            .pool_idle_timeout(self.pool_idle_timeout);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        if let Some(cert) = &self.ca_cert {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        builder
+    }
+
+    /// The CKB RPC client for `url`, with proxy, CA, authentication, timeouts and
+    /// keep-alive applied. Clients for the same URL share one connection pool.
+    pub fn ckb_client(&self, url: &str) -> Result<CkbRpcClient, Error> {
+        let mut clients = self.ckb_clients.lock().unwrap();
+        if let Some(client) = clients.get(url) {
+            return Ok(client.clone());
+        }
+        let client =
+            CkbRpcClient::with_builder(url, |builder| self.apply(builder)).map_err(|e| {
+                Error::Config(format!(
+                    "Failed to create CKB RPC client for {}: {}",
+                    url, e
+                ))
+            })?;
+        clients.insert(url.to_string(), client.clone());
+        Ok(client)
+    }
+
+    /// The CKB light client RPC client for `url`, set up like `ckb_client`
+    pub fn light_client(&self, url: &str) -> Result<LightClientRpcClient, Error> {
+        let mut clients = self.light_clients.lock().unwrap();
+        if let Some(client) = clients.get(url) {
+            return Ok(client.clone());
+        }
+        let client = LightClientRpcClient::with_builder(url, |builder| self.apply(builder))
+            .map_err(|e| {
+                Error::Config(format!(
+                    "Failed to create CKB light client RPC client for {}: {}",
+                    url, e
+                ))
+            })?;
+        clients.insert(url.to_string(), client.clone());
+        Ok(client)
+    }
+
+    /// The rate limiter of the CKB RPC endpoint at `url`, `None` without a limit
+    pub fn ckb_rate_limiter(&self, url: &str) -> Option<Arc<RateLimiter>> {
+        self.rate_limiter(self.rate_limit?, url)
+    }
+
+    /// The rate limiter of the Fiber node at `url`, `None` without a limit
+    pub fn fiber_rate_limiter(&self, url: &str) -> Option<Arc<RateLimiter>> {
+        self.rate_limiter(self.fiber_rate_limit?, url)
+    }
+
+    fn rate_limiter(&self, limit: RateLimit, url: &str) -> Option<Arc<RateLimiter>> {
+        let mut limiters = self.rate_limiters.lock().unwrap();
+        let limiter = limiters
+            .entry(url.to_string())
+            .or_insert_with(|| Arc::new(RateLimiter::new(url, limit)));
+        Some(limiter.clone())
+    }
+
+    /// A TCP connection to `host:port` through the proxy, for connections reqwest
+    /// doesn't make. `None` without a proxy or when RPC_NO_PROXY exempts the host.
+    pub fn tunnel(&self, host: &str, port: u16) -> Option<io::Result<TcpStream>> {
+        let proxy = self.proxy_url.as_ref()?;
+        if self
+            .no_proxy
+            .as_deref()
+            .is_some_and(|no_proxy| bypasses_proxy(no_proxy, host))
+        {
+            return None;
+        }
+        Some(crate::proxy::connect(
+            proxy,
+            host,
+            port,
+            self.connect_timeout,
+        ))
+    }
+
+    /// TLS settings for connections reqwest doesn't make, trusting RPC_CA_CERT too
+    pub fn tls_connector(&self) -> native_tls::Result<native_tls::TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(cert) = &self.ca_cert_native {
+            builder.add_root_certificate(cert.clone());
+        }
+        builder.build()
+    }
+
+    /// The client for third-party services, with proxy, CA, timeouts and keep-alive
+    /// applied and the credentials left out. Clones share one connection pool.
+    pub fn shared_client(&self, purpose: &str) -> Result<reqwest::blocking::Client, Error> {
+        if let Some(client) = self.shared_client.get() {
+            return Ok(client.clone());
+        }
+        let client = self.blocking_client(HeaderMap::new(), purpose)?;
+        Ok(self.shared_client.get_or_init(|| client).clone())
+    }
+
+    /// The client for Fiber nodes, set up like `shared_client` and sending the
+    /// FIBER_RPC_* credentials and headers rather than the CKB RPC ones
+    pub fn fiber_client(&self) -> Result<reqwest::blocking::Client, Error> {
+        if let Some(client) = self.fiber_client.get() {
+            return Ok(client.clone());
+        }
+        let client = self.blocking_client(self.fiber_headers.clone(), "Fiber RPC")?;
+        Ok(self.fiber_client.get_or_init(|| client).clone())
+    }
+
+    fn blocking_client(
+        &self,
+        headers: HeaderMap,
+        purpose: &str,
+    ) -> Result<reqwest::blocking::Client, Error> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .default_headers(headers)
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .tcp_keepalive(self.keepalive)
+            .pool_idle_timeout(self.pool_idle_timeout);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        if let Some(cert) = &self.ca_cert {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        builder
+            .build()
+            .map_err(|e| Error::Config(format!("Failed to create {} HTTP client: {}", purpose, e)))
+    }
+}
+
+/// Create a client for the CKB RPC endpoints of `backend` at `urls`, the primary
+/// first, that uses the configured proxy, CA and authentication and retries transient
+/// failures as configured by the RPC_MAX_ATTEMPTS and RPC_RETRY_DELAY_MS env vars.
+/// Calls fail over to the next endpoint when one fails; with RPC_LOAD_BALANCE=1, cell
+/// lookups are spread over all healthy endpoints. Requests over RPC_RATE_LIMIT wait for
+/// their turn.
+pub fn new_ckb_client(
+    urls: &[&str],
+    backend: ChainBackend,
+    settings: &HttpSettings,
+) -> Result<RetryingClient, Error> {
+    if urls.is_empty() {
+        return Err(Error::Config("No CKB RPC endpoint configured".to_string()));
+    }
+    let endpoints = urls
+        .iter()
+        .map(|url| {
+            Ok(Endpoint {
+                url: url.to_string(),
+                backend: match backend {
+                    ChainBackend::FullNode => {
+                        Backend::FullNode(CkbRpcAsyncClient::from(&settings.ckb_client(url)?))
+                    }
+                    ChainBackend::LightClient => Backend::LightClient(LightClient::new(
+                        LightClientRpcAsyncClient::from(&settings.light_client(url)?),
+                        settings.ckb_rate_limiter(url),
+                    )?),
+                },
+                limiter: settings.ckb_rate_limiter(url),
+                failed_at: Mutex::new(None),
+            })
+        })
+        .collect::<Result<_, Error>>()?;
+    Ok(RetryingClient {
+        endpoints,
+        policy: RetryPolicy::from_env()?,
+        cooldown: env_secs("RPC_FAILOVER_COOLDOWN_SECS")?.unwrap_or(DEFAULT_FAILOVER_COOLDOWN),
+        load_balance: env::var("RPC_LOAD_BALANCE").is_ok_and(|v| v == "1"),
+        next: AtomicUsize::new(0),
+        cells: CellCache::from_env()?,
+    })
+}
+
+/// The runtime RPC calls run on. `main` enters it for the whole run, so the async
+/// calls of `RetryingClient` and those of ckb-sdk's blocking clients share its
+/// connection pools.
+pub fn runtime() -> Result<tokio::runtime::Runtime, Error> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::io("Failed to start the RPC runtime"))
+}
+
+/// Run `future` to completion from synchronous code, on the runtime entered on the
+/// calling thread, or on one of its own on a thread that entered none
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => tokio::task::block_in_place(|| runtime.block_on(future)),
+        Err(_) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to start an RPC runtime")
+            .block_on(future),
+    }
+}
+
+/// The outputs of `futures` in order, with up to RPC_CONCURRENCY of them running at
+/// once
+pub async fn concurrently<F: Future>(
+    futures: impl IntoIterator<Item = F>,
+) -> Result<Vec<F::Output>, Error> {
+    let concurrency = env_parse("RPC_CONCURRENCY")?.unwrap_or(DEFAULT_CONCURRENCY);
+    Ok(stream::iter(futures)
+        .buffered(concurrency.max(1))
+        .collect()
+        .await)
+}
+
+/// Exponential backoff for transient RPC failures
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Read the RPC_MAX_ATTEMPTS and RPC_RETRY_DELAY_MS env vars
+    pub fn from_env() -> Result<Self, Error> {
+        let max_attempts: u32 = env_parse("RPC_MAX_ATTEMPTS")?.unwrap_or(DEFAULT_MAX_ATTEMPTS);
+        let base_delay =
+            env_parse("RPC_RETRY_DELAY_MS")?.map_or(DEFAULT_RETRY_DELAY, Duration::from_millis);
+        Ok(RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+        })
+    }
+
+    /// No retries, for probes that poll on their own
+    pub fn single_attempt() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::ZERO,
+        }
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Delay before retry number `retry` (starting at 1): the base delay doubled per
+    /// retry up to MAX_RETRY_DELAY, plus up to 50% jitter so parallel runs don't retry
+    /// in lockstep
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << (retry - 1).min(16))
+            .min(MAX_RETRY_DELAY);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        delay + delay.mul_f64(f64::from(nanos % 1000) / 2000.0)
+    }
+}
+
+/// Connection failures, timeouts and internal node errors (-32603) are worth retrying;
+/// anything else, like an invalid request or a rejected transaction, is not
+fn is_transient(error: &RpcError) -> bool {
+    match error {
+        RpcError::Http(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+        RpcError::Rpc(e) => e.code.code() == -32603,
+        _ => false,
+    }
+}
+
+/// Record a cell lookup in the audit log, with where its cells came from
+fn record_cell_query(search_key: &SearchKey, cells: &[Cell], source: &str) {
+    audit::record(
+        "cell_query",
+        json!({
+            "script": search_key.script,
+            "script_type": search_key.script_type,
+            "filter": search_key.filter,
+            "cells": cells.len(),
+            "capacity": cells.iter().map(|cell| cell.output.capacity.value()).sum::<u64>(),
+            "source": source,
+        }),
+    );
+}
+
+/// `search_key` narrowed to cells created in blocks `from..=to`
+fn in_blocks(search_key: &SearchKey, from: u64, to: u64) -> SearchKey {
+    let mut search_key = search_key.clone();
+    let filter = search_key
+        .filter
+        .get_or_insert_with(SearchKeyFilter::default);
+    filter.block_range = Some([from.into(), (to + 1).into()]);
+    search_key
+}
+
+/// CKB RPC client retrying transient failures with exponential backoff, failing over
+/// between its endpoints. Exposes the subset of `CkbRpcClient` calls the tool uses as
+/// async methods, answered by a light client from what it can tell when the backend is
+/// one; synchronous code runs them with `block_on`.
+pub struct RetryingClient {
+    /// The primary first, then the fallbacks in the order configured
+    endpoints: Vec<Endpoint>,
+    policy: RetryPolicy,
+    cooldown: Duration,
+    /// Spread cell lookups over the healthy endpoints instead of preferring the primary
+    load_balance: bool,
+    /// Endpoint the next balanced lookup starts from
+    next: AtomicUsize,
+    /// Live cells of `collect_cells`, `None` when disabled
+    cells: Option<CellCache>,
+}
+
+struct Endpoint {
+    url: String,
+    backend: Backend,
+    limiter: Option<Arc<RateLimiter>>,
+    /// When a call to the endpoint last failed, cleared once one succeeds
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl Endpoint {
+    fn is_healthy(&self, cooldown: Duration) -> bool {
+        self.failed_at
+            .lock()
+            .unwrap()
+            .is_none_or(|at| at.elapsed() >= cooldown)
+    }
+}
+
+enum Backend {
+    FullNode(CkbRpcAsyncClient),
+    LightClient(LightClient),
+}
+
+impl Backend {
+    fn full_node(&self, method: &str) -> Result<&CkbRpcAsyncClient, RpcError> {
+        match self {
+            Backend::FullNode(client) => Ok(client),
+            Backend::LightClient(_) => Err(RpcError::Other(anyhow!(
+                "{} is not supported by the CKB light client backend",
+                method
+            ))),
+        }
+    }
+}
+
+impl RetryingClient {
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// URL of the primary endpoint
+    pub fn url(&self) -> &str {
+        &self.endpoints[0].url
+    }
+
+    /// Endpoints in the order a call tries them: the healthy ones first, from the
+    /// primary on or, for a `balanced` call with load balancing on, from the next one
+    /// in turn, then those cooling down after a failure
+    fn endpoint_order(&self, balanced: bool) -> Vec<&Endpoint> {
+        let count = self.endpoints.len();
+        let start = if balanced && self.load_balance {
+            self.next.fetch_add(1, Ordering::Relaxed) % count
+        } else {
+            0
+        };
+        let (healthy, cooling): (Vec<_>, Vec<_>) = (0..count)
+            .map(|i| &self.endpoints[(start + i) % count])
+            .partition(|endpoint| endpoint.is_healthy(self.cooldown));
+        healthy.into_iter().chain(cooling).collect()
+    }
+
+    /// Run `call` on the first endpoint in order, failing over at once to the next
+    /// healthy one on a transient failure and backing off when there is none. Every
+    /// endpoint gets at least one attempt.
+    async fn retry<T>(
+        &self,
+        method: &str,
+        balanced: bool,
+        mut call: impl for<'a> FnMut(&'a Backend) -> BoxFuture<'a, Result<T, RpcError>>,
+    ) -> Result<T, RpcError> {
+        let max_attempts = self.policy.max_attempts.max(self.endpoints.len() as u32);
+        let mut attempt = 1;
+        loop {
+            let order = self.endpoint_order(balanced);
+            let endpoint = order[0];
+            // The light client limits each of the requests a call makes on its own
+            if let (Backend::FullNode(_), Some(limiter)) = (&endpoint.backend, &endpoint.limiter) {
+                limiter.acquire_async().await;
+            }
+            let started = Instant::now();
+            let result = call(&endpoint.backend).await;
+            metrics::observe_rpc("ckb", method, started.elapsed(), result.is_ok());
+            match result {
+                Err(e) if attempt < max_attempts && is_transient(&e) => {
+                    if self.endpoints.len() > 1 {
+                        *endpoint.failed_at.lock().unwrap() = Some(Instant::now());
+                    }
+                    match order.get(1).filter(|next| next.is_healthy(self.cooldown)) {
+                        Some(next) => warn!(
+                            "RPC {} on {} failed (attempt {}/{}), failing over to {}: {}",
+                            method, endpoint.url, attempt, max_attempts, next.url, e
+                        ),
+                        None => {
+                            let delay = self.policy.delay(attempt);
+                            warn!(
+                                "RPC {} failed (attempt {}/{}), retrying in {:.1}s: {}",
+                                method,
+                                attempt,
+                                max_attempts,
+                                delay.as_secs_f64(),
+                                e
+                            );
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                    attempt += 1;
+                }
+                result => {
+                    if result.is_ok() && endpoint.failed_at.lock().unwrap().take().is_some() {
+                        info!("CKB RPC endpoint {} recovered", endpoint.url);
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+
+    pub async fn get_cells(
+        &self,
+        search_key: SearchKey,
+        order: Order,
+        limit: Uint32,
+        after: Option<JsonBytes>,
+    ) -> Result<Pagination<Cell>, RpcError> {
+        self.retry("get_cells", true, |backend| match backend {
+            Backend::FullNode(c) => c
+                .get_cells(search_key.clone(), order.clone(), limit, after.clone())
+                .boxed(),
+            Backend::LightClient(c) => c
+                .get_cells(search_key.clone(), order.clone(), limit, after.clone())
+                .boxed(),
+        })
+        .await
+    }
+
+    /// All live cells matching `search_key`, in block order. With the cell cache on,
+    /// a repeated lookup only pages through the blocks indexed since the last one.
+    pub async fn collect_cells(&self, search_key: SearchKey) -> Result<Vec<Cell>, RpcError> {
+        let Some(cache) = &self.cells else {
+            let cells = self.page_cells(search_key.clone()).await?;
+            record_cell_query(&search_key, &cells, "indexer");
+            return Ok(cells);
+        };
+        let tip = self
+            .get_indexer_tip()
+            .await?
+            .map_or(0, |tip| tip.block_number.value());
+        let cells = match cache.get(&search_key) {
+            Some(cached) if cached.tip >= tip => {
+                debug!("Cell cache hit: {} cell(s)", cached.cells.len());
+                record_cell_query(&search_key, &cached.cells, "cache");
+                cached.cells
+            }
+            Some(mut cached) => {
+                let new = self
+                    .page_cells(in_blocks(&search_key, cached.tip + 1, tip))
+                    .await?;
+                debug!(
+                    "Cell cache topped up with {} cell(s) of blocks {}..={}",
+                    new.len(),
+                    cached.tip + 1,
+                    tip
+                );
+                cache.extend(&search_key, new.clone(), tip);
+                cached.cells.extend(new);
+                record_cell_query(&search_key, &cached.cells, "cache+indexer");
+                cached.cells
+            }
+            None => {
+                let cells = self.page_cells(in_blocks(&search_key, 0, tip)).await?;
+                cache.insert(&search_key, cells.clone(), tip);
+                record_cell_query(&search_key, &cells, "indexer");
+                cells
+            }
+        };
+        Ok(cells)
+    }
+
+    /// Page through all cells matching `search_key`, with a counter once it takes more
+    /// than one page
+    async fn page_cells(&self, search_key: SearchKey) -> Result<Vec<Cell>, RpcError> {
+        let mut cells = Vec::new();
+        let mut cursor = None;
+        let mut progress = None;
+        loop {
+            let page = self
+                .get_cells(
+                    search_key.clone(),
+                    Order::Asc,
+                    CELLS_PAGE_SIZE.into(),
+                    cursor,
+                )
+                .await?;
+            if page.objects.is_empty() {
+                return Ok(cells);
+            }
+            let full = page.objects.len() >= CELLS_PAGE_SIZE as usize;
+            cells.extend(page.objects);
+            cursor = Some(page.last_cursor);
+            if full || progress.is_some() {
+                progress
+                    .get_or_insert_with(|| Progress::spinner("Collecting cells"))
+                    .set_message(format!("Collecting cells: {} so far", cells.len()));
+            }
+        }
+    }
+
+    pub async fn get_cells_capacity(
+        &self,
+        search_key: SearchKey,
+    ) -> Result<Option<CellsCapacity>, RpcError> {
+        let capacity = self
+            .retry("get_cells_capacity", true, |backend| match backend {
+                Backend::FullNode(c) => c.get_cells_capacity(search_key.clone()).boxed(),
+                Backend::LightClient(c) => c
+                    .get_cells_capacity(search_key.clone())
+                    .map(|capacity| capacity.map(Some))
+                    .boxed(),
+            })
+            .await?;
+        audit::record(
+            "capacity_query",
+            json!({
+                "script": search_key.script,
+                "script_type": search_key.script_type,
+                "filter": search_key.filter,
+                "capacity": capacity.as_ref().map(|c| c.capacity.value()),
+            }),
+        );
+        Ok(capacity)
+    }
+
+    /// The light client only serves the genesis block
+    pub async fn get_block_by_number(
+        &self,
+        number: BlockNumber,
+    ) -> Result<Option<BlockView>, RpcError> {
+        self.retry("get_block_by_number", true, |backend| match backend {
+            Backend::LightClient(c) if number.value() == 0 => {
+                c.get_genesis_block().map(|block| block.map(Some)).boxed()
+            }
+            backend => match backend.full_node("get_block_by_number") {
+                Ok(c) => c.get_block_by_number(number).boxed(),
+                Err(e) => async { Err(e) }.boxed(),
+            },
+        })
+        .await
+    }
+
+    /// The light client syncs each script on its own, waited for by `get_cells`, so its
+    /// tip counts as indexed
+    pub async fn get_indexer_tip(&self) -> Result<Option<Tip>, RpcError> {
+        self.retry("get_indexer_tip", false, |backend| match backend {
+            Backend::FullNode(c) => c.get_indexer_tip().boxed(),
+            Backend::LightClient(c) => c
+                .get_tip_header()
+                .map(|header| {
+                    header.map(|header| {
+                        Some(Tip {
+                            block_hash: header.hash,
+                            block_number: header.inner.number,
+                        })
+                    })
+                })
+                .boxed(),
+        })
+        .await
+    }
+
+    pub async fn get_tip_block_number(&self) -> Result<BlockNumber, RpcError> {
+        self.retry("get_tip_block_number", false, |backend| match backend {
+            Backend::FullNode(c) => c.get_tip_block_number().boxed(),
+            Backend::LightClient(c) => c
+                .get_tip_header()
+                .map(|header| header.map(|header| header.inner.number))
+                .boxed(),
+        })
+        .await
+    }
+
+    pub async fn get_live_cell(
+        &self,
+        out_point: OutPoint,
+        with_data: bool,
+    ) -> Result<CellWithStatus, RpcError> {
+        self.retry("get_live_cell", true, |backend| match backend {
+            Backend::FullNode(c) => c.get_live_cell(out_point.clone(), with_data).boxed(),
+            Backend::LightClient(c) => c.get_live_cell(out_point.clone(), with_data).boxed(),
+        })
+        .await
+    }
+
+    pub async fn get_transaction(
+        &self,
+        hash: H256,
+    ) -> Result<Option<TransactionWithStatusResponse>, RpcError> {
+        self.retry("get_transaction", false, |backend| match backend {
+            Backend::FullNode(c) => c.get_transaction(hash.clone()).boxed(),
+            Backend::LightClient(c) => c.get_transaction(hash.clone()).boxed(),
+        })
+        .await
+    }
+
+    /// A retry after a lost response finds the transaction already in the pool, which
+    /// counts as sent
+    pub async fn send_transaction(
+        &self,
+        tx: Transaction,
+        outputs_validator: Option<OutputsValidator>,
+    ) -> Result<H256, RpcError> {
+        let tx_hash: H256 = packed::Transaction::from(tx.clone())
+            .calc_tx_hash()
+            .unpack();
+        let mut resent = false;
+        let sent = self
+            .retry("send_transaction", false, |backend| {
+                let (tx, outputs_validator, tx_hash) =
+                    (tx.clone(), outputs_validator.clone(), tx_hash.clone());
+                let retried = resent;
+                resent = true;
+                async move {
+                    let sent = match backend {
+                        Backend::FullNode(c) => c.send_transaction(tx, outputs_validator).await,
+                        Backend::LightClient(c) => c.send_transaction(tx).await,
+                    };
+                    match sent {
+                        Err(RpcError::Rpc(e))
+                            if retried && e.code.code() == DUPLICATED_TX_ERROR =>
+                        {
+                            Ok(tx_hash)
+                        }
+                        result => result,
+                    }
+                }
+                .boxed()
+            })
+            .await;
+        if let Some(cache) = &self.cells {
+            let spent: Vec<OutPoint> = tx
+                .inputs
+                .iter()
+                .map(|input| input.previous_output.clone())
+                .collect();
+            match &sent {
+                Ok(_) => cache.spend(&spent),
+                Err(RpcError::Rpc(e)) if e.code.code() == FAILED_TO_RESOLVE_ERROR => {
+                    cache.invalidate(&spent)
+                }
+                Err(_) => {}
+            }
+        }
+        metrics::transaction_sent(sent.is_ok());
+        audit::record(
+            "broadcast",
+            match &sent {
+                Ok(_) => json!({"tx_hash": format!("{:#x}", tx_hash), "result": "sent"}),
+                Err(e) => json!({
+                    "tx_hash": format!("{:#x}", tx_hash),
+                    "result": "failed",
+                    "error": e.to_string(),
+                }),
+            },
+        );
+        sent
+    }
+
+    /// Mine a block on a dev chain with `generate_block`, or with `get_block_template`
+    /// and `submit_block` when the IntegrationTest module is off. The template's nonce
+    /// is only accepted by the dummy PoW of dev chains. A retry after a lost response
+    /// may mine an extra block.
+    pub async fn generate_block(&self) -> Result<H256, RpcError> {
+        self.retry("generate_block", false, |backend| {
+            async move {
+                let c = backend.full_node("generate_block")?;
+                match c.generate_block().await {
+                    Err(RpcError::Rpc(e)) if e.code.code() == METHOD_NOT_FOUND_ERROR => {
+                        let template = c.get_block_template(None, None, None).await?;
+                        let work_id = template.work_id.value().to_string();
+                        let block = packed::Block::from(template);
+                        c.submit_block(work_id, block.into()).await
+                    }
+                    result => result,
+                }
+            }
+            .boxed()
+        })
+        .await
+    }
+}
+
+/// Whether `host` is in `no_proxy`: `*`, the host itself, or a domain it is under
+fn bypasses_proxy(no_proxy: &str, host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    no_proxy.split(',').map(str::trim).any(|entry| {
+        let domain = entry.trim_start_matches('.');
+        entry == "*"
+            || (!domain.is_empty()
+                && (host.eq_ignore_ascii_case(domain)
+                    || host
+                        .to_ascii_lowercase()
+                        .ends_with(&format!(".{}", domain.to_ascii_lowercase()))))
+    })
+}
+
+/// Headers from the `{prefix}_BEARER_TOKEN`, `{prefix}_BASIC_AUTH` (`user:password`)
+/// and `{prefix}_HEADERS` env vars
+fn auth_headers(prefix: &str) -> Result<HeaderMap, Error> {
+    let mut headers = HeaderMap::new();
+    if let Ok(token) = env::var(format!("{}_BEARER_TOKEN", prefix)) {
+        let name = format!("{}_BEARER_TOKEN", prefix);
+        headers.insert(
+            AUTHORIZATION,
+            sensitive_header(&name, &format!("Bearer {}", token))?,
+        );
+    }
+    if let Ok(credentials) = env::var(format!("{}_BASIC_AUTH", prefix)) {
+        if !credentials.contains(':') {
+            return Err(Error::Config(format!(
+                "{}_BASIC_AUTH must be in the form user:password",
+                prefix
+            )));
+        }
+        let name = format!("{}_BASIC_AUTH", prefix);
+        let value = format!("Basic {}", BASE64.encode(credentials));
+        headers.insert(AUTHORIZATION, sensitive_header(&name, &value)?);
+    }
+    if let Ok(extra) = env::var(format!("{}_HEADERS", prefix)) {
+        for line in extra.split(['\n', ';']).filter(|l| !l.trim().is_empty()) {
+            let (name, value) = line.split_once(':').ok_or_else(|| {
+                Error::Config(format!("Invalid {}_HEADERS entry {:?}", prefix, line))
+            })?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|e| Error::Config(format!("Invalid header name {:?}: {}", name, e)))?;
+            let value = sensitive_header(&format!("{}_HEADERS {}", prefix, name), value.trim())?;
+            headers.insert(name, value);
+        }
+    }
+    Ok(headers)
+}
+
+/// A header value that is kept out of debug output. The error names only `source`,
+/// since the value holds credentials.
+fn sensitive_header(source: &str, value: &str) -> Result<HeaderValue, Error> {
+    let mut value = HeaderValue::from_str(value)
+        .map_err(|_| Error::Config(format!("Invalid header value in {}", source)))?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// Overall deadline for the whole startup flow, checked by every polling loop so a
+/// stalled step fails with a clear error instead of blocking indefinitely
+#[derive(Clone, Copy)]
+pub struct Deadline {
+    limit: Option<(Instant, Duration)>,
+}
+
+impl Deadline {
+    /// Read the deadline from the OPERATION_DEADLINE_SECS env var; unset means no deadline
+    pub fn from_env() -> Result<Self, Error> {
+        Ok(Deadline {
+            limit: env_secs("OPERATION_DEADLINE_SECS")?.map(|d| (Instant::now() + d, d)),
+        })
+    }
+
+    /// Fail if the deadline has passed, naming the step that was in progress
+    pub fn check(&self, step: &str) -> Result<(), Error> {
+        match self.limit {
+            Some((at, duration)) if Instant::now() >= at => Err(Error::Timeout(format!(
+                "Operation deadline of {}s exceeded while {}",
+                duration.as_secs(),
+                step
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Read a duration in whole seconds from the given env var, `None` when it is unset
+pub fn env_secs(name: &str) -> Result<Option<Duration>, Error> {
+    Ok(env_parse(name)?.map(Duration::from_secs))
+}
+
+/// Parse the given env var, `None` when it is unset
+pub fn env_parse<T>(name: &str) -> Result<Option<T>, Error>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    env::var(name)
+        .ok()
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|e| Error::Config(format!("Invalid {} value {:?}: {}", name, value, e)))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_proxy_matches_hosts_and_domains() {
+        let no_proxy = "localhost, .internal,example.com";
+        assert!(bypasses_proxy(no_proxy, "localhost"));
+        assert!(bypasses_proxy(no_proxy, "LOCALHOST"));
+        assert!(bypasses_proxy(no_proxy, "node.internal"));
+        assert!(bypasses_proxy(no_proxy, "a.b.internal"));
+        assert!(bypasses_proxy(no_proxy, "internal"));
+        assert!(bypasses_proxy(no_proxy, "example.com"));
+        assert!(bypasses_proxy(no_proxy, "rpc.Example.COM"));
+        assert!(!bypasses_proxy(no_proxy, "notexample.com"));
+        assert!(!bypasses_proxy(no_proxy, "example.com.evil"));
+        assert!(!bypasses_proxy(no_proxy, "testnet.ckb.dev"));
+    }
+
+    #[test]
+    fn no_proxy_wildcard_and_empty_entries() {
+        assert!(bypasses_proxy("*", "anything.example"));
+        assert!(!bypasses_proxy("", "localhost"));
+        assert!(!bypasses_proxy(" , .", "localhost"));
+    }
+
+    #[test]
+    fn no_proxy_matches_bracketed_ipv6_hosts() {
+        assert!(bypasses_proxy("::1", "[::1]"));
+        assert!(bypasses_proxy("127.0.0.1", "127.0.0.1"));
+        assert!(!bypasses_proxy("127.0.0.1", "127.0.0.10"));
+    }
+}
@@ -0,0 +1,315 @@
+use crate::error::{Error, Result};
+use crate::fiber::{self, CHANNEL_READY, FiberClient, PAYMENT_FAILED, PAYMENT_SUCCESS};
+use crate::network::Token;
+use crate::rpc::{Deadline, HttpSettings};
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+// Polling interval of a step's payment
+const STEP_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// How long an offline node's peers may take to notice it is gone before paying it
+const OFFLINE_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// What a scenario step does to its payment
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum StepKind {
+    /// A plain payment
+    Pay,
+    /// Pay an invoice after its expiry passed
+    ExpiredInvoice,
+    /// Pay more than the payer's channels of the asset hold together
+    AboveCapacity,
+    /// Pay a node that is disconnected from all its channel peers, reconnecting it
+    /// afterwards
+    OfflineNode,
+}
+
+impl StepKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            StepKind::Pay => "pay",
+            StepKind::ExpiredInvoice => "expired_invoice",
+            StepKind::AboveCapacity => "above_capacity",
+            StepKind::OfflineNode => "offline_node",
+        }
+    }
+}
+
+/// A payment of a scenario with the outcome it must have
+pub struct ScenarioStep<'a> {
+    pub name: String,
+    pub kind: StepKind,
+    /// Node paying the invoice
+    pub from: &'a str,
+    pub from_rpc_url: &'a str,
+    /// Node issuing the invoice
+    pub to: &'a str,
+    pub to_rpc_url: &'a str,
+    /// UDT paid, CKB when None
+    pub token: Option<&'a Token>,
+    /// Base units paid, None for one more than the payer's ready channels of the asset
+    /// hold together
+    pub amount: Option<u128>,
+    /// Text the payment's error must contain. None expects success for `pay` and any
+    /// error for the other kinds.
+    pub expect_error: Option<String>,
+    /// Expiry of the invoice of an `expired_invoice` step
+    pub expiry: Duration,
+}
+
+impl ScenarioStep<'_> {
+    fn expected(&self) -> String {
+        match (&self.expect_error, self.kind) {
+            (Some(text), _) => format!("error containing {:?}", text),
+            (None, StepKind::Pay) => "success".to_string(),
+            (None, _) => "an error".to_string(),
+        }
+    }
+
+    /// Whether a payment that failed with `observed`, or succeeded when None, is what
+    /// the step expects
+    fn passes(&self, observed: Option<&str>) -> bool {
+        match (&self.expect_error, observed) {
+            (Some(text), Some(observed)) => observed.contains(text.as_str()),
+            (Some(_), None) => false,
+            (None, observed) => observed.is_some() != matches!(self.kind, StepKind::Pay),
+        }
+    }
+}
+
+/// Outcome of a step
+#[derive(Serialize)]
+pub struct StepOutcome {
+    pub step: String,
+    pub kind: &'static str,
+    pub expected: String,
+    /// `succeeded`, or the error the payment failed with
+    pub observed: String,
+    pub passed: bool,
+}
+
+/// Run `steps` in order against the funded network, recording each step's outcome.
+/// `nodes` (name, RPC URL) are the Fiber nodes an offline node is reconnected to.
+/// Only a step that can't be set up, e.g. a node that doesn't answer, stops the run.
+pub fn run(
+    steps: &[ScenarioStep],
+    nodes: &[(&str, &str)],
+    currency: &str,
+    settings: &HttpSettings,
+    deadline: &Deadline,
+) -> Result<Vec<StepOutcome>> {
+    let mut outcomes = Vec::new();
+    for step in steps {
+        info!(
+            "Step {}: {} from {} to {}",
+            step.name,
+            step.kind.name(),
+            step.from,
+            step.to
+        );
+        let observed = run_step(step, nodes, currency, settings, deadline)?;
+        let passed = step.passes(observed.as_deref());
+        let observed = observed.unwrap_or_else(|| "succeeded".to_string());
+        if passed {
+            info!("Step {} passed: {}", step.name, observed);
+        } else {
+            error!(
+                "Step {} failed: expected {}, observed {}",
+                step.name,
+                step.expected(),
+                observed
+            );
+        }
+        outcomes.push(StepOutcome {
+            step: step.name.clone(),
+            kind: step.kind.name(),
+            expected: step.expected(),
+            observed,
+            passed,
+        });
+    }
+    Ok(outcomes)
+}
+
+/// Make the step's payment. Returns the error it failed with, None when it succeeded.
+fn run_step(
+    step: &ScenarioStep,
+    nodes: &[(&str, &str)],
+    currency: &str,
+    settings: &HttpSettings,
+    deadline: &Deadline,
+) -> Result<Option<String>> {
+    let payer = FiberClient::new(step.from_rpc_url, settings)?;
+    let payee = FiberClient::new(step.to_rpc_url, settings)?;
+    let udt_script = step
+        .token
+        .map(|token| crate::get_sudt_type_script(&token.script));
+    let amount = match step.amount {
+        Some(amount) => amount,
+        None => {
+            let held: u128 = payer
+                .list_channels(None, false)?
+                .iter()
+                .filter(|c| {
+                    c.funding_udt_type_script == udt_script && c.state.state_name == CHANNEL_READY
+                })
+                .map(|c| c.local_balance.value())
+                .sum();
+            held + 1
+        }
+    };
+    let expiry = matches!(step.kind, StepKind::ExpiredInvoice).then_some(step.expiry);
+    let (invoice, _) = fiber::create_invoice(
+        &payee,
+        amount,
+        currency,
+        udt_script.as_ref(),
+        &format!("fiber-demo scenario {}", step.name),
+        expiry,
+    )?;
+    match step.kind {
+        StepKind::ExpiredInvoice => {
+            // Invoice expiry has a resolution of seconds
+            thread::sleep(step.expiry + Duration::from_secs(1));
+            attempt_payment(&payer, &invoice, deadline)
+        }
+        StepKind::OfflineNode => {
+            let peers: Vec<String> = payee
+                .list_channels(None, false)?
+                .into_iter()
+                .map(|c| c.peer_id)
+                .fold(Vec::new(), |mut peers, peer| {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                    peers
+                });
+            for peer in &peers {
+                payee.disconnect_peer(peer)?;
+            }
+            info!("Disconnected {} from {} peer(s)", step.to, peers.len());
+            thread::sleep(OFFLINE_SETTLE_TIME);
+            let observed = attempt_payment(&payer, &invoice, deadline);
+            reconnect(&payee, step.to, &peers, nodes, settings);
+            observed
+        }
+        StepKind::Pay | StepKind::AboveCapacity => attempt_payment(&payer, &invoice, deadline),
+    }
+}
+
+/// Pay `invoice` once, without retrying, and wait until the payment settles. Returns
+/// the error of `send_payment` or the payment's failure, None when it succeeded.
+fn attempt_payment(
+    payer: &FiberClient,
+    invoice: &str,
+    deadline: &Deadline,
+) -> Result<Option<String>> {
+    let mut payment = match payer.send_payment(invoice) {
+        Ok(payment) => payment,
+        Err(e) => return Ok(Some(e.to_string())),
+    };
+    let timeout = fiber::payment_timeout()?;
+    let started = Instant::now();
+    loop {
+        match payment.status.as_str() {
+            PAYMENT_SUCCESS => return Ok(None),
+            PAYMENT_FAILED => {
+                return Ok(Some(
+                    payment
+                        .failed_error
+                        .unwrap_or_else(|| "no reason given".to_string()),
+                ));
+            }
+            _ => {}
+        }
+        deadline.check("waiting for a scenario payment to settle")?;
+        if started.elapsed() >= timeout {
+            return Err(Error::Timeout(format!(
+                "Payment {} still {} after {}s",
+                payment.payment_hash,
+                payment.status,
+                timeout.as_secs()
+            )));
+        }
+        thread::sleep(STEP_POLL_INTERVAL);
+        payment = payer.get_payment(&payment.payment_hash)?;
+    }
+}
+
+/// Connect `node` back to the `peers` that are configured nodes, warning about the
+/// others, which have to reconnect on their own
+fn reconnect(
+    payee: &FiberClient,
+    node: &str,
+    peers: &[String],
+    nodes: &[(&str, &str)],
+    settings: &HttpSettings,
+) {
+    let mut addresses = Vec::new();
+    for (name, url) in nodes {
+        match FiberClient::new(url, settings).and_then(|client| client.node_info()) {
+            Ok(info) => {
+                if let Some((address, peer_id)) = info.peer_address()
+                    && peers.iter().any(|peer| peer == peer_id)
+                {
+                    addresses.push((peer_id.to_string(), address.to_string()));
+                }
+            }
+            Err(e) => warn!("Failed to look up the address of {}: {}", name, e),
+        }
+    }
+    for peer in peers {
+        match addresses.iter().find(|(peer_id, _)| peer_id == peer) {
+            Some((_, address)) => {
+                if let Err(e) = payee.connect_peer(address) {
+                    warn!("Failed to reconnect {} to {}: {}", node, peer, e);
+                }
+            }
+            None => warn!(
+                "{} is no configured node, {} reconnects to it on its own",
+                peer, node
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(kind: StepKind, expect_error: Option<&str>) -> ScenarioStep<'static> {
+        ScenarioStep {
+            name: "step".to_string(),
+            kind,
+            from: "Node1",
+            from_rpc_url: "http://node1",
+            to: "Node3",
+            to_rpc_url: "http://node3",
+            token: None,
+            amount: Some(1),
+            expect_error: expect_error.map(str::to_string),
+            expiry: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn steps_pass_on_the_expected_outcome() {
+        let pay = step(StepKind::Pay, None);
+        assert!(pay.passes(None));
+        assert!(!pay.passes(Some("no path found")));
+
+        let expired = step(StepKind::ExpiredInvoice, Some("expired"));
+        assert!(expired.passes(Some(
+            "Fiber RPC send_payment failed: invoice is expired (-32000)"
+        )));
+        assert!(!expired.passes(Some("no path found")));
+        assert!(!expired.passes(None));
+
+        let offline = step(StepKind::OfflineNode, None);
+        assert!(offline.passes(Some("any error")));
+        assert!(!offline.passes(None));
+    }
+}
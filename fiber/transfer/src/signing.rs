@@ -0,0 +1,260 @@
+use crate::error::{Error, Result};
+use crate::{LiveCell, get_lock_script_from_private_key};
+use ckb_jsonrpc_types::Script;
+use ckb_sdk::ScriptId;
+use ckb_sdk::constants::SIGHASH_TYPE_HASH;
+use ckb_sdk::traits::{OffchainTransactionDependencyProvider, SecpCkbRawKeySigner, Signer};
+use ckb_sdk::tx_builder::unlock_tx;
+use ckb_sdk::unlock::{ScriptUnlocker, SecpSighashUnlocker};
+use ckb_types::core::TransactionView;
+use ckb_types::packed::WitnessArgs;
+use ckb_types::prelude::*;
+use std::collections::HashMap;
+use tracing::instrument;
+
+// Size of a recoverable secp256k1 signature in a sighash witness
+const SIGNATURE_SIZE: usize = 65;
+
+/// Inputs of a transaction sharing one lock script, signed together by one key
+pub struct InputGroup {
+    pub lock: Script,
+    /// Input indices in the transaction, in ascending order
+    pub input_indices: Vec<usize>,
+}
+
+/// Group inputs by lock script, in order of each group's first input
+pub fn group_inputs(input_cells: &[&LiveCell]) -> Vec<InputGroup> {
+    let mut groups: Vec<InputGroup> = Vec::new();
+    for (index, cell) in input_cells.iter().enumerate() {
+        match groups.iter_mut().find(|g| g.lock == cell.output.lock) {
+            Some(group) => group.input_indices.push(index),
+            None => groups.push(InputGroup {
+                lock: cell.output.lock.clone(),
+                input_indices: vec![index],
+            }),
+        }
+    }
+    groups
+}
+
+/// Private keys available for signing, indexed by the sighash lock they unlock
+#[derive(Default)]
+pub struct Signers {
+    keys: HashMap<Script, String>,
+}
+
+impl Signers {
+    pub fn new(private_keys_hex: &[&str]) -> Result<Self> {
+        let mut signers = Signers::default();
+        for key in private_keys_hex {
+            signers.add_key(key)?;
+        }
+        Ok(signers)
+    }
+
+    pub fn add_key(&mut self, private_key_hex: &str) -> Result<()> {
+        self.keys.insert(
+            get_lock_script_from_private_key(private_key_hex)?,
+            private_key_hex.to_string(),
+        );
+        Ok(())
+    }
+
+    /// Resolve the signing key of every input group, failing with the first group
+    /// that none of the known keys can unlock
+    fn resolve(&self, groups: &[InputGroup]) -> Result<Vec<secp256k1::SecretKey>> {
+        groups
+            .iter()
+            .map(|group| {
+                let key = self.keys.get(&group.lock).ok_or_else(|| {
+                    Error::Signing(format!(
+                        "missing signer for inputs {:?} locked by code_hash {:#x}, args 0x{}",
+                        group.input_indices,
+                        group.lock.code_hash,
+                        hex::encode(group.lock.args.as_bytes())
+                    ))
+                })?;
+                // Keys were validated by add_key
+                let private_key_bytes = hex::decode(key).unwrap();
+                Ok(secp256k1::SecretKey::from_slice(&private_key_bytes).unwrap())
+            })
+            .collect()
+    }
+}
+
+/// Build one witness per input: a WitnessArgs with a zeroed 65-byte signature
+/// placeholder at the first input of each lock script group, and empty bytes for the
+/// remaining inputs of the group, as the sighash lock expects
+pub fn build_witnesses(input_cells: &[&LiveCell]) -> Vec<ckb_types::packed::Bytes> {
+    let placeholder = WitnessArgs::new_builder()
+        .lock(Some(ckb_types::bytes::Bytes::from(vec![0u8; SIGNATURE_SIZE])).pack())
+        .build();
+
+    let mut witnesses = vec![ckb_types::packed::Bytes::default(); input_cells.len()];
+    for group in group_inputs(input_cells) {
+        witnesses[group.input_indices[0]] = placeholder.as_bytes().pack();
+    }
+    witnesses
+}
+
+/// Sign transaction. `input_cells` are the live cells spent by the transaction; the
+/// signer resolves input locks from them instead of opening another RPC connection.
+/// Every input group is signed by its own key from `signers`.
+#[instrument(skip_all, fields(inputs = input_cells.len()))]
+pub fn sign_transaction(
+    tx: TransactionView,
+    signers: &Signers,
+    input_cells: &[&LiveCell],
+) -> Result<TransactionView> {
+    let secret_keys = signers.resolve(&group_inputs(input_cells))?;
+    let signer = SecpCkbRawKeySigner::new_with_secret_keys(secret_keys);
+    unlock(tx, Box::new(signer), input_cells)
+}
+
+/// Fill the sighash witnesses of a transaction with signatures from `signer`, failing
+/// when an input group is left unsigned
+pub fn unlock(
+    tx: TransactionView,
+    signer: Box<dyn Signer>,
+    input_cells: &[&LiveCell],
+) -> Result<TransactionView> {
+    let mut tx_dep_provider = OffchainTransactionDependencyProvider::default();
+    for cell in input_cells {
+        tx_dep_provider.cells.insert(
+            (cell.out_point.tx_hash.clone(), cell.out_point.index.value()),
+            (
+                cell.output.clone().into(),
+                cell.output_data.clone().into_bytes(),
+            ),
+        );
+    }
+
+    let script_id = ScriptId::new_type(SIGHASH_TYPE_HASH.clone());
+    let unlocker = SecpSighashUnlocker::from(signer);
+
+    let mut unlockers: HashMap<ScriptId, Box<dyn ScriptUnlocker>> = HashMap::new();
+    unlockers.insert(script_id, Box::new(unlocker));
+
+    let (signed_tx, still_locked) =
+        unlock_tx(tx, &tx_dep_provider, &unlockers).map_err(|e| Error::Signing(e.to_string()))?;
+    if !still_locked.is_empty() {
+        return Err(Error::Signing(format!(
+            "{} input group(s) left unsigned, unsupported lock script(s): {:?}",
+            still_locked.len(),
+            still_locked
+                .iter()
+                .map(|group| group.script.code_hash())
+                .collect::<Vec<_>>()
+        )));
+    }
+    Ok(signed_tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_jsonrpc_types::{CellOutput, JsonBytes, OutPoint};
+    use ckb_types::H256;
+
+    const KEY_A: &str = "d00c06bfd800d27397002dca6fb0993d5ba6399b4238b2f29ee9deb97593d2bc";
+    const KEY_B: &str = "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8";
+
+    fn cell(byte: u8, key: &str) -> LiveCell {
+        LiveCell {
+            out_point: OutPoint {
+                tx_hash: H256([byte; 32]),
+                index: 0.into(),
+            },
+            output: CellOutput {
+                capacity: 100_00000000.into(),
+                lock: get_lock_script_from_private_key(key).unwrap(),
+                type_: None,
+            },
+            output_data: JsonBytes::default(),
+        }
+    }
+
+    /// Inputs locked by A, B, A, B, with the placeholder witnesses of `build_witnesses`
+    fn interleaved() -> (Vec<LiveCell>, TransactionView) {
+        let cells = vec![
+            cell(1, KEY_A),
+            cell(2, KEY_B),
+            cell(3, KEY_A),
+            cell(4, KEY_B),
+        ];
+        let refs: Vec<&LiveCell> = cells.iter().collect();
+        let tx = TransactionView::new_advanced_builder()
+            .inputs(cells.iter().map(crate::build_cell_input))
+            .witnesses(build_witnesses(&refs))
+            .build();
+        (cells, tx)
+    }
+
+    fn secret_key(key: &str) -> secp256k1::SecretKey {
+        secp256k1::SecretKey::from_slice(&hex::decode(key).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn places_a_placeholder_at_the_first_input_of_each_group() {
+        let (cells, tx) = interleaved();
+        let refs: Vec<&LiveCell> = cells.iter().collect();
+        let groups = group_inputs(&refs);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].input_indices, [0, 2]);
+        assert_eq!(groups[1].input_indices, [1, 3]);
+
+        let witnesses: Vec<_> = tx.witnesses().into_iter().map(|w| w.raw_data()).collect();
+        let placeholder = WitnessArgs::from_slice(&witnesses[0]).unwrap();
+        assert_eq!(
+            placeholder.lock().to_opt().unwrap().raw_data(),
+            vec![0u8; SIGNATURE_SIZE]
+        );
+        assert_eq!(witnesses[1], witnesses[0]);
+        assert!(witnesses[2].is_empty());
+        assert!(witnesses[3].is_empty());
+    }
+
+    #[test]
+    fn signs_each_group_with_its_own_key() {
+        let (cells, tx) = interleaved();
+        let refs: Vec<&LiveCell> = cells.iter().collect();
+        let signers = Signers::new(&[KEY_A, KEY_B]).unwrap();
+        let signed = sign_transaction(tx, &signers, &refs).unwrap();
+        let witnesses: Vec<_> = signed
+            .witnesses()
+            .into_iter()
+            .map(|w| w.raw_data())
+            .collect();
+        for head in &witnesses[..2] {
+            let signature = WitnessArgs::from_slice(head)
+                .unwrap()
+                .lock()
+                .to_opt()
+                .unwrap();
+            assert_eq!(signature.raw_data().len(), SIGNATURE_SIZE);
+            assert_ne!(signature.raw_data(), vec![0u8; SIGNATURE_SIZE]);
+        }
+        assert_ne!(witnesses[0], witnesses[1]);
+        assert!(witnesses[2].is_empty());
+        assert!(witnesses[3].is_empty());
+    }
+
+    #[test]
+    fn fails_without_a_signer_for_a_group() {
+        let (cells, tx) = interleaved();
+        let refs: Vec<&LiveCell> = cells.iter().collect();
+
+        let signers = Signers::new(&[KEY_A]).unwrap();
+        let Err(Error::Signing(message)) = sign_transaction(tx.clone(), &signers, &refs) else {
+            panic!("a group without a key must fail");
+        };
+        assert!(message.starts_with("missing signer for inputs [1, 3]"));
+        assert!(!message.contains(KEY_B) && !message.contains(KEY_A));
+
+        let signer = SecpCkbRawKeySigner::new_with_secret_keys(vec![secret_key(KEY_A)]);
+        let Err(Error::Signing(message)) = unlock(tx, Box::new(signer), &refs) else {
+            panic!("a group left unsigned must fail");
+        };
+        assert!(message.starts_with("1 input group(s) left unsigned"));
+    }
+}
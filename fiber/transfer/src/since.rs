@@ -0,0 +1,164 @@
+use ckb_sdk::{Since, SinceType};
+use ckb_types::core::{EpochNumberWithFraction, TransactionView};
+use ckb_types::prelude::*;
+
+// Width of the value of a since, below its flags
+const VALUE_BITS: u32 = 56;
+
+/// Parse a since for the `--since` flag: `block:N`, `epoch:N` or `epoch:N+I/L` (index I
+/// of an epoch of length L), or `time:SECONDS` (a Unix timestamp, or seconds since the
+/// input was committed), each absolute unless prefixed by `relative:`, or else a raw
+/// `0x` hex value
+pub fn parse(value: &str) -> Result<Since, String> {
+    if let Some(hex) = value.strip_prefix("0x") {
+        let since = u64::from_str_radix(hex, 16)
+            .map(Since::from_raw_value)
+            .map_err(|e| format!("invalid since {:?}: {}", value, e))?;
+        if !since.flags_is_valid() {
+            return Err(format!("{} has invalid since flags", value));
+        }
+        return Ok(since);
+    }
+    let (relative, rest) = match value.strip_prefix("relative:") {
+        Some(rest) => (true, rest),
+        None => (false, value.strip_prefix("absolute:").unwrap_or(value)),
+    };
+    let invalid = || {
+        format!(
+            "invalid since {:?}, expected [relative:]block:N, [relative:]epoch:N[+I/L], \
+             [relative:]time:SECONDS or a 0x hex value",
+            value
+        )
+    };
+    let number = |n: &str| n.parse::<u64>().map_err(|_| invalid());
+    let (metric, value) = rest.split_once(':').ok_or_else(invalid)?;
+    let (since_type, value) = match metric {
+        "block" => (SinceType::BlockNumber, number(value)?),
+        "time" => (SinceType::Timestamp, number(value)?),
+        "epoch" => {
+            let (epoch, fraction) = match value.split_once('+') {
+                Some((epoch, fraction)) => {
+                    let (index, length) = fraction.split_once('/').ok_or_else(invalid)?;
+                    (number(epoch)?, Some((number(index)?, number(length)?)))
+                }
+                None => (number(value)?, None),
+            };
+            let (index, length) = fraction.unwrap_or((0, 1));
+            if epoch > EpochNumberWithFraction::NUMBER_MAXIMUM_VALUE
+                || length == 0
+                || length > EpochNumberWithFraction::LENGTH_MAXIMUM_VALUE
+                || index >= length
+            {
+                return Err(format!("{:?} is not a valid epoch", value));
+            }
+            let epoch = EpochNumberWithFraction::new(epoch, index, length);
+            (SinceType::EpochNumberWithFraction, epoch.full_value())
+        }
+        _ => return Err(invalid()),
+    };
+    if value >> VALUE_BITS != 0 {
+        return Err(format!("since {:?} is too large", value));
+    }
+    Ok(Since::new(since_type, value, relative))
+}
+
+/// A since as `parse` takes it
+pub fn describe(since: Since) -> String {
+    let prefix = if since.is_relative() { "relative:" } else { "" };
+    match since.extract_metric() {
+        Some((SinceType::BlockNumber, n)) => format!("{}block:{}", prefix, n),
+        Some((SinceType::Timestamp, n)) => format!("{}time:{}", prefix, n),
+        Some((SinceType::EpochNumberWithFraction, n)) => {
+            let epoch = EpochNumberWithFraction::from_full_value(n);
+            format!(
+                "{}epoch:{}+{}/{}",
+                prefix,
+                epoch.number(),
+                epoch.index(),
+                epoch.length()
+            )
+        }
+        None => format!("{:#x}", since.value()),
+    }
+}
+
+/// The transaction with every input spendable only once `since` is met. The since
+/// field has a fixed size, so the transaction's size and fee stay the same.
+pub fn apply(tx: TransactionView, since: Since) -> TransactionView {
+    let inputs: Vec<_> = tx
+        .inputs()
+        .into_iter()
+        .map(|input| input.as_builder().since(since.value()).build())
+        .collect();
+    tx.as_advanced_builder().set_inputs(inputs).build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_describes_each_metric() {
+        for text in [
+            "block:100",
+            "relative:block:6",
+            "time:1700000000",
+            "relative:time:3600",
+            "epoch:5+0/1",
+            "relative:epoch:1+2/3",
+        ] {
+            assert_eq!(describe(parse(text).unwrap()), text);
+        }
+        assert_eq!(describe(parse("absolute:block:7").unwrap()), "block:7");
+        assert_eq!(describe(parse("epoch:5").unwrap()), "epoch:5+0/1");
+    }
+
+    #[test]
+    fn parses_raw_values() {
+        let since = parse("0x8000000000000064").unwrap();
+        assert!(since.is_relative());
+        assert_eq!(describe(since), "relative:block:100");
+        // Reserved flag bits set
+        assert!(parse("0x0100000000000000").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        for text in [
+            "",
+            "block",
+            "block:-1",
+            "height:5",
+            "epoch:1+3/3",
+            "epoch:1+0/0",
+            "epoch:1+1",
+            "block:72057594037927936",
+            "0xzz",
+        ] {
+            assert!(parse(text).is_err(), "{} parsed", text);
+        }
+    }
+
+    #[test]
+    fn apply_sets_every_input_without_changing_the_size() {
+        use ckb_types::packed::{CellInput, OutPoint};
+        let input = CellInput::new_builder()
+            .previous_output(OutPoint::new_builder().build())
+            .build();
+        let tx = TransactionView::new_advanced_builder()
+            .inputs([input.clone(), input])
+            .build();
+        let since = parse("block:42").unwrap();
+        let applied = apply(tx.clone(), since);
+        assert_eq!(
+            applied.data().serialized_size_in_block(),
+            tx.data().serialized_size_in_block()
+        );
+        assert!(
+            applied
+                .inputs()
+                .into_iter()
+                .all(|input| Unpack::<u64>::unpack(&input.since()) == since.value())
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Lists what a snapshot holds and where it came from, in the snapshot directory
+const MANIFEST_FILE: &str = "snapshot.json";
+
+/// A file or directory of the demo's state and its copy in a snapshot
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    /// Where the state lives, as given when saving, so relative to the directory the
+    /// snapshot was saved from
+    pub path: PathBuf,
+    /// The copy, relative to the snapshot directory
+    pub copy: PathBuf,
+    /// Whether the path existed when saving; restoring deletes absent paths
+    pub present: bool,
+}
+
+impl Entry {
+    pub fn new(path: PathBuf, copy: PathBuf) -> Self {
+        let present = path.exists();
+        Entry {
+            path,
+            copy,
+            present,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    /// Unix time the snapshot was saved at
+    created_at: u64,
+    entries: Vec<Entry>,
+}
+
+/// Copy the entries that exist into a new snapshot directory, replacing an existing one
+/// only with `force`. The copies are written next to it first, so a failed save leaves
+/// no partial snapshot behind. Returns the entries recorded.
+pub fn save(dir: &Path, entries: Vec<Entry>, force: bool) -> Result<Vec<Entry>> {
+    if dir.exists() && !force {
+        return Err(Error::Snapshot(format!(
+            "{} exists, pass --force to replace it",
+            dir.display()
+        )));
+    }
+    if !entries.iter().any(|entry| entry.present) {
+        return Err(Error::Snapshot(
+            "Nothing to snapshot, bootstrap the demo first".to_string(),
+        ));
+    }
+    let mut partial = dir.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let fail = |path: &Path, e: io::Error| {
+        let _ = remove(&partial);
+        Error::Snapshot(format!("Failed to save {}: {}", path.display(), e))
+    };
+    let _ = remove(&partial);
+    for entry in entries.iter().filter(|entry| entry.present) {
+        copy_tree(&entry.path, &partial.join(&entry.copy)).map_err(|e| fail(&entry.path, e))?;
+    }
+    let manifest = Manifest {
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        entries,
+    };
+    fs::write(
+        partial.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(&manifest).unwrap(),
+    )
+    .map_err(|e| fail(Path::new(MANIFEST_FILE), e))?;
+    if dir.exists() {
+        remove(dir).map_err(|e| fail(dir, e))?;
+    }
+    fs::rename(&partial, dir).map_err(|e| fail(dir, e))?;
+    Ok(manifest.entries)
+}
+
+/// Read what a snapshot holds
+pub fn entries(dir: &Path) -> Result<Vec<Entry>> {
+    let path = dir.join(MANIFEST_FILE);
+    let content = fs::read_to_string(&path).map_err(|e| {
+        Error::Snapshot(format!(
+            "{} is not a snapshot, failed to read {}: {}",
+            dir.display(),
+            path.display(),
+            e
+        ))
+    })?;
+    let manifest: Manifest = serde_json::from_str(&content)
+        .map_err(|e| Error::Snapshot(format!("Invalid {}: {}", path.display(), e)))?;
+    Ok(manifest.entries)
+}
+
+/// Replace the state of every entry with its copy in the snapshot, deleting the paths
+/// that were absent when saving
+pub fn restore(dir: &Path, entries: &[Entry]) -> Result<()> {
+    for entry in entries {
+        let fail = |e: io::Error| {
+            Error::Snapshot(format!(
+                "Failed to restore {}, restore again or reset: {}",
+                entry.path.display(),
+                e
+            ))
+        };
+        if entry.path.exists() {
+            remove(&entry.path).map_err(fail)?;
+        }
+        if entry.present {
+            copy_tree(&dir.join(&entry.copy), &entry.path).map_err(fail)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy a file, or a directory with everything in it, creating the parents of `to`
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if !from.is_dir() {
+        fs::copy(from, to)?;
+        return Ok(());
+    }
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
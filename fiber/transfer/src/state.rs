@@ -0,0 +1,75 @@
+use crate::error::{Error, Result};
+use ckb_types::H256;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A transaction sent by `setup`, recorded as soon as it is broadcast
+#[derive(Serialize, Deserialize)]
+pub struct SentTransaction {
+    pub tx_hash: H256,
+    /// What the transaction was sent for, e.g. `setup` or `setup (per node)`
+    pub purpose: String,
+    /// Names of the accounts it funds
+    pub recipients: Vec<String>,
+}
+
+/// Transactions sent by funding runs, persisted so a re-run after a crash checks them
+/// on chain instead of funding the same accounts twice
+#[derive(Serialize, Deserialize)]
+pub struct RunState {
+    #[serde(skip)]
+    path: PathBuf,
+    pub sent: Vec<SentTransaction>,
+}
+
+impl RunState {
+    /// Start a fresh state that will be saved to `path`
+    pub fn new(path: &Path) -> Self {
+        RunState {
+            path: path.to_path_buf(),
+            sent: Vec::new(),
+        }
+    }
+
+    /// Load the state file, or start fresh if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let mut state: RunState = serde_json::from_str(&content).map_err(|e| {
+                    Error::Config(format!("Invalid state file {}: {}", path.display(), e))
+                })?;
+                state.path = path.to_path_buf();
+                Ok(state)
+            }
+            Err(_) => Ok(RunState::new(path)),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let content = serde_json::to_string_pretty(self).unwrap();
+        fs::write(&self.path, content).map_err(Error::io(format!(
+            "Failed to write state file {}",
+            self.path.display()
+        )))
+    }
+
+    /// Record a broadcast transaction and save the state right away, before waiting
+    /// for it to commit
+    pub fn record(&mut self, tx_hash: H256, purpose: &str, recipients: Vec<String>) -> Result<()> {
+        self.sent.push(SentTransaction {
+            tx_hash,
+            purpose: purpose.to_string(),
+            recipients,
+        });
+        self.save()
+    }
+
+    /// The latest transaction sent to the account named `name`
+    pub fn sent_to(&self, name: &str) -> Option<&SentTransaction> {
+        self.sent
+            .iter()
+            .rev()
+            .find(|tx| tx.recipients.iter().any(|r| r == name))
+    }
+}
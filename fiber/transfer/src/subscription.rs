@@ -0,0 +1,170 @@
+use crate::rpc::HttpSettings;
+use ckb_jsonrpc_types::HeaderView;
+use serde_json::{Value, json};
+use std::env;
+use std::net::TcpStream;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+use tungstenite::Connector;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+// Delay before reconnecting a dropped subscription
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// Silence after which the connection is taken for dead and reopened
+const READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// New tip headers pushed by the node's `new_tip_header` subscription over WebSocket,
+/// for waiters that react to the next block instead of polling at a fixed interval
+pub struct TipEvents {
+    state: watch::Sender<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Headers received so far
+    received: u64,
+    connected: bool,
+}
+
+/// The tip subscription of this run at CKB_WS_URL, e.g. ws://127.0.0.1:28114, started
+/// on the first call. `None` without CKB_WS_URL.
+pub fn tip_events() -> Option<&'static TipEvents> {
+    static EVENTS: OnceLock<Option<Arc<TipEvents>>> = OnceLock::new();
+    EVENTS
+        .get_or_init(|| {
+            let url = env::var("CKB_WS_URL").ok()?;
+            let events = Arc::new(TipEvents {
+                state: watch::Sender::default(),
+            });
+            let subscriber = events.clone();
+            thread::spawn(move || subscriber.run(&url));
+            Some(events)
+        })
+        .as_deref()
+}
+
+impl TipEvents {
+    /// Headers received so far, to wait for the ones after with `wait_after`
+    pub fn received(&self) -> u64 {
+        self.state.borrow().received
+    }
+
+    /// Wait until more than `received` headers arrived, for at most `timeout`. False
+    /// when none did, or at once when the subscription is down, so the caller polls
+    /// on its own instead.
+    pub async fn wait_after(&self, received: u64, timeout: Duration) -> bool {
+        let mut state = self.state.subscribe();
+        let arrived = state.wait_for(|state| state.received > received || !state.connected);
+        matches!(
+            tokio::time::timeout(timeout, arrived).await,
+            Ok(Ok(state)) if state.received > received
+        )
+    }
+
+    /// Keep the subscription open, reconnecting when it drops
+    fn run(&self, url: &str) {
+        let mut reported = false;
+        loop {
+            match self.subscribe(url) {
+                Ok(mut socket) => {
+                    info!("Subscribed to new tip headers at {}", url);
+                    reported = false;
+                    self.set_connected(true);
+                    let error = self.receive(&mut socket);
+                    warn!("Tip subscription at {} dropped: {}", url, error);
+                }
+                // Reported once until the subscription comes back, waiters poll meanwhile
+                Err(e) if !reported => {
+                    warn!(
+                        "Failed to subscribe to new tip headers at {}, polling instead: {}",
+                        url, e
+                    );
+                    reported = true;
+                }
+                Err(e) => debug!("Tip subscription at {} still failing: {}", url, e),
+            }
+            self.set_connected(false);
+            thread::sleep(RECONNECT_DELAY);
+        }
+    }
+
+    /// Open the WebSocket, through RPC_PROXY when one is set, and subscribe
+    fn subscribe(&self, url: &str) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, String> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+        let host = parsed.host_str().ok_or("no host in the URL")?;
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let settings = HttpSettings::from_env().map_err(|e| e.to_string())?;
+        let stream = match settings.tunnel(host, port) {
+            Some(stream) => stream.map_err(|e| format!("proxy: {}", e))?,
+            None => {
+                let address = host.trim_start_matches('[').trim_end_matches(']');
+                TcpStream::connect((address, port)).map_err(|e| e.to_string())?
+            }
+        };
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        // The scheme decides on TLS, `wss://` is wrapped in it through the proxy too
+        let connector = settings.tls_connector().map_err(|e| e.to_string())?;
+        let (mut socket, _) = tungstenite::client_tls_with_config(
+            url,
+            stream,
+            None,
+            Some(Connector::NativeTls(connector)),
+        )
+        .map_err(|e| e.to_string())?;
+        let request = json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "method": "subscribe",
+            "params": ["new_tip_header"],
+        });
+        socket
+            .send(Message::text(request.to_string()))
+            .map_err(|e| e.to_string())?;
+        Ok(socket)
+    }
+
+    /// Record the headers of the subscription's notifications until the connection
+    /// fails, returning why
+    fn receive(&self, socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> String {
+        loop {
+            let text = match socket.read() {
+                Ok(Message::Text(text)) => text,
+                Ok(Message::Close(frame)) => {
+                    return format!("closed by the node ({:?})", frame);
+                }
+                Ok(_) => continue,
+                Err(e) => return e.to_string(),
+            };
+            let message: Value = match serde_json::from_str(text.as_str()) {
+                Ok(message) => message,
+                Err(e) => return format!("invalid message: {}", e),
+            };
+            if let Some(error) = message.get("error") {
+                return format!("subscribe failed: {}", error);
+            }
+            // The answer to `subscribe` carries the subscription id, notifications the
+            // header as a JSON string
+            let Some(header) = message.pointer("/params/result").and_then(Value::as_str) else {
+                continue;
+            };
+            match serde_json::from_str::<HeaderView>(header) {
+                Ok(header) => {
+                    let number = header.inner.number.value();
+                    debug!("New tip header {} ({:#x})", number, header.hash);
+                    self.state.send_modify(|state| state.received += 1);
+                }
+                Err(e) => return format!("invalid header: {}", e),
+            }
+        }
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.state.send_modify(|state| state.connected = connected);
+    }
+}
@@ -0,0 +1,443 @@
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Once;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How long a child may take to exit after SIGTERM before it is killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+// Log filter of the Fiber nodes unless FNN_LOG is set, as in the container's start.sh
+const FNN_LOG_FILTER: &str = "info,fnn::watchtower::actor=warn";
+// Password of the nodes' encrypted keys unless FIBER_SECRET_KEY_PASSWORD is set, as in
+// the container's start.sh
+const FIBER_SECRET_KEY_PASSWORD: &str = "12345678";
+
+/// Pid file of a Fiber node run by `run-nodes` or `up`, in the node's directory
+pub const FNN_PID_FILE: &str = "fnn.pid";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static HANDLE_INTERRUPTS: Once = Once::new();
+
+/// Catch Ctrl-C and SIGTERM from here on, so supervising loops can stop their children
+/// instead of the process dying with them still running. A second signal exits at once.
+pub fn handle_interrupts() {
+    HANDLE_INTERRUPTS.call_once(|| {
+        let installed = ctrlc::set_handler(|| {
+            if INTERRUPTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+        });
+        if let Err(e) = installed {
+            warn!(
+                "Failed to handle interrupts, children may outlive this tool: {}",
+                e
+            );
+        }
+    });
+}
+
+/// Whether Ctrl-C or SIGTERM arrived since `handle_interrupts`
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Start `command` with its stdout and stderr in `log_path`, truncated unless `append`
+pub fn spawn_logged(
+    command: &mut Command,
+    name: &str,
+    log_path: &Path,
+    append: bool,
+) -> std::io::Result<Child> {
+    let log = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(log_path)?;
+    let stderr = log.try_clone()?;
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(log)
+        .stderr(stderr)
+        .spawn()?;
+    info!(
+        "Started {} (pid {}), logging to {}",
+        name,
+        child.id(),
+        log_path.display()
+    );
+    Ok(child)
+}
+
+/// Ask a running child to exit with SIGTERM, killing it when it doesn't in time
+pub fn stop(name: &str, child: &mut Child) {
+    if !child.try_wait().is_ok_and(|status| status.is_none()) {
+        let _ = child.wait();
+        return;
+    }
+    info!("Stopping {}", name);
+    #[cfg(unix)]
+    {
+        // The pid is our own unreaped child
+        signal(child.id(), libc::SIGTERM);
+        let started = Instant::now();
+        while started.elapsed() < STOP_TIMEOUT {
+            if !child.try_wait().is_ok_and(|status| status.is_none()) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        warn!(
+            "{} did not exit within {}s, killing it",
+            name,
+            STOP_TIMEOUT.as_secs()
+        );
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Send `signal` to a process, returning whether it exists. Signal 0 only checks.
+#[cfg(unix)]
+fn signal(pid: u32, signal: i32) -> bool {
+    // SAFETY: kill has no memory effects
+    unsafe { libc::kill(pid as libc::pid_t, signal) == 0 }
+}
+
+/// A file recording a running child and the process supervising it, so `reset` can
+/// stop them from another run. Removed when dropped.
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create(path: &Path, child: &Child) -> Result<Self> {
+        std::fs::write(path, format!("{}\n{}\n", child.id(), std::process::id()))
+            .map_err(|e| Error::Node(format!("Failed to write {}: {}", path.display(), e)))?;
+        Ok(PidFile(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// The child and supervisor pids of a pid file, `None` once the file is gone
+fn read_pid_file(path: &Path) -> Result<Option<(u32, u32)>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(Error::Node(format!(
+                "Failed to read {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+    let pids = content
+        .lines()
+        .map(|line| line.trim().parse())
+        .collect::<std::result::Result<Vec<u32>, _>>()
+        .map_err(|e| Error::Node(format!("Invalid pid file {}: {}", path.display(), e)))?;
+    let [child, supervisor] = pids[..] else {
+        return Err(Error::Node(format!(
+            "Invalid pid file {}: expected two pids",
+            path.display()
+        )));
+    };
+    Ok(Some((child, supervisor)))
+}
+
+/// Whether the child recorded in a pid file still runs
+pub fn recorded_running(path: &Path) -> Result<bool> {
+    let Some((child, _)) = read_pid_file(path)? else {
+        return Ok(false);
+    };
+    #[cfg(unix)]
+    return Ok(signal(child, 0));
+    // Without a way to check, a pid file left behind means running
+    #[cfg(not(unix))]
+    {
+        let _ = child;
+        Ok(true)
+    }
+}
+
+/// Stop the child recorded in a pid file: ask its supervisor to stop it, so a restart
+/// policy doesn't bring it back, or signal the child itself when the supervisor is
+/// gone. Kills the child when it doesn't exit in time, then removes the file.
+pub fn stop_recorded(path: &Path) -> Result<()> {
+    // Gone when its supervisor stopped it already, e.g. along with another child
+    let Some((child, supervisor)) = read_pid_file(path)? else {
+        return Ok(());
+    };
+    #[cfg(unix)]
+    if signal(child, 0) {
+        if supervisor != std::process::id() && signal(supervisor, libc::SIGTERM) {
+            info!("Stopping process {} supervising {}", supervisor, child);
+        } else {
+            info!("Stopping process {}", child);
+            signal(child, libc::SIGTERM);
+        }
+        // A supervisor stops its children one after another
+        let started = Instant::now();
+        while signal(child, 0) && started.elapsed() < STOP_TIMEOUT * 3 {
+            thread::sleep(Duration::from_millis(100));
+        }
+        if signal(child, libc::SIGKILL) {
+            warn!("Process {} did not exit in time, killed it", child);
+        }
+    }
+    #[cfg(not(unix))]
+    warn!(
+        "Not stopping process {} (supervised by {}) on this platform",
+        child, supervisor
+    );
+    let _ = std::fs::remove_file(path);
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum RestartPolicy {
+    /// A node that exits ends the run
+    Never,
+    /// Restart nodes that exit with an error or a signal
+    OnFailure,
+    /// Restart nodes whenever they exit
+    Always,
+}
+
+/// How to run the Fiber nodes
+pub struct FnnSettings<'a> {
+    /// `fnn` binary, looked up in PATH unless it is a path
+    pub fnn_bin: &'a Path,
+    pub restart: RestartPolicy,
+    /// Restarts of a node before the run fails
+    pub max_restarts: u32,
+    pub restart_delay: Duration,
+    /// Directory of the node logs, each node's own directory when unset
+    pub log_dir: Option<&'a Path>,
+}
+
+/// A Fiber node to run: `fnn -c <dir>/config.yml -d <dir>`, as written by `gen-config`
+pub struct FnnNode {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+impl FnnNode {
+    /// File name of the node's log
+    pub fn log_file(&self) -> String {
+        format!("{}.log", self.name.to_lowercase())
+    }
+}
+
+struct Running {
+    node: FnnNode,
+    log_path: PathBuf,
+    child: Option<(Child, PidFile)>,
+    restarts: u32,
+    /// When a node that exited is started again
+    restart_at: Option<Instant>,
+}
+
+/// Fiber nodes running as child processes of this tool, restarted by the policy when
+/// they exit. Dropping it stops them.
+pub struct FiberNodes<'a> {
+    settings: &'a FnnSettings<'a>,
+    nodes: Vec<Running>,
+}
+
+impl<'a> FiberNodes<'a> {
+    /// Start every node, failing when one of them can't be started
+    pub fn start(nodes: Vec<FnnNode>, settings: &'a FnnSettings<'a>) -> Result<Self> {
+        let mut running = FiberNodes {
+            settings,
+            nodes: Vec::new(),
+        };
+        for node in nodes {
+            let config = node.dir.join("config.yml");
+            if !config.exists() {
+                return Err(Error::Node(format!(
+                    "{} has no {}, write it with gen-config",
+                    node.name,
+                    config.display()
+                )));
+            }
+            let log_dir = settings.log_dir.unwrap_or(&node.dir);
+            let log_path = log_dir.join(node.log_file());
+            running.nodes.push(Running {
+                node,
+                log_path,
+                child: None,
+                restarts: 0,
+                restart_at: None,
+            });
+        }
+        if let Some(dir) = settings.log_dir {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| Error::Node(format!("Failed to create {}: {}", dir.display(), e)))?;
+        }
+        for index in 0..running.nodes.len() {
+            running.spawn(index, false)?;
+        }
+        Ok(running)
+    }
+
+    fn spawn(&mut self, index: usize, append: bool) -> Result<()> {
+        let settings = self.settings;
+        let running = &mut self.nodes[index];
+        let node = &running.node;
+        let password = std::env::var("FIBER_SECRET_KEY_PASSWORD")
+            .unwrap_or_else(|_| FIBER_SECRET_KEY_PASSWORD.to_string());
+        let log_filter = std::env::var("FNN_LOG").unwrap_or_else(|_| FNN_LOG_FILTER.to_string());
+        let mut command = Command::new(settings.fnn_bin);
+        command
+            .arg("-c")
+            .arg("config.yml")
+            .arg("-d")
+            .arg(".")
+            // Relative paths of the config, e.g. the chain spec, resolve in the node's
+            // directory
+            .current_dir(&node.dir)
+            .env("RUST_LOG", log_filter)
+            .env("FIBER_SECRET_KEY_PASSWORD", password);
+        let child =
+            spawn_logged(&mut command, &node.name, &running.log_path, append).map_err(|e| {
+                Error::Node(format!(
+                    "Failed to start {} ({}): {}",
+                    node.name,
+                    settings.fnn_bin.display(),
+                    e
+                ))
+            })?;
+        let pid_file = match PidFile::create(&node.dir.join(FNN_PID_FILE), &child) {
+            Ok(pid_file) => pid_file,
+            Err(e) => {
+                let mut child = child;
+                stop(&node.name, &mut child);
+                return Err(e);
+            }
+        };
+        running.child = Some((child, pid_file));
+        running.restart_at = None;
+        Ok(())
+    }
+
+    /// Keep the nodes running, restarting them by the policy, until an interrupt or
+    /// every node stopped for good. Fails when a node exits that may not be restarted.
+    pub fn supervise(&mut self) -> Result<()> {
+        handle_interrupts();
+        loop {
+            if interrupted() {
+                info!("Interrupted, stopping the Fiber nodes");
+                return Ok(());
+            }
+            if !self.poll()? {
+                info!("Every Fiber node exited");
+                return Ok(());
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Check on every node once, restarting them by the policy. Returns whether any
+    /// node is still running or due to restart.
+    pub fn poll(&mut self) -> Result<bool> {
+        for index in 0..self.nodes.len() {
+            self.check(index)?;
+        }
+        Ok(self
+            .nodes
+            .iter()
+            .any(|node| node.child.is_some() || node.restart_at.is_some()))
+    }
+
+    fn check(&mut self, index: usize) -> Result<()> {
+        let settings = self.settings;
+        let running = &mut self.nodes[index];
+        if let Some(at) = running.restart_at {
+            if Instant::now() >= at {
+                running.restarts += 1;
+                info!(
+                    "Restarting {} ({}/{})",
+                    running.node.name, running.restarts, settings.max_restarts
+                );
+                return self.spawn(index, true);
+            }
+            return Ok(());
+        }
+        let Some((child, _)) = &mut running.child else {
+            return Ok(());
+        };
+        let status = match child.try_wait() {
+            Ok(Some(status)) => status,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                return Err(Error::Node(format!(
+                    "Failed to check on {}: {}",
+                    running.node.name, e
+                )));
+            }
+        };
+        running.child = None;
+        // The terminal's Ctrl-C reaches the nodes too, they are not crashing
+        if interrupted() {
+            return Ok(());
+        }
+        let name = &running.node.name;
+        let log = running.log_path.display();
+        if !restarts(settings.restart, status) {
+            if status.success() {
+                warn!("{} exited, see {}", name, log);
+                return Ok(());
+            }
+            return Err(Error::Node(format!(
+                "{} exited ({}), see {}",
+                name, status, log
+            )));
+        }
+        if running.restarts >= settings.max_restarts {
+            return Err(Error::Node(format!(
+                "{} exited ({}) after {} restarts, see {}",
+                name, status, running.restarts, log
+            )));
+        }
+        error!(
+            "{} exited ({}), restarting in {}s, see {}",
+            name,
+            status,
+            settings.restart_delay.as_secs(),
+            log
+        );
+        running.restart_at = Some(Instant::now() + settings.restart_delay);
+        Ok(())
+    }
+}
+
+/// Whether the policy restarts a node that exited with `status`. With `never`, only a
+/// clean exit is not an error.
+fn restarts(policy: RestartPolicy, status: ExitStatus) -> bool {
+    match policy {
+        RestartPolicy::Never => false,
+        RestartPolicy::OnFailure => !status.success(),
+        RestartPolicy::Always => true,
+    }
+}
+
+impl Drop for FiberNodes<'_> {
+    fn drop(&mut self) {
+        for running in self.nodes.iter_mut().rev() {
+            if let Some((child, _)) = &mut running.child {
+                stop(&running.node.name, child);
+            }
+        }
+    }
+}
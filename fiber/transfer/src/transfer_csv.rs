@@ -0,0 +1,212 @@
+use crate::amount::{CKB_DECIMALS, format_amount};
+use crate::network::Token;
+use ckb_jsonrpc_types::Script as JsonScript;
+use ckb_types::core::TransactionView;
+use ckb_types::packed::Script;
+use ckb_types::prelude::*;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::OnceLock;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use tracing::warn;
+
+const HEADER: &str = "timestamp,tx_hash,output_index,lock_args,asset,amount,base_units,change";
+
+// Type scripts of the configured tokens with their names and decimals
+static TOKENS: OnceLock<Vec<(Script, String, u8)>> = OnceLock::new();
+
+/// The CSV file broadcast transactions are exported to, set by TRANSFER_CSV
+fn path() -> Option<String> {
+    env::var("TRANSFER_CSV")
+        .ok()
+        .filter(|path| !path.is_empty())
+}
+
+pub fn enabled() -> bool {
+    path().is_some()
+}
+
+/// Name the outputs of these tokens by the token, the others by their UDT args
+pub fn set_tokens(tokens: &[Token]) {
+    let _ = TOKENS.set(
+        tokens
+            .iter()
+            .map(|token| {
+                let script = crate::get_sudt_type_script(&token.script);
+                (script.into(), token.name.clone(), token.decimals)
+            })
+            .collect(),
+    );
+}
+
+/// Append a row per output of a broadcast `tx` to the CSV file, when exporting: its
+/// asset (CKB for plain cells, else the token) and amount, the lock args it goes to
+/// and whether it is change back to `sender_lock`. A failure to write is warned
+/// about and never fails the transfer.
+pub fn record(tx: &TransactionView, sender_lock: &JsonScript) {
+    let Some(path) = path() else {
+        return;
+    };
+    let timestamp = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default();
+    let rows = rows(
+        tx,
+        sender_lock,
+        TOKENS.get().map_or(&[], Vec::as_slice),
+        &timestamp,
+    );
+
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| {
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "{}", HEADER)?;
+            }
+            file.write_all(rows.as_bytes())
+        });
+    if let Err(e) = written {
+        warn!(
+            "Failed to export transaction {:#x} to {}: {}",
+            tx.hash(),
+            path,
+            e
+        );
+    }
+}
+
+/// The CSV rows of the outputs of `tx`, naming the outputs of `tokens` by the token
+fn rows(
+    tx: &TransactionView,
+    sender_lock: &JsonScript,
+    tokens: &[(Script, String, u8)],
+    timestamp: &str,
+) -> String {
+    let tx_hash = format!("{:#x}", tx.hash());
+    let mut rows = String::new();
+    for (index, (output, data)) in tx.outputs_with_data_iter().enumerate() {
+        let (asset, amount, decimals) = match output.type_().to_opt() {
+            None => {
+                let capacity: u64 = output.capacity().unpack();
+                ("CKB".to_string(), u128::from(capacity), CKB_DECIMALS)
+            }
+            Some(type_script) => {
+                let amount = crate::parse_sudt_amount(&data);
+                match tokens.iter().find(|(script, _, _)| *script == type_script) {
+                    Some((_, name, decimals)) => (name.clone(), amount, *decimals),
+                    None => (
+                        format!("UDT 0x{}", hex::encode(type_script.args().raw_data())),
+                        amount,
+                        0,
+                    ),
+                }
+            }
+        };
+        let fields = [
+            timestamp.to_string(),
+            tx_hash.clone(),
+            index.to_string(),
+            format!("0x{}", hex::encode(output.lock().args().raw_data())),
+            asset,
+            format_amount(amount, decimals),
+            amount.to_string(),
+            (JsonScript::from(output.lock()) == *sender_lock).to_string(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| quote(field)).collect();
+        rows.push_str(&fields.join(","));
+        rows.push('\n');
+    }
+    rows
+}
+
+/// A CSV field, quoted when it holds a separator, quote or line break
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_jsonrpc_types::{JsonBytes, ScriptHashType};
+    use ckb_types::core::Capacity;
+    use ckb_types::packed::CellOutput;
+    use ckb_types::{H256, h256};
+
+    fn script(code_hash: H256, args: &[u8]) -> JsonScript {
+        JsonScript {
+            code_hash,
+            hash_type: ScriptHashType::Type,
+            args: JsonBytes::from_vec(args.to_vec()),
+        }
+    }
+
+    fn output(lock: &JsonScript, type_: Option<&JsonScript>, shannons: u64) -> CellOutput {
+        CellOutput::new_builder()
+            .lock(Script::from(lock.clone()))
+            .type_(type_.cloned().map(Script::from).pack())
+            .capacity(Capacity::shannons(shannons).pack())
+            .build()
+    }
+
+    #[test]
+    fn quotes_only_fields_that_need_it() {
+        assert_eq!(quote("0x1234"), "0x1234");
+        assert_eq!(quote("UDT 0xab"), "UDT 0xab");
+        assert_eq!(quote("1,5"), "\"1,5\"");
+        assert_eq!(quote("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(quote("two\nlines"), "\"two\nlines\"");
+        assert_eq!(quote("cr\r"), "\"cr\r\"");
+    }
+
+    #[test]
+    fn rows_name_assets_and_mark_change() {
+        let lock_hash = h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8");
+        let sudt_hash = h256!("0xc5e5dcf215925f7ef4dfaf5f4b4f105bc321c02776d6e7d52a1db3fcd9d011a4");
+        let sender = script(lock_hash.clone(), &[1; 20]);
+        let recipient = script(lock_hash, &[2; 20]);
+        let token = script(sudt_hash.clone(), &[3; 32]);
+        let other_token = script(sudt_hash, &[4; 32]);
+        let tx = TransactionView::new_advanced_builder()
+            .output(output(&recipient, None, 100_00000000))
+            .output_data(ckb_types::packed::Bytes::default())
+            .output(output(&recipient, Some(&token), 142_00000000))
+            .output_data(1_050u128.to_le_bytes().to_vec().pack())
+            .output(output(&sender, Some(&other_token), 142_00000000))
+            .output_data(7u128.to_le_bytes().to_vec().pack())
+            .output(output(&sender, None, 61_50000000))
+            .output_data(ckb_types::packed::Bytes::default())
+            .build();
+        let tokens = [(Script::from(token), "RUSD".to_string(), 2)];
+
+        let rows = rows(&tx, &sender, &tokens, "2026-10-15T00:00:00Z");
+        let fields: Vec<Vec<&str>> = rows
+            .lines()
+            .map(|row| row.split(',').skip(2).collect())
+            .collect();
+        let recipient_args = format!("0x{}", hex::encode([2; 20]));
+        let sender_args = format!("0x{}", hex::encode([1; 20]));
+        let other_asset = format!("UDT 0x{}", hex::encode([4; 32]));
+        assert_eq!(
+            fields,
+            [
+                vec!["0", &recipient_args, "CKB", "100", "10000000000", "false"],
+                vec!["1", &recipient_args, "RUSD", "10.5", "1050", "false"],
+                vec!["2", &sender_args, &other_asset, "7", "7", "true"],
+                vec!["3", &sender_args, "CKB", "61.5", "6150000000", "true"],
+            ]
+        );
+        let tx_hash = format!("{:#x}", tx.hash());
+        assert!(
+            rows.lines()
+                .all(|row| { row.starts_with(&format!("2026-10-15T00:00:00Z,{},", tx_hash)) })
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use crate::report::Report;
+use crate::rpc::HttpSettings;
+use serde_json::{Value, json};
+use std::env;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+// Attempts at delivering a notification to one webhook, retried after a second and then
+// twice as long each time
+const WEBHOOK_MAX_ATTEMPTS: u32 = 3;
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Post a notification of `event` to every webhook in WEBHOOK_URLS (comma-separated),
+/// unless WEBHOOK_EVENTS lists the events to send and leaves it out. Slack incoming
+/// webhooks get `text` as their message; other URLs get the JSON of `fields` with the
+/// event, the time in Unix milliseconds, the process id and `text`. A webhook that
+/// can't be reached is warned about and never fails the run.
+pub fn notify(event: &str, text: &str, fields: Value) {
+    let Ok(urls) = env::var("WEBHOOK_URLS") else {
+        return;
+    };
+    if let Ok(events) = env::var("WEBHOOK_EVENTS")
+        && !events.split(',').any(|e| e.trim() == event)
+    {
+        debug!(
+            "Not notifying webhooks of {}, left out of WEBHOOK_EVENTS",
+            event
+        );
+        return;
+    }
+
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let mut payload = json!({
+        "event": event,
+        "time_ms": millis,
+        "pid": process::id(),
+        "text": text,
+    });
+    if let (Value::Object(payload), Value::Object(fields)) = (&mut payload, fields) {
+        payload.extend(fields);
+    }
+    let slack_message = json!({ "text": text });
+
+    let http = match HttpSettings::from_env().and_then(|s| s.shared_client("webhook")) {
+        Ok(http) => http,
+        Err(e) => {
+            warn!("Not notifying webhooks of {}: {}", event, e);
+            return;
+        }
+    };
+    for url in urls.split(',').map(str::trim).filter(|url| !url.is_empty()) {
+        let body = if url.starts_with("https://hooks.slack.com/") {
+            &slack_message
+        } else {
+            &payload
+        };
+        let mut delay = WEBHOOK_RETRY_DELAY;
+        for attempt in 1..=WEBHOOK_MAX_ATTEMPTS {
+            match http
+                .post(url)
+                .json(body)
+                .send()
+                .and_then(|response| response.error_for_status())
+            {
+                Ok(_) => {
+                    info!("Notified {} of {}", redact(url), event);
+                    break;
+                }
+                Err(e) if attempt < WEBHOOK_MAX_ATTEMPTS => {
+                    debug!(
+                        "Webhook {} failed (attempt {}/{}), retrying in {}s: {}",
+                        redact(url),
+                        attempt,
+                        WEBHOOK_MAX_ATTEMPTS,
+                        delay.as_secs(),
+                        e.without_url()
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(e) => warn!(
+                    "Failed to notify {} of {}: {}",
+                    redact(url),
+                    event,
+                    e.without_url()
+                ),
+            }
+        }
+    }
+}
+
+/// Notify the webhooks that `command` (`up` or `setup`) finished bootstrapping, with
+/// its report: `bootstrap_finished` when all went well, `stage_timeout` when a stage
+/// ran out of time and `bootstrap_failed` otherwise
+pub fn bootstrap_done(command: &str, report: &Report, timed_out: bool) {
+    let status = report.status();
+    let event = match status {
+        "OK" => "bootstrap_finished",
+        _ if timed_out => "stage_timeout",
+        _ => "bootstrap_failed",
+    };
+    let mut text = format!(
+        "Fiber demo `{}` {} in {}s",
+        command,
+        status,
+        report.elapsed().as_secs()
+    );
+    if let Some(error) = report.error() {
+        text.push_str(&format!(": {}", error));
+    }
+    notify(
+        event,
+        &text,
+        json!({ "command": command, "status": status, "report": report.to_json() }),
+    );
+}
+
+/// Notify the webhooks that the transfer-type `command` failed with `error`
+pub fn transfer_failed(command: &str, error: &str) {
+    notify(
+        "transfer_failed",
+        &format!("Fiber demo `{}` failed: {}", command, error),
+        json!({ "command": command, "status": "FAILED", "error": error }),
+    );
+}
+
+/// The URL without its path, which holds the secret of Slack and most other webhooks
+fn redact(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => format!(
+            "{}://{}{}/...",
+            url.scheme(),
+            url.host_str().unwrap_or(""),
+            url.port()
+                .map(|port| format!(":{}", port))
+                .unwrap_or_default()
+        ),
+        Err(_) => "an invalid webhook URL".to_string(),
+    }
+}